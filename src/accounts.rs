@@ -0,0 +1,573 @@
+use crate::common::{data_file, load_json, prepend_prefix, save_json, Backend};
+use crate::i18n::{tr, trf};
+use crate::settings::Settings;
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long a link-token stays valid
+const LINK_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [
+        (
+            "link obs USERNAME [BACKEND]",
+            "Link your Matrix user to an OBS account (needs verification).",
+        ),
+        (
+            "link obs verify",
+            "Finish linking, after putting the token into your home project.",
+        ),
+        ("whoami", "Show which OBS accounts you are linked to."),
+        (
+            "unlink [BACKEND]",
+            "Remove the link to your OBS account(s).",
+        ),
+    ];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+/// Which OBS account a Matrix user has on a backend
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ObsAccount {
+    pub matrix_user: String,
+    pub backend: String,
+    pub obs_user: String,
+}
+
+/// Mapping of Matrix users to OBS accounts, shared between all handlers.
+/// Accounts from the config are fixed, linked ones are stored in the XDG data-directory.
+#[derive(Clone, Default)]
+pub struct Accounts {
+    configured: Arc<Vec<ObsAccount>>,
+    linked: Arc<Mutex<Vec<ObsAccount>>>,
+    path: Option<PathBuf>,
+}
+
+impl Accounts {
+    pub fn new(configured: Vec<ObsAccount>) -> Self {
+        let path = data_file("accounts.json");
+        let linked: Vec<ObsAccount> = load_json(&path);
+        println!("Loaded {} linked OBS accounts", linked.len());
+
+        Accounts {
+            configured: Arc::new(configured),
+            linked: Arc::new(Mutex::new(linked)),
+            path,
+        }
+    }
+
+    /// Calls f with all known accounts, linked ones first
+    fn find<F: Fn(&ObsAccount) -> bool>(&self, f: F) -> Option<ObsAccount> {
+        let linked = self.linked.lock().ok()?;
+        linked
+            .iter()
+            .chain(self.configured.iter())
+            .find(|x| f(x))
+            .cloned()
+    }
+
+    pub fn obs_user(&self, matrix_user: &str, backend: &str) -> Option<String> {
+        self.find(|x| x.matrix_user == matrix_user && x.backend == backend)
+            .map(|x| x.obs_user)
+    }
+
+    /// Reverse lookup, used to address people in notifications
+    pub fn matrix_user(&self, obs_user: &str, backend: &str) -> Option<String> {
+        self.find(|x| x.obs_user == obs_user && x.backend == backend)
+            .map(|x| x.matrix_user)
+    }
+
+    /// All accounts of a Matrix user, and whether they come from the config
+    pub fn accounts_of(&self, matrix_user: &str) -> Vec<(ObsAccount, bool)> {
+        let mut res = Vec::new();
+        if let Ok(linked) = self.linked.lock() {
+            res.extend(
+                linked
+                    .iter()
+                    .filter(|x| x.matrix_user == matrix_user)
+                    .map(|x| (x.clone(), false)),
+            );
+        }
+        res.extend(
+            self.configured
+                .iter()
+                .filter(|x| x.matrix_user == matrix_user)
+                .map(|x| (x.clone(), true)),
+        );
+        res
+    }
+
+    /// Replaces an existing link of this Matrix user on the same backend
+    pub fn link(&self, account: ObsAccount) {
+        if let Ok(mut linked) = self.linked.lock() {
+            linked.retain(|x| {
+                !(x.matrix_user == account.matrix_user && x.backend == account.backend)
+            });
+            linked.push(account);
+            save_json(&self.path, &*linked);
+        }
+    }
+
+    /// Returns how many links got removed
+    pub fn unlink(&self, matrix_user: &str, backend: Option<&str>) -> usize {
+        let mut linked = match self.linked.lock() {
+            Ok(x) => x,
+            Err(_) => return 0,
+        };
+        let before = linked.len();
+        linked.retain(|x| {
+            x.matrix_user != matrix_user || backend.map(|b| x.backend != b).unwrap_or(false)
+        });
+        let removed = before - linked.len();
+        if removed > 0 {
+            save_json(&self.path, &*linked);
+        }
+        removed
+    }
+}
+
+struct PendingLink {
+    backend: usize,
+    obs_user: String,
+    token: String,
+    since: Instant,
+}
+
+/// Handles link, whoami and unlink
+pub struct AccountHandler {
+    prefix: Option<String>,
+    settings: Settings,
+    backends: Vec<Backend>,
+    accounts: Accounts,
+    /// Links waiting for verification, per Matrix user
+    pending: HashMap<String, PendingLink>,
+}
+
+fn generate_token(matrix_user: &str) -> String {
+    // RandomState is seeded randomly, which is good enough for a short-lived token
+    let mut hasher = RandomState::new().build_hasher();
+    matrix_user.hash(&mut hasher);
+    SystemTime::now().hash(&mut hasher);
+    format!("obsbot-verify-{:016x}", hasher.finish())
+}
+
+impl AccountHandler {
+    pub fn new(
+        prefix: Option<String>,
+        settings: Settings,
+        backends: Vec<Backend>,
+        accounts: Accounts,
+    ) -> Self {
+        AccountHandler {
+            prefix,
+            settings,
+            backends,
+            accounts,
+            pending: HashMap::new(),
+        }
+    }
+
+    fn find_backend(&self, name: Option<&str>) -> Option<usize> {
+        match name {
+            Some(name) => self
+                .backends
+                .iter()
+                .position(|backend| backend.details.domain == name),
+            None if !self.backends.is_empty() => Some(0),
+            None => None,
+        }
+    }
+
+    fn start_link(
+        &mut self,
+        sender: &str,
+        obs_user: &str,
+        backend: Option<&str>,
+        lang: &str,
+    ) -> String {
+        let backend = match self.find_backend(backend) {
+            Some(x) => x,
+            None => {
+                return trf(
+                    lang,
+                    "Sorry, I don't know the backend {}",
+                    &[&backend.unwrap_or_default()],
+                )
+            }
+        };
+        let details = &self.backends[backend].details;
+
+        let token = generate_token(sender);
+        let answer = trf(
+            lang,
+            "To prove that you are {0} on {1}, add the token {2} to the description of your home project ({3}) and then send 'link obs verify' within {4} minutes. You can remove the token again afterwards.",
+            &[
+                &obs_user,
+                &details.domain,
+                &token,
+                &format!(
+                    "https://{}.{}/project/meta/home:{}",
+                    details.buildprefix, details.domain, obs_user
+                ),
+                &(LINK_TIMEOUT.as_secs() / 60),
+            ],
+        );
+
+        self.pending.insert(
+            sender.to_string(),
+            PendingLink {
+                backend,
+                obs_user: obs_user.to_string(),
+                token,
+                since: Instant::now(),
+            },
+        );
+        answer
+    }
+
+    fn verify_link(&mut self, sender: &str, lang: &str) -> String {
+        let pending =
+            match self.pending.remove(sender) {
+                Some(x) if x.since.elapsed() < LINK_TIMEOUT => x,
+                _ => return tr(
+                    lang,
+                    "There is no link waiting for verification. Start with 'link obs USERNAME'.",
+                ),
+            };
+        let backend = &self.backends[pending.backend];
+
+        let project = format!("home:{}", pending.obs_user);
+        let description = match backend.obs_api.project_meta(&project) {
+            Ok(meta) => meta.description.unwrap_or_default(),
+            Err(x) => {
+                println!("Could not get meta of {}: {:?}", project, x);
+                self.pending.insert(sender.to_string(), pending);
+                return trf(
+                    lang,
+                    "Sorry, I could not look at {}. Please try again later.",
+                    &[&project],
+                );
+            }
+        };
+
+        if !description.contains(&pending.token) {
+            let answer = trf(
+                lang,
+                "I could not find {} in the description of {}. Please check and try again.",
+                &[&pending.token, &project],
+            );
+            self.pending.insert(sender.to_string(), pending);
+            return answer;
+        }
+
+        self.accounts.link(ObsAccount {
+            matrix_user: sender.to_string(),
+            backend: backend.details.domain.to_string(),
+            obs_user: pending.obs_user.clone(),
+        });
+        println!(
+            "Linked {} to {} on {}",
+            sender, pending.obs_user, backend.details.domain
+        );
+        trf(
+            lang,
+            "You are now linked to {} on {}",
+            &[&pending.obs_user, &backend.details.domain],
+        )
+    }
+
+    fn whoami(&self, sender: &str, lang: &str) -> String {
+        let accounts = self.accounts.accounts_of(sender);
+        if accounts.is_empty() {
+            return tr(lang, "You are not linked to any OBS account.");
+        }
+
+        let mut answer = trf(lang, "{} is linked to:", &[&sender]);
+        for (account, configured) in accounts {
+            answer += "\n";
+            answer += &trf(lang, "{} on {}", &[&account.obs_user, &account.backend]);
+            if configured {
+                answer += &format!(" ({})", tr(lang, "from config"));
+            }
+        }
+        answer
+    }
+
+    /// The answer to a message, or None if it is not meant for this handler
+    fn answer(&mut self, sender: &str, room: &str, body: &str) -> Option<String> {
+        let prefix = self.prefix.as_deref().unwrap_or("");
+        let body = body.trim();
+        if !body.starts_with(prefix) {
+            return None;
+        }
+        // Without a prefix, only answer chat like 'link is broken' if it is clearly meant for us
+        let has_prefix = !prefix.is_empty();
+
+        let lang = self.settings.language(room);
+        let words = body[prefix.len()..].split_whitespace().collect::<Vec<_>>();
+        let answer = match words.as_slice() {
+            ["link", "obs", "verify"] => self.verify_link(sender, &lang),
+            ["link", "obs", user] => self.start_link(sender, user, None, &lang),
+            ["link", "obs", user, backend] => self.start_link(sender, user, Some(backend), &lang),
+            ["link", "obs", ..] => tr(&lang, "Usage: link obs USERNAME [BACKEND]"),
+            ["link", ..] if has_prefix => tr(&lang, "Usage: link obs USERNAME [BACKEND]"),
+            ["whoami"] => self.whoami(sender, &lang),
+            ["unlink"] => self.unlink(sender, None, &lang),
+            ["unlink", backend] if self.find_backend(Some(backend)).is_some() => {
+                self.unlink(sender, Some(backend), &lang)
+            }
+            ["unlink", backend] if has_prefix => {
+                trf(&lang, "Sorry, I don't know the backend {}", &[backend])
+            }
+            _ => return None,
+        };
+        Some(answer)
+    }
+
+    fn unlink(&mut self, sender: &str, backend: Option<&str>, lang: &str) -> String {
+        self.pending.remove(sender);
+        let removed = self.accounts.unlink(sender, backend);
+        let still_configured =
+            self.accounts
+                .accounts_of(sender)
+                .into_iter()
+                .any(|(account, configured)| {
+                    configured && backend.map(|b| account.backend == b).unwrap_or(true)
+                });
+
+        let mut answer = trf(lang, "Removed {} linked account(s).", &[&removed]);
+        if still_configured {
+            answer += " ";
+            answer += &tr(
+                lang,
+                "Accounts from the config can only be removed by the bot admin.",
+            );
+        }
+        answer
+    }
+}
+
+impl MessageHandler for AccountHandler {
+    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
+        match self.answer(&message.sender, &message.room, &message.body) {
+            Some(answer) => {
+                bot.send_message(&answer, &message.room, MessageType::TextMessage);
+                HandleResult::StopHandling
+            }
+            None => HandleResult::ContinueHandling,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ConnectionDetails;
+    use crate::obs_api::{FakeObsApi, ProjectMeta};
+
+    const DETAILS: ConnectionDetails = ConnectionDetails {
+        domain: "opensuse.org",
+        login: "opensuse:opensuse",
+        buildprefix: "build",
+        apiprefix: "api",
+        rabbitprefix: "rabbit",
+        rabbitscope: "opensuse",
+    };
+
+    const ALICE: &str = "@alice:example.org";
+
+    /// Accounts stored in a file only this test uses
+    fn accounts(name: &str, configured: Vec<ObsAccount>) -> Accounts {
+        let path =
+            std::env::temp_dir().join(format!("obs_chat_bot-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Accounts {
+            configured: Arc::new(configured),
+            linked: Arc::new(Mutex::new(Vec::new())),
+            path: Some(path),
+        }
+    }
+
+    /// A handler whose API knows the home project of alice, with description
+    fn handler(accounts: &Accounts, prefix: Option<&str>, description: &str) -> AccountHandler {
+        let mut api = FakeObsApi::default();
+        api.projects.insert(
+            "home:alice".to_string(),
+            ProjectMeta {
+                description: Some(description.to_string()),
+                persons: Vec::new(),
+                groups: Vec::new(),
+            },
+        );
+        AccountHandler::new(
+            prefix.map(|x| x.to_string()),
+            Settings::default(),
+            vec![Backend {
+                details: DETAILS,
+                obs_api: Arc::new(api),
+            }],
+            accounts.clone(),
+        )
+    }
+
+    fn pending_token(handler: &AccountHandler) -> String {
+        handler.pending[ALICE].token.clone()
+    }
+
+    #[test]
+    fn link_verify_whoami_unlink() {
+        let accounts = accounts("link", Vec::new());
+        let mut handler = handler(&accounts, None, "Nothing yet");
+
+        let answer = handler.answer(ALICE, "!room", "link obs alice").unwrap();
+        let token = pending_token(&handler);
+        assert!(answer.contains(&token));
+        assert!(answer.contains("https://build.opensuse.org/project/meta/home:alice"));
+
+        // The token is not in the description yet
+        let answer = handler.answer(ALICE, "!room", "link obs verify").unwrap();
+        assert!(answer.starts_with("I could not find"));
+        assert_eq!(accounts.obs_user(ALICE, "opensuse.org"), None);
+
+        let mut handler = AccountHandler {
+            pending: std::mem::take(&mut handler.pending),
+            ..self::handler(&accounts, None, &format!("My stuff {}", token))
+        };
+        let answer = handler.answer(ALICE, "!room", "link obs verify").unwrap();
+        assert_eq!(answer, "You are now linked to alice on opensuse.org");
+        assert_eq!(
+            accounts.obs_user(ALICE, "opensuse.org").as_deref(),
+            Some("alice")
+        );
+        assert_eq!(
+            accounts.matrix_user("alice", "opensuse.org").as_deref(),
+            Some(ALICE)
+        );
+
+        // Persisted
+        let stored: Vec<ObsAccount> = load_json(&accounts.path);
+        assert_eq!(accounts.linked.lock().unwrap().clone(), stored);
+        assert_eq!(stored.len(), 1);
+
+        let answer = handler.answer(ALICE, "!room", "whoami").unwrap();
+        assert_eq!(
+            answer,
+            format!("{} is linked to:\nalice on opensuse.org", ALICE)
+        );
+
+        let answer = handler
+            .answer(ALICE, "!room", "unlink opensuse.org")
+            .unwrap();
+        assert_eq!(answer, "Removed 1 linked account(s).");
+        assert_eq!(accounts.obs_user(ALICE, "opensuse.org"), None);
+        let stored: Vec<ObsAccount> = load_json(&accounts.path);
+        assert!(stored.is_empty());
+        let _ = std::fs::remove_file(accounts.path.as_ref().unwrap());
+    }
+
+    #[test]
+    fn tokens_expire() {
+        let accounts = accounts("expire", Vec::new());
+        let mut handler = handler(&accounts, None, "");
+        handler.answer(ALICE, "!room", "link obs alice");
+        let token = pending_token(&handler);
+        let mut handler = AccountHandler {
+            pending: std::mem::take(&mut handler.pending),
+            ..self::handler(&accounts, None, &token)
+        };
+        // Instants can't go back further than the boot of the machine
+        match Instant::now().checked_sub(LINK_TIMEOUT) {
+            Some(since) => handler.pending.get_mut(ALICE).unwrap().since = since,
+            None => return,
+        }
+
+        let answer = handler.answer(ALICE, "!room", "link obs verify").unwrap();
+        assert!(answer.starts_with("There is no link waiting"));
+        assert_eq!(accounts.obs_user(ALICE, "opensuse.org"), None);
+        assert!(handler.pending.is_empty());
+    }
+
+    #[test]
+    fn configured_accounts_stay() {
+        let configured = ObsAccount {
+            matrix_user: ALICE.to_string(),
+            backend: "opensuse.org".to_string(),
+            obs_user: "alice-configured".to_string(),
+        };
+        let accounts = accounts("configured", vec![configured]);
+        let mut handler = handler(&accounts, None, "");
+
+        let answer = handler.answer(ALICE, "!room", "whoami").unwrap();
+        assert!(answer.ends_with("alice-configured on opensuse.org (from config)"));
+
+        let answer = handler.answer(ALICE, "!room", "unlink").unwrap();
+        assert_eq!(
+            answer,
+            "Removed 0 linked account(s). Accounts from the config can only be removed by the bot admin."
+        );
+        assert_eq!(
+            accounts.obs_user(ALICE, "opensuse.org").as_deref(),
+            Some("alice-configured")
+        );
+
+        // Linked accounts take precedence, and only they are removed
+        accounts.link(ObsAccount {
+            matrix_user: ALICE.to_string(),
+            backend: "opensuse.org".to_string(),
+            obs_user: "alice".to_string(),
+        });
+        assert_eq!(
+            accounts.obs_user(ALICE, "opensuse.org").as_deref(),
+            Some("alice")
+        );
+        assert_eq!(accounts.unlink(ALICE, None), 1);
+        assert_eq!(
+            accounts.obs_user(ALICE, "opensuse.org").as_deref(),
+            Some("alice-configured")
+        );
+        let _ = std::fs::remove_file(accounts.path.as_ref().unwrap());
+    }
+
+    #[test]
+    fn chat_without_prefix() {
+        let accounts = accounts("chat", Vec::new());
+        let mut handler = handler(&accounts, None, "");
+        for chat in [
+            "link is broken",
+            "link https://build.opensuse.org/package/show/devel:foo/bar",
+            "unlink that",
+            "whoami is that",
+        ] {
+            assert_eq!(handler.answer(ALICE, "!room", chat), None, "{}", chat);
+        }
+        assert!(handler.pending.is_empty());
+        // Clearly meant for the bot
+        assert_eq!(
+            handler.answer(ALICE, "!room", "link obs").as_deref(),
+            Some("Usage: link obs USERNAME [BACKEND]")
+        );
+
+        let mut handler = self::handler(&accounts, Some("!bot "), "");
+        assert_eq!(handler.answer(ALICE, "!room", "link is broken"), None);
+        assert_eq!(
+            handler
+                .answer(ALICE, "!room", "!bot link is broken")
+                .as_deref(),
+            Some("Usage: link obs USERNAME [BACKEND]")
+        );
+        assert_eq!(
+            handler
+                .answer(ALICE, "!room", "!bot unlink that")
+                .as_deref(),
+            Some("Sorry, I don't know the backend that")
+        );
+    }
+}
@@ -0,0 +1,862 @@
+use crate::accounts::Accounts;
+use crate::build_res::PackageKey;
+use crate::common::{data_file, prepend_prefix, Backend, SubscriptionKey};
+use crate::i18n::{tr, trf};
+use crate::obs_api::{GroupRole, ObsApi, PersonRole};
+use crate::reference;
+use crate::settings::Settings;
+use crate::submitrequests::RequestKey;
+use anyhow::Result;
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// How long destructive actions wait for a 'confirm'
+const CONFIRMATION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+const REVIEWER_KINDS: [&str; 4] = ["user", "group", "project", "package"];
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [
+        (
+            "rebuild OBS_PACKAGE_URL [REPO] [ARCH]",
+            "Trigger a rebuild of a package you maintain.",
+        ),
+        (
+            "accept OBS_REQUEST_URL [COMMENT]",
+            "Accept a request to a package you maintain (needs confirm).",
+        ),
+        (
+            "decline OBS_REQUEST_URL [COMMENT]",
+            "Decline a request to a package you maintain (needs confirm).",
+        ),
+        (
+            "addreview OBS_REQUEST_URL user|group|project|package NAME [COMMENT]",
+            "Add a reviewer to a request you created or maintain.",
+        ),
+        ("confirm", "Execute your last accept/decline."),
+        ("cancel", "Drop your last accept/decline."),
+    ];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+#[derive(Debug, Clone)]
+enum Action {
+    Rebuild {
+        key: PackageKey,
+        repository: Option<String>,
+        arch: Option<String>,
+    },
+    ChangeState {
+        key: RequestKey,
+        new_state: &'static str,
+        comment: String,
+    },
+    AddReview {
+        key: RequestKey,
+        reviewer_kind: String,
+        reviewer: String,
+        comment: String,
+    },
+}
+
+impl std::fmt::Display for Action {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Rebuild {
+                key,
+                repository,
+                arch,
+            } => write!(
+                f,
+                "rebuild {} ({} / {})",
+                key,
+                repository.as_deref().unwrap_or("all repositories"),
+                arch.as_deref().unwrap_or("all archs")
+            ),
+            Action::ChangeState { key, new_state, .. } => {
+                write!(f, "change state of request {} to {}", key, new_state)
+            }
+            Action::AddReview {
+                key,
+                reviewer_kind,
+                reviewer,
+                ..
+            } => write!(
+                f,
+                "add review by {} {} to request {}",
+                reviewer_kind, reviewer, key
+            ),
+        }
+    }
+}
+
+impl Action {
+    fn needs_confirmation(&self) -> bool {
+        matches!(self, Action::ChangeState { .. })
+    }
+}
+
+struct PendingAction {
+    action: Action,
+    backend: usize,
+    obs_user: String,
+    since: Instant,
+}
+
+pub struct ActionHandler {
+    prefix: Option<String>,
+    settings: Settings,
+    backends: Vec<Backend>,
+    accounts: Accounts,
+    /// Actions waiting for a 'confirm', per (room, sender)
+    pending: HashMap<(String, String), PendingAction>,
+    audit_log: Option<PathBuf>,
+}
+
+fn has_role(persons: &[PersonRole], obs_user: &str, role: &str) -> bool {
+    persons
+        .iter()
+        .any(|person| person.userid == obs_user && person.role == role)
+}
+
+fn groups_with_role<'a>(
+    groups: &'a [GroupRole],
+    role: &'a str,
+) -> impl Iterator<Item = String> + 'a {
+    groups
+        .iter()
+        .filter(move |group| group.role == role)
+        .map(|group| group.groupid.clone())
+}
+
+/// Maintainers of the project are implicitly maintainers of all its packages.
+/// Members of maintainer groups are maintainers as well.
+fn is_maintainer(
+    obs_api: &dyn ObsApi,
+    obs_user: &str,
+    project: &str,
+    package: Option<&str>,
+) -> Result<bool> {
+    let mut groups = Vec::new();
+    if let Some(package) = package {
+        let meta = obs_api.package_meta(project, package)?;
+        if has_role(&meta.persons, obs_user, "maintainer") {
+            return Ok(true);
+        }
+        groups.extend(groups_with_role(&meta.groups, "maintainer"));
+    }
+    let meta = obs_api.project_meta(project)?;
+    if has_role(&meta.persons, obs_user, "maintainer") {
+        return Ok(true);
+    }
+    groups.extend(groups_with_role(&meta.groups, "maintainer"));
+
+    // Groups need extra queries, so only if the user is no maintainer directly
+    for group in groups {
+        if obs_api.group_members(&group)?.iter().any(|x| x == obs_user) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// If the words of a message are meant for us. Without a prefix, normal chat
+/// can start with the same words ("confirm the meeting", "rebuild it later"),
+/// so the actions need a URL and confirm/cancel a pending action of the sender.
+fn is_action(words: &[&str], has_prefix: bool, has_pending: bool) -> bool {
+    let has_url = words
+        .get(1)
+        .map(|x| reference::parse(x).is_some())
+        .unwrap_or(false);
+    match words.first() {
+        Some(&"rebuild") | Some(&"accept") | Some(&"decline") | Some(&"addreview") => {
+            has_prefix || has_url
+        }
+        Some(&"confirm") | Some(&"cancel") => has_prefix || (words.len() == 1 && has_pending),
+        _ => false,
+    }
+}
+
+impl ActionHandler {
+    pub fn new(
+        prefix: Option<String>,
+        settings: Settings,
+        backends: Vec<Backend>,
+        accounts: Accounts,
+    ) -> Self {
+        ActionHandler {
+            prefix,
+            settings,
+            backends,
+            accounts,
+            pending: HashMap::new(),
+            audit_log: data_file("audit.log"),
+        }
+    }
+
+    /// Shorthands like sr#1234 go to the first backend
+    fn find_backend(&self, url: &str) -> Option<usize> {
+        let link = reference::parse(url)?;
+        self.backends
+            .iter()
+            .position(|backend| link.is_on(backend.details.domain))
+    }
+
+    fn parse_action(&self, words: &[&str], lang: &str) -> Result<(usize, Action), String> {
+        let url = words
+            .get(1)
+            .ok_or_else(|| trf(lang, "Usage: {} URL ...", &[&words[0]]))?;
+        let backend = self
+            .find_backend(url)
+            .ok_or_else(|| trf(lang, "Sorry, {} is not on a backend I know", &[url]))?;
+        let not_a_request = || trf(lang, "Sorry, {} is not a request URL", &[url]);
+        let comment = |start: usize| words.get(start..).unwrap_or(&[]).join(" ");
+
+        let action = match words[0] {
+            "rebuild" => Action::Rebuild {
+                key: PackageKey::parse(url, false)
+                    .ok_or_else(|| trf(lang, "Sorry, {} is not a package URL", &[url]))?,
+                repository: words.get(2).map(|x| x.to_string()),
+                arch: words.get(3).map(|x| x.to_string()),
+            },
+            "accept" | "decline" => Action::ChangeState {
+                key: RequestKey::parse(url, false).ok_or_else(not_a_request)?,
+                new_state: if words[0] == "accept" {
+                    "accepted"
+                } else {
+                    "declined"
+                },
+                comment: comment(2),
+            },
+            "addreview" => {
+                let reviewer_kind = words.get(2).copied().unwrap_or("");
+                let reviewer = words.get(3).copied().unwrap_or("");
+                if !REVIEWER_KINDS.contains(&reviewer_kind) || reviewer.is_empty() {
+                    return Err(trf(
+                        lang,
+                        "Usage: addreview URL {} NAME [COMMENT]",
+                        &[&REVIEWER_KINDS.join("|")],
+                    ));
+                }
+                Action::AddReview {
+                    key: RequestKey::parse(url, false).ok_or_else(not_a_request)?,
+                    reviewer_kind: reviewer_kind.to_string(),
+                    reviewer: reviewer.to_string(),
+                    comment: comment(4),
+                }
+            }
+            _ => return Err(trf(lang, "Unknown action {}", &[&words[0]])),
+        };
+        Ok((backend, action))
+    }
+
+    fn is_authorized(&self, backend: &Backend, obs_user: &str, action: &Action) -> Result<bool> {
+        let obs_api = backend.obs_api.as_ref();
+        match action {
+            Action::Rebuild { key, .. } => {
+                is_maintainer(obs_api, obs_user, &key.project, Some(&key.package))
+            }
+            Action::ChangeState { key, .. } | Action::AddReview { key, .. } => {
+                let request = obs_api.request(&key.id)?;
+                if let Action::AddReview { .. } = action {
+                    if request.creator.as_deref() == Some(obs_user) {
+                        return Ok(true);
+                    }
+                }
+
+                let targets = request
+                    .actions
+                    .iter()
+                    .filter_map(|x| x.target.as_ref())
+                    .collect::<Vec<_>>();
+                if targets.is_empty() {
+                    return Ok(false);
+                }
+                for target in targets {
+                    if !is_maintainer(
+                        obs_api,
+                        obs_user,
+                        &target.project,
+                        target.package.as_deref(),
+                    )? {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    fn execute(&self, sender: &str, pending: &PendingAction) -> Result<()> {
+        let backend = &self.backends[pending.backend];
+        let obs_api = backend.obs_api.as_ref();
+        let on_behalf = format!("(via chat, on behalf of {})", pending.obs_user);
+
+        let res = match &pending.action {
+            Action::Rebuild {
+                key,
+                repository,
+                arch,
+            } => obs_api.rebuild(
+                &key.project,
+                &key.package,
+                repository.as_deref(),
+                arch.as_deref(),
+            ),
+            Action::ChangeState {
+                key,
+                new_state,
+                comment,
+            } => obs_api.change_request_state(
+                &key.id,
+                new_state,
+                format!("{} {}", comment, on_behalf).trim_start(),
+            ),
+            Action::AddReview {
+                key,
+                reviewer_kind,
+                reviewer,
+                comment,
+            } => obs_api.add_review(
+                &key.id,
+                reviewer_kind,
+                reviewer,
+                format!("{} {}", comment, on_behalf).trim_start(),
+            ),
+        };
+
+        self.audit(&format!(
+            "{} as {} on {}: {} -> {}",
+            sender,
+            pending.obs_user,
+            backend.details.domain,
+            pending.action,
+            match &res {
+                Ok(_) => "done".to_string(),
+                Err(x) => format!("failed ({})", x),
+            }
+        ));
+        res
+    }
+
+    fn audit(&self, line: &str) {
+        println!("AUDIT: {}", line);
+
+        let path = match &self.audit_log {
+            Some(x) => x,
+            None => return,
+        };
+        let res = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{} {}", chrono::Local::now().to_rfc3339(), line));
+        if let Err(x) = res {
+            println!("ERROR! Could not write audit log {:?}: {:?}", path, x);
+        }
+    }
+
+    fn execute_and_answer(&self, sender: &str, pending: &PendingAction, lang: &str) -> String {
+        match self.execute(sender, pending) {
+            Ok(_) => trf(lang, "Done: {}", &[&pending.action]),
+            Err(x) => trf(lang, "Sorry, could not {}: {}", &[&pending.action, &x]),
+        }
+    }
+
+    fn handle_action(&mut self, room: &str, sender: &str, words: &[&str]) -> String {
+        let lang = self.settings.language(room);
+        let (backend, action) = match self.parse_action(words, &lang) {
+            Ok(x) => x,
+            Err(x) => return x,
+        };
+
+        let domain = self.backends[backend].details.domain;
+        let obs_user = match self.accounts.obs_user(sender, domain) {
+            Some(x) => x,
+            None => {
+                return trf(
+                    &lang,
+                    "Sorry, I don't know your OBS account on {}. Please link it first with 'link obs USERNAME'.",
+                    &[&domain],
+                )
+            }
+        };
+
+        match self.is_authorized(&self.backends[backend], &obs_user, &action) {
+            Ok(true) => {}
+            Ok(false) => {
+                self.audit(&format!(
+                    "{} as {} on {}: {} -> not authorized",
+                    sender, obs_user, domain, action
+                ));
+                return trf(
+                    &lang,
+                    "Sorry, {} is not allowed to {}",
+                    &[&obs_user, &action],
+                );
+            }
+            Err(x) => {
+                println!("Could not check authorization: {:?}", x);
+                return trf(
+                    &lang,
+                    "Sorry, I could not check if you are allowed to {}",
+                    &[&action],
+                );
+            }
+        }
+
+        let pending = PendingAction {
+            action,
+            backend,
+            obs_user,
+            since: Instant::now(),
+        };
+
+        if !pending.action.needs_confirmation() {
+            return self.execute_and_answer(sender, &pending, &lang);
+        }
+
+        let answer = trf(
+            &lang,
+            "About to {} on {} as {}. Reply 'confirm' within {} minutes to proceed, or 'cancel'.",
+            &[
+                &pending.action,
+                &domain,
+                &pending.obs_user,
+                &(CONFIRMATION_TIMEOUT.as_secs() / 60),
+            ],
+        );
+        self.pending
+            .insert((room.to_string(), sender.to_string()), pending);
+        answer
+    }
+
+    fn handle_confirmation(&mut self, room: &str, sender: &str, confirmed: bool) -> String {
+        let lang = self.settings.language(room);
+        let pending = match self.pending.remove(&(room.to_string(), sender.to_string())) {
+            Some(x) if x.since.elapsed() < CONFIRMATION_TIMEOUT => x,
+            _ => return tr(&lang, "There is nothing to confirm or cancel."),
+        };
+
+        if confirmed {
+            self.execute_and_answer(sender, &pending, &lang)
+        } else {
+            trf(&lang, "Cancelled: {}", &[&pending.action])
+        }
+    }
+
+    /// The answer to a message, or None if it is not meant for this handler
+    fn answer(&mut self, room: &str, sender: &str, body: &str) -> Option<String> {
+        self.pending
+            .retain(|_, pending| pending.since.elapsed() < CONFIRMATION_TIMEOUT);
+
+        let prefix = self.prefix.as_deref().unwrap_or("");
+        // Only the first line is the command, the rest may be anything
+        let body = body.trim().lines().next().unwrap_or("").trim();
+        if !body.starts_with(prefix) {
+            return None;
+        }
+
+        let words = body[prefix.len()..].split_whitespace().collect::<Vec<_>>();
+        let has_pending = self
+            .pending
+            .contains_key(&(room.to_string(), sender.to_string()));
+        if !is_action(&words, self.prefix.is_some(), has_pending) {
+            return None;
+        }
+
+        match words.first() {
+            Some(&"rebuild") | Some(&"accept") | Some(&"decline") | Some(&"addreview") => {
+                Some(self.handle_action(room, sender, &words))
+            }
+            Some(&"confirm") => Some(self.handle_confirmation(room, sender, true)),
+            Some(&"cancel") => Some(self.handle_confirmation(room, sender, false)),
+            _ => None,
+        }
+    }
+}
+
+impl MessageHandler for ActionHandler {
+    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
+        match self.answer(&message.room, &message.sender, &message.body) {
+            Some(answer) => {
+                bot.send_message(&answer, &message.room, MessageType::TextMessage);
+                // Don't let the subscribers mistake the URL for a subscription
+                HandleResult::StopHandling
+            }
+            None => HandleResult::ContinueHandling,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::ObsAccount;
+    use crate::common::ConnectionDetails;
+    use crate::obs_api::{FakeObsApi, PackageMeta, ProjectMeta, Request};
+    use std::sync::Arc;
+
+    const DETAILS: ConnectionDetails = ConnectionDetails {
+        domain: "opensuse.org",
+        login: "opensuse:opensuse",
+        buildprefix: "build",
+        apiprefix: "api",
+        rabbitprefix: "rabbit",
+        rabbitscope: "opensuse",
+    };
+    const ROOM: &str = "!room:example.org";
+    const PACKAGE_URL: &str = "https://build.opensuse.org/package/show/devel:foo/bar";
+
+    fn fake_api() -> FakeObsApi {
+        let mut api = FakeObsApi::default();
+        let package: PackageMeta = quick_xml::de::from_str(
+            r#"<package name="bar" project="devel:foo">
+  <person userid="bob" role="maintainer"/>
+  <person userid="carol" role="bugowner"/>
+</package>"#,
+        )
+        .unwrap();
+        let project: ProjectMeta = quick_xml::de::from_str(
+            r#"<project name="devel:foo"><person userid="dave" role="maintainer"/></project>"#,
+        )
+        .unwrap();
+        api.packages
+            .insert(("devel:foo".to_string(), "bar".to_string()), package);
+        api.projects.insert("devel:foo".to_string(), project);
+
+        let package: PackageMeta = quick_xml::de::from_str(
+            r#"<package name="baz" project="devel:foo"><group groupid="baz-team" role="maintainer"/><group groupid="readers" role="reader"/></package>"#,
+        )
+        .unwrap();
+        api.packages
+            .insert(("devel:foo".to_string(), "baz".to_string()), package);
+        api.groups
+            .insert("baz-team".to_string(), vec!["erin".to_string()]);
+        api.groups
+            .insert("readers".to_string(), vec!["frank".to_string()]);
+
+        let package: PackageMeta = quick_xml::de::from_str(
+            r#"<package name="qux" project="devel:other"><person userid="zed" role="maintainer"/></package>"#,
+        )
+        .unwrap();
+        api.packages
+            .insert(("devel:other".to_string(), "qux".to_string()), package);
+        api.projects.insert(
+            "devel:other".to_string(),
+            quick_xml::de::from_str(r#"<project name="devel:other"/>"#).unwrap(),
+        );
+
+        let state = r#"<state name="review" who="alice" when="2024-01-02T10:00:00"/>"#;
+        let requests = [
+            // To a package of bob
+            (
+                "1",
+                r#"<action type="submit"><target project="devel:foo" package="bar"/></action>"#,
+            ),
+            // Only half of it to a package of bob
+            (
+                "2",
+                r#"<action type="submit"><target project="devel:foo" package="bar"/></action>
+                   <action type="submit"><target project="devel:other" package="qux"/></action>"#,
+            ),
+            // Without any target
+            ("3", r#"<action type="delete"/>"#),
+        ];
+        for (id, actions) in requests {
+            let request: Request = quick_xml::de::from_str(&format!(
+                r#"<request id="{}" creator="alice">{}{}</request>"#,
+                id, actions, state
+            ))
+            .unwrap();
+            api.requests.insert(id.to_string(), request);
+        }
+        api
+    }
+
+    /// A handler with the matrix users @NAME:example.org linked to the OBS users NAME
+    fn handler(api: &Arc<FakeObsApi>, prefix: Option<&str>, audit_log: &str) -> ActionHandler {
+        let accounts = Accounts::default();
+        for user in ["alice", "bob", "carol", "dave"] {
+            accounts.link(ObsAccount {
+                matrix_user: format!("@{}:example.org", user),
+                backend: DETAILS.domain.to_string(),
+                obs_user: user.to_string(),
+            });
+        }
+        let path = std::env::temp_dir().join(format!(
+            "obs_chat_bot-{}-{}.log",
+            audit_log,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        ActionHandler {
+            audit_log: Some(path),
+            ..ActionHandler::new(
+                prefix.map(|x| x.to_string()),
+                Settings::default(),
+                vec![Backend {
+                    details: DETAILS,
+                    obs_api: api.clone(),
+                }],
+                accounts,
+            )
+        }
+    }
+
+    fn request_action(id: &str, review: bool) -> Action {
+        let key = RequestKey::parse(&format!("sr#{}", id), false).unwrap();
+        if review {
+            Action::AddReview {
+                key,
+                reviewer_kind: "user".to_string(),
+                reviewer: "zed".to_string(),
+                comment: String::new(),
+            }
+        } else {
+            Action::ChangeState {
+                key,
+                new_state: "accepted",
+                comment: String::new(),
+            }
+        }
+    }
+
+    #[test]
+    fn request_authorization() {
+        let api = Arc::new(fake_api());
+        let handler = handler(&api, None, "authorization");
+        let backend = &handler.backends[0];
+        let allowed = |user: &str, id: &str, review: bool| {
+            handler
+                .is_authorized(backend, user, &request_action(id, review))
+                .unwrap()
+        };
+
+        assert!(allowed("bob", "1", false));
+        assert!(allowed("dave", "1", false));
+        assert!(!allowed("carol", "1", false));
+        // All targets have to be maintained
+        assert!(!allowed("bob", "2", false));
+        assert!(!allowed("bob", "2", true));
+        assert!(!allowed("zed", "2", false));
+        // The creator may only ask for reviews
+        assert!(!allowed("alice", "1", false));
+        assert!(allowed("alice", "1", true));
+        assert!(allowed("alice", "2", true));
+        // Nobody maintains nothing
+        assert!(!allowed("dave", "3", false));
+        assert!(handler
+            .is_authorized(backend, "bob", &request_action("4", false))
+            .is_err());
+    }
+
+    #[test]
+    fn confirm_and_cancel() {
+        let api = Arc::new(fake_api());
+        let mut handler = handler(&api, None, "confirm");
+        let bob = "@bob:example.org";
+
+        let answer = handler.answer(ROOM, bob, "accept sr#1 looks good").unwrap();
+        assert!(
+            answer.starts_with("About to change state of request"),
+            "{}",
+            answer
+        );
+        assert_eq!(api.count("change_request_state"), 0);
+        // Only for the sender, in the same room
+        assert_eq!(handler.answer(ROOM, "@carol:example.org", "confirm"), None);
+        assert_eq!(handler.answer("!other:example.org", bob, "confirm"), None);
+
+        let answer = handler.answer(ROOM, bob, "confirm").unwrap();
+        assert!(
+            answer.starts_with("Done: change state of request"),
+            "{}",
+            answer
+        );
+        assert_eq!(
+            api.count("change_request_state 1 accepted looks good (via chat, on behalf of bob)"),
+            1
+        );
+        // Only once
+        assert_eq!(handler.answer(ROOM, bob, "confirm"), None);
+
+        handler.answer(ROOM, bob, "decline sr#1").unwrap();
+        let answer = handler.answer(ROOM, bob, "cancel").unwrap();
+        assert!(
+            answer.starts_with("Cancelled: change state of request"),
+            "{}",
+            answer
+        );
+        assert_eq!(handler.answer(ROOM, bob, "confirm"), None);
+        assert_eq!(api.count("change_request_state"), 1);
+
+        // Rebuilds need no confirmation
+        let answer = handler
+            .answer(
+                ROOM,
+                bob,
+                &format!("rebuild {} openSUSE_Tumbleweed", PACKAGE_URL),
+            )
+            .unwrap();
+        assert!(answer.starts_with("Done: rebuild"), "{}", answer);
+        assert_eq!(
+            api.count("rebuild devel:foo/bar Some(\"openSUSE_Tumbleweed\") None"),
+            1
+        );
+    }
+
+    #[test]
+    fn confirmations_time_out() {
+        let api = Arc::new(fake_api());
+        let mut handler = handler(&api, Some("!bot "), "timeout");
+        let bob = "@bob:example.org";
+        handler.answer(ROOM, bob, "!bot accept sr#1").unwrap();
+        // Instants can't go back further than the boot of the machine
+        let since = match Instant::now().checked_sub(CONFIRMATION_TIMEOUT) {
+            Some(x) => x,
+            None => return,
+        };
+        for pending in handler.pending.values_mut() {
+            pending.since = since;
+        }
+
+        assert_eq!(
+            handler.answer(ROOM, bob, "!bot confirm").as_deref(),
+            Some("There is nothing to confirm or cancel.")
+        );
+        assert_eq!(api.count("change_request_state"), 0);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let api = Arc::new(fake_api());
+        let mut handler = handler(&api, Some("!bot "), "parse");
+        let mut answer = |text: &str| {
+            handler
+                .answer(ROOM, "@bob:example.org", &format!("!bot {}", text))
+                .unwrap()
+        };
+
+        assert_eq!(answer("rebuild"), "Usage: rebuild URL ...");
+        assert_eq!(
+            answer("rebuild https://build.suse.de/package/show/a/b"),
+            "Sorry, https://build.suse.de/package/show/a/b is not on a backend I know"
+        );
+        assert_eq!(answer("rebuild sr#1"), "Sorry, sr#1 is not a package URL");
+        assert_eq!(
+            answer(&format!("accept {}", PACKAGE_URL)),
+            format!("Sorry, {} is not a request URL", PACKAGE_URL)
+        );
+        let usage = "Usage: addreview URL user|group|project|package NAME [COMMENT]";
+        assert_eq!(answer("addreview sr#1 reviewer zed"), usage);
+        assert_eq!(answer("addreview sr#1 user"), usage);
+        assert_eq!(api.count(""), 0);
+
+        let answer = handler
+            .answer(ROOM, "@erin:example.org", "!bot accept sr#1")
+            .unwrap();
+        assert!(answer.starts_with("Sorry, I don't know your OBS account on opensuse.org"));
+    }
+
+    #[test]
+    fn actions_are_audited() {
+        let api = Arc::new(fake_api());
+        let mut handler = handler(&api, None, "audit");
+        handler
+            .answer(
+                ROOM,
+                "@bob:example.org",
+                &format!("rebuild {}", PACKAGE_URL),
+            )
+            .unwrap();
+        handler
+            .answer(ROOM, "@carol:example.org", "addreview sr#1 user zed please")
+            .unwrap();
+        handler
+            .answer(ROOM, "@alice:example.org", "addreview sr#1 user zed please")
+            .unwrap();
+
+        let path = handler.audit_log.clone().unwrap();
+        let log = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let lines = log
+            .lines()
+            .map(|x| x.split_once(' ').unwrap().1)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "@bob:example.org as bob on opensuse.org: rebuild devel:foo/bar (all repositories / all archs) -> done",
+                "@carol:example.org as carol on opensuse.org: add review by user zed to request 1 -> not authorized",
+                "@alice:example.org as alice on opensuse.org: add review by user zed to request 1 -> done",
+            ]
+        );
+        assert_eq!(
+            api.count("add_review 1 user zed please (via chat, on behalf of alice)"),
+            1
+        );
+    }
+
+    #[test]
+    fn maintainers() {
+        let api = fake_api();
+        assert!(is_maintainer(&api, "bob", "devel:foo", Some("bar")).unwrap());
+        assert_eq!(api.count("project_meta"), 0);
+        // Of the project, so of all its packages
+        assert!(is_maintainer(&api, "dave", "devel:foo", Some("bar")).unwrap());
+        assert!(is_maintainer(&api, "dave", "devel:foo", None).unwrap());
+        assert!(!is_maintainer(&api, "bob", "devel:foo", None).unwrap());
+        // Other roles don't count
+        assert!(!is_maintainer(&api, "carol", "devel:foo", Some("bar")).unwrap());
+        assert!(is_maintainer(&api, "bob", "devel:other", Some("bar")).is_err());
+        assert_eq!(api.count("group_members"), 0);
+    }
+
+    #[test]
+    fn group_maintainers() {
+        let api = fake_api();
+        assert!(is_maintainer(&api, "erin", "devel:foo", Some("baz")).unwrap());
+        assert!(!is_maintainer(&api, "frank", "devel:foo", Some("baz")).unwrap());
+        // Only the maintainer group is asked
+        assert_eq!(api.count("group_members readers"), 0);
+        // The group maintains only the package, not the whole project
+        assert!(!is_maintainer(&api, "erin", "devel:foo", Some("bar")).unwrap());
+    }
+
+    #[test]
+    fn actions_without_prefix() {
+        let url = "https://build.opensuse.org/request/show/1234";
+        assert!(is_action(&["accept", url], false, false));
+        assert!(is_action(&["rebuild", "sr#1234"], false, false));
+        assert!(!is_action(&["accept", "the", "offer"], false, false));
+        assert!(!is_action(&["rebuild"], false, false));
+        assert!(!is_action(&["addreview", "later"], false, false));
+
+        assert!(is_action(&["confirm"], false, true));
+        assert!(!is_action(&["confirm"], false, false));
+        assert!(!is_action(&["cancel", "the", "meeting"], false, true));
+        assert!(!is_action(&["status", url], false, true));
+    }
+
+    #[test]
+    fn actions_with_prefix() {
+        // Usage errors and 'nothing to confirm' are answered
+        assert!(is_action(&["rebuild"], true, false));
+        assert!(is_action(&["confirm"], true, false));
+        assert!(is_action(&["cancel", "it"], true, false));
+        assert!(!is_action(&["list"], true, false));
+    }
+
+    #[test]
+    fn help_lists_all_reviewer_kinds() {
+        let help = help_str(None);
+        assert!(help
+            .iter()
+            .any(|(usage, _)| usage.contains(&REVIEWER_KINDS.join("|"))));
+    }
+}
@@ -0,0 +1,284 @@
+use crate::commands::CommandRouter;
+use crate::common::{html_escape, prepend_prefix, Target};
+use crate::i18n::{tr, trf};
+use crate::matrix_api::MatrixApi;
+use crate::settings::Settings;
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [
+        (
+            "admin rooms|subs|stats",
+            "For admins: Joined rooms, all subscriptions or events per routing key.",
+        ),
+        (
+            "admin unsub-room ROOM",
+            "For admins: Remove all subscriptions of a room.",
+        ),
+        (
+            "admin broadcast TEXT",
+            "For admins: Send a message to all joined rooms.",
+        ),
+    ];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EventCounts {
+    /// Events from the message queue
+    pub received: u64,
+    /// Notifications they caused
+    pub sent: u64,
+}
+
+/// Events per routing key since the start, for 'admin stats'
+#[derive(Clone)]
+pub struct Stats {
+    counts: Arc<Mutex<BTreeMap<String, EventCounts>>>,
+    started: Instant,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            counts: Arc::new(Mutex::new(BTreeMap::new())),
+            started: Instant::now(),
+        }
+    }
+
+    fn count<F: FnOnce(&mut EventCounts)>(&self, routing_key: &str, f: F) {
+        if let Ok(mut counts) = self.counts.lock() {
+            f(counts.entry(routing_key.to_string()).or_default());
+        }
+    }
+
+    pub fn received(&self, routing_key: &str) {
+        self.count(routing_key, |x| x.received += 1);
+    }
+
+    pub fn sent(&self, routing_key: &str) {
+        self.count(routing_key, |x| x.sent += 1);
+    }
+}
+
+/// 'admin ...' commands, only for the Matrix users configured as admins
+pub struct AdminHandler {
+    pub prefix: Option<String>,
+    pub admins: Vec<String>,
+    pub router: CommandRouter,
+    pub stats: Stats,
+    /// For the language of the room
+    pub settings: Settings,
+    /// For the joined rooms, matrix_bot_api does not know them
+    pub matrix_api: Option<Arc<MatrixApi>>,
+}
+
+impl AdminHandler {
+    /// Joined rooms, or the subscribed ones if the Matrix API is not available
+    fn rooms(&self) -> BTreeSet<String> {
+        let joined = self.matrix_api.as_ref().map(|x| x.joined_rooms());
+        match joined {
+            Some(Ok(rooms)) => rooms.into_iter().collect(),
+            Some(Err(x)) => {
+                println!("ERROR! Could not get joined rooms: {:?}", x);
+                BTreeSet::new()
+            }
+            None => self
+                .router
+                .all_subscriptions()
+                .into_iter()
+                .filter_map(|(target, _)| match target {
+                    Target::Room(room) => Some(room),
+                    Target::User(_) => None,
+                })
+                .collect(),
+        }
+    }
+
+    fn list_rooms(&self, lang: &str) -> String {
+        let mut per_target = BTreeMap::new();
+        for (target, _) in self.router.all_subscriptions() {
+            *per_target.entry(target).or_insert(0) += 1;
+        }
+
+        let rooms = self.rooms();
+        let header = if self.matrix_api.is_some() {
+            trf(lang, "Joined {} rooms:", &[&rooms.len()])
+        } else {
+            trf(
+                lang,
+                "{} rooms with subscriptions (without a Matrix API login, joined rooms without any are unknown):",
+                &[&rooms.len()],
+            )
+        };
+        let mut lines = vec![header];
+        for room in &rooms {
+            let count = per_target
+                .get(&Target::Room(room.clone()))
+                .copied()
+                .unwrap_or(0);
+            lines.push(trf(lang, "{}: {} subscriptions", &[room, &count]));
+        }
+        for (target, count) in &per_target {
+            if let Target::User(user) = target {
+                lines.push(trf(lang, "{} (personal): {} subscriptions", &[user, count]));
+            }
+        }
+        lines.join("\n")
+    }
+
+    fn list_subscriptions(&self, lang: &str) -> String {
+        let mut per_target = BTreeMap::new();
+        for (target, entry) in self.router.all_subscriptions() {
+            let options = if entry.options.is_empty() {
+                String::new()
+            } else {
+                format!(" ({})", entry.options.join("; "))
+            };
+            per_target
+                .entry(target)
+                .or_insert_with(Vec::new)
+                .push(format!(
+                    "  {} {} {}{}",
+                    entry.backend, entry.kind, entry.name, options
+                ));
+        }
+        if per_target.is_empty() {
+            return tr(lang, "No subscriptions at all");
+        }
+
+        let mut lines = Vec::new();
+        for (target, mut entries) in per_target {
+            entries.sort();
+            lines.push(match target {
+                Target::Room(room) => format!("{}:", room),
+                Target::User(user) => trf(lang, "{} (personal):", &[&user]),
+            });
+            lines.append(&mut entries);
+        }
+        lines.join("\n")
+    }
+
+    fn list_stats(&self, lang: &str) -> String {
+        let uptime = self.stats.started.elapsed().as_secs();
+        let mut lines = vec![trf(
+            lang,
+            "Events since {}h {}m (received / sent notifications):",
+            &[&(uptime / 3600), &(uptime % 3600 / 60)],
+        )];
+        let counts = self
+            .stats
+            .counts
+            .lock()
+            .map(|x| x.clone())
+            .unwrap_or_default();
+        let mut total = EventCounts::default();
+        for (routing_key, count) in &counts {
+            lines.push(format!(
+                "{}: {} / {}",
+                routing_key, count.received, count.sent
+            ));
+            total.received += count.received;
+            total.sent += count.sent;
+        }
+        lines.push(trf(lang, "Total: {} / {}", &[&total.received, &total.sent]));
+        lines.join("\n")
+    }
+
+    fn broadcast(&self, bot: &ActiveBot, text: &str, lang: &str) -> String {
+        let rooms = self.rooms();
+        for room in &rooms {
+            bot.send_message(text, room, MessageType::TextMessage);
+        }
+        if self.matrix_api.is_some() {
+            trf(lang, "Sent to {} rooms", &[&rooms.len()])
+        } else {
+            trf(
+                lang,
+                "Sent to the {} rooms with subscriptions (without a Matrix API login, joined rooms are unknown)",
+                &[&rooms.len()],
+            )
+        }
+    }
+
+    fn handle_admin_command(&self, bot: &ActiveBot, message: &Message, command: &str) -> String {
+        let lang = self.settings.language(&message.room);
+        if !self.admins.contains(&message.sender) {
+            return tr(&lang, "Sorry, only admins of this bot can do that.");
+        }
+
+        let (verb, rest) = command
+            .split_once(char::is_whitespace)
+            .map(|(verb, rest)| (verb, rest.trim()))
+            .unwrap_or((command, ""));
+        match (verb, rest) {
+            ("rooms", "") => self.list_rooms(&lang),
+            ("subs", "") => self.list_subscriptions(&lang),
+            ("stats", "") => self.list_stats(&lang),
+            ("unsub-room", room) if !room.is_empty() && !room.contains(char::is_whitespace) => {
+                let count = self.router.unsubscribe_room(room);
+                println!("Admin {} removed the subscriptions of {}", message.sender, room);
+                trf(&lang, "Removed {} subscriptions of {}", &[&count, &room])
+            }
+            ("broadcast", text) if !text.is_empty() => {
+                println!("Admin {} broadcasts: {}", message.sender, text);
+                self.broadcast(bot, text, &lang)
+            }
+            _ => tr(
+                &lang,
+                "Usage: admin rooms | admin subs | admin stats | admin unsub-room ROOM | admin broadcast TEXT",
+            ),
+        }
+    }
+}
+
+impl MessageHandler for AdminHandler {
+    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
+        let prefix = self.prefix.as_deref().unwrap_or("");
+        let body = message.body.trim();
+        if !body.starts_with(prefix) {
+            return HandleResult::ContinueHandling;
+        }
+
+        let command = match body[prefix.len()..].trim().strip_prefix("admin") {
+            Some(x) if x.is_empty() || x.starts_with(char::is_whitespace) => x.trim(),
+            _ => return HandleResult::ContinueHandling,
+        };
+        let answer = self.handle_admin_command(bot, message, command);
+
+        // Room and user IDs are long, so a monospace block reads better
+        let html = format!("<pre><code>{}</code></pre>", html_escape(&answer));
+        bot.send_html_message(&answer, &html, &message.room, MessageType::TextMessage);
+        HandleResult::StopHandling
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rooms_without_matrix_api() {
+        let admin = AdminHandler {
+            prefix: None,
+            admins: vec!["@admin:example.org".to_string()],
+            router: CommandRouter::new(Settings::default(), None),
+            stats: Stats::new(),
+            settings: Settings::default(),
+            matrix_api: None,
+        };
+        assert!(admin.rooms().is_empty());
+        assert_eq!(
+            admin.list_rooms("en"),
+            "0 rooms with subscriptions (without a Matrix API login, joined rooms without any are unknown):"
+        );
+        assert!(admin
+            .list_rooms("de")
+            .starts_with("0 Räume mit Abonnements"));
+    }
+}
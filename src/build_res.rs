@@ -1,17 +1,22 @@
-use crate::common::{prepend_prefix, ConnectionDetails, MessageParseResult, Subscriber};
-use anyhow::{anyhow, Result};
-use lapin::{
-    message::{Delivery, DeliveryResult},
-    options::*,
-    Connection, ConsumerDelegate,
+use crate::commands::{Command, CommandHandler, Token, Verb};
+use crate::common::{
+    html_escape, mention_pills, prepend_prefix, ConnectionDetails, EventHandler,
+    MessageParseResult, Shared, Subscriber, SubscriptionKey, Target,
 };
-use matrix_bot_api::handlers::{HandleResult, MessageHandler};
-use matrix_bot_api::{ActiveBot, MatrixBot, Message, MessageType};
-use serde::Deserialize;
-use serde_json;
+use crate::i18n::{tr, trf};
+use crate::listing::ListEntry;
+use crate::obs_api::{ObsApi, PersonRole};
+use crate::outbox::EventKind;
+use crate::reference::Reference;
+use crate::templates;
+use anyhow::{anyhow, Result};
+use lapin::{message::Delivery, Connection};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use serde::{Deserialize, Serialize};
 use std::collections::hash_map::HashMap;
-use std::convert::TryFrom;
+use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 const KEY_BUILD_SUCCESS: &str = "obs.package.build_success";
 const KEY_BUILD_FAIL: &str = "obs.package.build_fail";
@@ -28,14 +33,19 @@ pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
             "Unsubscribe from a package. Get no more notifications.",
         ),
         (
-            "list packages",
-            "List all packages currently subscribed to.",
+            "status OBS_PACKAGE_URL",
+            "Show the current build results of a package.",
         ),
     ];
 
     prepend_prefix(prefix, &without_prefix)
 }
 
+/// If what reference points to can be subscribed to here, for checking the config
+pub fn subscribable(reference: &Reference, summary: bool) -> bool {
+    PackageKey::from_reference(reference, summary).is_some()
+}
+
 #[derive(Debug, Clone, std::cmp::PartialEq, std::cmp::Eq, Hash)]
 pub struct PackageKey {
     pub project: String,
@@ -48,30 +58,37 @@ impl std::fmt::Display for PackageKey {
     }
 }
 
-impl TryFrom<String> for PackageKey {
-    type Error = ();
+impl SubscriptionKey for PackageKey {
+    type State = BuildState;
 
-    fn try_from(line: String) -> Result<Self, Self::Error> {
-        let line = line.trim();
-        if line.contains('\n') {
-            return Err(());
+    fn from_reference(reference: &Reference, summary: bool) -> Option<Self> {
+        match reference {
+            Reference::Package { project, package } if !summary => Some(PackageKey {
+                project: project.clone(),
+                package: package.clone(),
+            }),
+            _ => None,
         }
+    }
+}
 
-        let parts: Vec<_> = line.split('/').collect();
-        if parts.len() < 4 {
-            return Err(());
-        }
+/// (project, package, repository, arch)
+type BuildTarget = (String, String, String, String);
 
-        let mut iter = parts.iter().rev();
-        // These unwraps cannot fail, as there have to be at least 2 parts
-        let package = iter.next().unwrap().trim().to_string();
-        let project = iter.next().unwrap().trim().to_string();
+/// How long the maintainers of a package are cached
+const MAINTAINERS_TTL: Duration = Duration::from_secs(60 * 60);
+/// Upper limit of packages whose maintainers are cached
+const MAINTAINERS_CACHE_SIZE: usize = 1000;
 
-        Ok(PackageKey { project, package })
-    }
+#[derive(Default, Clone)]
+pub struct BuildState {
+    /// Builds whose last result was a failure, to recognize fixes
+    failing: HashSet<BuildTarget>,
+    /// OBS users responsible for a package, per (project, package), and when they were queried
+    maintainers: HashMap<(String, String), (Instant, Vec<String>)>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 struct BuildSuccessInfo {
     arch: String,
     repository: String,
@@ -92,52 +109,381 @@ struct BuildSuccessInfo {
     previouslyfailed: Option<String>,
 }
 
-impl MessageHandler for Subscriber<PackageKey> {
-    /// Will be called for every text message send to a room the bot is in
-    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
-        let res = self.handle_message_helper(bot, &message.body, &message.room);
+/// Everything the build templates can use
+#[derive(Serialize)]
+struct BuildContext<'a> {
+    #[serde(flatten)]
+    info: &'a BuildSuccessInfo,
+    changetype: &'a str,
+    url: String,
+    log_url: String,
+    log_excerpt: &'a [String],
+    /// Plain and HTML version of the users to mention
+    mentions: String,
+    mention_pills: String,
+}
+
+/// How much of the end of a build log to look at for errors
+const LOG_TAIL_SIZE: usize = 64 * 1024;
+/// Maximum number of lines in a log excerpt
+const LOG_EXCERPT_LINES: usize = 15;
+/// Lines (lowercase) containing any of these probably explain a failed build
+const LOG_ERROR_MARKERS: [&str; 12] = [
+    "error:",
+    "error ",
+    "fatal",
+    "undefined reference",
+    "no such file",
+    "cannot find",
+    "command not found",
+    "make: ***",
+    "segmentation fault",
+    "nothing provides",
+    "bad exit status",
+    "test failed",
+];
 
-        if res == MessageParseResult::SomethingForMe {
-            match self.register() {
-                Err(x) => {
-                    println!("Error while registering: {:?}", x);
-                }
-                Ok(consumer) => consumer.set_delegate(Box::new(self.clone())),
+/// Maintainers and bugowners of a package, or of its project if the package has none
+fn fetch_responsible_users(
+    obs_api: &dyn ObsApi,
+    project: &str,
+    package: &str,
+) -> Result<Vec<String>> {
+    let is_responsible =
+        |person: &&PersonRole| person.role == "maintainer" || person.role == "bugowner";
+
+    let persons = match obs_api.package_meta(project, package) {
+        Ok(meta) if meta.persons.iter().any(|x| is_responsible(&x)) => meta.persons,
+        _ => obs_api.project_meta(project)?.persons,
+    };
+    Ok(persons
+        .iter()
+        .filter(is_responsible)
+        .map(|x| x.userid.clone())
+        .collect())
+}
+
+/// Like fetch_responsible_users, but cached for MAINTAINERS_TTL, as every build event needs them
+fn responsible_users(
+    state: &Mutex<BuildState>,
+    obs_api: &dyn ObsApi,
+    project: &str,
+    package: &str,
+) -> Vec<String> {
+    let key = (project.to_string(), package.to_string());
+    if let Ok(state) = state.lock() {
+        if let Some((since, users)) = state.maintainers.get(&key) {
+            if since.elapsed() < MAINTAINERS_TTL {
+                return users.clone();
+            }
+        }
+    }
+
+    let users = match fetch_responsible_users(obs_api, project, package) {
+        Ok(x) => x,
+        Err(x) => {
+            println!(
+                "Could not get maintainers of {}/{}: {:?}",
+                project, package, x
+            );
+            return Vec::new();
+        }
+    };
+
+    if let Ok(mut state) = state.lock() {
+        if state.maintainers.len() >= MAINTAINERS_CACHE_SIZE {
+            state
+                .maintainers
+                .retain(|_, (since, _)| since.elapsed() < MAINTAINERS_TTL);
+        }
+        if state.maintainers.len() >= MAINTAINERS_CACHE_SIZE {
+            state.maintainers.clear();
+        }
+        state
+            .maintainers
+            .insert(key, (Instant::now(), users.clone()));
+    }
+    users
+}
+
+/// Removes the timestamp like '[  123s] ' of a build log line, but not e.g. '[ERROR] '
+fn strip_timestamp(line: &str) -> &str {
+    let timestamp = line
+        .strip_prefix('[')
+        .and_then(|x| x.split_once(']'))
+        .filter(|(time, _)| {
+            let time = time.trim_start();
+            time.len() > 1
+                && time.ends_with('s')
+                && time[..time.len() - 1].chars().all(|c| c.is_ascii_digit())
+        });
+    match timestamp {
+        Some((_, rest)) => rest.strip_prefix(' ').unwrap_or(rest),
+        None => line,
+    }
+}
+
+/// Picks the lines of a build log, that most likely explain the failure
+fn extract_log_errors(log: &str) -> Vec<String> {
+    let lines = log
+        .lines()
+        .map(strip_timestamp)
+        .map(|line| line.trim_end())
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>();
+
+    let mut errors = Vec::new();
+    for line in &lines {
+        let lower = line.to_lowercase();
+        if LOG_ERROR_MARKERS
+            .iter()
+            .any(|marker| lower.contains(marker))
+            && !errors.contains(line)
+        {
+            errors.push(*line);
+        }
+    }
+
+    // If nothing looks like an error, the end of the log is the next best thing
+    if errors.is_empty() {
+        errors = lines;
+    }
+
+    let skip = errors.len().saturating_sub(LOG_EXCERPT_LINES);
+    errors[skip..].iter().map(|x| x.to_string()).collect()
+}
+
+impl CommandHandler for Subscriber<PackageKey> {
+    fn owns(&self, url: &str) -> bool {
+        self.owns_url(url)
+    }
+
+    fn execute(&mut self, bot: &ActiveBot, message: &Message, command: &Command, urls: &[Token]) {
+        if command.verb == Verb::Status {
+            for url in urls {
+                self.send_status(bot, &url.text, &message.room);
             }
+            return;
+        }
+
+        if self.execute_command(bot, message, command, urls) == MessageParseResult::SomethingForMe {
+            self.start_listening();
         }
-        HandleResult::ContinueHandling
+    }
+
+    fn list(&self, room: &str, sender: &str) -> Vec<ListEntry> {
+        self.list_entries(room, sender)
+    }
+
+    fn all_subscriptions(&self) -> Vec<(Target, ListEntry)> {
+        self.all_entries()
+    }
+
+    fn unsubscribe_room(&mut self, room: &str) -> usize {
+        Subscriber::unsubscribe_room(self, room)
+    }
+
+    fn export(&self, room: &str) -> Vec<String> {
+        self.export_entries(room)
+    }
+
+    fn subscribe_default(&mut self, command: &Command, urls: &[Token], room: &str) -> Vec<String> {
+        let failed = self.subscribe_to_defaults(command, urls, room);
+        if failed.len() < urls.len() {
+            self.start_listening();
+        }
+        failed
     }
 }
 
 impl Subscriber<PackageKey> {
-    fn generate_messages(&self, jsondata: BuildSuccessInfo, changetype: &str) -> (String, String) {
-        let plain = format!(
-            "Build {}: {}/{} ({} / {})",
-            changetype, jsondata.project, jsondata.package, jsondata.arch, jsondata.repository,
-        );
+    fn send_status(&self, bot: &ActiveBot, line: &str, room: &str) {
+        let lang = self.settings.language(room);
+        let key = match PackageKey::parse(line, false) {
+            Some(x) => x,
+            None => {
+                bot.send_message(
+                    &tr(
+                        &lang,
+                        "Sorry, I could not parse that. Please post a package URL",
+                    ),
+                    room,
+                    MessageType::TextMessage,
+                );
+                return;
+            }
+        };
 
-        let html = format!(
-            "<strong>Build {}</strong>: <a href={}>{}/{}</a> ({} / {})",
-            if changetype == "succeeded" {
-                changetype.to_string()
+        let results = match self
+            .obs_api
+            .as_ref()
+            .map(|api| api.build_results(&key.project, &key.package))
+        {
+            Some(Ok(x)) => x,
+            Some(Err(x)) => {
+                println!("Could not get build results of {}: {:?}", key, x);
+                bot.send_message(
+                    &trf(
+                        &lang,
+                        "Sorry, I could not get the build results of {} on {}",
+                        &[&key, &self.server_details.domain],
+                    ),
+                    room,
+                    MessageType::TextMessage,
+                );
+                return;
+            }
+            None => return,
+        };
+
+        let mut rows = Vec::new();
+        for result in &results.results {
+            for status in &result.statuses {
+                if status.package != key.package {
+                    continue;
+                }
+                rows.push((
+                    result.repository.clone(),
+                    result.arch.clone(),
+                    status.code.clone(),
+                    status.details.clone().unwrap_or_default(),
+                ));
+            }
+        }
+        rows.sort();
+
+        if rows.is_empty() {
+            bot.send_message(
+                &trf(
+                    &lang,
+                    "No build results found for {} on {}",
+                    &[&key, &self.server_details.domain],
+                ),
+                room,
+                MessageType::TextMessage,
+            );
+            return;
+        }
+
+        let mut plain = trf(
+            &lang,
+            "Build results of {} on {}:",
+            &[&key, &self.server_details.domain],
+        );
+        let link = format!("<a href=\"{}\">{}</a>", key.url(&self.get_base_url()), key);
+        let mut html = trf(
+            &lang,
+            "Build results of {} on {}:",
+            &[&link, &self.server_details.domain],
+        );
+        html += "<br><table>";
+        for (repository, arch, code, details) in &rows {
+            let details = if details.is_empty() {
+                String::new()
             } else {
-                format!("<u>{}</u>", changetype)
-            },
-            format!(
+                format!(" ({})", details)
+            };
+            plain += &format!("\n{:<30} {:<10} {}{}", repository, arch, code, details);
+
+            let code = match code.as_str() {
+                "succeeded" | "excluded" | "disabled" => code.clone(),
+                "failed" | "unresolvable" | "broken" => format!("<strong><u>{}</u></strong>", code),
+                _ => format!("<em>{}</em>", html_escape(code)),
+            };
+            html += &format!(
+                "<tr><td>{}</td><td>{}</td><td>{}{}</td></tr>",
+                html_escape(repository),
+                html_escape(arch),
+                code,
+                html_escape(&details)
+            );
+        }
+        html += "</table>";
+
+        bot.send_html_message(&plain, &html, room, MessageType::TextMessage);
+    }
+
+    fn log_excerpt(&self, jsondata: &BuildSuccessInfo) -> Vec<String> {
+        let obs_api = match &self.obs_api {
+            Some(x) => x,
+            None => return Vec::new(),
+        };
+
+        match obs_api.build_log_tail(
+            &jsondata.project,
+            &jsondata.repository,
+            &jsondata.arch,
+            &jsondata.package,
+            LOG_TAIL_SIZE,
+        ) {
+            Ok(log) => extract_log_errors(&log),
+            Err(x) => {
+                println!(
+                    "Could not get build log of {}/{}: {:?}",
+                    jsondata.project, jsondata.package, x
+                );
+                Vec::new()
+            }
+        }
+    }
+
+    /// Linked maintainers and bugowners of the package (or its project, if it has none)
+    fn responsible_users(&self, jsondata: &BuildSuccessInfo) -> Vec<String> {
+        let obs_api = match &self.obs_api {
+            Some(x) => x,
+            None => return Vec::new(),
+        };
+        let users = responsible_users(
+            &self.state,
+            obs_api.as_ref(),
+            &jsondata.project,
+            &jsondata.package,
+        );
+        self.linked_matrix_users(users.iter().map(|x| x.as_str()))
+    }
+
+    fn generate_messages(
+        &self,
+        room: &str,
+        jsondata: &BuildSuccessInfo,
+        changetype: &str,
+        log_excerpt: &[String],
+        mentions: &[String],
+    ) -> (String, String) {
+        let (plain_pills, html_pills) = mention_pills(mentions);
+        let context = BuildContext {
+            info: jsondata,
+            changetype,
+            url: format!(
                 "{}/{}/{}",
                 self.get_base_url(),
                 jsondata.project,
+                jsondata.package
+            ),
+            log_url: format!(
+                "https://{}.{}/package/live_build_log/{}/{}/{}/{}",
+                self.server_details.buildprefix,
+                self.server_details.domain,
+                jsondata.project,
                 jsondata.package,
+                jsondata.repository,
+                jsondata.arch,
             ),
-            jsondata.project,
-            jsondata.package,
-            jsondata.arch,
-            jsondata.repository,
-        );
+            log_excerpt,
+            mentions: plain_pills,
+            mention_pills: html_pills,
+        };
 
-        (plain, html)
+        let event = if changetype == "failed" {
+            templates::EVENT_BUILD_FAILED
+        } else {
+            templates::EVENT_BUILD_SUCCEEDED
+        };
+        self.templates.render(room, event, &context)
     }
+}
 
+impl EventHandler for Subscriber<PackageKey> {
     fn delivery_wrapper(&self, delivery: Delivery) -> Result<()> {
         let data = std::str::from_utf8(&delivery.data)?;
         let jsondata: BuildSuccessInfo = serde_json::from_str(data)?;
@@ -158,14 +504,14 @@ impl Subscriber<PackageKey> {
             project: jsondata.project.clone(),
             package: jsondata.package.clone(),
         };
-        let rooms;
+        let targets;
         if let Ok(subscriptions) = self.subscriptions.lock() {
             // This is a message we are not subscribed to
             if !subscriptions.contains_key(&key) {
                 return Ok(());
             }
 
-            rooms = subscriptions[&key].clone();
+            targets = subscriptions[&key].clone();
         } else {
             return Ok(());
         }
@@ -175,70 +521,225 @@ impl Subscriber<PackageKey> {
             build_res, jsondata.project, jsondata.package, jsondata.arch
         );
 
-        if let Ok(bot) = self.bot.lock() {
-            let (plain, html) = self.generate_messages(jsondata, build_res);
-            for room in &rooms {
-                bot.send_html_message(&plain, &html, room, MessageType::TextMessage);
-            }
-        }
-
-        Ok(())
-    }
-}
+        let build_target = (
+            jsondata.project.clone(),
+            jsondata.package.clone(),
+            jsondata.repository.clone(),
+            jsondata.arch.clone(),
+        );
+        let was_failing = match self.state.lock() {
+            Ok(mut state) if build_res == "failed" => !state.failing.insert(build_target),
+            Ok(mut state) => state.failing.remove(&build_target),
+            Err(_) => false,
+        };
+        let kind = match (build_res, was_failing) {
+            ("failed", _) => EventKind::BuildFailed,
+            (_, true) => EventKind::BuildFixed,
+            (_, false) => EventKind::BuildSucceeded,
+        };
 
-impl ConsumerDelegate for Subscriber<PackageKey> {
-    fn on_new_delivery(&self, delivery: DeliveryResult) {
-        if let Ok(Some(delivery)) = delivery {
-            if let Some(channel) = &self.channel {
-                let _ = channel
-                    .basic_ack(delivery.delivery_tag, BasicAckOptions::default())
-                    .wait();
-            }
-            match self.delivery_wrapper(delivery) {
-                Ok(_) => {}
-                Err(x) => println!("Error while getting Event: {:?}. Skipping to continue", x),
-            }
+        let (log_excerpt, responsible) = if build_res == "failed" {
+            (
+                self.log_excerpt(&jsondata),
+                self.responsible_users(&jsondata),
+            )
         } else {
-            println!(
-                "Delivery not ok on {}: {:?}",
-                self.server_details.domain, delivery
+            (Vec::new(), Vec::new())
+        };
+
+        let sources = [key.url(&self.get_base_url())];
+        for target in &targets {
+            let room = match self.room_of(target) {
+                Some(x) => x,
+                None => continue,
+            };
+            let mentions = if build_res == "failed" {
+                self.mentions(&key, target, &responsible)
+            } else {
+                Vec::new()
+            };
+
+            let (plain, html) =
+                self.generate_messages(&room, &jsondata, build_res, &log_excerpt, &mentions);
+            self.send_notification(
+                delivery.routing_key.as_str(),
+                &room,
+                kind,
+                &sources,
+                &plain,
+                &html,
             );
         }
+
+        Ok(())
     }
 }
 
 pub fn init(
-    bot: &mut MatrixBot,
     details: &ConnectionDetails,
     conn: Connection,
-    prefix: Option<String>,
-    default_subs: &Option<Vec<(String, String)>>,
+    obs_api: Arc<dyn ObsApi>,
+    shared: &Shared,
 ) -> Result<()> {
-    let activebot = bot.get_activebot_clone();
-    let mut sub: Subscriber<PackageKey> = Subscriber {
+    let sub: Subscriber<PackageKey> = Subscriber {
         subtype: "package".to_string(),
         server_details: *details,
         connection: conn,
         channel: None,
+        events: None,
         subnames: SUBNAMES.to_vec(),
-        bot: Arc::new(Mutex::new(activebot)),
         subscriptions: Arc::new(Mutex::new(HashMap::new())),
-        prefix,
+        notify: Arc::new(Mutex::new(HashMap::new())),
+        state: Default::default(),
+        obs_api: Some(obs_api),
+        accounts: shared.accounts.clone(),
+        direct: shared.direct.clone(),
+        outbox: shared.outbox.clone(),
+        templates: shared.templates.clone(),
+        settings: shared.settings.clone(),
+        stats: shared.stats.clone(),
     };
 
-    match default_subs {
-        None => {}
-        Some(subs) => match sub.register() {
-            Err(_) => {}
-            Ok(consumer) => {
-                consumer.set_delegate(Box::new(sub.clone()));
-                for (room, url) in subs {
-                    sub.subscribe_to_defaults(&url, &room);
-                }
-            }
-        },
-    }
-    bot.add_handler(sub);
+    shared.router.add(Box::new(sub));
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::obs_api::{FakeObsApi, PackageMeta, ProjectMeta};
+
+    fn fake_api() -> FakeObsApi {
+        let mut api = FakeObsApi::default();
+        let package: PackageMeta = quick_xml::de::from_str(
+            r#"<package name="bar" project="devel:foo">
+  <person userid="bob" role="maintainer"/>
+  <person userid="carol" role="bugowner"/>
+  <person userid="dave" role="reviewer"/>
+</package>"#,
+        )
+        .unwrap();
+        let unmaintained: PackageMeta =
+            quick_xml::de::from_str(r#"<package name="baz" project="devel:foo"/>"#).unwrap();
+        let project: ProjectMeta = quick_xml::de::from_str(
+            r#"<project name="devel:foo"><person userid="erin" role="maintainer"/></project>"#,
+        )
+        .unwrap();
+        api.packages
+            .insert(("devel:foo".to_string(), "bar".to_string()), package);
+        api.packages
+            .insert(("devel:foo".to_string(), "baz".to_string()), unmaintained);
+        api.projects.insert("devel:foo".to_string(), project);
+        api
+    }
+
+    #[test]
+    fn responsible_users_of_package_or_project() {
+        let api = fake_api();
+        assert_eq!(
+            fetch_responsible_users(&api, "devel:foo", "bar").unwrap(),
+            ["bob", "carol"]
+        );
+        assert_eq!(
+            fetch_responsible_users(&api, "devel:foo", "baz").unwrap(),
+            ["erin"]
+        );
+        assert!(fetch_responsible_users(&api, "devel:other", "bar").is_err());
+    }
+
+    #[test]
+    fn responsible_users_are_cached() {
+        let api = fake_api();
+        let state = Mutex::new(BuildState::default());
+        for _ in 0..3 {
+            assert_eq!(
+                responsible_users(&state, &api, "devel:foo", "bar"),
+                ["bob", "carol"]
+            );
+        }
+        assert_eq!(api.count("package_meta"), 1);
+
+        // Expired entries are queried again
+        state
+            .lock()
+            .unwrap()
+            .maintainers
+            .values_mut()
+            .for_each(|(since, _)| *since -= MAINTAINERS_TTL);
+        responsible_users(&state, &api, "devel:foo", "bar");
+        assert_eq!(api.count("package_meta"), 2);
+
+        // Errors are not cached
+        assert!(responsible_users(&state, &api, "devel:other", "bar").is_empty());
+        assert!(responsible_users(&state, &api, "devel:other", "bar").is_empty());
+        assert_eq!(api.count("project_meta devel:other"), 2);
+    }
+
+    #[test]
+    fn timestamps_are_stripped() {
+        assert_eq!(strip_timestamp("[  123s] make all"), "make all");
+        assert_eq!(strip_timestamp("[12345s] "), "");
+        assert_eq!(strip_timestamp("[ERROR] Failed"), "[ERROR] Failed");
+        assert_eq!(strip_timestamp("[ 1s]   indented"), "  indented");
+        assert_eq!(strip_timestamp("[s] no time"), "[s] no time");
+        assert_eq!(strip_timestamp("no timestamp"), "no timestamp");
+    }
+
+    #[test]
+    fn errors_are_picked() {
+        let log = "[    1s] Building bar
+[    2s] gcc -c main.c
+[    3s] main.c:12:5: error: 'x' undeclared
+[    3s] main.c:12:5: error: 'x' undeclared
+[    4s] [ERROR] Test failed: test_x
+[    5s] make: *** [Makefile:10: all] Error 1
+[    6s] 
+[    7s] done";
+        assert_eq!(
+            extract_log_errors(log),
+            [
+                "main.c:12:5: error: 'x' undeclared",
+                "[ERROR] Test failed: test_x",
+                "make: *** [Makefile:10: all] Error 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn markers_ignore_case() {
+        let log = "[  1s] nothing provides libfoo needed by bar\n[  2s] FATAL: out of memory\n[  3s] all good";
+        assert_eq!(
+            extract_log_errors(log),
+            [
+                "nothing provides libfoo needed by bar",
+                "FATAL: out of memory"
+            ]
+        );
+    }
+
+    #[test]
+    fn end_of_log_without_errors() {
+        let log = (1..=20)
+            .map(|x| format!("[  {}s] line {}", x, x))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let excerpt = extract_log_errors(&log);
+        assert_eq!(excerpt.len(), LOG_EXCERPT_LINES);
+        assert_eq!(excerpt[0], "line 6");
+        assert_eq!(excerpt[LOG_EXCERPT_LINES - 1], "line 20");
+        assert!(extract_log_errors("").is_empty());
+    }
+
+    #[test]
+    fn at_most_the_last_errors() {
+        let log = (1..=30)
+            .map(|x| format!("[  {}s] error: number {}", x, x))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let excerpt = extract_log_errors(&log);
+        assert_eq!(excerpt.len(), LOG_EXCERPT_LINES);
+        assert_eq!(excerpt[0], "error: number 16");
+        assert_eq!(excerpt[LOG_EXCERPT_LINES - 1], "error: number 30");
+    }
+}
@@ -0,0 +1,746 @@
+use crate::common::{html_escape, Target};
+use crate::i18n::{tr, trf, DEFAULT_LANGUAGE};
+use crate::listing::{self, ListEntry, ListQuery};
+use crate::reference;
+use crate::settings::Settings;
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use std::sync::{Arc, Mutex};
+
+/// What can be given to 'list'
+pub const LIST_TYPES: [&str; 3] = ["packages", "requests", "tests"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verb {
+    Sub,
+    Unsub,
+    List,
+    Status,
+    Notify,
+    Snooze,
+    Unsnooze,
+    Flaky,
+}
+
+/// A word of a command, with its position (in chars) for error messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub text: String,
+    pub start: usize,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Command {
+    pub verb: Verb,
+    /// 'sub me' or 'unsub me'
+    pub personal: bool,
+    /// 'sub summary' or 'unsub summary'
+    pub summary: bool,
+    /// Just URLs were posted, without 'sub'
+    pub implicit: bool,
+    pub urls: Vec<Token>,
+    /// Everything else, like the list type, the users of 'notify' or the duration of 'snooze'
+    pub args: Vec<Token>,
+    /// The line without prefix, to point at tokens in errors
+    pub line: String,
+}
+
+/// What went wrong, and where
+#[derive(Debug, Clone)]
+pub struct CommandError {
+    pub line: String,
+    /// (start, len) of the bad part in line, in chars
+    pub span: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl CommandError {
+    pub fn new(line: &str, message: String) -> Self {
+        CommandError {
+            line: line.to_string(),
+            span: None,
+            message,
+        }
+    }
+
+    pub fn at(line: &str, token: &Token, message: String) -> Self {
+        CommandError {
+            line: line.to_string(),
+            span: Some((token.start, token.len.max(1))),
+            message,
+        }
+    }
+
+    /// From the first to the last token
+    pub fn spanning(line: &str, tokens: &[Token], message: String) -> Self {
+        match (tokens.first(), tokens.last()) {
+            (Some(first), Some(last)) => CommandError {
+                line: line.to_string(),
+                span: Some((first.start, last.start + last.len - first.start)),
+                message,
+            },
+            _ => CommandError::new(line, message),
+        }
+    }
+
+    /// Plain and HTML message, with the bad part of the line marked
+    pub fn render(&self) -> (String, String) {
+        let (start, len) = match self.span {
+            Some(x) => x,
+            None => return (self.message.clone(), html_escape(&self.message)),
+        };
+        let marker = format!("{}{}", " ".repeat(start), "^".repeat(len));
+        (
+            format!("{}\n{}\n{}", self.message, self.line, marker),
+            format!(
+                "{}<pre><code>{}\n{}</code></pre>",
+                html_escape(&self.message),
+                html_escape(&self.line),
+                marker
+            ),
+        )
+    }
+}
+
+pub fn send_error(bot: &ActiveBot, room: &str, error: &CommandError) {
+    let (plain, html) = error.render();
+    bot.send_html_message(&plain, &html, room, MessageType::TextMessage);
+}
+
+/// URLs and shorthands like sr#1234 or PROJECT/PACKAGE
+pub fn looks_like_url(text: &str) -> bool {
+    text.contains("://") || reference::parse(text).is_some()
+}
+
+/// Splits a line into words. Quotes ('...' or "...") group words, a backslash escapes the next char.
+pub fn tokenize(line: &str, lang: &str) -> Result<Vec<Token>, CommandError> {
+    let mut tokens = Vec::new();
+    let mut current: Option<Token> = None;
+    let mut quote: Option<(char, usize)> = None;
+    let mut chars = line.chars().enumerate();
+
+    while let Some((pos, c)) = chars.next() {
+        if quote.is_none() && c.is_whitespace() {
+            if let Some(mut token) = current.take() {
+                token.len = pos - token.start;
+                tokens.push(token);
+            }
+            continue;
+        }
+
+        let token = current.get_or_insert_with(|| Token {
+            text: String::new(),
+            start: pos,
+            len: 0,
+        });
+        match (c, quote) {
+            ('\\', _) => {
+                if let Some((_, escaped)) = chars.next() {
+                    token.text.push(escaped);
+                }
+            }
+            (c, Some((open, _))) if c == open => quote = None,
+            ('\'', None) | ('"', None) => quote = Some((c, pos)),
+            (c, _) => token.text.push(c),
+        }
+    }
+
+    if let Some((_, pos)) = quote {
+        let rest = Token {
+            text: String::new(),
+            start: pos,
+            len: line.chars().count() - pos,
+        };
+        return Err(CommandError::at(
+            line,
+            &rest,
+            tr(lang, "This quote is never closed"),
+        ));
+    }
+    if let Some(mut token) = current {
+        token.len = line.chars().count() - token.start;
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Parses a line of a message into a command. Returns None, if the line is not a
+/// subscription command (but maybe one for another handler).
+pub fn parse(line: &str, prefix: &str, lang: &str) -> Result<Option<Command>, CommandError> {
+    // Without a prefix, chat like 'status update later' or 'list of todos' starts with
+    // the same words. Only lines with a URL are taken as broken commands then.
+    let strict = !prefix.is_empty()
+        || line
+            .split_whitespace()
+            .skip(1)
+            .any(|x| x.contains("://") || reference::parse(x).is_some());
+    match parse_strict(line, prefix, lang) {
+        Err(_) if !strict => Ok(None),
+        x => x,
+    }
+}
+
+/// Like parse, but errors are returned for all lines starting with a command
+fn parse_strict(line: &str, prefix: &str, lang: &str) -> Result<Option<Command>, CommandError> {
+    let line = line.trim();
+    if !line.starts_with(prefix) {
+        return Ok(None);
+    }
+    let line = line[prefix.len()..].trim();
+
+    // Only tokenize commands, so quotes in other messages cause no errors
+    let (verb, skip) = match line.split_whitespace().next() {
+        Some("sub") => (Verb::Sub, 1),
+        Some("unsub") => (Verb::Unsub, 1),
+        Some("list") => (Verb::List, 1),
+        Some("status") => (Verb::Status, 1),
+        Some("notify") => (Verb::Notify, 1),
+        Some("snooze") => (Verb::Snooze, 1),
+        Some("unsnooze") => (Verb::Unsnooze, 1),
+        Some("flaky") => (Verb::Flaky, 1),
+        // Posting URLs (optionally with 'summary' in front) subscribes to them, but
+        // shorthands need a 'sub', as too many words look like PROJECT/PACKAGE
+        Some("summary") => (Verb::Sub, 0),
+        Some(x) if x.contains("://") => (Verb::Sub, 0),
+        _ => return Ok(None),
+    };
+    let tokens = tokenize(line, lang)?;
+    let rest = &tokens[skip.min(tokens.len())..];
+
+    let mut command = Command {
+        verb,
+        personal: false,
+        summary: false,
+        implicit: skip == 0,
+        urls: Vec::new(),
+        args: Vec::new(),
+        line: line.to_string(),
+    };
+    let mut rest = rest.iter().peekable();
+    if matches!(verb, Verb::Sub | Verb::Unsub) && rest.peek().map(|x| x.text == "me") == Some(true)
+    {
+        command.personal = true;
+        rest.next();
+    }
+    if matches!(verb, Verb::Sub | Verb::Unsub)
+        && rest.peek().map(|x| x.text == "summary") == Some(true)
+    {
+        command.summary = true;
+        rest.next();
+    }
+    for token in rest {
+        if looks_like_url(&token.text) {
+            command.urls.push(token.clone());
+        } else {
+            command.args.push(token.clone());
+        }
+    }
+
+    match validate(&command, lang) {
+        Ok(()) => Ok(Some(command)),
+        // Probably a URL followed by some chatting
+        Err(_) if command.implicit => Ok(None),
+        Err(x) => Err(x),
+    }
+}
+
+/// A line of a default_subs entry, where 'sub' is optional
+pub fn parse_entry(line: &str, lang: &str) -> Result<Option<Command>, CommandError> {
+    // Shorthands need the 'sub'
+    let line = match line.split_whitespace().next() {
+        Some("sub") => line.to_string(),
+        Some(_) => format!("sub {}", line),
+        None => return Ok(None),
+    };
+    match parse_strict(&line, "", lang)? {
+        Some(x) if x.verb == Verb::Sub => Ok(Some(x)),
+        _ => Ok(None),
+    }
+}
+
+fn validate(command: &Command, lang: &str) -> Result<(), CommandError> {
+    let line = &command.line;
+    let usage = |text: &str| Err(CommandError::new(line, tr(lang, text)));
+
+    match command.verb {
+        Verb::Sub | Verb::Unsub | Verb::Status | Verb::Unsnooze => {
+            if let Some(arg) = command.args.first() {
+                return Err(CommandError::at(line, arg, tr(lang, "Expected a URL here")));
+            }
+            if command.urls.is_empty() {
+                return match command.verb {
+                    Verb::Sub => usage("Usage: sub [me] [summary] URL..."),
+                    Verb::Unsub => usage("Usage: unsub [me] [summary] URL..."),
+                    Verb::Status => usage("Usage: status URL..."),
+                    _ => usage("Usage: unsnooze URL..."),
+                };
+            }
+        }
+        Verb::List => {
+            ListQuery::parse(command, lang)?;
+        }
+        Verb::Flaky => match command.args.as_slice() {
+            [arg] if arg.text == "tests" && command.urls.is_empty() => {}
+            _ => return usage("Usage: flaky tests"),
+        },
+        Verb::Notify => {
+            if let Some(arg) = command
+                .args
+                .iter()
+                .find(|x| x.text != "nobody" && !(x.text.starts_with('@') && x.text.contains(':')))
+            {
+                return Err(CommandError::at(
+                    line,
+                    arg,
+                    tr(lang, "Expected a Matrix user like @user:server here"),
+                ));
+            }
+            if command.args.is_empty() || command.urls.is_empty() {
+                return usage("Usage: notify @USER:SERVER... URL (or 'notify nobody URL')");
+            }
+        }
+        Verb::Snooze => {
+            if let Some(url) = command.urls.get(1) {
+                return Err(CommandError::at(
+                    line,
+                    url,
+                    tr(lang, "Only one URL can be snoozed at a time"),
+                ));
+            }
+            if command.args.is_empty() || command.urls.is_empty() {
+                return usage("Usage: snooze URL 2h | snooze URL until tomorrow | unsnooze URL");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Gets the commands for the URLs it owns (one per backend and subscription type)
+pub trait CommandHandler: Send {
+    fn owns(&self, url: &str) -> bool;
+
+    /// urls are the ones of command this handler owns. Commands without URLs (like
+    /// 'flaky tests') go to all handlers.
+    fn execute(&mut self, bot: &ActiveBot, message: &Message, command: &Command, urls: &[Token]);
+
+    /// Subscriptions visible to sender in room, for 'list'
+    fn list(&self, room: &str, sender: &str) -> Vec<ListEntry>;
+
+    /// Subscriptions of all rooms and users
+    fn all_subscriptions(&self) -> Vec<(Target, ListEntry)>;
+
+    /// Removes all subscriptions of room, returns how many there were
+    fn unsubscribe_room(&mut self, room: &str) -> usize;
+
+    /// Entries like the ones of default_subs, for the subscriptions of room
+    fn export(&self, room: &str) -> Vec<String>;
+
+    /// Subscribes room to the urls of a 'sub' command from default_subs (or an imported
+    /// file). Returns the ones that could not be subscribed to.
+    fn subscribe_default(&mut self, command: &Command, urls: &[Token], room: &str) -> Vec<String>;
+}
+
+/// Parses every line once and hands the URLs to the handlers they belong to
+#[derive(Clone)]
+pub struct CommandRouter {
+    handlers: Arc<Mutex<Vec<Box<dyn CommandHandler>>>>,
+    settings: Settings,
+    prefix: Option<String>,
+}
+
+impl CommandRouter {
+    pub fn new(settings: Settings, prefix: Option<String>) -> Self {
+        CommandRouter {
+            handlers: Arc::new(Mutex::new(Vec::new())),
+            settings,
+            prefix,
+        }
+    }
+
+    pub fn add(&self, handler: Box<dyn CommandHandler>) {
+        if let Ok(mut handlers) = self.handlers.lock() {
+            handlers.push(handler);
+        }
+    }
+
+    /// Subscriptions of all handlers, for admins
+    pub fn all_subscriptions(&self) -> Vec<(Target, ListEntry)> {
+        self.handlers
+            .lock()
+            .map(|x| x.iter().flat_map(|x| x.all_subscriptions()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn unsubscribe_room(&self, room: &str) -> usize {
+        self.handlers
+            .lock()
+            .map(|mut x| x.iter_mut().map(|x| x.unsubscribe_room(room)).sum())
+            .unwrap_or(0)
+    }
+
+    /// Entries like the ones of default_subs, for the subscriptions of room
+    pub fn export(&self, room: &str) -> Vec<String> {
+        self.handlers
+            .lock()
+            .map(|x| x.iter().flat_map(|x| x.export(room)).collect())
+            .unwrap_or_default()
+    }
+
+    /// Subscribes room to entries like the ones of default_subs: lines of URLs, 'sub' is
+    /// optional. Returns how many URLs were subscribed to, and the ones (or the lines) that
+    /// failed, with the reason in lang.
+    pub fn subscribe_entries(
+        &self,
+        room: &str,
+        entries: &[String],
+        lang: &str,
+    ) -> (usize, Vec<(String, String)>) {
+        let mut handlers = match self.handlers.lock() {
+            Ok(x) => x,
+            Err(_) => return (0, Vec::new()),
+        };
+
+        let mut subscribed = 0;
+        let mut failed = Vec::new();
+        for line in entries.iter().flat_map(|x| x.lines()) {
+            let command = match parse_entry(line, lang) {
+                Ok(Some(x)) => x,
+                Ok(None) => continue,
+                Err(x) => {
+                    failed.push((line.to_string(), x.message));
+                    continue;
+                }
+            };
+
+            let (owned, unowned) = group_urls(&handlers, &command.urls);
+            for url in unowned {
+                failed.push((url.text.clone(), tr(lang, "not on a backend I know")));
+            }
+            for (handler, urls) in handlers.iter_mut().zip(owned.iter()) {
+                if !urls.is_empty() {
+                    let failed_urls = handler.subscribe_default(&command, urls, room);
+                    subscribed += urls.len() - failed_urls.len();
+                    failed.extend(
+                        failed_urls
+                            .into_iter()
+                            .map(|x| (x, tr(lang, "not parsable"))),
+                    );
+                }
+            }
+        }
+        (subscribed, failed)
+    }
+
+    /// (room, entry) of the config
+    pub fn subscribe_to_defaults(&self, default_subs: &[(String, String)]) {
+        for (room, entry) in default_subs {
+            let (_, failed) =
+                self.subscribe_entries(room, std::slice::from_ref(entry), DEFAULT_LANGUAGE);
+            for (entry, reason) in failed {
+                println!("Default subscription {} of {}: {}", entry, room, reason);
+            }
+        }
+    }
+}
+
+/// URLs per handler (each one goes to the first handler owning it) and the ones nobody owns
+fn group_urls<'a>(
+    handlers: &[Box<dyn CommandHandler>],
+    urls: &'a [Token],
+) -> (Vec<Vec<Token>>, Vec<&'a Token>) {
+    let mut owned = vec![Vec::new(); handlers.len()];
+    let mut unowned = Vec::new();
+    for url in urls {
+        match handlers.iter().position(|x| x.owns(&url.text)) {
+            Some(i) => owned[i].push(url.clone()),
+            None => unowned.push(url),
+        }
+    }
+    (owned, unowned)
+}
+
+impl MessageHandler for CommandRouter {
+    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
+        let prefix = self.prefix.as_deref().unwrap_or("");
+        let room = &message.room;
+        let lang = self.settings.language(room);
+        let mut handlers = match self.handlers.lock() {
+            Ok(x) => x,
+            Err(_) => return HandleResult::ContinueHandling,
+        };
+
+        for line in message.body.lines() {
+            let command = match parse(line, prefix, &lang) {
+                Ok(Some(x)) => x,
+                Ok(None) => continue,
+                Err(x) => {
+                    send_error(bot, room, &x);
+                    continue;
+                }
+            };
+
+            if command.verb == Verb::List {
+                // Can't fail, the command was validated
+                if let Ok(query) = ListQuery::parse(&command, &lang) {
+                    let entries = handlers
+                        .iter()
+                        .flat_map(|x| x.list(room, &message.sender))
+                        .collect();
+                    let (plain, html) = listing::render(entries, &query, prefix, &lang);
+                    bot.send_html_message(&plain, &html, room, MessageType::TextMessage);
+                }
+                continue;
+            }
+
+            if command.urls.is_empty() {
+                for handler in handlers.iter_mut() {
+                    handler.execute(bot, message, &command, &[]);
+                }
+                continue;
+            }
+
+            let (owned, unowned) = group_urls(&handlers, &command.urls);
+            // Posting other links is fine
+            if !command.implicit {
+                for url in unowned {
+                    let message = if reference::parse(&url.text).is_some() {
+                        trf(&lang, "Sorry, {} is not on a backend I know", &[&url.text])
+                    } else {
+                        tr(&lang, "Sorry, I could not parse that URL")
+                    };
+                    send_error(bot, room, &CommandError::at(&command.line, url, message));
+                }
+            }
+            for (handler, urls) in handlers.iter_mut().zip(owned.iter()) {
+                if !urls.is_empty() {
+                    handler.execute(bot, message, &command, urls);
+                }
+            }
+        }
+
+        HandleResult::ContinueHandling
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://build.opensuse.org/package/show/devel:foo/bar";
+
+    fn texts(tokens: &[Token]) -> Vec<&str> {
+        tokens.iter().map(|x| x.text.as_str()).collect()
+    }
+
+    fn parse_en(line: &str, prefix: &str) -> Result<Option<Command>, CommandError> {
+        parse(line, prefix, DEFAULT_LANGUAGE)
+    }
+
+    fn parse_error(line: &str, prefix: &str) -> CommandError {
+        match parse_en(line, prefix) {
+            Err(x) => x,
+            Ok(x) => panic!("No error for {:?}: {:?}", line, x),
+        }
+    }
+
+    #[test]
+    fn tokens_and_positions() {
+        let tokens = tokenize("sub  me\tx", DEFAULT_LANGUAGE).unwrap();
+        assert_eq!(texts(&tokens), ["sub", "me", "x"]);
+        let spans = tokens.iter().map(|x| (x.start, x.len)).collect::<Vec<_>>();
+        assert_eq!(spans, [(0, 3), (5, 2), (8, 1)]);
+        assert!(tokenize("   ", DEFAULT_LANGUAGE).unwrap().is_empty());
+    }
+
+    #[test]
+    fn quotes_and_escapes() {
+        let tokens = tokenize(r#"a "b c" 'd "e"' f\ g h\"i"#, DEFAULT_LANGUAGE).unwrap();
+        assert_eq!(texts(&tokens), ["a", "b c", r#"d "e""#, "f g", r#"h"i"#]);
+        // The span covers the quotes
+        assert_eq!((tokens[1].start, tokens[1].len), (2, 5));
+
+        let tokens = tokenize(r#"x"y z"w ''"#, DEFAULT_LANGUAGE).unwrap();
+        assert_eq!(texts(&tokens), ["xy zw", ""]);
+        // Positions are in chars, not bytes
+        let tokens = tokenize("äö ü", DEFAULT_LANGUAGE).unwrap();
+        assert_eq!((tokens[1].start, tokens[1].len), (3, 1));
+    }
+
+    #[test]
+    fn unclosed_quote() {
+        let error = tokenize(r#"sub "abc def"#, DEFAULT_LANGUAGE).unwrap_err();
+        assert_eq!(error.span, Some((4, 8)));
+        let (plain, _) = error.render();
+        assert_eq!(
+            plain,
+            "This quote is never closed\nsub \"abc def\n    ^^^^^^^^"
+        );
+    }
+
+    #[test]
+    fn error_rendering() {
+        let error = CommandError::spanning(
+            "a <b> c",
+            &tokenize("a <b> c", DEFAULT_LANGUAGE).unwrap()[1..],
+            "Bad & wrong".to_string(),
+        );
+        let (plain, html) = error.render();
+        assert_eq!(plain, "Bad & wrong\na <b> c\n  ^^^^^");
+        assert_eq!(
+            html,
+            "Bad &amp; wrong<pre><code>a &lt;b&gt; c\n  ^^^^^</code></pre>"
+        );
+        let (plain, _) = CommandError::new("x", "Just text".to_string()).render();
+        assert_eq!(plain, "Just text");
+    }
+
+    #[test]
+    fn explicit_commands() {
+        let command = parse_en(&format!("sub me summary {}", URL), "")
+            .unwrap()
+            .unwrap();
+        assert_eq!(command.verb, Verb::Sub);
+        assert!(command.personal && command.summary && !command.implicit);
+        assert_eq!(texts(&command.urls), [URL]);
+
+        let command = parse_en("unsub sr#1234 devel:foo/bar", "")
+            .unwrap()
+            .unwrap();
+        assert_eq!(command.verb, Verb::Unsub);
+        assert_eq!(texts(&command.urls), ["sr#1234", "devel:foo/bar"]);
+
+        let command = parse_en(&format!("!bot notify @a:b nobody {}", URL), "!bot")
+            .unwrap()
+            .unwrap();
+        assert_eq!(command.verb, Verb::Notify);
+        assert_eq!(texts(&command.args), ["@a:b", "nobody"]);
+        assert_eq!(command.line, format!("notify @a:b nobody {}", URL));
+
+        let command = parse_en("flaky tests", "").unwrap().unwrap();
+        assert_eq!(command.verb, Verb::Flaky);
+        let command = parse_en(&format!("snooze {} until tomorrow", URL), "")
+            .unwrap()
+            .unwrap();
+        assert_eq!(texts(&command.args), ["until", "tomorrow"]);
+    }
+
+    #[test]
+    fn implicit_sub() {
+        let command = parse_en(&format!("{} {}", URL, URL), "").unwrap().unwrap();
+        assert_eq!(command.verb, Verb::Sub);
+        assert!(command.implicit);
+        assert_eq!(command.urls.len(), 2);
+
+        let command = parse_en(&format!("summary {}", URL), "").unwrap().unwrap();
+        assert!(command.implicit && command.summary);
+
+        // A URL followed by chatting, or shorthands without 'sub'
+        assert!(parse_en(&format!("{} is broken again", URL), "")
+            .unwrap()
+            .is_none());
+        assert!(parse_en("devel:foo/bar", "").unwrap().is_none());
+        assert!(parse_en(URL, "!bot").unwrap().is_none());
+    }
+
+    #[test]
+    fn verb_errors() {
+        let error = parse_error(&format!("sub {} please", URL), "");
+        assert_eq!(error.message, "Expected a URL here");
+        assert_eq!(error.span, Some((URL.len() + 5, 6)));
+
+        let usages = [
+            ("sub", "Usage: sub [me] [summary] URL..."),
+            ("unsub me", "Usage: unsub [me] [summary] URL..."),
+            ("status", "Usage: status URL..."),
+            ("unsnooze", "Usage: unsnooze URL..."),
+            ("flaky", "Usage: flaky tests"),
+            (
+                "list all of them",
+                "Usage: list [all|packages|requests|tests] [FILTER] [page N]",
+            ),
+            (
+                "list page x",
+                "Usage: list [all|packages|requests|tests] [FILTER] [page N]",
+            ),
+        ];
+        for (line, usage) in &usages {
+            let line = format!("!bot {}", line);
+            assert_eq!(parse_error(&line, "!bot").message, *usage, "{}", line);
+        }
+
+        let error = parse_error(&format!("notify bob {}", URL), "");
+        assert_eq!(
+            error.message,
+            "Expected a Matrix user like @user:server here"
+        );
+        assert_eq!(error.span, Some((7, 3)));
+        assert_eq!(
+            parse_error(&format!("notify {}", URL), "").message,
+            "Usage: notify @USER:SERVER... URL (or 'notify nobody URL')"
+        );
+
+        let error = parse_error(&format!("snooze {} sr#1 2h", URL), "");
+        assert_eq!(error.message, "Only one URL can be snoozed at a time");
+        assert_eq!(
+            parse_error(&format!("snooze {}", URL), "").message,
+            "Usage: snooze URL 2h | snooze URL until tomorrow | unsnooze URL"
+        );
+        assert!(parse_en(&format!("list {}", URL), "").is_err());
+    }
+
+    #[test]
+    fn personal_commands_need_the_prefix() {
+        assert!(parse_en("unsub me sr#1234", "!bot").unwrap().is_none());
+        assert!(parse_en("sub me sr#1234", "!bot").unwrap().is_none());
+        let command = parse_en("!bot unsub me sr#1234", "!bot").unwrap().unwrap();
+        assert_eq!(command.verb, Verb::Unsub);
+        assert!(command.personal);
+        // Only the whole word is the verb
+        assert!(parse_en("unsubscribe me sr#1234", "").unwrap().is_none());
+        assert!(parse_en("!bot unsubscribe me sr#1234", "!bot")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn chat_without_prefix() {
+        for line in [
+            "status update later",
+            "list of todos",
+            "sub-par results today",
+            "snooze 10 more minutes",
+            "notify the others",
+            "flaky wifi again",
+            "sub 'quoted",
+        ] {
+            assert!(parse_en(line, "").unwrap().is_none(), "{}", line);
+        }
+        // With a prefix, the same lines are meant as commands
+        assert!(parse_en("!bot status update later", "!bot").is_err());
+        assert!(parse_en("!bot list of todos", "!bot").is_err());
+        // Lines with a URL are probably commands gone wrong
+        assert!(parse_en(&format!("status {} now", URL), "").is_err());
+        assert!(parse_en("notify bob sr#1234", "").is_err());
+    }
+
+    #[test]
+    fn entries() {
+        let command = parse_entry(URL, DEFAULT_LANGUAGE).unwrap().unwrap();
+        assert_eq!(command.verb, Verb::Sub);
+        let command = parse_entry("summary sr#1234", DEFAULT_LANGUAGE)
+            .unwrap()
+            .unwrap();
+        assert!(command.summary);
+        let command = parse_entry("sub devel:foo/bar", DEFAULT_LANGUAGE)
+            .unwrap()
+            .unwrap();
+        assert_eq!(texts(&command.urls), ["devel:foo/bar"]);
+
+        // Entries have no prefix, but are always meant as commands
+        assert!(parse_entry("sub nothing", DEFAULT_LANGUAGE).is_err());
+        assert!(parse_entry("   ", DEFAULT_LANGUAGE).unwrap().is_none());
+        // Other verbs are not possible, 'sub' is put in front
+        assert!(parse_entry("status sr#1", DEFAULT_LANGUAGE).is_err());
+    }
+}
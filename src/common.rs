@@ -1,41 +1,137 @@
+use crate::accounts::Accounts;
+use crate::admin::Stats;
+use crate::commands::{send_error, Command, CommandError, CommandRouter, Token, Verb, LIST_TYPES};
+use crate::direct::DirectRooms;
+use crate::i18n::{format_time, tr, trf, DEFAULT_LANGUAGE};
+use crate::listing::ListEntry;
+use crate::obs_api::ObsApi;
+use crate::outbox::{EventKind, Outbox};
+use crate::reference::{self, Reference};
+use crate::settings::{parse_until, RoomSettings, Settings};
+use crate::templates::Templates;
 use anyhow::{anyhow, Result};
-use lapin::{options::*, types::FieldTable, Channel, Connection, Consumer, ExchangeKind};
-use matrix_bot_api::{ActiveBot, MessageType};
+use chrono::TimeZone;
+use lapin::{
+    message::{Delivery, DeliveryResult},
+    options::*,
+    types::FieldTable,
+    Channel, Connection, Consumer, ConsumerDelegate, ExchangeKind,
+};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::collections::{HashMap, HashSet};
-use std::convert::TryFrom;
-use std::sync::{Arc, Mutex};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
 
 #[derive(Debug, Clone, Copy)]
 pub struct ConnectionDetails {
     pub domain: &'static str,
     pub login: &'static str,
     pub buildprefix: &'static str,
+    pub apiprefix: &'static str,
     pub rabbitprefix: &'static str,
     pub rabbitscope: &'static str,
 }
 
+/// A chosen backend and its OBS API
+#[derive(Clone)]
+pub struct Backend {
+    pub details: ConnectionDetails,
+    pub obs_api: Arc<dyn ObsApi>,
+}
+
+/// State shared by the handlers of all backends
+#[derive(Clone)]
+pub struct Shared {
+    pub accounts: Accounts,
+    pub direct: DirectRooms,
+    pub outbox: Outbox,
+    pub templates: Templates,
+    pub settings: Settings,
+    /// The Subscribers of all backends register here for their commands
+    pub router: CommandRouter,
+    pub stats: Stats,
+}
+
+/// Everything a Subscriber can be subscribed to
+pub trait SubscriptionKey:
+    Send + Clone + std::hash::Hash + std::cmp::Eq + core::fmt::Display
+{
+    /// Additional state a Subscriber of this key-type needs to keep around
+    type State: Default + Send;
+
+    /// Link to the subscribed object, given the base-URL of the Subscriber
+    fn url(&self, base_url: &str) -> String {
+        format!("{}/{}", base_url, self)
+    }
+
+    /// Entry of default_subs (or an 'export'ed file) subscribing to it again
+    fn export(&self, base_url: &str) -> String {
+        self.url(base_url)
+    }
+
+    /// Key of what a URL points to, for 'sub summary URL' if summary
+    fn from_reference(reference: &Reference, summary: bool) -> Option<Self>;
+
+    /// Key of a URL or shorthand
+    fn parse(text: &str, summary: bool) -> Option<Self> {
+        reference::parse(text).and_then(|x| Self::from_reference(&x.reference, summary))
+    }
+}
+
+/// Where notifications of a subscription go to
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Target {
+    Room(String),
+    /// Personal subscription, delivered to the 1:1 room with this Matrix user
+    User(String),
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Target::Room(_) => write!(f, "room"),
+            Target::User(user) => write!(f, "{}", user),
+        }
+    }
+}
+
+/// What a Subscriber does with the events of the message queue
+pub trait EventHandler {
+    fn delivery_wrapper(&self, delivery: Delivery) -> Result<()>;
+}
+
+/// Matrix users to mention, per subscription (key and target)
+pub type NotifyMap<T> = HashMap<(T, Target), Vec<String>>;
+
 #[derive(Clone)]
 pub struct Subscriber<T>
 where
-    T: Send + Clone + std::hash::Hash + std::cmp::Eq + core::fmt::Display + TryFrom<String>,
+    T: SubscriptionKey,
 {
     pub server_details: ConnectionDetails,
     pub connection: Connection,
     pub channel: Option<Channel>,
-    pub bot: Arc<Mutex<ActiveBot>>,
+    /// To the worker thread handling the events, once registered
+    pub events: Option<mpsc::Sender<Delivery>>,
     pub subnames: Vec<&'static str>,
-    pub subscriptions: Arc<Mutex<HashMap<T, HashSet<String>>>>,
-    pub prefix: Option<String>,
+    pub subscriptions: Arc<Mutex<HashMap<T, HashSet<Target>>>>,
+    /// Matrix users to mention on failures, per subscription
+    pub notify: Arc<Mutex<NotifyMap<T>>>,
+    pub state: Arc<Mutex<T::State>>,
+    pub obs_api: Option<Arc<dyn ObsApi>>,
+    pub accounts: Accounts,
+    pub direct: DirectRooms,
+    /// Notifications go through here, not directly to the bot
+    pub outbox: Outbox,
+    pub templates: Templates,
+    /// For the language of replies
+    pub settings: Settings,
+    pub stats: Stats,
     pub subtype: String,
 }
 
-#[derive(Debug)]
-pub enum ScanLineResult {
-    NotForMe,
-    ListCommand,
-    PossiblyForMe,
-}
-
 #[derive(Debug, PartialEq)]
 pub enum MessageParseResult {
     NothingForMe,
@@ -44,8 +140,19 @@ pub enum MessageParseResult {
 
 impl<T> Subscriber<T>
 where
-    T: Send + Clone + std::hash::Hash + std::cmp::Eq + core::fmt::Display + TryFrom<String>,
+    T: SubscriptionKey,
 {
+    /// OBS user name, plus the Matrix user if they linked their account
+    pub fn display_user(&self, obs_user: &str) -> String {
+        match self
+            .accounts
+            .matrix_user(obs_user, self.server_details.domain)
+        {
+            Some(matrix_user) => format!("{} ({})", obs_user, matrix_user),
+            None => obs_user.to_string(),
+        }
+    }
+
     pub fn get_base_url(&self) -> String {
         let tail = if self.server_details.buildprefix == "openqa" {
             String::new()
@@ -59,50 +166,269 @@ where
         )
     }
 
-    pub fn list_keys(&self, bot: &ActiveBot, room: &str) {
-        if let Ok(subscriptions) = self.subscriptions.lock() {
-            let mut found_subscriptions = Vec::new();
+    /// Personal subscriptions are only visible in the 1:1 room with their user
+    pub fn visible_targets(&self, room: &str, sender: &str) -> Vec<Target> {
+        let mut targets = vec![Target::Room(room.to_string())];
+        if let Some(user) = self.direct.user_of(room, sender) {
+            targets.push(Target::User(user));
+        }
+        targets
+    }
 
-            for (key, value) in subscriptions.iter() {
-                if value.contains(room) {
-                    found_subscriptions.push(key.clone());
-                }
-            }
+    /// Room to send notifications for this target to
+    pub fn room_of(&self, target: &Target) -> Option<String> {
+        match target {
+            Target::Room(room) => Some(room.clone()),
+            Target::User(user) => self
+                .direct
+                .room_for(user)
+                .map_err(|x| println!("ERROR! No direct room for {}: {:?}", user, x))
+                .ok(),
+        }
+    }
 
-            let (htmlanswer, plainanswer) = if found_subscriptions.is_empty() {
-                let answer = "No subscriptions found";
-                (answer.to_string(), answer.to_string())
-            } else {
-                let mut unsorted = found_subscriptions
-                    .iter()
-                    .map(|x| format!("{}", x))
-                    .collect::<Vec<_>>();
-                unsorted.sort();
-
-                unsorted = found_subscriptions
-                    .iter()
-                    .map(|x| format!("<a href={}/{}>{}</a>", self.get_base_url(), x, x))
-                    .collect::<Vec<_>>();
-                unsorted.sort();
-
-                (unsorted.join("<br>"), unsorted.join(", "))
-            };
+    pub fn rooms_of(&self, targets: &HashSet<Target>) -> HashSet<String> {
+        targets.iter().filter_map(|x| self.room_of(x)).collect()
+    }
 
-            let plainanswer = format!("On {}: {}", self.server_details.domain, plainanswer);
-            let htmlanswer = format!("On {}:<br>{}", self.server_details.domain, htmlanswer);
+    /// 'notify @user:server... URL' or 'notify nobody URL'
+    fn set_notify(&self, key: T, target: Target, command: &Command, lang: &str) -> String {
+        let users = command
+            .args
+            .iter()
+            .filter(|x| x.text != "nobody")
+            .map(|x| x.text.clone())
+            .collect::<Vec<_>>();
+
+        let subscribed = self
+            .subscriptions
+            .lock()
+            .map(|x| x.get(&key).map(|x| x.contains(&target)).unwrap_or(false))
+            .unwrap_or(false);
+        if !subscribed {
+            return trf(lang, "There is no subscription to {} here", &[&key]);
+        }
 
-            bot.send_html_message(&plainanswer, &htmlanswer, room, MessageType::TextMessage);
+        let mut notify = match self.notify.lock() {
+            Ok(x) => x,
+            Err(_) => {
+                return tr(
+                    lang,
+                    "Sorry, I could not change that, due to an internal error.",
+                )
+            }
+        };
+        if users.is_empty() {
+            notify.remove(&(key.clone(), target));
+            trf(lang, "Nobody will be mentioned for {}", &[&key])
         } else {
-            println!("ERROR! list_keys: subscriptions not lockable");
-            bot.send_message(
-                "Sorry, I could not list your requests, due to an internal error.",
-                room,
-                MessageType::TextMessage,
+            let answer = trf(
+                lang,
+                "Will mention {} on failures of {}",
+                &[&users.join(", "), &key],
             );
+            notify.insert((key, target), users);
+            answer
+        }
+    }
+
+    /// 'snooze URL 2h', 'snooze URL until tomorrow' or 'unsnooze URL'
+    fn snooze(
+        &self,
+        key: T,
+        command: &Command,
+        room: &str,
+        lang: &str,
+    ) -> Result<String, CommandError> {
+        let key_url = key.url(&self.get_base_url());
+
+        if command.verb == Verb::Unsnooze {
+            self.outbox.snooze(room, &key_url, None);
+            return Ok(trf(lang, "Notifications for {} are back", &[&key]));
+        }
+
+        let args = command
+            .args
+            .iter()
+            .map(|x| x.text.as_str())
+            .collect::<Vec<_>>();
+        match parse_until(&args, self.outbox.now()) {
+            Some(until) => {
+                self.outbox.snooze(room, &key_url, Some(until));
+                Ok(trf(
+                    lang,
+                    "No notifications for {} until {}",
+                    &[&key, &format_time(lang, until)],
+                ))
+            }
+            None => Err(CommandError::spanning(
+                &command.line,
+                &command.args,
+                tr(
+                    lang,
+                    "Usage: snooze URL 2h | snooze URL until tomorrow | unsnooze URL",
+                ),
+            )),
+        }
+    }
+
+    /// Matrix users of the OBS users that linked their accounts
+    pub fn linked_matrix_users<'a, I: Iterator<Item = &'a str>>(
+        &self,
+        obs_users: I,
+    ) -> Vec<String> {
+        let mut res = Vec::new();
+        for obs_user in obs_users {
+            if let Some(user) = self
+                .accounts
+                .matrix_user(obs_user, self.server_details.domain)
+            {
+                if !res.contains(&user) {
+                    res.push(user);
+                }
+            }
+        }
+        res
+    }
+
+    /// Users configured via 'notify' for this subscription, plus the given ones
+    pub fn mentions(&self, key: &T, target: &Target, automatic: &[String]) -> Vec<String> {
+        let mut res = self
+            .notify
+            .lock()
+            .ok()
+            .and_then(|x| x.get(&(key.clone(), target.clone())).cloned())
+            .unwrap_or_default();
+        for user in automatic {
+            if !res.contains(user) {
+                res.push(user.clone());
+            }
+        }
+        res
+    }
+
+    /// Subscriptions of the targets, with their options (and the snoozes of room_settings)
+    fn entries(
+        &self,
+        targets: Option<&[Target]>,
+        room_settings: Option<&RoomSettings>,
+        lang: &str,
+    ) -> Vec<(Target, ListEntry)> {
+        let kind = match LIST_TYPES.iter().find(|x| x.starts_with(&self.subtype)) {
+            Some(x) => x,
+            None => return Vec::new(),
+        };
+        let subscriptions = match self.subscriptions.lock() {
+            Ok(x) => x,
+            Err(_) => {
+                println!("ERROR! entries: subscriptions not lockable");
+                return Vec::new();
+            }
+        };
+        let now = self.outbox.now();
+
+        let mut res = Vec::new();
+        for (key, subscribed) in subscriptions.iter() {
+            for target in subscribed {
+                if targets.map(|x| !x.contains(target)).unwrap_or(false) {
+                    continue;
+                }
+
+                let url = key.url(&self.get_base_url());
+                let mut options = Vec::new();
+                if let Target::User(user) = target {
+                    options.push(trf(lang, "personal for {}", &[user]));
+                }
+                let mentions = self.mentions(key, target, &[]);
+                if !mentions.is_empty() {
+                    options.push(trf(lang, "mentions {}", &[&mentions.join(", ")]));
+                }
+                if let Some(until) = room_settings.and_then(|x| x.snoozed.get(&url)) {
+                    if now.timestamp() < *until {
+                        let until = now.timezone().timestamp(*until, 0);
+                        options.push(trf(lang, "snoozed until {}", &[&format_time(lang, until)]));
+                    }
+                }
+
+                let entry = ListEntry {
+                    backend: self.server_details.domain.to_string(),
+                    kind,
+                    name: key.to_string(),
+                    url,
+                    options,
+                };
+                res.push((target.clone(), entry));
+            }
+        }
+        res
+    }
+
+    /// Subscriptions visible in room, with their options
+    pub fn list_entries(&self, room: &str, sender: &str) -> Vec<ListEntry> {
+        let targets = self.visible_targets(room, sender);
+        let lang = self.settings.language(room);
+        self.entries(Some(&targets), Some(&self.settings.get(room)), &lang)
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect()
+    }
+
+    /// Subscriptions of all rooms and users, for admins
+    pub fn all_entries(&self) -> Vec<(Target, ListEntry)> {
+        self.entries(None, None, DEFAULT_LANGUAGE)
+    }
+
+    /// Entries like the ones of default_subs, for the subscriptions of the room
+    pub fn export_entries(&self, room: &str) -> Vec<String> {
+        let target = Target::Room(room.to_string());
+        let base_url = self.get_base_url();
+        match self.subscriptions.lock() {
+            Ok(subscriptions) => subscriptions
+                .iter()
+                .filter(|(_, subscribed)| subscribed.contains(&target))
+                .map(|(key, _)| key.export(&base_url))
+                .collect(),
+            Err(_) => {
+                println!("ERROR! export_entries: subscriptions not lockable");
+                Vec::new()
+            }
         }
     }
 
-    pub fn subscribe(&mut self, key: T, room: &str) -> Result<String, String> {
+    /// Removes all subscriptions of the room, returns how many there were
+    pub fn unsubscribe_room(&mut self, room: &str) -> usize {
+        let target = Target::Room(room.to_string());
+        let mut count = 0;
+        if let Ok(mut subscriptions) = self.subscriptions.lock() {
+            for subscribed in subscriptions.values_mut() {
+                if subscribed.remove(&target) {
+                    count += 1;
+                }
+            }
+            subscriptions.retain(|_, x| !x.is_empty());
+        }
+        if let Ok(mut notify) = self.notify.lock() {
+            notify.retain(|(_, x), _| *x != target);
+        }
+        count
+    }
+
+    /// Hands a notification caused by an event to the outbox, counting it for 'admin stats'
+    pub fn send_notification(
+        &self,
+        routing_key: &str,
+        room: &str,
+        kind: EventKind,
+        sources: &[String],
+        plain: &str,
+        html: &str,
+    ) {
+        self.stats.sent(routing_key);
+        self.outbox.send(room, kind, sources, plain, html);
+    }
+
+    /// Replies are in lang
+    pub fn subscribe(&mut self, key: T, target: Target, lang: &str) -> Result<String, String> {
         if let Ok(mut subscriptions) = self.subscriptions.lock() {
             if !subscriptions.contains_key(&key) {
                 subscriptions.insert(key.clone(), HashSet::new());
@@ -110,101 +436,157 @@ where
             subscriptions
                 .get_mut(&key)
                 .unwrap() // We know its in there, we just added it above
-                .insert(room.to_string());
-
-            Ok(format!(
-                "Subscribing to {} on {}",
-                key, &self.server_details.domain
-            ))
+                .insert(target.clone());
+
+            Ok(match target {
+                Target::Room(_) => trf(
+                    lang,
+                    "Subscribing to {} on {}",
+                    &[&key, &self.server_details.domain],
+                ),
+                Target::User(user) => trf(
+                    lang,
+                    "Subscribing {} personally to {} on {}",
+                    &[&user, &key, &self.server_details.domain],
+                ),
+            })
         } else {
-            Err(format!("Sorry, I could not add your request {} on {} to the subscriptions, due to an internal error ({}).",
-                key, &self.server_details.domain, "subscriptions not lockable"))
+            Err(trf(lang, "Sorry, I could not add your request {} on {} to the subscriptions, due to an internal error ({}).",
+                &[&key, &self.server_details.domain, &"subscriptions not lockable"]))
         }
     }
 
-    pub fn unsubscribe(&mut self, key: T, room: &str) -> Result<String, String> {
+    /// Replies are in lang
+    pub fn unsubscribe(&mut self, key: T, target: Target, lang: &str) -> Result<String, String> {
         if let Ok(mut subscriptions) = self.subscriptions.lock() {
             if !subscriptions.contains_key(&key) {
-                return Ok(format!("Was not subscribed to {}", key));
+                return Ok(trf(lang, "Was not subscribed to {}", &[&key]));
             }
             subscriptions
                 .get_mut(&key)
                 .unwrap() // We know its in there, we just checked it above
-                .remove(room);
+                .remove(&target);
+            if let Ok(mut notify) = self.notify.lock() {
+                notify.remove(&(key.clone(), target.clone()));
+            }
 
             // Check if anybody still uses this key
             if subscriptions.get(&key).unwrap().is_empty() {
                 subscriptions.remove(&key);
             }
 
-            Ok(format!(
-                "Unsubscribing room from {} on {}",
-                key, &self.server_details.domain
+            let target = match target {
+                Target::Room(_) => tr(lang, "room"),
+                Target::User(user) => user,
+            };
+            Ok(trf(
+                lang,
+                "Unsubscribing {} from {} on {}",
+                &[&target, &key, &self.server_details.domain],
             ))
         } else {
-            Err(format!("Sorry, I could not remove your request {} on {} from the subscriptions, due to an internal error ({}).",
-                key, &self.server_details.domain, "subscriptions not lockable"))
+            Err(trf(lang, "Sorry, I could not remove your request {} on {} from the subscriptions, due to an internal error ({}).",
+                &[&key, &self.server_details.domain, &"subscriptions not lockable"]))
         }
     }
 
-    pub fn scan_line(&self, line: &str) -> ScanLineResult {
-        let prefix = self.prefix.as_deref().unwrap_or("");
-        if !line.starts_with(prefix) {
-            return ScanLineResult::NotForMe;
+    /// If the URL points to something this Subscriber handles. Shorthands are
+    /// owned by all backends, the first one gets them.
+    pub fn owns_url(&self, url: &str) -> bool {
+        match reference::parse(url) {
+            Some(link) => {
+                link.is_on(self.server_details.domain)
+                    && T::from_reference(&link.reference, false).is_some()
+            }
+            None => false,
         }
-        // Stripping away the prefix
-        let line = line[prefix.len()..].trim();
+    }
 
-        if line.starts_with(&format!("list {}", self.subtype)) {
-            return ScanLineResult::ListCommand;
+    /// 'sub me URL' and everything in a 1:1 room is a personal subscription
+    fn target_of(&self, command: &Command, room: &str, sender: &str) -> Target {
+        if command.personal {
+            Target::User(sender.to_string())
+        } else if let Some(user) = self.direct.user_of(room, sender) {
+            Target::User(user)
+        } else {
+            Target::Room(room.to_string())
         }
+    }
 
-        let search_url = format!("{}/{}/", self.server_details.domain, self.subtype);
-        // Check if its for me
-        if !line.contains(&search_url) {
-            return ScanLineResult::NotForMe;
+    /// Opens the 1:1 room of personal subscriptions right away, so the user sees where they go
+    fn open_direct_room(&self, target: &Target) -> Result<()> {
+        if let Target::User(user) = target {
+            self.direct.room_for(user)?;
         }
-
-        ScanLineResult::PossiblyForMe
+        Ok(())
     }
 
-    pub fn handle_message_helper(
+    /// Runs a command for the given URLs (the ones this Subscriber owns)
+    pub fn execute_command(
         &mut self,
         bot: &ActiveBot,
-        message: &str,
-        room: &str,
+        message: &Message,
+        command: &Command,
+        urls: &[Token],
     ) -> MessageParseResult {
+        let room = &message.room;
+        let lang = self.settings.language(room);
         let mut res = MessageParseResult::NothingForMe;
-        for line in message.lines() {
-            match self.scan_line(line) {
-                ScanLineResult::PossiblyForMe => { /* Continue below */ }
-                ScanLineResult::NotForMe => {
-                    continue;
-                }
-                ScanLineResult::ListCommand => {
-                    self.list_keys(bot, room);
-                    continue;
-                }
-            }
 
-            let key = match T::try_from(line.to_string()) {
-                Ok(x) => x,
-                Err(_) => {
-                    println!("Message not parsable");
-                    bot.send_message(
-                        "Sorry, I could not parse that. Please post a submitrequest URL",
-                        room,
-                        MessageType::TextMessage,
+        match command.verb {
+            // 'list' is handled by the CommandRouter
+            Verb::List | Verb::Flaky => return res,
+            _ => {}
+        }
+
+        let mut answers = Vec::new();
+        for url in urls {
+            let key = match T::parse(&url.text, command.summary) {
+                Some(x) => x,
+                None => {
+                    println!("URL {} not parsable", url.text);
+                    let error = CommandError::at(
+                        &command.line,
+                        url,
+                        tr(&lang, "Sorry, I could not parse that URL"),
                     );
+                    send_error(bot, room, &error);
                     continue;
                 }
             };
 
-            let result = if line.starts_with("unsub") {
-                self.unsubscribe(key, room)
-            } else {
-                res = MessageParseResult::SomethingForMe;
-                self.subscribe(key, room)
+            let target = self.target_of(command, room, &message.sender);
+            let result = match command.verb {
+                Verb::Sub => {
+                    if let Err(x) = self.open_direct_room(&target) {
+                        Err(trf(
+                            &lang,
+                            "Sorry, I could not open a direct chat with you ({})",
+                            &[&x],
+                        ))
+                    } else {
+                        res = MessageParseResult::SomethingForMe;
+                        self.subscribe(key, target, &lang)
+                    }
+                }
+                Verb::Unsub => self.unsubscribe(key, target, &lang),
+                Verb::Notify => Ok(self.set_notify(key, target, command, &lang)),
+                Verb::Snooze | Verb::Unsnooze => match self.snooze(key, command, room, &lang) {
+                    Ok(x) => Ok(x),
+                    Err(x) => {
+                        send_error(bot, room, &x);
+                        continue;
+                    }
+                },
+                Verb::Status | Verb::List | Verb::Flaky => {
+                    let error = CommandError::at(
+                        &command.line,
+                        url,
+                        tr(&lang, "This command does not work for this URL"),
+                    );
+                    send_error(bot, room, &error);
+                    continue;
+                }
             };
 
             match result {
@@ -212,44 +594,69 @@ where
                 // change in the future
                 Ok(message) | Err(message) => {
                     println!("{}", message);
-                    bot.send_message(&message, room, MessageType::TextMessage)
+                    answers.push(message);
                 }
             }
         }
+
+        if !answers.is_empty() {
+            bot.send_message(&answers.join("\n"), room, MessageType::TextMessage);
+        }
         res
     }
 
-    pub fn subscribe_to_defaults(&mut self, message: &str, room: &str) {
-        for line in message.lines() {
-            match self.scan_line(line) {
-                ScanLineResult::PossiblyForMe => { /* Continue below */ }
-                ScanLineResult::NotForMe | ScanLineResult::ListCommand => {
-                    continue;
-                }
-            }
-
-            let key = match T::try_from(line.to_string()) {
-                Ok(x) => x,
-                Err(_) => {
-                    println!("Message {} not parsable", line);
+    /// Subscribes the room to the URLs of a default_subs entry (the ones this Subscriber owns)
+    /// Returns the URLs that could not be subscribed to
+    pub fn subscribe_to_defaults(
+        &mut self,
+        command: &Command,
+        urls: &[Token],
+        room: &str,
+    ) -> Vec<String> {
+        let mut failed = Vec::new();
+        for url in urls {
+            let key = match T::parse(&url.text, command.summary) {
+                Some(x) => x,
+                None => {
+                    println!("URL {} not parsable", url.text);
+                    failed.push(url.text.clone());
                     continue;
                 }
             };
 
-            let result = if !line.starts_with("unsub") {
-                self.subscribe(key, room)
-            } else {
-                continue;
-            };
-
-            match result {
-                // We just print the result-message no matter Ok/Err, but this might
-                // change in the future
-                Ok(message) | Err(message) => {
+            match self.subscribe(key, Target::Room(room.to_string()), DEFAULT_LANGUAGE) {
+                Ok(message) => println!("{}", message),
+                Err(message) => {
                     println!("{}", message);
+                    failed.push(url.text.clone());
                 }
             }
         }
+        failed
+    }
+
+    /// Registers at the message queue and handles its events from then on
+    pub fn start_listening(&mut self)
+    where
+        Self: ConsumerDelegate + EventHandler + Clone + 'static,
+    {
+        match self.register() {
+            Err(x) => {
+                println!("Error while registering: {:?}", x);
+            }
+            Ok(consumer) => {
+                // Events may need HTTP requests, which must not block the message queue
+                let (sender, receiver) = mpsc::channel::<Delivery>();
+                let worker = self.clone();
+                std::thread::spawn(move || {
+                    for delivery in receiver {
+                        handle_delivery(&worker, delivery);
+                    }
+                });
+                self.events = Some(sender);
+                consumer.set_delegate(Box::new(self.clone()));
+            }
+        }
     }
 
     pub fn register(&mut self) -> Result<Consumer> {
@@ -309,6 +716,42 @@ where
     }
 }
 
+fn handle_delivery(handler: &impl EventHandler, delivery: Delivery) {
+    if let Err(x) = handler.delivery_wrapper(delivery) {
+        println!("Error while getting Event: {:?}. Skipping to continue", x);
+    }
+}
+
+impl<T> ConsumerDelegate for Subscriber<T>
+where
+    T: SubscriptionKey + Sync + 'static,
+    Self: EventHandler,
+{
+    fn on_new_delivery(&self, delivery: DeliveryResult) {
+        if let Ok(Some(delivery)) = delivery {
+            self.stats.received(delivery.routing_key.as_str());
+            if let Some(channel) = &self.channel {
+                let _ = channel
+                    .basic_ack(delivery.delivery_tag, BasicAckOptions::default())
+                    .wait();
+            }
+            match &self.events {
+                Some(events) => {
+                    if let Err(x) = events.send(delivery) {
+                        println!("ERROR! Worker of {} is gone: {:?}", self.subtype, x);
+                    }
+                }
+                None => handle_delivery(self, delivery),
+            }
+        } else {
+            println!(
+                "Delivery not ok on {}: {:?}",
+                self.server_details.domain, delivery
+            );
+        }
+    }
+}
+
 pub fn prepend_prefix(
     prefix: Option<&str>,
     without_prefix: &[(&str, &str)],
@@ -321,3 +764,68 @@ pub fn prepend_prefix(
     }
     res
 }
+
+/// Plain text and HTML pills mentioning these Matrix users
+pub fn mention_pills(users: &[String]) -> (String, String) {
+    let html = users
+        .iter()
+        .map(|x| {
+            format!(
+                "<a href=\"https://matrix.to/#/{}\">{}</a>",
+                x,
+                html_escape(x)
+            )
+        })
+        .collect::<Vec<_>>();
+    (users.join(", "), html.join(", "))
+}
+
+/// Escapes text, so it can be put verbatim into an HTML-message
+pub fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Path of a file in the XDG data-directory of the bot (created if needed)
+pub fn data_file(name: &str) -> Option<PathBuf> {
+    xdg::BaseDirectories::with_prefix("obs_chat_bot")
+        .map_err(anyhow::Error::from)
+        .and_then(|dirs| Ok(dirs.place_data_file(name)?))
+        .map_err(|x| println!("ERROR! No data file {} possible: {:?}", name, x))
+        .ok()
+}
+
+/// Loads persisted data, or returns the default if there is none (yet)
+pub fn load_json<T: DeserializeOwned + Default>(path: &Option<PathBuf>) -> T {
+    let path = match path {
+        Some(x) if x.exists() => x,
+        _ => return T::default(),
+    };
+
+    match std::fs::read_to_string(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|data| Ok(serde_json::from_str(&data)?))
+    {
+        Ok(x) => x,
+        Err(x) => {
+            println!("ERROR! Could not load {:?}: {:?}", path, x);
+            T::default()
+        }
+    }
+}
+
+pub fn save_json<T: Serialize>(path: &Option<PathBuf>, data: &T) {
+    let path = match path {
+        Some(x) => x,
+        None => return,
+    };
+
+    if let Err(x) = serde_json::to_string_pretty(data)
+        .map_err(anyhow::Error::from)
+        .and_then(|data| Ok(std::fs::write(path, data)?))
+    {
+        println!("ERROR! Could not save {:?}: {:?}", path, x);
+    }
+}
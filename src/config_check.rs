@@ -0,0 +1,485 @@
+use crate::accounts::ObsAccount;
+use crate::commands;
+use crate::i18n::DEFAULT_LANGUAGE;
+use crate::obs_api::ObsCredentials;
+use crate::reference;
+use crate::settings;
+use crate::{build_res, openqa, submitrequests, SUPPORTED_BACKENDS};
+use config::{Config, ConfigError};
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Something wrong in the config file
+#[derive(Debug, Clone)]
+pub struct Problem {
+    /// 1-based, if it could be found
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+/// Prints all problems, like compilers do: 'config.toml:12: message'
+pub fn report(path: &Path, problems: &[Problem]) {
+    for problem in problems {
+        match problem.line {
+            Some(line) => println!("{}:{}: {}", path.display(), line, problem.message),
+            None => println!("{}: {}", path.display(), problem.message),
+        }
+    }
+}
+
+/// All top-level keys the bot reads
+const KNOWN_KEYS: [&str; 15] = [
+    "user",
+    "password",
+    "homeserver_url",
+    "backends",
+    "prefix",
+    "admins",
+    "default_subs",
+    "obs_credentials",
+    "obs_accounts",
+    "openqa_flaky_window",
+    "rate_limit_burst",
+    "rate_limit_per_minute",
+    "timezone",
+    "template_dir",
+    "templates",
+];
+
+/// Number of single character edits to get from a to b
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    for (i, x) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, y) in b.iter().enumerate() {
+            let substituted = previous[j] + usize::from(x != *y);
+            current.push(substituted.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// '!id:server' or '#alias:server'
+fn is_room(room: &str) -> bool {
+    match room.split_once(':') {
+        Some((local, server)) => {
+            (local.starts_with('!') || local.starts_with('#'))
+                && local.len() > 1
+                && !server.is_empty()
+                && !room.contains(char::is_whitespace)
+        }
+        None => false,
+    }
+}
+
+struct Checker<'a> {
+    settings: &'a Config,
+    /// Of the file, to find the lines of the problems
+    lines: Vec<&'a str>,
+    problems: Vec<Problem>,
+}
+
+impl<'a> Checker<'a> {
+    /// Line where key is set, or its table starts
+    fn line_of_key(&self, key: &str) -> Option<usize> {
+        self.lines
+            .iter()
+            .position(|line| {
+                let line = line.trim();
+                let assigned = line
+                    .strip_prefix(key)
+                    .map(|x| x.trim_start().starts_with('='))
+                    .unwrap_or(false);
+                assigned || line == format!("[{}]", key) || line == format!("[[{}]]", key)
+            })
+            .map(|x| x + 1)
+    }
+
+    /// First line from after on containing text, for values of a key
+    fn line_of_value(&self, after: Option<usize>, text: &str) -> Option<usize> {
+        let start = after.unwrap_or(1) - 1;
+        self.lines
+            .iter()
+            .skip(start)
+            .position(|line| line.contains(text))
+            .map(|x| x + start + 1)
+            .or(after)
+    }
+
+    fn add(&mut self, line: Option<usize>, message: String) {
+        self.problems.push(Problem { line, message });
+    }
+
+    /// Value of key, or a problem if it has the wrong type
+    fn optional<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        match self.settings.get::<T>(key) {
+            Ok(x) => Some(x),
+            Err(ConfigError::NotFound(_)) => None,
+            Err(x) => {
+                self.add(self.line_of_key(key), format!("{}: {}", key, x));
+                None
+            }
+        }
+    }
+
+    fn required<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        if let Err(ConfigError::NotFound(_)) = self.settings.get::<T>(key) {
+            self.add(None, format!("{} is missing", key));
+        }
+        self.optional(key)
+    }
+
+    fn check_backends(&mut self) -> Vec<String> {
+        let line = self.line_of_key("backends");
+        let backends = self.required::<Vec<String>>("backends").unwrap_or_default();
+        if backends.is_empty() && line.is_some() {
+            self.add(
+                line,
+                "backends is empty, the bot would not do anything".to_string(),
+            );
+        }
+        for (i, backend) in backends.iter().enumerate() {
+            if !SUPPORTED_BACKENDS.contains(&backend.as_str()) {
+                self.add(
+                    line,
+                    format!(
+                        "Backend {} is not supported, only {}",
+                        backend,
+                        SUPPORTED_BACKENDS.join(", ")
+                    ),
+                );
+            } else if backends[..i].contains(backend) {
+                self.add(line, format!("Backend {} is given twice", backend));
+            }
+        }
+        backends
+    }
+
+    /// The backends of obs_credentials and obs_accounts
+    fn check_obs_tables(&mut self, backends: &[String]) {
+        let credentials = self
+            .optional::<Vec<ObsCredentials>>("obs_credentials")
+            .unwrap_or_default();
+        let accounts = self
+            .optional::<Vec<ObsAccount>>("obs_accounts")
+            .unwrap_or_default();
+        let used = credentials
+            .iter()
+            .map(|x| ("obs_credentials", &x.backend))
+            .chain(accounts.iter().map(|x| ("obs_accounts", &x.backend)));
+
+        let mut after = HashMap::new();
+        for (key, backend) in used {
+            let start = *after.entry(key).or_insert_with(|| self.line_of_key(key));
+            let line = self.line_of_value(start, &format!("\"{}\"", backend));
+            after.insert(key, line);
+            if !backends.contains(backend) {
+                self.add(
+                    line,
+                    format!("{}: backend {} is not in backends", key, backend),
+                );
+            }
+        }
+    }
+
+    fn check_default_subs(&mut self, backends: &[String]) {
+        let default_subs = match self.optional::<Vec<(String, String)>>("default_subs") {
+            Some(x) => x,
+            None => return,
+        };
+
+        // Entries are searched one after another, as the same room is in many of them
+        let mut after = self.line_of_key("default_subs");
+        for (room, entry) in &default_subs {
+            after = self.line_of_value(after, &format!("\"{}\"", room));
+            if !is_room(room) {
+                self.add(
+                    after,
+                    format!(
+                        "default_subs: {} is neither a room ID (!id:server) nor an alias (#room:server)",
+                        room
+                    ),
+                );
+            }
+
+            for text in entry.lines() {
+                let line = self.line_of_value(after, text.trim());
+                let command = match commands::parse_entry(text, DEFAULT_LANGUAGE) {
+                    Ok(Some(x)) => x,
+                    Ok(None) => continue,
+                    Err(x) => {
+                        self.add(line, format!("default_subs: {}: {}", text, x.message));
+                        continue;
+                    }
+                };
+
+                for url in &command.urls {
+                    let link = match reference::parse(&url.text) {
+                        Some(x) => x,
+                        None => {
+                            self.add(line, format!("default_subs: could not parse {}", url.text));
+                            continue;
+                        }
+                    };
+                    let summary = command.summary;
+                    if !backends.iter().any(|x| link.is_on(x)) {
+                        self.add(
+                            line,
+                            format!("default_subs: {} is not on one of the backends", url.text),
+                        );
+                    } else if !build_res::subscribable(&link.reference, summary)
+                        && !submitrequests::subscribable(&link.reference, summary)
+                        && !openqa::subscribable(&link.reference, summary)
+                    {
+                        self.add(
+                            line,
+                            format!("default_subs: {} cannot be subscribed to", url.text),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Keys the bot doesn't know are mostly typos, which would silently be ignored
+    fn check_unknown_keys(&mut self) {
+        let mut keys = self
+            .settings
+            .cache
+            .clone()
+            .into_table()
+            .map(|x| x.into_keys().collect::<Vec<_>>())
+            .unwrap_or_default();
+        keys.sort();
+        for key in keys {
+            if KNOWN_KEYS.contains(&key.as_str()) {
+                continue;
+            }
+            let message = match KNOWN_KEYS.iter().find(|x| edit_distance(&key, x) <= 2) {
+                Some(known) => format!("Unknown key {}, did you mean {}?", key, known),
+                None => format!("Unknown key {}", key),
+            };
+            self.add(self.line_of_key(&key), message);
+        }
+    }
+
+    fn check(&mut self) {
+        self.check_unknown_keys();
+        for key in ["user", "password"] {
+            self.required::<String>(key);
+        }
+        if let Some(url) = self.required::<String>("homeserver_url") {
+            let valid = Url::parse(&url)
+                .map(|x| x.scheme() == "https" || x.scheme() == "http")
+                .unwrap_or(false);
+            if !valid {
+                self.add(
+                    self.line_of_key("homeserver_url"),
+                    format!("homeserver_url {} is not an http(s) URL", url),
+                );
+            }
+        }
+        let backends = self.check_backends();
+
+        self.optional::<String>("prefix");
+        for admin in self.optional::<Vec<String>>("admins").unwrap_or_default() {
+            if !admin.starts_with('@') || !admin.contains(':') {
+                self.add(
+                    self.line_of_key("admins"),
+                    format!("admins: {} is not a Matrix user like @user:server", admin),
+                );
+            }
+        }
+        self.optional::<usize>("openqa_flaky_window");
+        self.optional::<f64>("rate_limit_burst");
+        self.optional::<f64>("rate_limit_per_minute");
+        if let Some(timezone) = self.optional::<String>("timezone") {
+            if settings::Timezone::parse(&timezone).is_none() {
+                self.add(
+                    self.line_of_key("timezone"),
+                    format!("timezone {} is not like +02:00 or Europe/Berlin", timezone),
+                );
+            }
+        }
+        self.optional::<String>("template_dir");
+        self.optional::<HashMap<String, String>>("templates");
+
+        self.check_obs_tables(&backends);
+        self.check_default_subs(&backends);
+    }
+}
+
+/// All problems of the config file, which settings were loaded from
+pub fn check(path: &Path, settings: &Config) -> Vec<Problem> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(x) => {
+            return vec![Problem {
+                line: None,
+                message: format!("Could not read it: {}", x),
+            }]
+        }
+    };
+
+    let mut checker = Checker {
+        settings,
+        lines: text.lines().collect(),
+        problems: Vec::new(),
+    };
+    checker.check();
+    checker.problems.sort_by_key(|x| x.line);
+    checker.problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VALID: &str = r#"user = "bot"
+password = "secret"
+homeserver_url = "https://matrix.example.org"
+backends = ["opensuse.org"]
+timezone = "+02:00"
+default_subs = [
+    ["!abc:example.org", "sub https://build.opensuse.org/package/show/openSUSE:Factory/hello"],
+]
+"#;
+
+    /// Problems of the config file with text, as lines and messages
+    fn check_toml(name: &str, text: &str) -> Vec<(Option<usize>, String)> {
+        let path =
+            std::env::temp_dir().join(format!("obs_chat_bot-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        let mut settings = Config::default();
+        settings.merge(config::File::from(path.clone())).unwrap();
+        let problems = check(&path, &settings);
+        let _ = std::fs::remove_file(&path);
+        problems.into_iter().map(|x| (x.line, x.message)).collect()
+    }
+
+    /// For line_of_key and line_of_value, which don't look at the settings
+    fn lines_checker<'a>(settings: &'a Config, text: &'a str) -> Checker<'a> {
+        Checker {
+            settings,
+            lines: text.lines().collect(),
+            problems: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn rooms() {
+        assert!(is_room("!abc:example.org"));
+        assert!(is_room("#room:example.org"));
+        assert!(!is_room("abc:example.org"));
+        assert!(!is_room("!abc"));
+        assert!(!is_room("!:example.org"));
+        assert!(!is_room("!abc:"));
+        assert!(!is_room("#my room:example.org"));
+    }
+
+    #[test]
+    fn lines_of_keys() {
+        let settings = Config::default();
+        let checker = lines_checker(&settings, VALID);
+        assert_eq!(checker.line_of_key("user"), Some(1));
+        assert_eq!(checker.line_of_key("homeserver_url"), Some(3));
+        assert_eq!(checker.line_of_key("default_subs"), Some(6));
+        assert_eq!(checker.line_of_key("prefix"), None);
+        // Not a prefix of another key
+        assert_eq!(checker.line_of_key("back"), None);
+
+        let checker = lines_checker(
+            &settings,
+            "user = \"bot\"\n\n[[obs_credentials]]\nbackend = \"x\"\n[templates]\n",
+        );
+        assert_eq!(checker.line_of_key("obs_credentials"), Some(3));
+        assert_eq!(checker.line_of_key("templates"), Some(5));
+    }
+
+    #[test]
+    fn lines_of_values() {
+        let settings = Config::default();
+        let checker = lines_checker(&settings, "a = [\n  \"x\",\n  \"y\",\n  \"x\",\n]\n");
+        assert_eq!(checker.line_of_value(Some(1), "\"x\""), Some(2));
+        assert_eq!(checker.line_of_value(Some(3), "\"x\""), Some(4));
+        assert_eq!(checker.line_of_value(None, "\"y\""), Some(3));
+        // Falls back to the line of the key
+        assert_eq!(checker.line_of_value(Some(1), "\"z\""), Some(1));
+        assert_eq!(checker.line_of_value(None, "\"z\""), None);
+    }
+
+    #[test]
+    fn valid_config() {
+        assert_eq!(check_toml("valid", VALID), vec![]);
+    }
+
+    #[test]
+    fn problems_have_lines() {
+        let text = VALID
+            .replace("https://matrix", "matrix")
+            .replace("\"opensuse.org\"]", "\"opensuse.org\", \"example.org\"]")
+            .replace("!abc:example.org", "abc")
+            + "openqa_flaky_window = \"many\"\n";
+        let problems = check_toml("lines", &text);
+        assert_eq!(
+            problems.iter().map(|x| x.0).collect::<Vec<_>>(),
+            vec![Some(3), Some(4), Some(7), Some(9)],
+            "{:?}",
+            problems
+        );
+        assert!(problems[0].1.contains("not an http(s) URL"));
+        assert!(problems[1].1.contains("example.org is not supported"));
+        assert!(problems[2].1.contains("abc is neither a room ID"));
+        assert!(problems[3].1.starts_with("openqa_flaky_window"));
+    }
+
+    #[test]
+    fn missing_keys() {
+        let problems = check_toml("missing", "backends = [\"opensuse.org\"]\n");
+        for key in ["user", "password", "homeserver_url"] {
+            assert!(
+                problems.contains(&(None, format!("{} is missing", key))),
+                "{:?}",
+                problems
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let text = VALID.replace("default_subs", "default_sub") + "colour = \"blue\"\n";
+        assert_eq!(
+            check_toml("unknown", &text),
+            vec![
+                (
+                    Some(6),
+                    "Unknown key default_sub, did you mean default_subs?".to_string()
+                ),
+                (Some(9), "Unknown key colour".to_string()),
+            ]
+        );
+        assert_eq!(edit_distance("default_sub", "default_subs"), 1);
+        assert_eq!(edit_distance("pasword", "password"), 1);
+        assert_eq!(edit_distance("timezone", "timezone"), 0);
+        assert_eq!(edit_distance("prefix", "user"), 5);
+    }
+
+    #[test]
+    fn timezones() {
+        let text = VALID.replace("+02:00", "Europe/Berlin");
+        assert_eq!(check_toml("iana", &text), vec![]);
+
+        let text = VALID.replace("+02:00", "Mars/Olympus_Mons");
+        assert_eq!(
+            check_toml("timezone", &text),
+            vec![(
+                Some(5),
+                "timezone Mars/Olympus_Mons is not like +02:00 or Europe/Berlin".to_string()
+            )]
+        );
+    }
+}
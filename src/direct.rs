@@ -0,0 +1,225 @@
+use crate::common::{data_file, load_json, save_json};
+use crate::matrix_api::MatrixApi;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long the members of a room are trusted, as we get no membership events
+const CHECK_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Rooms we already looked at -> (when, the user we are alone with, if any)
+type CheckedRooms = HashMap<String, (Instant, Option<String>)>;
+
+/// The 1:1 rooms of the bot with Matrix users, shared between all handlers.
+/// Known rooms are stored in the XDG data-directory, so they are reused after a restart.
+#[derive(Clone, Default)]
+pub struct DirectRooms {
+    api: Option<Arc<MatrixApi>>,
+    /// Matrix user -> room
+    rooms: Arc<Mutex<HashMap<String, String>>>,
+    checked: Arc<Mutex<CheckedRooms>>,
+    path: Option<PathBuf>,
+}
+
+impl DirectRooms {
+    /// Without an API, no new rooms can be opened
+    pub fn new(api: Option<Arc<MatrixApi>>) -> Self {
+        let path = data_file("direct_rooms.json");
+        let rooms: HashMap<String, String> = load_json(&path);
+
+        DirectRooms {
+            api,
+            rooms: Arc::new(Mutex::new(rooms)),
+            checked: Arc::new(Mutex::new(HashMap::new())),
+            path,
+        }
+    }
+
+    fn remember(&self, user: &str, room: &str) {
+        if let Ok(mut rooms) = self.rooms.lock() {
+            rooms.insert(user.to_string(), room.to_string());
+            save_json(&self.path, &*rooms);
+        }
+    }
+
+    /// Returns the 1:1 room with the user, opening one if needed
+    pub fn room_for(&self, user: &str) -> Result<String> {
+        let rooms = self
+            .rooms
+            .lock()
+            .map_err(|_| anyhow!("direct rooms not lockable"))?;
+        if let Some(room) = rooms.get(user) {
+            return Ok(room.clone());
+        }
+        drop(rooms);
+
+        let api = self
+            .api
+            .as_ref()
+            .ok_or_else(|| anyhow!("Cannot open direct chats without a Matrix API login"))?;
+        let room = api.create_direct_room(user)?;
+        println!("Opened direct room {} with {}", room, user);
+        self.remember(user, &room);
+        Ok(room)
+    }
+
+    /// Drops room from the known 1:1 rooms, e.g. once others joined it
+    fn forget(&self, room: &str) {
+        if let Ok(mut rooms) = self.rooms.lock() {
+            let count = rooms.len();
+            rooms.retain(|_, x| x != room);
+            if rooms.len() != count {
+                save_json(&self.path, &*rooms);
+            }
+        }
+    }
+
+    /// If room is a 1:1 room between the bot and sender, returns sender
+    pub fn user_of(&self, room: &str, sender: &str) -> Option<String> {
+        if let Some((since, user)) = self.checked.lock().ok()?.get(room) {
+            // Somebody else talking in a 1:1 room means its members changed
+            let changed = user.as_ref().is_some_and(|x| x != sender);
+            if since.elapsed() < CHECK_TTL && !changed {
+                return user.clone().filter(|x| x == sender);
+            }
+        }
+
+        let api = match self.api.as_ref() {
+            Some(x) => x,
+            // Only the rooms we opened ourselves are known then
+            None => {
+                let rooms = self.rooms.lock().ok()?;
+                return rooms
+                    .get(sender)
+                    .filter(|x| *x == room)
+                    .map(|_| sender.to_string());
+            }
+        };
+        // Rooms the user opened with the bot count as well
+        let user = match api.joined_members(room) {
+            Ok(members) => {
+                if members.len() == 2
+                    && members.iter().any(|x| x == api.user_id())
+                    && members.iter().any(|x| x == sender)
+                {
+                    Some(sender.to_string())
+                } else {
+                    None
+                }
+            }
+            Err(x) => {
+                println!("Could not get members of {}: {:?}", room, x);
+                return None;
+            }
+        };
+
+        match &user {
+            Some(user) => {
+                let known = self
+                    .rooms
+                    .lock()
+                    .map(|x| x.contains_key(user))
+                    .unwrap_or(true);
+                if !known {
+                    self.remember(user, room);
+                }
+            }
+            None => self.forget(room),
+        }
+        if let Ok(mut checked) = self.checked.lock() {
+            checked.insert(room.to_string(), (Instant::now(), user.clone()));
+        }
+        user
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::MockServer;
+    use serde_json::json;
+
+    /// Rooms of a homeserver, whose members can change
+    fn rooms_with(members: Arc<Mutex<Vec<&'static str>>>) -> (MockServer, DirectRooms) {
+        let server = MockServer::start(move |request| {
+            if request.path.ends_with("/login") {
+                let body = json!({"access_token": "token", "user_id": "@bot:example.org"});
+                return (200, body.to_string());
+            }
+            let joined = members
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|x| (x.to_string(), json!({})))
+                .collect::<serde_json::Map<_, _>>();
+            (200, json!({ "joined": joined }).to_string())
+        });
+        let api = MatrixApi::login_with_session(&server.url, "bot", "secret", &None).unwrap();
+        let direct = DirectRooms {
+            api: Some(Arc::new(api)),
+            path: None,
+            ..Default::default()
+        };
+        (server, direct)
+    }
+
+    #[test]
+    fn membership_changes_are_noticed() {
+        let members = Arc::new(Mutex::new(vec!["@bot:example.org", "@alice:example.org"]));
+        let (server, direct) = rooms_with(members.clone());
+
+        assert_eq!(
+            direct.user_of("!dm:example.org", "@alice:example.org"),
+            Some("@alice:example.org".to_string())
+        );
+        assert_eq!(
+            direct.user_of("!dm:example.org", "@alice:example.org"),
+            Some("@alice:example.org".to_string())
+        );
+        assert_eq!(server.paths().len(), 2);
+        assert_eq!(
+            direct.room_for("@alice:example.org").unwrap(),
+            "!dm:example.org"
+        );
+
+        // Bob joined and talks, so the room is no 1:1 room anymore
+        members.lock().unwrap().push("@bob:example.org");
+        assert_eq!(direct.user_of("!dm:example.org", "@bob:example.org"), None);
+        assert_eq!(
+            direct.user_of("!dm:example.org", "@alice:example.org"),
+            None
+        );
+        assert_eq!(server.paths().len(), 3);
+        assert!(direct.rooms.lock().unwrap().is_empty());
+
+        // Bob left again, which only the next check after CHECK_TTL notices
+        members.lock().unwrap().pop();
+        assert_eq!(
+            direct.user_of("!dm:example.org", "@alice:example.org"),
+            None
+        );
+        direct.checked.lock().unwrap().clear();
+        assert_eq!(
+            direct.user_of("!dm:example.org", "@alice:example.org"),
+            Some("@alice:example.org".to_string())
+        );
+    }
+
+    #[test]
+    fn without_api_only_known_rooms() {
+        let direct = DirectRooms::default();
+        direct.remember("@alice:example.org", "!dm:example.org");
+        assert_eq!(
+            direct.user_of("!dm:example.org", "@alice:example.org"),
+            Some("@alice:example.org".to_string())
+        );
+        assert_eq!(direct.user_of("!dm:example.org", "@bob:example.org"), None);
+        assert_eq!(
+            direct.user_of("!other:example.org", "@alice:example.org"),
+            None
+        );
+        assert!(direct.room_for("@bob:example.org").is_err());
+    }
+}
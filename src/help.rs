@@ -1,14 +1,23 @@
+use crate::accounts;
+use crate::actions;
+use crate::admin;
 use crate::build_res;
+use crate::i18n::{self, tr};
 use crate::leave;
 use crate::openqa;
+use crate::outbox;
+use crate::settings::Settings;
 use crate::submitrequests;
+use crate::templates;
+use crate::transfer;
 
 use matrix_bot_api::handlers::{extract_command, HandleResult, MessageHandler};
 use matrix_bot_api::{ActiveBot, Message, MessageType};
 
-#[derive(Debug)]
 pub struct HelpHandler {
     pub prefix: Option<String>,
+    /// For the language of the room
+    pub settings: Settings,
 }
 
 impl MessageHandler for HelpHandler {
@@ -21,20 +30,56 @@ impl MessageHandler for HelpHandler {
             return HandleResult::ContinueHandling;
         }
 
-        let mut items = vec![("help".to_string(), "Print this help".to_string())];
+        let prefix = self.prefix.as_deref().unwrap_or("");
+        let mut items = vec![
+            ("help".to_string(), "Print this help".to_string()),
+            (
+                format!("{}sub URL...", prefix),
+                "Subscribe to one or more URLs (same as just posting them), or shorthands like sr#1234, openqa#1234 or PROJECT/PACKAGE. Quote arguments containing spaces.".to_string(),
+            ),
+            (
+                format!("{}list [all|packages|requests|tests] [FILTER] [page N]", prefix),
+                "List the subscriptions of this room (and your personal ones) on all backends, optionally only one type or the ones containing FILTER.".to_string(),
+            ),
+            (
+                format!("{}sub me URL", prefix),
+                "Subscribe personally, notifications go to a direct chat with you (as does everything posted there).".to_string(),
+            ),
+            (
+                format!("{}unsub me URL", prefix),
+                "Remove a personal subscription.".to_string(),
+            ),
+            (
+                format!("{}notify @USER:SERVER... URL", prefix),
+                "Mention these users when a subscribed build fails or request gets declined ('nobody' to stop).".to_string(),
+            ),
+        ];
         items.append(&mut leave::help_str(self.prefix.as_deref()));
         items.append(&mut build_res::help_str(self.prefix.as_deref()));
         items.append(&mut submitrequests::help_str(self.prefix.as_deref()));
         items.append(&mut openqa::help_str(self.prefix.as_deref()));
+        items.append(&mut outbox::help_str(self.prefix.as_deref()));
+        items.append(&mut transfer::help_str(self.prefix.as_deref()));
+        items.append(&mut templates::help_str(self.prefix.as_deref()));
+        items.append(&mut i18n::help_str(self.prefix.as_deref()));
+        items.append(&mut accounts::help_str(self.prefix.as_deref()));
+        items.append(&mut actions::help_str(self.prefix.as_deref()));
+        items.append(&mut admin::help_str(self.prefix.as_deref()));
 
-        let mut plainmsg = "Hi, I'm a friendly robot and provide these options:".to_string();
+        let lang = self.settings.language(&message.room);
+        let items = items
+            .into_iter()
+            .map(|(key, text)| (key, tr(&lang, &text)))
+            .collect::<Vec<_>>();
+        let greeting = tr(&lang, "Hi, I'm a friendly robot and provide these options:");
+
+        let mut plainmsg = greeting.clone();
         for (key, text) in &items {
             plainmsg += "\n";
             plainmsg += &format!("{:<35} - {}", key, text)
         }
 
-        let mut htmlmsg =
-            "<h3>Hi, I'm a friendly robot and provide these options:</h3>".to_string();
+        let mut htmlmsg = format!("<h3>{}</h3>", greeting);
         htmlmsg += "\n";
         htmlmsg += "<table>";
         for (key, text) in &items {
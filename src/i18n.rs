@@ -0,0 +1,1143 @@
+use crate::common::prepend_prefix;
+use crate::settings::{RoomSettings, Settings};
+use chrono::{DateTime, Datelike, TimeZone};
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use std::fmt::Display;
+
+/// (code, name) of all languages the bot speaks
+pub const LANGUAGES: [(&str, &str); 3] = [("en", "English"), ("de", "Deutsch"), ("cs", "Čeština")];
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+/// English text -> (German, Czech). The English text is the key, so untranslated texts
+/// simply stay English. {} are placeholders, filled in order by trf().
+const CATALOG: &[(&str, &str, &str)] = &[
+    // Help
+    (
+        "Hi, I'm a friendly robot and provide these options:",
+        "Hallo, ich bin ein freundlicher Roboter und biete diese Befehle an:",
+        "Ahoj, jsem přátelský robot a nabízím tyto příkazy:",
+    ),
+    ("Print this help", "Diese Hilfe anzeigen", "Vypsat tuto nápovědu"),
+    (
+        "Subscribe personally, notifications go to a direct chat with you (as does everything posted there).",
+        "Persönlich abonnieren, Benachrichtigungen kommen in einen Direktchat mit dir (wie alles, was dort gepostet wird).",
+        "Osobní odběr, upozornění chodí do přímého chatu s tebou (stejně jako vše, co tam pošleš).",
+    ),
+    (
+        "Subscribe to one or more URLs (same as just posting them), or shorthands like sr#1234, openqa#1234 or PROJECT/PACKAGE. Quote arguments containing spaces.",
+        "Eine oder mehrere URLs abonnieren (wie beim einfachen Posten), oder Kurzformen wie sr#1234, openqa#1234 oder PROJEKT/PAKET. Argumente mit Leerzeichen in Anführungszeichen setzen.",
+        "Odebírat jednu nebo více URL (stejné jako je jen poslat), nebo zkratky jako sr#1234, openqa#1234 či PROJEKT/BALÍČEK. Argumenty s mezerami dej do uvozovek.",
+    ),
+    (
+        "List the subscriptions of this room (and your personal ones) on all backends, optionally only one type or the ones containing FILTER.",
+        "Die Abonnements dieses Raums (und deine persönlichen) auf allen Backends auflisten, optional nur eine Art oder die, die FILTER enthalten.",
+        "Vypsat odběry této místnosti (a tvé osobní) na všech backendech, volitelně jen jeden typ nebo ty, které obsahují FILTER.",
+    ),
+    (
+        "For admins: Joined rooms, all subscriptions or events per routing key.",
+        "Für Admins: Beigetretene Räume, alle Abonnements oder Ereignisse pro Routing-Key.",
+        "Pro administrátory: Připojené místnosti, všechny odběry nebo události podle routing key.",
+    ),
+    (
+        "For admins: Remove all subscriptions of a room.",
+        "Für Admins: Alle Abonnements eines Raums entfernen.",
+        "Pro administrátory: Zrušit všechny odběry místnosti.",
+    ),
+    (
+        "For admins: Send a message to all joined rooms.",
+        "Für Admins: Eine Nachricht an alle beigetretenen Räume senden.",
+        "Pro administrátory: Poslat zprávu do všech připojených místností.",
+    ),
+    (
+        "Get a file with the subscriptions of this room, usable as default_subs in the config or with 'import'.",
+        "Eine Datei mit den Abonnements dieses Raums erhalten, verwendbar als default_subs in der Konfiguration oder mit 'import'.",
+        "Získat soubor s odběry této místnosti, použitelný jako default_subs v konfiguraci nebo s 'import'.",
+    ),
+    (
+        "Subscribe this room to everything in the file you posted last (or in the text after 'import').",
+        "Diesen Raum alles aus der zuletzt von dir geschickten Datei (oder dem Text nach 'import') abonnieren lassen.",
+        "Přihlásit tuto místnost k odběru všeho ze souboru, který jsi poslal naposledy (nebo z textu za 'import').",
+    ),
+    (
+        "Remove a personal subscription.",
+        "Ein persönliches Abonnement entfernen.",
+        "Zrušit osobní odběr.",
+    ),
+    (
+        "Mention these users when a subscribed build fails or request gets declined ('nobody' to stop).",
+        "Diese Benutzer erwähnen, wenn ein abonnierter Build fehlschlägt oder ein Request abgelehnt wird ('nobody' zum Beenden).",
+        "Zmínit tyto uživatele, když odebíraný build selže nebo je požadavek zamítnut ('nobody' pro ukončení).",
+    ),
+    ("Leave the current room", "Diesen Raum verlassen", "Opustit tuto místnost"),
+    ("Shutdown the bot completely", "Den Bot komplett beenden", "Úplně vypnout bota"),
+    (
+        "Subscribe to a package. Get notification if build-status changes.",
+        "Ein Paket abonnieren. Benachrichtigung, wenn sich der Build-Status ändert.",
+        "Odebírat balíček. Upozornění při změně stavu buildu.",
+    ),
+    (
+        "Unsubscribe from a package. Get no more notifications.",
+        "Ein Paket abbestellen. Keine weiteren Benachrichtigungen.",
+        "Zrušit odběr balíčku. Žádná další upozornění.",
+    ),
+    (
+        "Show the current build results of a package.",
+        "Die aktuellen Build-Ergebnisse eines Pakets anzeigen.",
+        "Zobrazit aktuální výsledky buildu balíčku.",
+    ),
+    (
+        "Subscribe to a SR/MR. Get notification if state changes.",
+        "Einen SR/MR abonnieren. Benachrichtigung, wenn sich der Status ändert.",
+        "Odebírat SR/MR. Upozornění při změně stavu.",
+    ),
+    (
+        "Unsubscribe from a SR/MR. Get no more notifications.",
+        "Einen SR/MR abbestellen. Keine weiteren Benachrichtigungen.",
+        "Zrušit odběr SR/MR. Žádná další upozornění.",
+    ),
+    (
+        "Show state, open reviews and history of a SR/MR.",
+        "Status, offene Reviews und Verlauf eines SR/MR anzeigen.",
+        "Zobrazit stav, otevřené revize a historii SR/MR.",
+    ),
+    (
+        "Subscribe to a test. Get notification if test-status changes. Restarts are followed.",
+        "Einen Test abonnieren. Benachrichtigung, wenn sich der Test-Status ändert. Neustarts werden verfolgt.",
+        "Odebírat test. Upozornění při změně stavu testu. Restarty jsou sledovány.",
+    ),
+    (
+        "Unsubscribe from a test. Get no more notifications.",
+        "Einen Test abbestellen. Keine weiteren Benachrichtigungen.",
+        "Zrušit odběr testu. Žádná další upozornění.",
+    ),
+    (
+        "Subscribe to all tests of a job group (group_overview URL).",
+        "Alle Tests einer Job-Gruppe abonnieren (group_overview-URL).",
+        "Odebírat všechny testy skupiny jobů (URL group_overview).",
+    ),
+    (
+        "Subscribe to all tests matching the distri, version, groupid, build, test, flavor, machine and arch of a tests/overview URL. Wildcards (*, ?) are allowed.",
+        "Alle Tests abonnieren, die zu distri, version, groupid, build, test, flavor, machine und arch einer tests/overview-URL passen. Platzhalter (*, ?) sind erlaubt.",
+        "Odebírat všechny testy odpovídající distri, version, groupid, build, test, flavor, machine a arch z URL tests/overview. Zástupné znaky (*, ?) jsou povoleny.",
+    ),
+    (
+        "Get only one summary per build (also for OPENQA_OVERVIEW_URL), once all its jobs are done.",
+        "Nur eine Zusammenfassung pro Build bekommen (auch für OPENQA_OVERVIEW_URL), sobald alle Jobs fertig sind.",
+        "Dostat jen jedno shrnutí za build (i pro OPENQA_OVERVIEW_URL), jakmile jsou hotové všechny jeho joby.",
+    ),
+    (
+        "List the most flaky tests of the subscriptions of this room.",
+        "Die instabilsten Tests der Abonnements dieses Raums auflisten.",
+        "Vypsat nejnestabilnější testy z odběrů této místnosti.",
+    ),
+    (
+        "Show if this room gets live events or reports.",
+        "Anzeigen, ob dieser Raum Live-Ereignisse oder Berichte bekommt.",
+        "Zobrazit, zda tato místnost dostává živé události nebo přehledy.",
+    ),
+    (
+        "Only post a daily report of all events in this room.",
+        "In diesem Raum nur einen täglichen Bericht aller Ereignisse posten.",
+        "V této místnosti posílat jen denní přehled všech událostí.",
+    ),
+    (
+        "Only post a weekly report (DAY is mon, tue, ...).",
+        "Nur einen wöchentlichen Bericht posten (DAY ist mon, tue, ...).",
+        "Posílat jen týdenní přehled (DAY je mon, tue, ...).",
+    ),
+    (
+        "Post the report collected so far.",
+        "Den bisher gesammelten Bericht posten.",
+        "Poslat dosud shromážděný přehled.",
+    ),
+    (
+        "Go back to live events.",
+        "Zurück zu Live-Ereignissen.",
+        "Vrátit se k živým událostem.",
+    ),
+    (
+        "Drop all notifications in this room for a while.",
+        "Alle Benachrichtigungen in diesem Raum eine Weile verwerfen.",
+        "Na chvíli zahazovat všechna upozornění v této místnosti.",
+    ),
+    (
+        "Drop notifications of one subscription for a while.",
+        "Benachrichtigungen eines Abonnements eine Weile verwerfen.",
+        "Na chvíli zahazovat upozornění jednoho odběru.",
+    ),
+    (
+        "Get notifications of a subscription again.",
+        "Benachrichtigungen eines Abonnements wieder bekommen.",
+        "Znovu dostávat upozornění odběru.",
+    ),
+    (
+        "Hold notifications during these hours, post them as one message afterwards.",
+        "Benachrichtigungen in diesen Stunden zurückhalten und danach als eine Nachricht posten.",
+        "V těchto hodinách upozornění zadržet a poté je poslat jako jednu zprávu.",
+    ),
+    (
+        "Show which message templates this room uses.",
+        "Anzeigen, welche Nachrichtenvorlagen dieser Raum verwendet.",
+        "Zobrazit, které šablony zpráv tato místnost používá.",
+    ),
+    (
+        "Use another set of message templates in this room ('default' to go back).",
+        "Andere Nachrichtenvorlagen in diesem Raum verwenden ('default' für die Standardvorlagen).",
+        "Použít v této místnosti jinou sadu šablon zpráv ('default' pro návrat).",
+    ),
+    (
+        "Show the language of this room.",
+        "Die Sprache dieses Raums anzeigen.",
+        "Zobrazit jazyk této místnosti.",
+    ),
+    (
+        "Change the language of this room (en, de or cs).",
+        "Die Sprache dieses Raums ändern (en, de oder cs).",
+        "Změnit jazyk této místnosti (en, de nebo cs).",
+    ),
+    (
+        "Link your Matrix user to an OBS account (needs verification).",
+        "Deinen Matrix-Benutzer mit einem OBS-Konto verknüpfen (mit Verifizierung).",
+        "Propojit tvého uživatele Matrixu s účtem OBS (vyžaduje ověření).",
+    ),
+    (
+        "Finish linking, after putting the token into your home project.",
+        "Die Verknüpfung abschließen, nachdem das Token im Home-Projekt steht.",
+        "Dokončit propojení poté, co vložíš token do svého domovského projektu.",
+    ),
+    (
+        "Show which OBS accounts you are linked to.",
+        "Anzeigen, mit welchen OBS-Konten du verknüpft bist.",
+        "Zobrazit, se kterými účty OBS jsi propojen.",
+    ),
+    (
+        "Remove the link to your OBS account(s).",
+        "Die Verknüpfung mit deinen OBS-Konten entfernen.",
+        "Zrušit propojení s tvými účty OBS.",
+    ),
+    (
+        "Trigger a rebuild of a package you maintain.",
+        "Einen Rebuild eines von dir betreuten Pakets anstoßen.",
+        "Spustit rebuild balíčku, který spravuješ.",
+    ),
+    (
+        "Accept a request to a package you maintain (needs confirm).",
+        "Einen Request an ein von dir betreutes Paket annehmen (mit confirm).",
+        "Přijmout požadavek na balíček, který spravuješ (vyžaduje confirm).",
+    ),
+    (
+        "Decline a request to a package you maintain (needs confirm).",
+        "Einen Request an ein von dir betreutes Paket ablehnen (mit confirm).",
+        "Zamítnout požadavek na balíček, který spravuješ (vyžaduje confirm).",
+    ),
+    (
+        "Add a reviewer to a request you created or maintain.",
+        "Einen Reviewer zu einem Request hinzufügen, den du erstellt hast oder betreust.",
+        "Přidat recenzenta k požadavku, který jsi vytvořil nebo spravuješ.",
+    ),
+    (
+        "Execute your last accept/decline.",
+        "Dein letztes accept/decline ausführen.",
+        "Provést tvůj poslední accept/decline.",
+    ),
+    (
+        "Drop your last accept/decline.",
+        "Dein letztes accept/decline verwerfen.",
+        "Zahodit tvůj poslední accept/decline.",
+    ),
+    // Subscriptions
+    (
+        "Sorry, I could not parse that URL",
+        "Entschuldigung, diese URL verstehe ich nicht",
+        "Promiň, této URL nerozumím",
+    ),
+    (
+        "Sorry, I could not parse that. Please post a submitrequest URL",
+        "Entschuldigung, das verstehe ich nicht. Bitte poste eine Submitrequest-URL",
+        "Promiň, tomu nerozumím. Pošli prosím URL submitrequestu",
+    ),
+    (
+        "This quote is never closed",
+        "Dieses Anführungszeichen wird nie geschlossen",
+        "Tyto uvozovky nejsou nikdy uzavřeny",
+    ),
+    (
+        "Expected a URL here",
+        "Hier wird eine URL erwartet",
+        "Zde se očekává URL",
+    ),
+    (
+        "Expected a Matrix user like @user:server here",
+        "Hier wird ein Matrix-Benutzer wie @user:server erwartet",
+        "Zde se očekává uživatel Matrixu jako @user:server",
+    ),
+    (
+        "Only one URL can be snoozed at a time",
+        "Es kann nur eine URL auf einmal pausiert werden",
+        "Najednou lze pozastavit jen jednu URL",
+    ),
+    (
+        "Sorry, {} is not on a backend I know",
+        "Entschuldigung, {} liegt auf keinem mir bekannten Backend",
+        "Promiň, {} není na žádném backendu, který znám",
+    ),
+    (
+        "This command does not work for this URL",
+        "Dieser Befehl funktioniert für diese URL nicht",
+        "Tento příkaz pro tuto URL nefunguje",
+    ),
+    (
+        "Usage: sub [me] [summary] URL...",
+        "Verwendung: sub [me] [summary] URL...",
+        "Použití: sub [me] [summary] URL...",
+    ),
+    (
+        "Usage: unsub [me] [summary] URL...",
+        "Verwendung: unsub [me] [summary] URL...",
+        "Použití: unsub [me] [summary] URL...",
+    ),
+    (
+        "Usage: status URL...",
+        "Verwendung: status URL...",
+        "Použití: status URL...",
+    ),
+    (
+        "Usage: unsnooze URL...",
+        "Verwendung: unsnooze URL...",
+        "Použití: unsnooze URL...",
+    ),
+    (
+        "Usage: flaky tests",
+        "Verwendung: flaky tests",
+        "Použití: flaky tests",
+    ),
+    (
+        "Usage: notify @USER:SERVER... URL (or 'notify nobody URL')",
+        "Verwendung: notify @USER:SERVER... URL (oder 'notify nobody URL')",
+        "Použití: notify @USER:SERVER... URL (nebo 'notify nobody URL')",
+    ),
+    (
+        "There is no subscription to {} here",
+        "Hier gibt es kein Abonnement von {}",
+        "Tady neexistuje žádný odběr {}",
+    ),
+    (
+        "Sorry, I could not change that, due to an internal error.",
+        "Entschuldigung, wegen eines internen Fehlers konnte ich das nicht ändern.",
+        "Promiň, kvůli vnitřní chybě jsem to nemohl změnit.",
+    ),
+    (
+        "Nobody will be mentioned for {}",
+        "Für {} wird niemand erwähnt",
+        "U {} nebude nikdo zmíněn",
+    ),
+    (
+        "Will mention {} on failures of {}",
+        "Erwähne {} bei Fehlern von {}",
+        "Při selhání {1} zmíním {0}",
+    ),
+    (
+        "Notifications for {} are back",
+        "Benachrichtigungen für {} sind wieder da",
+        "Upozornění pro {} jsou zpět",
+    ),
+    (
+        "No notifications for {} until {}",
+        "Keine Benachrichtigungen für {} bis {}",
+        "Žádná upozornění pro {} do {}",
+    ),
+    (
+        "Usage: snooze URL 2h | snooze URL until tomorrow | unsnooze URL",
+        "Verwendung: snooze URL 2h | snooze URL until tomorrow | unsnooze URL",
+        "Použití: snooze URL 2h | snooze URL until tomorrow | unsnooze URL",
+    ),
+    (
+        "Usage: list [all|packages|requests|tests] [FILTER] [page N]",
+        "Verwendung: list [all|packages|requests|tests] [FILTER] [page N]",
+        "Použití: list [all|packages|requests|tests] [FILTER] [page N]",
+    ),
+    (
+        "Subscriptions ({} in total):",
+        "Abonnements ({} insgesamt):",
+        "Odběry (celkem {}):",
+    ),
+    ("packages", "Pakete", "balíčky"),
+    ("requests", "Requests", "požadavky"),
+    ("tests", "Tests", "testy"),
+    ("personal for {}", "persönlich für {}", "osobně pro {}"),
+    ("mentions {}", "erwähnt {}", "zmiňuje {}"),
+    ("snoozed until {}", "pausiert bis {}", "pozastaveno do {}"),
+    (
+        "Page {} of {}, '{}' shows more.",
+        "Seite {} von {}, '{}' zeigt mehr.",
+        "Strana {} z {}, '{}' ukáže další.",
+    ),
+    ("Page {} of {}.", "Seite {} von {}.", "Strana {} z {}."),
+    (
+        "No subscriptions to export",
+        "Keine Abonnements zum Exportieren",
+        "Žádné odběry k exportu",
+    ),
+    (
+        "Sorry, I found no file of yours to import. Post it first, or put its content after 'import'.",
+        "Entschuldigung, ich habe keine Datei von dir zum Importieren gefunden. Schick sie zuerst, oder schreib ihren Inhalt nach 'import'.",
+        "Promiň, nenašel jsem od tebe žádný soubor k importu. Nejdřív ho pošli, nebo napiš jeho obsah za 'import'.",
+    ),
+    (
+        "Sorry, I could not read the file: {}",
+        "Entschuldigung, ich konnte die Datei nicht lesen: {}",
+        "Promiň, soubor se mi nepodařilo přečíst: {}",
+    ),
+    (
+        "Subscribed to {} URLs.",
+        "{} URLs abonniert.",
+        "Přihlášeno k odběru {} URL.",
+    ),
+    (
+        "These entries failed:",
+        "Diese Einträge sind fehlgeschlagen:",
+        "Tyto položky selhaly:",
+    ),
+    (
+        "not on a backend I know",
+        "nicht auf einem mir bekannten Backend",
+        "není na backendu, který znám",
+    ),
+    ("not parsable", "nicht lesbar", "nelze zpracovat"),
+    (
+        "No subscriptions found",
+        "Keine Abonnements gefunden",
+        "Nenalezeny žádné odběry",
+    ),
+    (
+        "Subscribing to {} on {}",
+        "Abonniere {} auf {}",
+        "Odebírám {} na {}",
+    ),
+    (
+        "Subscribing {} personally to {} on {}",
+        "Abonniere {} persönlich {} auf {}",
+        "Uživatel {} osobně odebírá {} na {}",
+    ),
+    (
+        "Sorry, I could not add your request {} on {} to the subscriptions, due to an internal error ({}).",
+        "Entschuldigung, wegen eines internen Fehlers konnte ich {} auf {} nicht abonnieren ({}).",
+        "Promiň, kvůli vnitřní chybě jsem nemohl přidat {} na {} do odběrů ({}).",
+    ),
+    (
+        "Was not subscribed to {}",
+        "{} war nicht abonniert",
+        "{} nebylo odebíráno",
+    ),
+    (
+        "Unsubscribing {} from {} on {}",
+        "Bestelle {} von {} auf {} ab",
+        "Ruším odběr {} pro {} na {}",
+    ),
+    (
+        "Sorry, I could not remove your request {} on {} from the subscriptions, due to an internal error ({}).",
+        "Entschuldigung, wegen eines internen Fehlers konnte ich {} auf {} nicht abbestellen ({}).",
+        "Promiň, kvůli vnitřní chybě jsem nemohl odebrat {} na {} z odběrů ({}).",
+    ),
+    (
+        "Sorry, I could not open a direct chat with you ({})",
+        "Entschuldigung, ich konnte keinen Direktchat mit dir öffnen ({})",
+        "Promiň, nemohl jsem s tebou otevřít přímý chat ({})",
+    ),
+    ("room", "Raum", "místnost"),
+    // Reports and room settings
+    ("Build failures", "Fehlgeschlagene Builds", "Selhané buildy"),
+    ("Fixed packages", "Reparierte Pakete", "Opravené balíčky"),
+    ("Successful builds", "Erfolgreiche Builds", "Úspěšné buildy"),
+    ("Request changes", "Request-Änderungen", "Změny požadavků"),
+    (
+        "Failed openQA tests",
+        "Fehlgeschlagene openQA-Tests",
+        "Selhané testy openQA",
+    ),
+    (
+        "Passed openQA tests",
+        "Bestandene openQA-Tests",
+        "Úspěšné testy openQA",
+    ),
+    ("Other events", "Andere Ereignisse", "Ostatní události"),
+    ("... and {} more", "... und {} weitere", "... a {} dalších"),
+    (
+        "Report since the last one",
+        "Bericht seit dem letzten",
+        "Přehled od posledního",
+    ),
+    (
+        "During the quiet hours",
+        "Während der Ruhezeit",
+        "Během klidových hodin",
+    ),
+    (
+        "{} more events were suppressed, summary",
+        "{} weitere Ereignisse wurden unterdrückt, Zusammenfassung",
+        "{} dalších událostí bylo potlačeno, shrnutí",
+    ),
+    (
+        "This room gets a report {}",
+        "Dieser Raum bekommt einen Bericht {}",
+        "Tato místnost dostává přehled {}",
+    ),
+    (
+        "This room gets live events",
+        "Dieser Raum bekommt Live-Ereignisse",
+        "Tato místnost dostává živé události",
+    ),
+    (
+        "Nothing collected since the last report",
+        "Seit dem letzten Bericht wurde nichts gesammelt",
+        "Od posledního přehledu se nic nenashromáždilo",
+    ),
+    (
+        "Usage: digest daily HH:MM | digest weekly DAY HH:MM | digest off",
+        "Verwendung: digest daily HH:MM | digest weekly DAY HH:MM | digest off",
+        "Použití: digest daily HH:MM | digest weekly DAY HH:MM | digest off",
+    ),
+    (
+        "Sorry, I could not parse that time. Please use e.g. 'digest daily 09:00'",
+        "Entschuldigung, diese Zeit verstehe ich nicht. Bitte z.B. 'digest daily 09:00' verwenden",
+        "Promiň, tomuto času nerozumím. Použij např. 'digest daily 09:00'",
+    ),
+    (
+        "From now on, this room gets a report {} ({})",
+        "Ab jetzt bekommt dieser Raum einen Bericht {} ({})",
+        "Od teď tato místnost dostává přehled {} ({})",
+    ),
+    (
+        "From now on, this room gets live events",
+        "Ab jetzt bekommt dieser Raum Live-Ereignisse",
+        "Od teď tato místnost dostává živé události",
+    ),
+    ("daily at {}", "täglich um {}", "denně v {}"),
+    ("weekly on {} at {}", "wöchentlich am {} um {}", "týdně v {} v {}"),
+    (
+        "This room is muted until {}",
+        "Dieser Raum ist stummgeschaltet bis {}",
+        "Tato místnost je ztlumená do {}",
+    ),
+    (
+        "This room is not muted",
+        "Dieser Raum ist nicht stummgeschaltet",
+        "Tato místnost není ztlumená",
+    ),
+    (
+        "Usage: mute 2h | mute until 18:00 | mute until tomorrow | mute off",
+        "Verwendung: mute 2h | mute until 18:00 | mute until tomorrow | mute off",
+        "Použití: mute 2h | mute until 18:00 | mute until tomorrow | mute off",
+    ),
+    (
+        "Muted until {}",
+        "Stummgeschaltet bis {}",
+        "Ztlumeno do {}",
+    ),
+    (
+        "Not muted anymore",
+        "Nicht mehr stummgeschaltet",
+        "Už není ztlumeno",
+    ),
+    (
+        "Quiet hours of this room: {} ({})",
+        "Ruhezeit dieses Raums: {} ({})",
+        "Klidové hodiny této místnosti: {} ({})",
+    ),
+    (
+        "This room has no quiet hours",
+        "Dieser Raum hat keine Ruhezeit",
+        "Tato místnost nemá klidové hodiny",
+    ),
+    (
+        "Usage: quiet HH:MM-HH:MM | quiet off",
+        "Verwendung: quiet HH:MM-HH:MM | quiet off",
+        "Použití: quiet HH:MM-HH:MM | quiet off",
+    ),
+    (
+        "Notifications will be held from {} ({})",
+        "Benachrichtigungen werden zurückgehalten von {} ({})",
+        "Upozornění budou zadržena v čase {} ({})",
+    ),
+    (
+        "No more quiet hours",
+        "Keine Ruhezeit mehr",
+        "Žádné další klidové hodiny",
+    ),
+    // Templates and languages
+    (
+        "This room uses the {} templates. Available: default{}",
+        "Dieser Raum verwendet die Vorlagen {}. Verfügbar: default{}",
+        "Tato místnost používá šablony {}. Dostupné: default{}",
+    ),
+    (
+        "Sorry, there is no template set {}",
+        "Entschuldigung, es gibt keine Vorlagen {}",
+        "Promiň, sada šablon {} neexistuje",
+    ),
+    (
+        "Usage: templates [SET]",
+        "Verwendung: templates [SET]",
+        "Použití: templates [SET]",
+    ),
+    (
+        "This room now uses the {} templates",
+        "Dieser Raum verwendet jetzt die Vorlagen {}",
+        "Tato místnost nyní používá šablony {}",
+    ),
+    (
+        "This room speaks {}. Available: {}",
+        "Dieser Raum spricht {}. Verfügbar: {}",
+        "Tato místnost mluví jazykem {}. Dostupné: {}",
+    ),
+    (
+        "This room now speaks {}",
+        "Dieser Raum spricht jetzt {}",
+        "Tato místnost nyní mluví jazykem {}",
+    ),
+    (
+        "Sorry, I don't speak {}. Available: {}",
+        "Entschuldigung, ich spreche kein {}. Verfügbar: {}",
+        "Promiň, jazykem {} nemluvím. Dostupné: {}",
+    ),
+    // Build results and requests
+    (
+        "Sorry, I could not parse that. Please post a package URL",
+        "Entschuldigung, das verstehe ich nicht. Bitte poste eine Paket-URL",
+        "Promiň, tomu nerozumím. Pošli prosím URL balíčku",
+    ),
+    (
+        "Sorry, I could not get the build results of {} on {}",
+        "Entschuldigung, ich konnte die Build-Ergebnisse von {} auf {} nicht abrufen",
+        "Promiň, nepodařilo se mi získat výsledky buildu {} na {}",
+    ),
+    (
+        "No build results found for {} on {}",
+        "Keine Build-Ergebnisse für {} auf {} gefunden",
+        "Pro {} na {} nebyly nalezeny žádné výsledky buildu",
+    ),
+    (
+        "Build results of {} on {}:",
+        "Build-Ergebnisse von {} auf {}:",
+        "Výsledky buildu {} na {}:",
+    ),
+    (
+        "Sorry, I need OBS credentials for {} to look up requests. Please ask the admin of this bot to add them to obs_credentials in the config.",
+        "Entschuldigung, um Requests nachzuschlagen, brauche ich OBS-Zugangsdaten für {}. Bitte den Admin dieses Bots, sie in obs_credentials der Konfiguration einzutragen.",
+        "Promiň, k vyhledání požadavků potřebuji přihlašovací údaje OBS pro {}. Požádej správce tohoto bota, aby je přidal do obs_credentials v konfiguraci.",
+    ),
+    (
+        "Sorry, I could not get request {} on {}",
+        "Entschuldigung, ich konnte Request {} auf {} nicht abrufen",
+        "Promiň, nepodařilo se mi získat požadavek {} na {}",
+    ),
+    (
+        "Request {} on {}: {}",
+        "Request {} auf {}: {}",
+        "Požadavek {} na {}: {}",
+    ),
+    ("by {}, {}", "von {}, {}", "od {}, {}"),
+    ("by {}", "von {}", "od {}"),
+    ("Actions", "Aktionen", "Akce"),
+    ("Open reviews", "Offene Reviews", "Otevřené revize"),
+    ("History", "Verlauf", "Historie"),
+    // openQA
+    (
+        "No flaky tests found",
+        "Keine instabilen Tests gefunden",
+        "Nenalezeny žádné nestabilní testy",
+    ),
+    (
+        "Flaky tests on {}:",
+        "Instabile Tests auf {}:",
+        "Nestabilní testy na {}:",
+    ),
+    (
+        "{}: changed {} times in the last {} runs",
+        "{}: {} Änderungen in den letzten {} Läufen",
+        "{}: změněno {}krát za posledních {} běhů",
+    ),
+    (
+        "{} passed, {} softfailed, {} failed, {} incomplete",
+        "{} bestanden, {} mit Warnungen, {} fehlgeschlagen, {} unvollständig",
+        "{} prošlo, {} s varováním, {} selhalo, {} nedokončeno",
+    ),
+    (
+        "Build {} ({}) finished: {}",
+        "Build {} ({}) abgeschlossen: {}",
+        "Build {} ({}) dokončen: {}",
+    ),
+    ("Failed", "Fehlgeschlagen", "Selhaly"),
+    (
+        "Job {} was restarted as {}",
+        "Job {} wurde als {} neu gestartet",
+        "Job {} byl restartován jako {}",
+    ),
+    (
+        "Job {} was cloned as {}",
+        "Job {} wurde als {} geklont",
+        "Job {} byl naklonován jako {}",
+    ),
+    // Accounts and actions
+    (
+        "Sorry, I don't know the backend {}",
+        "Entschuldigung, das Backend {} kenne ich nicht",
+        "Promiň, backend {} neznám",
+    ),
+    (
+        "To prove that you are {0} on {1}, add the token {2} to the description of your home project ({3}) and then send 'link obs verify' within {4} minutes. You can remove the token again afterwards.",
+        "Um zu beweisen, dass du {0} auf {1} bist, füge das Token {2} in die Beschreibung deines Home-Projekts ({3}) ein und schicke dann innerhalb von {4} Minuten 'link obs verify'. Danach kannst du das Token wieder entfernen.",
+        "Abys dokázal, že jsi {0} na {1}, přidej token {2} do popisu svého domovského projektu ({3}) a pak do {4} minut pošli 'link obs verify'. Token pak můžeš zase odstranit.",
+    ),
+    (
+        "There is no link waiting for verification. Start with 'link obs USERNAME'.",
+        "Es wartet keine Verknüpfung auf Verifizierung. Beginne mit 'link obs USERNAME'.",
+        "Žádné propojení nečeká na ověření. Začni příkazem 'link obs USERNAME'.",
+    ),
+    (
+        "Sorry, I could not look at {}. Please try again later.",
+        "Entschuldigung, ich konnte {} nicht ansehen. Bitte versuche es später noch einmal.",
+        "Promiň, nemohl jsem se podívat na {}. Zkus to prosím později.",
+    ),
+    (
+        "I could not find {} in the description of {}. Please check and try again.",
+        "Ich konnte {} nicht in der Beschreibung von {} finden. Bitte prüfe es und versuche es noch einmal.",
+        "V popisu {1} jsem nenašel {0}. Zkontroluj to prosím a zkus to znovu.",
+    ),
+    (
+        "You are now linked to {} on {}",
+        "Du bist jetzt mit {} auf {} verknüpft",
+        "Nyní jsi propojen s {} na {}",
+    ),
+    (
+        "You are not linked to any OBS account.",
+        "Du bist mit keinem OBS-Konto verknüpft.",
+        "Nejsi propojen s žádným účtem OBS.",
+    ),
+    ("{} is linked to:", "{} ist verknüpft mit:", "{} je propojen s:"),
+    ("{} on {}", "{} auf {}", "{} na {}"),
+    ("from config", "aus der Konfiguration", "z konfigurace"),
+    (
+        "Removed {} linked account(s).",
+        "{} verknüpfte(s) Konto/Konten entfernt.",
+        "Odebráno propojených účtů: {}.",
+    ),
+    (
+        "Accounts from the config can only be removed by the bot admin.",
+        "Konten aus der Konfiguration kann nur der Admin des Bots entfernen.",
+        "Účty z konfigurace může odebrat jen správce bota.",
+    ),
+    (
+        "Usage: link obs USERNAME [BACKEND]",
+        "Verwendung: link obs USERNAME [BACKEND]",
+        "Použití: link obs USERNAME [BACKEND]",
+    ),
+    ("Usage: {} URL ...", "Verwendung: {} URL ...", "Použití: {} URL ..."),
+    (
+        "Usage: addreview URL {} NAME [COMMENT]",
+        "Verwendung: addreview URL {} NAME [COMMENT]",
+        "Použití: addreview URL {} NAME [COMMENT]",
+    ),
+    (
+        "Sorry, {} is not a package URL",
+        "Entschuldigung, {} ist keine Paket-URL",
+        "Promiň, {} není URL balíčku",
+    ),
+    (
+        "Sorry, {} is not a request URL",
+        "Entschuldigung, {} ist keine Request-URL",
+        "Promiň, {} není URL požadavku",
+    ),
+    ("Unknown action {}", "Unbekannte Aktion {}", "Neznámá akce {}"),
+    (
+        "Sorry, I don't know your OBS account on {}. Please link it first with 'link obs USERNAME'.",
+        "Entschuldigung, ich kenne dein OBS-Konto auf {} nicht. Bitte verknüpfe es zuerst mit 'link obs USERNAME'.",
+        "Promiň, neznám tvůj účet OBS na {}. Nejdřív ho prosím propoj pomocí 'link obs USERNAME'.",
+    ),
+    (
+        "Sorry, {} is not allowed to {}",
+        "Entschuldigung, {} darf nicht: {}",
+        "Promiň, {} nesmí: {}",
+    ),
+    (
+        "Sorry, I could not check if you are allowed to {}",
+        "Entschuldigung, ich konnte nicht prüfen, ob du das darfst: {}",
+        "Promiň, nemohl jsem ověřit, zda smíš: {}",
+    ),
+    (
+        "About to {} on {} as {}. Reply 'confirm' within {} minutes to proceed, or 'cancel'.",
+        "Gleich: {} auf {} als {}. Antworte innerhalb von {} Minuten mit 'confirm', um fortzufahren, oder mit 'cancel'.",
+        "Chystám se: {} na {} jako {}. Odpověz do {} minut 'confirm' pro pokračování, nebo 'cancel'.",
+    ),
+    ("Done: {}", "Erledigt: {}", "Hotovo: {}"),
+    (
+        "Sorry, could not {}: {}",
+        "Entschuldigung, fehlgeschlagen: {}: {}",
+        "Promiň, nepodařilo se: {}: {}",
+    ),
+    (
+        "There is nothing to confirm or cancel.",
+        "Es gibt nichts zu bestätigen oder abzubrechen.",
+        "Není nic k potvrzení ani zrušení.",
+    ),
+    ("Cancelled: {}", "Abgebrochen: {}", "Zrušeno: {}"),
+    // Leaving and admin commands
+    ("Bye!", "Tschüss!", "Ahoj!"),
+    (
+        "Joined {} rooms:",
+        "In {} Räumen beigetreten:",
+        "Připojen do {} místností:",
+    ),
+    (
+        "{} rooms with subscriptions (without a Matrix API login, joined rooms without any are unknown):",
+        "{} Räume mit Abonnements (ohne Matrix-API-Login sind beigetretene Räume ohne Abonnements unbekannt):",
+        "{} místností s odběry (bez přihlášení k Matrix API nejsou připojené místnosti bez odběrů známé):",
+    ),
+    (
+        "{}: {} subscriptions",
+        "{}: {} Abonnements",
+        "{}: {} odběrů",
+    ),
+    (
+        "{} (personal): {} subscriptions",
+        "{} (persönlich): {} Abonnements",
+        "{} (osobní): {} odběrů",
+    ),
+    (
+        "{} (personal):",
+        "{} (persönlich):",
+        "{} (osobní):",
+    ),
+    (
+        "No subscriptions at all",
+        "Überhaupt keine Abonnements",
+        "Žádné odběry",
+    ),
+    (
+        "Events since {}h {}m (received / sent notifications):",
+        "Ereignisse seit {}h {}m (empfangen / gesendete Benachrichtigungen):",
+        "Události za {}h {}m (přijaté / odeslaná oznámení):",
+    ),
+    ("Total: {} / {}", "Gesamt: {} / {}", "Celkem: {} / {}"),
+    (
+        "Sent to {} rooms",
+        "An {} Räume gesendet",
+        "Odesláno do {} místností",
+    ),
+    (
+        "Sent to the {} rooms with subscriptions (without a Matrix API login, joined rooms are unknown)",
+        "An die {} Räume mit Abonnements gesendet (ohne Matrix-API-Login sind beigetretene Räume unbekannt)",
+        "Odesláno do {} místností s odběry (bez přihlášení k Matrix API nejsou připojené místnosti známé)",
+    ),
+    (
+        "Sorry, only admins of this bot can do that.",
+        "Das dürfen leider nur Admins dieses Bots.",
+        "Promiň, to smí jen admini tohoto bota.",
+    ),
+    (
+        "Removed {} subscriptions of {}",
+        "{} Abonnements von {} entfernt",
+        "Odstraněno {} odběrů místnosti {}",
+    ),
+    (
+        "Usage: admin rooms | admin subs | admin stats | admin unsub-room ROOM | admin broadcast TEXT",
+        "Verwendung: admin rooms | admin subs | admin stats | admin unsub-room RAUM | admin broadcast TEXT",
+        "Použití: admin rooms | admin subs | admin stats | admin unsub-room MÍSTNOST | admin broadcast TEXT",
+    ),
+    // Words used in notifications
+    ("succeeded", "erfolgreich", "úspěšný"),
+    ("failed", "fehlgeschlagen", "selhal"),
+    ("changed", "geändert", "změněn"),
+    ("changed by admin", "vom Admin geändert", "změněn adminem"),
+    ("deleted", "gelöscht", "smazán"),
+    ("commented", "kommentiert", "okomentován"),
+    ("new", "neu", "nový"),
+    ("review", "im Review", "v revizi"),
+    ("accepted", "angenommen", "přijat"),
+    ("declined", "abgelehnt", "zamítnut"),
+    ("revoked", "zurückgezogen", "stažen"),
+    ("superseded", "ersetzt", "nahrazen"),
+    ("passed", "bestanden", "prošel"),
+    ("softfailed", "mit Warnungen bestanden", "prošel s varováním"),
+    ("incomplete", "unvollständig", "nedokončen"),
+    ("skipped", "übersprungen", "přeskočen"),
+    ("user_cancelled", "abgebrochen", "zrušen"),
+    ("timeout_exceeded", "Zeitüberschreitung", "vypršel čas"),
+    // Weekdays, as in WEEKDAYS
+    ("mon", "Mo", "po"),
+    ("tue", "Di", "út"),
+    ("wed", "Mi", "st"),
+    ("thu", "Do", "čt"),
+    ("fri", "Fr", "pá"),
+    ("sat", "Sa", "so"),
+    ("sun", "So", "ne"),
+];
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [
+        ("language", "Show the language of this room."),
+        (
+            "language en|de|cs",
+            "Change the language of this room (en, de or cs).",
+        ),
+    ];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+/// Translation of text, or text itself if there is none
+pub fn tr(lang: &str, text: &str) -> String {
+    let column = match lang {
+        "de" => 1,
+        "cs" => 2,
+        _ => return text.to_string(),
+    };
+
+    CATALOG
+        .iter()
+        .find(|entry| entry.0 == text)
+        .map(|entry| if column == 1 { entry.1 } else { entry.2 })
+        .unwrap_or(text)
+        .to_string()
+}
+
+/// Translation of text with its placeholders filled. {} takes the next argument,
+/// {0}, {1}, ... a specific one (for languages with another word order).
+pub fn trf(lang: &str, text: &str, args: &[&dyn Display]) -> String {
+    let translated = tr(lang, text);
+    let mut res = String::new();
+    let mut next = 0;
+    let mut rest = translated.as_str();
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(x) => start + x,
+            None => break,
+        };
+        res += &rest[..start];
+        let index = if end == start + 1 {
+            next += 1;
+            next - 1
+        } else {
+            match rest[start + 1..end].parse::<usize>() {
+                Ok(x) => x,
+                Err(_) => {
+                    // Not a placeholder
+                    res += &rest[start..=end];
+                    rest = &rest[end + 1..];
+                    continue;
+                }
+            }
+        };
+        if let Some(arg) = args.get(index) {
+            res += &arg.to_string();
+        }
+        rest = &rest[end + 1..];
+    }
+    res + rest
+}
+
+/// Like "%a %H:%M", with the weekday in the language
+pub fn format_time<T: TimeZone>(lang: &str, time: DateTime<T>) -> String
+where
+    T::Offset: Display,
+{
+    let day = crate::settings::WEEKDAYS[time.weekday().num_days_from_monday() as usize];
+    let day = if lang == DEFAULT_LANGUAGE {
+        time.format("%a").to_string()
+    } else {
+        tr(lang, day)
+    };
+    format!("{} {}", day, time.format("%H:%M"))
+}
+
+/// The 'language' command, setting the language of a room
+#[derive(Clone)]
+pub struct LanguageHandler {
+    pub settings: Settings,
+    pub prefix: Option<String>,
+}
+
+impl LanguageHandler {
+    fn handle_language_command(&self, room: &str, words: &[&str]) -> String {
+        let lang = self.settings.language(room);
+        let available = LANGUAGES
+            .iter()
+            .map(|(code, name)| format!("{} ({})", code, name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let name_of = |code: &str| {
+            LANGUAGES
+                .iter()
+                .find(|x| x.0 == code)
+                .map(|x| x.1)
+                .unwrap_or(DEFAULT_LANGUAGE)
+        };
+
+        match words {
+            [] => trf(
+                &lang,
+                "This room speaks {}. Available: {}",
+                &[&name_of(&lang), &available],
+            ),
+            [code] if LANGUAGES.iter().any(|x| x.0 == *code) => {
+                let code = code.to_string();
+                self.settings.update(room, |settings: &mut RoomSettings| {
+                    settings.language = Some(code.clone())
+                });
+                trf(&code, "This room now speaks {}", &[&name_of(&code)])
+            }
+            [code, ..] => trf(
+                &lang,
+                "Sorry, I don't speak {}. Available: {}",
+                &[code, &available],
+            ),
+        }
+    }
+}
+
+impl MessageHandler for LanguageHandler {
+    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
+        let prefix = self.prefix.as_deref().unwrap_or("");
+        let body = message.body.trim();
+        if !body.starts_with(prefix) {
+            return HandleResult::ContinueHandling;
+        }
+
+        let words = body[prefix.len()..].split_whitespace().collect::<Vec<_>>();
+        let answer = match words.split_first() {
+            Some((&"language", rest)) => self.handle_language_command(&message.room, rest),
+            _ => return HandleResult::ContinueHandling,
+        };
+
+        bot.send_message(&answer, &message.room, MessageType::TextMessage);
+        HandleResult::StopHandling
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sources of all modules with texts to translate
+    const SOURCES: [(&str, &str); 16] = [
+        ("accounts.rs", include_str!("accounts.rs")),
+        ("actions.rs", include_str!("actions.rs")),
+        ("admin.rs", include_str!("admin.rs")),
+        ("build_res.rs", include_str!("build_res.rs")),
+        ("commands.rs", include_str!("commands.rs")),
+        ("common.rs", include_str!("common.rs")),
+        ("help.rs", include_str!("help.rs")),
+        ("leave.rs", include_str!("leave.rs")),
+        ("listing.rs", include_str!("listing.rs")),
+        ("openqa.rs", include_str!("openqa.rs")),
+        ("outbox.rs", include_str!("outbox.rs")),
+        ("settings.rs", include_str!("settings.rs")),
+        ("submitrequests.rs", include_str!("submitrequests.rs")),
+        ("templates.rs", include_str!("templates.rs")),
+        ("transfer.rs", include_str!("transfer.rs")),
+        ("main.rs", include_str!("main.rs")),
+    ];
+
+    /// The string literals passed as text to tr() and trf() in source
+    fn keys_of(source: &str) -> Vec<String> {
+        let mut keys = Vec::new();
+        for call in ["tr(", "trf("].iter() {
+            for (start, _) in source.match_indices(call) {
+                let before = source[..start].chars().last().unwrap_or(' ');
+                if before.is_alphanumeric() || before == '_' || before == '.' {
+                    continue;
+                }
+                // The language comes first
+                let rest = &source[start + call.len()..];
+                let rest = match rest.find(',') {
+                    Some(x) => rest[x + 1..].trim_start(),
+                    None => continue,
+                };
+                let mut chars = rest.chars();
+                if chars.next() != Some('"') {
+                    continue;
+                }
+                let mut key = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.next() {
+                            Some('n') => key.push('\n'),
+                            Some(x) => key.push(x),
+                            None => break,
+                        },
+                        x => key.push(x),
+                    }
+                }
+                keys.push(key);
+            }
+        }
+        keys
+    }
+
+    /// Number of {} and {N} placeholders
+    fn placeholders(text: &str) -> usize {
+        text.split('{')
+            .skip(1)
+            .filter(|x| match x.find('}') {
+                Some(end) => x[..end].chars().all(|c| c.is_ascii_digit()),
+                None => false,
+            })
+            .count()
+    }
+
+    #[test]
+    fn all_texts_are_translated() {
+        let mut missing = Vec::new();
+        for (file, source) in SOURCES.iter() {
+            for key in keys_of(source) {
+                if !CATALOG.iter().any(|entry| entry.0 == key) {
+                    missing.push(format!("{}: {}", file, key));
+                }
+            }
+        }
+        assert!(
+            missing.is_empty(),
+            "Not in CATALOG:\n{}",
+            missing.join("\n")
+        );
+    }
+
+    #[test]
+    fn translations_have_all_placeholders() {
+        for (en, de, cs) in CATALOG {
+            assert_eq!(placeholders(en), placeholders(de), "{} -> {}", en, de);
+            assert_eq!(placeholders(en), placeholders(cs), "{} -> {}", en, cs);
+        }
+    }
+
+    #[test]
+    fn catalog_has_no_duplicates() {
+        for (i, entry) in CATALOG.iter().enumerate() {
+            assert!(
+                !CATALOG[..i].iter().any(|x| x.0 == entry.0),
+                "Twice in CATALOG: {}",
+                entry.0
+            );
+        }
+    }
+
+    #[test]
+    fn placeholders_are_filled() {
+        assert_eq!(trf("en", "Page {} of {}.", &[&1, &3]), "Page 1 of 3.");
+        assert_eq!(
+            trf(
+                "cs",
+                "Will mention {} on failures of {}",
+                &[&"@a:b", &"sr#1"]
+            ),
+            "Při selhání sr#1 zmíním @a:b"
+        );
+        assert_eq!(
+            trf("de", "{not a placeholder} {}", &[&1]),
+            "{not a placeholder} 1"
+        );
+        assert_eq!(tr("de", "not in the catalog"), "not in the catalog");
+    }
+}
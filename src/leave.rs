@@ -1,30 +1,36 @@
 use crate::common::prepend_prefix;
-use matrix_bot_api::handlers::HandleResult::{ContinueHandling, StopHandling};
-use matrix_bot_api::handlers::{HandleResult, StatelessHandler};
-use matrix_bot_api::{ActiveBot, MatrixBot, Message, MessageType};
+use crate::i18n::tr;
+use crate::settings::Settings;
+use matrix_bot_api::handlers::{extract_command, HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
 
-pub fn shutdown(bot: &ActiveBot, message: &Message, _cmd: &str) -> HandleResult {
-    bot.send_message("Bye!", &message.room, MessageType::RoomNotice);
-    bot.shutdown();
-    ContinueHandling
+/// 'leave' and 'shutdown'
+pub struct LeaveHandler {
+    pub prefix: Option<String>,
+    /// For the language of the room
+    pub settings: Settings,
 }
 
-pub fn leave(bot: &ActiveBot, message: &Message, _cmd: &str) -> HandleResult {
-    bot.send_message("Bye!", &message.room, MessageType::RoomNotice);
-    bot.leave_room(&message.room);
-    StopHandling
-}
+impl MessageHandler for LeaveHandler {
+    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
+        let command = match extract_command(&message.body, self.prefix.as_deref().unwrap_or("")) {
+            Some(x) => x,
+            None => return HandleResult::ContinueHandling,
+        };
+        if command != "leave" && command != "shutdown" {
+            return HandleResult::ContinueHandling;
+        }
 
-pub fn register_handler(bot: &mut MatrixBot, prefix: Option<&str>) {
-    let mut handler = StatelessHandler::new();
-    match prefix {
-        Some(x) => handler.set_cmd_prefix(x),
-        None => handler.set_cmd_prefix(""),
+        let lang = self.settings.language(&message.room);
+        bot.send_message(&tr(&lang, "Bye!"), &message.room, MessageType::RoomNotice);
+        if command == "shutdown" {
+            bot.shutdown();
+            HandleResult::ContinueHandling
+        } else {
+            bot.leave_room(&message.room);
+            HandleResult::StopHandling
+        }
     }
-
-    handler.register_handle("leave", leave);
-    handler.register_handle("shutdown", shutdown);
-    bot.add_handler(handler);
 }
 
 pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
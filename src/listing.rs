@@ -0,0 +1,175 @@
+use crate::commands::{Command, CommandError, LIST_TYPES};
+use crate::common::html_escape;
+use crate::i18n::{tr, trf};
+
+/// Subscriptions per page of 'list'
+const PAGE_SIZE: usize = 20;
+
+/// A subscription, as shown by 'list'
+#[derive(Debug, Clone)]
+pub struct ListEntry {
+    pub backend: String,
+    /// One of LIST_TYPES
+    pub kind: &'static str,
+    pub name: String,
+    pub url: String,
+    /// Like who it is for, whom to mention or until when it is snoozed
+    pub options: Vec<String>,
+}
+
+/// 'list [all|packages|requests|tests] [FILTER] [page N]'
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListQuery {
+    pub kind: Option<String>,
+    /// Only subscriptions containing this text
+    pub filter: Option<String>,
+    pub page: usize,
+}
+
+impl ListQuery {
+    pub fn parse(command: &Command, lang: &str) -> Result<Self, CommandError> {
+        let usage = tr(
+            lang,
+            "Usage: list [all|packages|requests|tests] [FILTER] [page N]",
+        );
+        if let Some(url) = command.urls.first() {
+            return Err(CommandError::at(&command.line, url, usage));
+        }
+
+        let mut query = ListQuery {
+            kind: None,
+            filter: None,
+            page: 1,
+        };
+        let mut args = command.args.iter().peekable();
+        if let Some(arg) = args.peek() {
+            if arg.text == "all" {
+                args.next();
+            } else if LIST_TYPES.contains(&arg.text.as_str()) {
+                query.kind = Some(arg.text.clone());
+                args.next();
+            }
+        }
+        while let Some(arg) = args.next() {
+            if arg.text == "page" {
+                match args.next().and_then(|x| x.text.parse::<usize>().ok()) {
+                    Some(page) if page > 0 => query.page = page,
+                    _ => return Err(CommandError::at(&command.line, arg, usage)),
+                }
+            } else if query.filter.is_none() {
+                query.filter = Some(arg.text.to_lowercase());
+            } else {
+                return Err(CommandError::at(&command.line, arg, usage));
+            }
+        }
+        Ok(query)
+    }
+
+    fn matches(&self, entry: &ListEntry) -> bool {
+        if let Some(kind) = &self.kind {
+            if kind != entry.kind {
+                return false;
+            }
+        }
+        match &self.filter {
+            Some(filter) => [&entry.name, &entry.url, &entry.backend]
+                .iter()
+                .copied()
+                .chain(entry.options.iter())
+                .any(|x| x.to_lowercase().contains(filter)),
+            None => true,
+        }
+    }
+
+    /// The command showing the given page
+    fn command_for_page(&self, prefix: &str, page: usize) -> String {
+        let mut words = vec![format!("{}list", prefix)];
+        words.extend(self.kind.clone());
+        words.extend(self.filter.as_ref().map(|x| match x.contains(' ') {
+            true => format!("\"{}\"", x),
+            false => x.clone(),
+        }));
+        words.push(format!("page {}", page));
+        words.join(" ")
+    }
+}
+
+/// Plain and HTML reply to 'list', grouped by backend and type. Entries are expected in
+/// the order of the groups, but are sorted within them.
+pub fn render(
+    entries: Vec<ListEntry>,
+    query: &ListQuery,
+    prefix: &str,
+    lang: &str,
+) -> (String, String) {
+    let mut entries = entries
+        .into_iter()
+        .filter(|x| query.matches(x))
+        .collect::<Vec<_>>();
+    if entries.is_empty() {
+        let answer = tr(lang, "No subscriptions found");
+        return (answer.clone(), html_escape(&answer));
+    }
+
+    let mut groups: Vec<(String, &'static str)> = Vec::new();
+    for entry in &entries {
+        if !groups.contains(&(entry.backend.clone(), entry.kind)) {
+            groups.push((entry.backend.clone(), entry.kind));
+        }
+    }
+    entries.sort_by_key(|x| {
+        let group = groups
+            .iter()
+            .position(|g| g.0 == x.backend && g.1 == x.kind);
+        (group, x.name.clone())
+    });
+
+    let pages = entries.len().div_ceil(PAGE_SIZE);
+    let page = query.page.min(pages);
+    let shown = &entries[(page - 1) * PAGE_SIZE..(page * PAGE_SIZE).min(entries.len())];
+
+    let headline = trf(lang, "Subscriptions ({} in total):", &[&entries.len()]);
+    let mut plain = headline.clone();
+    let mut html = format!("<b>{}</b>", html_escape(&headline));
+    let mut current = None;
+    for entry in shown {
+        if current != Some((&entry.backend, entry.kind)) {
+            if current.is_some() {
+                html += "</ul>";
+            }
+            current = Some((&entry.backend, entry.kind));
+            let group = format!("{}, {}", entry.backend, tr(lang, entry.kind));
+            plain += &format!("\n{}:", group);
+            html += &format!("<br>{}:<ul>", html_escape(&group));
+        }
+
+        let options = if entry.options.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", entry.options.join("; "))
+        };
+        plain += &format!("\n  {}{}", entry.name, options);
+        html += &format!(
+            "<li><a href=\"{}\">{}</a>{}</li>",
+            html_escape(&entry.url),
+            html_escape(&entry.name),
+            html_escape(&options)
+        );
+    }
+    html += "</ul>";
+
+    if pages > 1 {
+        let footer = if page < pages {
+            trf(
+                lang,
+                "Page {} of {}, '{}' shows more.",
+                &[&page, &pages, &query.command_for_page(prefix, page + 1)],
+            )
+        } else {
+            trf(lang, "Page {} of {}.", &[&page, &pages])
+        };
+        plain += &format!("\n{}", footer);
+        html += &html_escape(&footer);
+    }
+    (plain, html)
+}
@@ -1,26 +1,64 @@
+mod accounts;
+mod actions;
+mod admin;
 mod build_res;
+mod commands;
 mod common;
+mod config_check;
+mod direct;
 mod help;
+mod i18n;
 mod leave;
+mod listing;
+mod matrix_api;
+#[cfg(test)]
+mod mock_server;
+mod obs_api;
 mod openqa;
+mod outbox;
+mod reference;
+mod settings;
 mod submitrequests;
+mod templates;
+mod transfer;
+mod transport;
 
+use accounts::{AccountHandler, Accounts, ObsAccount};
+use actions::ActionHandler;
+use admin::{AdminHandler, Stats};
 use anyhow::{anyhow, Result};
-use common::ConnectionDetails;
-use config;
+use chrono::Offset;
+use commands::CommandRouter;
+use common::{Backend, ConnectionDetails, Shared};
+use direct::DirectRooms;
 use help::HelpHandler;
+use i18n::LanguageHandler;
+use leave::LeaveHandler;
+use matrix_api::MatrixApi;
 use matrix_bot_api::MatrixBot;
+use obs_api::{ObsApi, ObsClient, ObsCredentials};
+use outbox::{Outbox, RateLimit};
+use settings::Settings;
+use std::collections::{HashMap, HashSet};
 use std::env::args;
-use xdg;
+use std::sync::Arc;
+use std::sync::Mutex;
+use templates::Templates;
+use transfer::TransferHandler;
+use transport::{BotTransport, Transport};
 
 use lapin::{Connection, ConnectionProperties};
 
 const SUPPORTED_BACKENDS: [&str; 2] = ["opensuse.org", "suse.de"];
+const DEFAULT_FLAKY_WINDOW: usize = 10;
+const DEFAULT_RATE_LIMIT_BURST: f64 = 10.0;
+const DEFAULT_RATE_LIMIT_PER_MINUTE: f64 = 6.0;
 
 const SUSE_CONNECTION: ConnectionDetails = ConnectionDetails {
     domain: "suse.de",
     login: "suse:suse",
     buildprefix: "build",
+    apiprefix: "api",
     rabbitprefix: "rabbit",
     rabbitscope: "suse",
 };
@@ -29,14 +67,18 @@ const OPENSUSE_CONNECTION: ConnectionDetails = ConnectionDetails {
     domain: "opensuse.org",
     login: "opensuse:opensuse",
     buildprefix: "build",
+    apiprefix: "api",
     rabbitprefix: "rabbit",
     rabbitscope: "opensuse",
 };
 
 fn main() -> Result<()> {
+    // With --check-config, only the config file gets checked
+    let check_only = args().any(|x| x == "--check-config");
+
     // ================== Search for config file  ==================
     // If we have a commandline argument, use that. If not, search XDG-paths
-    let config_path = match args().nth(1) {
+    let config_path = match args().skip(1).find(|x| x != "--check-config") {
         Some(x) => std::path::PathBuf::from(x),
         None => {
             let dirs = xdg::BaseDirectories::with_prefix("obs_chat_bot")?;
@@ -52,7 +94,22 @@ fn main() -> Result<()> {
 
     // ================== Loading credentials ==================
     let mut settings = config::Config::default();
-    settings.merge(config::File::from(config_path))?;
+    let problems = match settings.merge(config::File::from(config_path.clone())) {
+        Ok(_) => config_check::check(&config_path, &settings),
+        // Syntax errors come with their line already
+        Err(x) => vec![config_check::Problem {
+            line: None,
+            message: x.to_string(),
+        }],
+    };
+    if !problems.is_empty() {
+        config_check::report(&config_path, &problems);
+        std::process::exit(1);
+    }
+    if check_only {
+        println!("{}: OK", config_path.display());
+        return Ok(());
+    }
 
     let user = settings.get_str("user")?;
     let password = settings.get_str("password")?;
@@ -62,33 +119,181 @@ fn main() -> Result<()> {
 
     let prefix = settings.get_str("prefix").ok();
 
+    // Matrix users allowed to use the 'admin' commands
+    let admins = settings.get::<Vec<String>>("admins").unwrap_or_default();
+
     let default_subs = settings.get::<Vec<(String, String)>>("default_subs").ok();
+
+    let obs_credentials = settings
+        .get::<Vec<ObsCredentials>>("obs_credentials")
+        .unwrap_or_default();
+
+    let obs_accounts = settings
+        .get::<Vec<ObsAccount>>("obs_accounts")
+        .unwrap_or_default();
+
+    let flaky_window = settings
+        .get::<usize>("openqa_flaky_window")
+        .unwrap_or(DEFAULT_FLAKY_WINDOW);
+
+    let rate_limit = RateLimit {
+        burst: settings
+            .get::<f64>("rate_limit_burst")
+            .unwrap_or(DEFAULT_RATE_LIMIT_BURST),
+        per_minute: settings
+            .get::<f64>("rate_limit_per_minute")
+            .unwrap_or(DEFAULT_RATE_LIMIT_PER_MINUTE),
+    };
+
+    // Timezone of the digest schedules, defaults to the current offset of the machine
+    let timezone = match settings.get_str("timezone") {
+        Ok(x) => settings::Timezone::parse(&x)
+            .ok_or_else(|| anyhow!("timezone {} is not like +02:00 or Europe/Berlin", x))?,
+        Err(_) => settings::Timezone::Fixed(chrono::Local::now().offset().fix()),
+    };
+
+    // Overrides of the default message templates
+    let template_dir = settings.get_str("template_dir").ok();
+    let configured_templates = settings
+        .get::<HashMap<String, String>>("templates")
+        .unwrap_or_default();
     // =========================================================
 
-    // Check if backends are supported
-    for backend in &backends {
-        if !SUPPORTED_BACKENDS.contains(&backend.as_str()) {
-            panic!("Backend {} is not supported!", backend);
-        }
-    }
+    // Per-room settings, like the language
+    let room_settings = Settings::new();
 
     // Defining the first handler for general help output
     let help_handler = HelpHandler {
         prefix: prefix.clone(),
+        settings: room_settings.clone(),
     };
 
     // Creating the bot
     let mut bot = MatrixBot::new(help_handler);
 
     // Add another handler to handle leave and shutdown
-    leave::register_handler(&mut bot, prefix.as_deref());
+    bot.add_handler(LeaveHandler {
+        prefix: prefix.clone(),
+        settings: room_settings.clone(),
+    });
 
-    // Establish connections to all chosen backends
+    bot.add_handler(LanguageHandler {
+        settings: room_settings.clone(),
+        prefix: prefix.clone(),
+    });
+
+    // OBS API clients for all chosen backends
+    let mut api_backends = Vec::new();
     for details in [OPENSUSE_CONNECTION, SUSE_CONNECTION].iter() {
         if !backends.contains(&details.domain.to_string()) {
             continue;
         }
 
+        let credentials = obs_credentials
+            .iter()
+            .find(|x| x.backend == details.domain)
+            .cloned();
+        let obs_api: Arc<dyn ObsApi> = Arc::new(ObsClient::new(
+            &format!("https://{}.{}", details.apiprefix, details.domain),
+            credentials,
+        )?);
+        api_backends.push(Backend {
+            details: *details,
+            obs_api,
+        });
+    }
+
+    // Second login, for what matrix_bot_api cannot do (like opening direct chats)
+    let matrix_api = match MatrixApi::login(&homeserver_url, &user, &password) {
+        Ok(x) => Some(Arc::new(x)),
+        Err(x) => {
+            println!(
+                "Matrix API login failed, personal subscriptions will not work: {:?}",
+                x
+            );
+            None
+        }
+    };
+    let direct = DirectRooms::new(matrix_api.clone());
+
+    // Only the Matrix API tells us about rate limits of the homeserver
+    let transport: Arc<dyn Transport> = match matrix_api.clone() {
+        Some(x) => x,
+        None => Arc::new(BotTransport(Mutex::new(bot.get_activebot_clone()))),
+    };
+
+    // Actions go before the subscribers, as their commands contain URLs as well
+    let accounts = Accounts::new(obs_accounts);
+    let account_handler = AccountHandler::new(
+        prefix.clone(),
+        room_settings.clone(),
+        api_backends.clone(),
+        accounts.clone(),
+    );
+    bot.add_handler(account_handler);
+    let action_handler = ActionHandler::new(
+        prefix.clone(),
+        room_settings.clone(),
+        api_backends.clone(),
+        accounts.clone(),
+    );
+    bot.add_handler(action_handler);
+
+    // The outbox all notifications go through
+    let outbox = Outbox::new(
+        transport,
+        room_settings.clone(),
+        timezone,
+        prefix.clone(),
+        rate_limit,
+    );
+    outbox.start_scheduler();
+    bot.add_handler(outbox.clone());
+
+    let templates = Templates::new(
+        room_settings.clone(),
+        prefix.clone(),
+        template_dir.as_deref().map(std::path::Path::new),
+        &configured_templates,
+    )?;
+    bot.add_handler(templates.clone());
+
+    // Parses the subscription commands for the handlers of all backends
+    let router = CommandRouter::new(room_settings.clone(), prefix.clone());
+    // Before the router, as imported content contains URLs
+    bot.add_handler(TransferHandler {
+        prefix: prefix.clone(),
+        settings: room_settings.clone(),
+        router: router.clone(),
+        matrix_api: matrix_api.clone(),
+    });
+    bot.add_handler(router.clone());
+
+    let stats = Stats::new();
+    bot.add_handler(AdminHandler {
+        prefix: prefix.clone(),
+        admins,
+        router: router.clone(),
+        stats: stats.clone(),
+        settings: room_settings.clone(),
+        matrix_api: matrix_api.clone(),
+    });
+
+    let shared = Shared {
+        accounts,
+        direct,
+        outbox,
+        templates,
+        settings: room_settings,
+        router,
+        stats,
+    };
+
+    // Establish connections to all chosen backends
+    for backend in &api_backends {
+        let details = &backend.details;
+        let obs_api = backend.obs_api.clone();
+
         let addr = format!(
             "amqps://{login}@{prefix}.{domain}/%2f", // don't know why /%2f is needed, but it fails without it
             login = details.login,
@@ -100,31 +305,18 @@ fn main() -> Result<()> {
         println!("CONNECTED TO {}", &addr);
 
         // Subscribe to build_success/build_fails
-        build_res::init(
-            &mut bot,
-            details,
-            conn.clone(),
-            prefix.clone(),
-            &default_subs,
-        )?;
+        build_res::init(details, conn.clone(), obs_api.clone(), &shared)?;
 
         // Subscribe to request-changes
-        submitrequests::init(
-            &mut bot,
-            details,
-            conn.clone(),
-            prefix.clone(),
-            &default_subs,
-        )?;
+        submitrequests::init(details, conn.clone(), obs_api.clone(), &shared)?;
 
         // Subscribe to openQA-changes (module will modify buildprefix to openqa)
-        openqa::init(
-            &mut bot,
-            details,
-            conn.clone(),
-            prefix.clone(),
-            &default_subs,
-        )?;
+        openqa::init(details, conn.clone(), &shared, flaky_window)?;
+    }
+
+    if let Some(default_subs) = default_subs {
+        let default_subs = resolve_default_rooms(matrix_api.as_deref(), default_subs);
+        shared.router.subscribe_to_defaults(&default_subs);
     }
 
     // Blocking call until shutdown is issued
@@ -132,3 +324,75 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Room ID of a room of default_subs (ID or alias), joining it if the bot is not in it yet
+fn resolve_room(api: &MatrixApi, room: &str, joined: &mut HashSet<String>) -> Option<String> {
+    let id = if room.starts_with('#') {
+        match api.resolve_alias(room) {
+            Ok(x) => x,
+            Err(x) => {
+                println!(
+                    "ERROR! Room alias {} of default_subs could not be resolved, skipping its subscriptions: {:?}",
+                    room, x
+                );
+                return None;
+            }
+        }
+    } else {
+        room.to_string()
+    };
+
+    if !joined.contains(&id) {
+        // Joining by alias finds the servers of the room, joining by ID might not
+        match api.join(room) {
+            Ok(_) => {
+                println!("Joined {} for default_subs", room);
+                joined.insert(id.clone());
+            }
+            Err(x) => println!("ERROR! Could not join {} for default_subs: {:?}", room, x),
+        }
+    }
+    Some(id)
+}
+
+/// default_subs with room aliases (#room:server) replaced by room IDs. Entries of aliases
+/// that cannot be resolved are dropped.
+fn resolve_default_rooms(
+    api: Option<&MatrixApi>,
+    default_subs: Vec<(String, String)>,
+) -> Vec<(String, String)> {
+    let api = match api {
+        Some(x) => x,
+        None => {
+            for (room, entry) in default_subs.iter().filter(|x| x.0.starts_with('#')) {
+                println!(
+                    "ERROR! Default subscription {} of {} skipped, room aliases need the Matrix API login",
+                    entry, room
+                );
+            }
+            return default_subs
+                .into_iter()
+                .filter(|x| !x.0.starts_with('#'))
+                .collect();
+        }
+    };
+
+    let mut joined = match api.joined_rooms() {
+        Ok(x) => x.into_iter().collect(),
+        Err(x) => {
+            println!("ERROR! Could not get joined rooms: {:?}", x);
+            HashSet::new()
+        }
+    };
+    let mut resolved = HashMap::new();
+    let mut res = Vec::new();
+    for (room, entry) in default_subs {
+        let id = resolved
+            .entry(room.clone())
+            .or_insert_with(|| resolve_room(api, &room, &mut joined));
+        if let Some(id) = id {
+            res.push((id.clone(), entry));
+        }
+    }
+    res
+}
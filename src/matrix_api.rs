@@ -0,0 +1,459 @@
+use crate::common::{data_file, load_json, save_json};
+use anyhow::{anyhow, Result};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Method, RequestBuilder, Response, Url};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+const CLIENT_API: [&str; 3] = ["_matrix", "client", "v3"];
+/// Authenticated media, since Matrix 1.11
+const MEDIA_API: [&str; 4] = ["_matrix", "client", "v1", "media"];
+/// Uploads, and downloads on older homeservers
+const LEGACY_MEDIA_API: [&str; 3] = ["_matrix", "media", "v3"];
+
+/// The parts of the Matrix client-server API that matrix_bot_api does not offer.
+/// Uses its own login (and therefore device) next to the one of the bot, kept across restarts.
+pub struct MatrixApi {
+    homeserver_url: Url,
+    access_token: String,
+    user_id: String,
+    client: reqwest::Client,
+    /// For unique transaction IDs of sent events
+    txn_counter: AtomicU64,
+}
+
+/// Error response of the homeserver
+#[derive(Deserialize, Debug, Default)]
+pub struct MatrixError {
+    #[serde(skip)]
+    pub status: u16,
+    #[serde(default)]
+    pub errcode: String,
+    #[serde(default)]
+    pub error: String,
+    pub retry_after_ms: Option<u64>,
+}
+
+impl std::fmt::Display for MatrixError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}: {}", self.status, self.errcode, self.error)
+    }
+}
+
+impl std::error::Error for MatrixError {}
+
+#[derive(Deserialize)]
+struct LoginResponse {
+    access_token: String,
+    user_id: String,
+    #[serde(default)]
+    device_id: String,
+}
+
+#[derive(Deserialize)]
+struct WhoamiResponse {
+    user_id: String,
+}
+
+/// A login of the API, stored so restarts don't create a new device every time
+#[derive(Serialize, Deserialize)]
+struct Session {
+    homeserver_url: String,
+    user: String,
+    access_token: String,
+    device_id: String,
+}
+
+#[derive(Deserialize)]
+struct RoomIdResponse {
+    room_id: String,
+}
+
+#[derive(Deserialize)]
+struct UploadResponse {
+    content_uri: String,
+}
+
+#[derive(Deserialize)]
+struct MessagesResponse {
+    chunk: Vec<RoomEvent>,
+}
+
+#[derive(Deserialize)]
+struct RoomEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    sender: String,
+    #[serde(default)]
+    content: Value,
+}
+
+#[derive(Deserialize)]
+struct JoinedRoomsResponse {
+    joined_rooms: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct JoinedMembersResponse {
+    joined: HashMap<String, Value>,
+}
+
+impl MatrixApi {
+    /// Reuses the login of an earlier start, if it is still valid
+    pub fn login(homeserver_url: &str, user: &str, password: &str) -> Result<Self> {
+        let path = data_file("matrix_session.json");
+        Self::login_with_session(homeserver_url, user, password, &path)
+    }
+
+    /// Like login, with the session stored in path
+    pub fn login_with_session(
+        homeserver_url: &str,
+        user: &str,
+        password: &str,
+        path: &Option<PathBuf>,
+    ) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(30))
+            .build()?;
+        let mut api = MatrixApi {
+            homeserver_url: Url::parse(homeserver_url)?,
+            access_token: String::new(),
+            user_id: String::new(),
+            client,
+            txn_counter: AtomicU64::new(0),
+        };
+
+        let session: Option<Session> = load_json(path);
+        let mut device_id = None;
+        if let Some(session) =
+            session.filter(|x| x.homeserver_url == homeserver_url && x.user == user)
+        {
+            api.access_token = session.access_token;
+            match api.call::<WhoamiResponse>(Method::GET, &["account", "whoami"], None) {
+                Ok(response) => {
+                    println!("Reusing the Matrix API device {}", session.device_id);
+                    api.user_id = response.user_id;
+                    return Ok(api);
+                }
+                Err(x) => {
+                    println!("Stored Matrix API login is not valid anymore: {:?}", x);
+                    api.access_token = String::new();
+                    device_id = Some(session.device_id);
+                }
+            }
+        }
+
+        let mut body = json!({
+            "type": "m.login.password",
+            "identifier": { "type": "m.id.user", "user": user },
+            "password": password,
+            "initial_device_display_name": "obs_chat_bot API",
+        });
+        // Logging in again with the old device replaces its token instead of adding a device
+        if let Some(device_id) = device_id.filter(|x| !x.is_empty()) {
+            body["device_id"] = json!(device_id);
+        }
+        let response: LoginResponse = api.call(Method::POST, &["login"], Some(body))?;
+        api.access_token = response.access_token;
+        api.user_id = response.user_id;
+        save_json(
+            path,
+            &Session {
+                homeserver_url: homeserver_url.to_string(),
+                user: user.to_string(),
+                access_token: api.access_token.clone(),
+                device_id: response.device_id,
+            },
+        );
+        Ok(api)
+    }
+
+    /// Full user ID of the bot, e.g. @bot:example.org
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+
+    /// Segments get percent-encoded, so room IDs and aliases can be passed verbatim
+    fn request(&self, method: Method, api: &[&str], segments: &[&str]) -> Result<RequestBuilder> {
+        let mut url = self.homeserver_url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("Homeserver URL {} cannot be a base", self.homeserver_url))?
+            .pop_if_empty()
+            .extend(api)
+            .extend(segments);
+
+        let mut builder = self.client.request(method, url);
+        if !self.access_token.is_empty() {
+            builder = builder.bearer_auth(&self.access_token);
+        }
+        Ok(builder)
+    }
+
+    /// Turns error responses into a MatrixError
+    fn send(builder: RequestBuilder, segments: &[&str]) -> Result<Response> {
+        let mut response = builder.send()?;
+        if !response.status().is_success() {
+            let mut error = response.json::<MatrixError>().unwrap_or_default();
+            error.status = response.status().as_u16();
+            return Err(anyhow::Error::from(error)
+                .context(format!("Matrix API call {} failed", segments.join("/"))));
+        }
+        Ok(response)
+    }
+
+    fn call<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        segments: &[&str],
+        body: Option<Value>,
+    ) -> Result<T> {
+        let mut builder = self.request(method, &CLIENT_API, segments)?;
+        if let Some(body) = body {
+            builder = builder.json(&body);
+        }
+        Ok(Self::send(builder, segments)?.json()?)
+    }
+
+    fn txn_id(&self) -> String {
+        format!(
+            "obsbot-{}-{}",
+            chrono::Utc::now().timestamp_millis(),
+            self.txn_counter.fetch_add(1, Ordering::SeqCst)
+        )
+    }
+
+    /// Creates a new 1:1 room and invites the user. Returns the room ID.
+    pub fn create_direct_room(&self, user: &str) -> Result<String> {
+        let response: RoomIdResponse = self.call(
+            Method::POST,
+            &["createRoom"],
+            Some(json!({
+                "is_direct": true,
+                "preset": "trusted_private_chat",
+                "invite": [user],
+            })),
+        )?;
+        Ok(response.room_id)
+    }
+
+    pub fn send_message(&self, room: &str, plain: &str, html: &str) -> Result<()> {
+        let txn_id = self.txn_id();
+        let _: Value = self.call(
+            Method::PUT,
+            &["rooms", room, "send", "m.room.message", &txn_id],
+            Some(json!({
+                "msgtype": "m.text",
+                "body": plain,
+                "format": "org.matrix.custom.html",
+                "formatted_body": html,
+            })),
+        )?;
+        Ok(())
+    }
+
+    /// Room ID of an alias like #room:example.org
+    pub fn resolve_alias(&self, alias: &str) -> Result<String> {
+        let response: RoomIdResponse =
+            self.call(Method::GET, &["directory", "room", alias], None)?;
+        Ok(response.room_id)
+    }
+
+    /// Joins a room by ID or alias, returns its ID
+    pub fn join(&self, room: &str) -> Result<String> {
+        let response: RoomIdResponse = self.call(Method::POST, &["join", room], Some(json!({})))?;
+        Ok(response.room_id)
+    }
+
+    pub fn joined_rooms(&self) -> Result<Vec<String>> {
+        let response: JoinedRoomsResponse = self.call(Method::GET, &["joined_rooms"], None)?;
+        Ok(response.joined_rooms)
+    }
+
+    pub fn joined_members(&self, room: &str) -> Result<Vec<String>> {
+        let response: JoinedMembersResponse =
+            self.call(Method::GET, &["rooms", room, "joined_members"], None)?;
+        Ok(response.joined.into_keys().collect())
+    }
+
+    /// Uploads a file to the media repository, returns its mxc:// URI
+    pub fn upload(&self, name: &str, content_type: &str, data: Vec<u8>) -> Result<String> {
+        let builder = self
+            .request(Method::POST, &LEGACY_MEDIA_API, &["upload"])?
+            .query(&[("filename", name)])
+            .header(CONTENT_TYPE, content_type)
+            .body(data);
+        let response: UploadResponse = Self::send(builder, &["upload"])?.json()?;
+        Ok(response.content_uri)
+    }
+
+    pub fn send_file(
+        &self,
+        room: &str,
+        name: &str,
+        mxc: &str,
+        content_type: &str,
+        size: usize,
+    ) -> Result<()> {
+        let txn_id = self.txn_id();
+        let _: Value = self.call(
+            Method::PUT,
+            &["rooms", room, "send", "m.room.message", &txn_id],
+            Some(json!({
+                "msgtype": "m.file",
+                "body": name,
+                "filename": name,
+                "url": mxc,
+                "info": { "mimetype": content_type, "size": size },
+            })),
+        )?;
+        Ok(())
+    }
+
+    /// Content of an mxc:// URI, refusing files larger than max_size bytes
+    pub fn download(&self, mxc: &str, max_size: u64) -> Result<Vec<u8>> {
+        let (server, media_id) = mxc
+            .strip_prefix("mxc://")
+            .and_then(|x| x.split_once('/'))
+            .ok_or_else(|| anyhow!("{} is not an mxc:// URI", mxc))?;
+        let segments = ["download", server, media_id];
+
+        let builder = self.request(Method::GET, &MEDIA_API, &segments)?;
+        let response = match Self::send(builder, &segments) {
+            Ok(x) => x,
+            Err(_) => {
+                let builder = self.request(Method::GET, &LEGACY_MEDIA_API, &segments)?;
+                Self::send(builder, &segments)?
+            }
+        };
+        if response.content_length().is_some_and(|x| x > max_size) {
+            return Err(anyhow!("{} is larger than {} bytes", mxc, max_size));
+        }
+        // The length may be missing or wrong, only read one byte too many
+        let mut data = Vec::new();
+        response.take(max_size + 1).read_to_end(&mut data)?;
+        if data.len() as u64 > max_size {
+            return Err(anyhow!("{} is larger than {} bytes", mxc, max_size));
+        }
+        Ok(data)
+    }
+
+    /// (name, mxc:// URI) of the newest file sender posted among the recent messages of room
+    pub fn latest_file(&self, room: &str, sender: &str) -> Result<Option<(String, String)>> {
+        let segments = ["rooms", room, "messages"];
+        let builder = self
+            .request(Method::GET, &CLIENT_API, &segments)?
+            .query(&[("dir", "b"), ("limit", "50")]);
+        let response: MessagesResponse = Self::send(builder, &segments)?.json()?;
+
+        Ok(response
+            .chunk
+            .into_iter()
+            .filter(|x| x.kind == "m.room.message" && x.sender == sender)
+            .filter(|x| x.content["msgtype"] == "m.file")
+            .find_map(|x| {
+                let url = x.content["url"].as_str()?.to_string();
+                let name = x.content["body"].as_str().unwrap_or("").to_string();
+                Some((name, url))
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::MockServer;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    fn login(server: &MockServer) -> MatrixApi {
+        MatrixApi::login_with_session(&server.url, "bot", "secret", &None).unwrap()
+    }
+
+    /// A session file only this test uses
+    fn session_path(name: &str) -> Option<PathBuf> {
+        let path =
+            std::env::temp_dir().join(format!("obs_chat_bot-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Some(path)
+    }
+
+    #[test]
+    fn download_is_capped() {
+        let server = MockServer::start(|request| {
+            if request.path.ends_with("/login") {
+                let body = json!({"access_token": "token", "user_id": "@bot:example.org"});
+                (200, body.to_string())
+            } else if request.path.ends_with("/small") {
+                (200, "0123456789".to_string())
+            } else {
+                (200, "x".repeat(100))
+            }
+        });
+        let api = login(&server);
+        assert_eq!(api.user_id(), "@bot:example.org");
+
+        assert_eq!(
+            api.download("mxc://example.org/small", 10).unwrap(),
+            b"0123456789"
+        );
+        assert!(api.download("mxc://example.org/large", 10).is_err());
+        assert!(api.download("https://example.org/small", 10).is_err());
+        assert_eq!(
+            server.paths()[1],
+            "/_matrix/client/v1/media/download/example.org/small"
+        );
+    }
+
+    #[test]
+    fn login_is_reused() {
+        let valid = Arc::new(AtomicBool::new(true));
+        let token_valid = valid.clone();
+        let server = MockServer::start(move |request| {
+            if request.path.ends_with("/login") {
+                let body = json!({
+                    "access_token": "token",
+                    "user_id": "@bot:example.org",
+                    "device_id": "DEVICE",
+                });
+                (200, body.to_string())
+            } else if token_valid.load(Ordering::SeqCst) {
+                (200, json!({"user_id": "@bot:example.org"}).to_string())
+            } else {
+                (401, json!({"errcode": "M_UNKNOWN_TOKEN"}).to_string())
+            }
+        });
+        let path = session_path("login_is_reused");
+        let login = || MatrixApi::login_with_session(&server.url, "bot", "secret", &path);
+
+        login().unwrap();
+        let api = login().unwrap();
+        assert_eq!(api.user_id(), "@bot:example.org");
+        assert_eq!(
+            server.paths(),
+            vec![
+                "/_matrix/client/v3/login",
+                "/_matrix/client/v3/account/whoami"
+            ]
+        );
+        assert!(!server.requests()[0].body.contains("device_id"));
+
+        // An expired token means a new login, on the same device
+        valid.store(false, Ordering::SeqCst);
+        login().unwrap();
+        let requests = server.requests();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[3].path.ends_with("/login"));
+        assert!(requests[3].body.contains(r#""device_id":"DEVICE""#));
+
+        // Another user does not get the session
+        MatrixApi::login_with_session(&server.url, "other", "secret", &path).unwrap();
+        assert!(server.requests()[4].path.ends_with("/login"));
+
+        let _ = std::fs::remove_file(path.unwrap());
+    }
+}
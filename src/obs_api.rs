@@ -0,0 +1,826 @@
+use anyhow::{anyhow, Result};
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+#[cfg(test)]
+use std::collections::HashMap;
+#[cfg(test)]
+use std::sync::Mutex;
+
+/// Login for the OBS API of one backend
+#[derive(Deserialize, Clone)]
+pub struct ObsCredentials {
+    pub backend: String,
+    pub user: String,
+    pub password: String,
+}
+
+// Keeps the password out of logs
+impl std::fmt::Debug for ObsCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ObsCredentials")
+            .field("backend", &self.backend)
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The OBS API needs a login for this, but the backend has no obs_credentials
+#[derive(Debug)]
+pub struct NoCredentials {
+    pub api_url: String,
+    pub path: String,
+}
+
+impl std::fmt::Display for NoCredentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "No OBS credentials configured for {}, cannot do {}",
+            self.api_url, self.path
+        )
+    }
+}
+
+impl std::error::Error for NoCredentials {}
+
+/// Everything the bot wants to know from the OBS API.
+/// Implemented by ObsClient, but can be swapped out for a local fake.
+pub trait ObsApi: Send + Sync {
+    /// Results of all repositories and archs of a package
+    fn build_results(&self, project: &str, package: &str) -> Result<ResultList>;
+    /// Details of a request, including its reviews and history
+    fn request(&self, id: &str) -> Result<Request>;
+    fn package_meta(&self, project: &str, package: &str) -> Result<PackageMeta>;
+    fn project_meta(&self, project: &str) -> Result<ProjectMeta>;
+    /// User ids of the members of a group
+    fn group_members(&self, group: &str) -> Result<Vec<String>>;
+    /// The last (at most) max_size bytes of a build log
+    fn build_log_tail(
+        &self,
+        project: &str,
+        repository: &str,
+        arch: &str,
+        package: &str,
+        max_size: usize,
+    ) -> Result<String>;
+
+    // Actions, always executed with the credentials of the bot
+    fn rebuild(
+        &self,
+        project: &str,
+        package: &str,
+        repository: Option<&str>,
+        arch: Option<&str>,
+    ) -> Result<()>;
+    /// new_state is e.g. accepted, declined or revoked
+    fn change_request_state(&self, id: &str, new_state: &str, comment: &str) -> Result<()>;
+    /// reviewer_kind is one of user, group, project or package
+    fn add_review(
+        &self,
+        id: &str,
+        reviewer_kind: &str,
+        reviewer: &str,
+        comment: &str,
+    ) -> Result<()>;
+}
+
+// ================== Typed responses ==================
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ResultList {
+    #[serde(rename = "result", default)]
+    pub results: Vec<RepositoryResult>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RepositoryResult {
+    #[serde(rename = "@repository")]
+    pub repository: String,
+    #[serde(rename = "@arch")]
+    pub arch: String,
+    #[serde(rename = "status", default)]
+    pub statuses: Vec<PackageStatus>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PackageStatus {
+    #[serde(rename = "@package")]
+    pub package: String,
+    /// succeeded, failed, unresolvable, blocked, scheduled, ...
+    #[serde(rename = "@code")]
+    pub code: String,
+    pub details: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Request {
+    #[serde(rename = "@id")]
+    pub id: String,
+    #[serde(rename = "@creator")]
+    pub creator: Option<String>,
+    #[serde(rename = "action", default)]
+    pub actions: Vec<RequestAction>,
+    pub state: RequestState,
+    #[serde(rename = "review", default)]
+    pub reviews: Vec<Review>,
+    #[serde(rename = "history", default)]
+    pub history: Vec<HistoryEntry>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RequestAction {
+    /// submit, delete, maintenance_incident, add_role, ...
+    #[serde(rename = "@type")]
+    pub kind: String,
+    pub source: Option<ActionTarget>,
+    pub target: Option<ActionTarget>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ActionTarget {
+    #[serde(rename = "@project")]
+    pub project: String,
+    #[serde(rename = "@package")]
+    pub package: Option<String>,
+    #[serde(rename = "@repository")]
+    pub repository: Option<String>,
+}
+
+impl std::fmt::Display for ActionTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.package, &self.repository) {
+            (Some(package), _) => write!(f, "{}/{}", self.project, package),
+            (None, Some(repository)) => write!(f, "{} ({})", self.project, repository),
+            (None, None) => write!(f, "{}", self.project),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct RequestState {
+    #[serde(rename = "@name")]
+    pub name: String,
+    #[serde(rename = "@who")]
+    pub who: Option<String>,
+    #[serde(rename = "@when")]
+    pub when: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Review {
+    #[serde(rename = "@state")]
+    pub state: String,
+    #[serde(rename = "@by_user")]
+    pub by_user: Option<String>,
+    #[serde(rename = "@by_group")]
+    pub by_group: Option<String>,
+    #[serde(rename = "@by_project")]
+    pub by_project: Option<String>,
+    #[serde(rename = "@by_package")]
+    pub by_package: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HistoryEntry {
+    #[serde(rename = "@who")]
+    pub who: Option<String>,
+    #[serde(rename = "@when")]
+    pub when: Option<String>,
+    pub description: Option<String>,
+    pub comment: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PersonRole {
+    #[serde(rename = "@userid")]
+    pub userid: String,
+    #[serde(rename = "@role")]
+    pub role: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GroupRole {
+    #[serde(rename = "@groupid")]
+    pub groupid: String,
+    #[serde(rename = "@role")]
+    pub role: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PackageMeta {
+    #[serde(rename = "person", default)]
+    pub persons: Vec<PersonRole>,
+    #[serde(rename = "group", default)]
+    pub groups: Vec<GroupRole>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ProjectMeta {
+    pub description: Option<String>,
+    #[serde(rename = "person", default)]
+    pub persons: Vec<PersonRole>,
+    #[serde(rename = "group", default)]
+    pub groups: Vec<GroupRole>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct Group {
+    /// The members are wrapped into another 'person'
+    person: Option<GroupMembers>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct GroupMembers {
+    #[serde(rename = "person", default)]
+    members: Vec<GroupMember>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct GroupMember {
+    #[serde(rename = "@userid")]
+    userid: String,
+}
+
+/// Listing of files, like the entry of a build log
+#[derive(Deserialize, Debug, Clone)]
+struct Directory {
+    #[serde(rename = "entry", default)]
+    entries: Vec<DirectoryEntry>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct DirectoryEntry {
+    #[serde(rename = "@size")]
+    size: usize,
+}
+
+// ================== HTTP client ==================
+
+pub struct ObsClient {
+    /// e.g. https://api.opensuse.org
+    api_url: String,
+    credentials: Option<ObsCredentials>,
+    client: reqwest::Client,
+}
+
+impl ObsClient {
+    /// Without credentials, only the anonymous /public routes can be used
+    pub fn new(api_url: &str, credentials: Option<ObsCredentials>) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(std::time::Duration::from_secs(10))
+            .build()?;
+        Ok(ObsClient {
+            api_url: api_url.trim_end_matches('/').to_string(),
+            credentials,
+            client,
+        })
+    }
+
+    /// URL of the API path, each segment percent-encoded
+    fn url(&self, segments: &[&str]) -> Result<Url> {
+        let mut url = Url::parse(&self.api_url)?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("{} cannot have a path", self.api_url))?
+            .pop_if_empty()
+            .extend(segments);
+        Ok(url)
+    }
+
+    fn get_text(&self, segments: &[&str], query: &[(&str, &str)]) -> Result<String> {
+        // Anonymous users have to go through /public (which does not know about requests or groups)
+        let public = !matches!(segments.first(), Some(&"request") | Some(&"group"));
+        let url = match (&self.credentials, public) {
+            (Some(_), _) => self.url(segments)?,
+            (None, true) => self.url(&[&["public"], segments].concat())?,
+            (None, false) => return Err(self.no_credentials(segments).into()),
+        };
+
+        let mut builder = self.client.get(url.clone());
+        if !query.is_empty() {
+            builder = builder.query(query);
+        }
+        if let Some(credentials) = &self.credentials {
+            builder = builder.basic_auth(&credentials.user, Some(&credentials.password));
+        }
+
+        let mut response = builder.send()?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "OBS API returned {} for {}",
+                response.status(),
+                url.path()
+            ));
+        }
+        Ok(response.text()?)
+    }
+
+    fn no_credentials(&self, segments: &[&str]) -> NoCredentials {
+        NoCredentials {
+            api_url: self.api_url.clone(),
+            path: format!("/{}", segments.join("/")),
+        }
+    }
+
+    fn post(&self, segments: &[&str], query: &[(&str, &str)]) -> Result<()> {
+        let url = self.url(segments)?;
+        let credentials = self
+            .credentials
+            .as_ref()
+            .ok_or_else(|| self.no_credentials(segments))?;
+
+        let response = self
+            .client
+            .post(url.clone())
+            .basic_auth(&credentials.user, Some(&credentials.password))
+            .query(query)
+            .send()?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "OBS API returned {} for {}",
+                response.status(),
+                url.path()
+            ));
+        }
+        Ok(())
+    }
+
+    fn get_xml<T: DeserializeOwned>(&self, segments: &[&str], query: &[(&str, &str)]) -> Result<T> {
+        let text = self.get_text(segments, query)?;
+        Ok(quick_xml::de::from_str(&text)?)
+    }
+}
+
+impl ObsApi for ObsClient {
+    fn build_results(&self, project: &str, package: &str) -> Result<ResultList> {
+        self.get_xml(&["build", project, "_result"], &[("package", package)])
+    }
+
+    fn request(&self, id: &str) -> Result<Request> {
+        self.get_xml(&["request", id], &[("withfullhistory", "1")])
+    }
+
+    fn package_meta(&self, project: &str, package: &str) -> Result<PackageMeta> {
+        self.get_xml(&["source", project, package, "_meta"], &[])
+    }
+
+    fn project_meta(&self, project: &str) -> Result<ProjectMeta> {
+        self.get_xml(&["source", project, "_meta"], &[])
+    }
+
+    fn group_members(&self, group: &str) -> Result<Vec<String>> {
+        let group: Group = self.get_xml(&["group", group], &[])?;
+        Ok(group
+            .person
+            .map(|x| x.members.into_iter().map(|x| x.userid).collect())
+            .unwrap_or_default())
+    }
+
+    fn build_log_tail(
+        &self,
+        project: &str,
+        repository: &str,
+        arch: &str,
+        package: &str,
+        max_size: usize,
+    ) -> Result<String> {
+        let log_path = ["build", project, repository, arch, package, "_log"];
+
+        // Get the size of the log first, so we only need to download its end
+        let directory: Directory = self.get_xml(&log_path, &[("view", "entry")])?;
+        let size = directory.entries.first().map(|x| x.size).unwrap_or(0);
+
+        let start = size.saturating_sub(max_size).to_string();
+        self.get_text(&log_path, &[("nostream", "1"), ("start", &start)])
+    }
+
+    fn rebuild(
+        &self,
+        project: &str,
+        package: &str,
+        repository: Option<&str>,
+        arch: Option<&str>,
+    ) -> Result<()> {
+        let mut query = vec![("cmd", "rebuild"), ("package", package)];
+        if let Some(repository) = repository {
+            query.push(("repository", repository));
+        }
+        if let Some(arch) = arch {
+            query.push(("arch", arch));
+        }
+        self.post(&["build", project], &query)
+    }
+
+    fn change_request_state(&self, id: &str, new_state: &str, comment: &str) -> Result<()> {
+        self.post(
+            &["request", id],
+            &[
+                ("cmd", "changestate"),
+                ("newstate", new_state),
+                ("comment", comment),
+            ],
+        )
+    }
+
+    fn add_review(
+        &self,
+        id: &str,
+        reviewer_kind: &str,
+        reviewer: &str,
+        comment: &str,
+    ) -> Result<()> {
+        let by = format!("by_{}", reviewer_kind);
+        self.post(
+            &["request", id],
+            &[("cmd", "addreview"), (&by, reviewer), ("comment", comment)],
+        )
+    }
+}
+
+// ================== Fake for tests ==================
+
+/// Answers from canned responses and records all calls
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeObsApi {
+    pub results: HashMap<(String, String), ResultList>,
+    pub requests: HashMap<String, Request>,
+    pub packages: HashMap<(String, String), PackageMeta>,
+    pub projects: HashMap<String, ProjectMeta>,
+    pub groups: HashMap<String, Vec<String>>,
+    /// Per 'project/repository/arch/package'
+    pub logs: HashMap<String, String>,
+    /// Like 'package_meta devel:foo/bar', oldest first
+    pub calls: Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+impl FakeObsApi {
+    fn call(&self, call: String) {
+        self.calls.lock().unwrap().push(call);
+    }
+
+    /// Number of recorded calls starting with prefix
+    pub fn count(&self, prefix: &str) -> usize {
+        let calls = self.calls.lock().unwrap();
+        calls.iter().filter(|x| x.starts_with(prefix)).count()
+    }
+
+    fn lookup<K, V>(map: &HashMap<K, V>, key: &K) -> Result<V>
+    where
+        K: std::hash::Hash + Eq + std::fmt::Debug,
+        V: Clone,
+    {
+        map.get(key)
+            .cloned()
+            .ok_or_else(|| anyhow!("OBS API returned 404 Not Found for {:?}", key))
+    }
+}
+
+#[cfg(test)]
+impl ObsApi for FakeObsApi {
+    fn build_results(&self, project: &str, package: &str) -> Result<ResultList> {
+        self.call(format!("build_results {}/{}", project, package));
+        Self::lookup(&self.results, &(project.to_string(), package.to_string()))
+    }
+
+    fn request(&self, id: &str) -> Result<Request> {
+        self.call(format!("request {}", id));
+        Self::lookup(&self.requests, &id.to_string())
+    }
+
+    fn package_meta(&self, project: &str, package: &str) -> Result<PackageMeta> {
+        self.call(format!("package_meta {}/{}", project, package));
+        Self::lookup(&self.packages, &(project.to_string(), package.to_string()))
+    }
+
+    fn project_meta(&self, project: &str) -> Result<ProjectMeta> {
+        self.call(format!("project_meta {}", project));
+        Self::lookup(&self.projects, &project.to_string())
+    }
+
+    fn group_members(&self, group: &str) -> Result<Vec<String>> {
+        self.call(format!("group_members {}", group));
+        Self::lookup(&self.groups, &group.to_string())
+    }
+
+    fn build_log_tail(
+        &self,
+        project: &str,
+        repository: &str,
+        arch: &str,
+        package: &str,
+        max_size: usize,
+    ) -> Result<String> {
+        let path = format!("{}/{}/{}/{}", project, repository, arch, package);
+        self.call(format!("build_log_tail {}", path));
+        let log = Self::lookup(&self.logs, &path)?;
+        Ok(log[log.len().saturating_sub(max_size)..].to_string())
+    }
+
+    fn rebuild(
+        &self,
+        project: &str,
+        package: &str,
+        repository: Option<&str>,
+        arch: Option<&str>,
+    ) -> Result<()> {
+        self.call(format!(
+            "rebuild {}/{} {:?} {:?}",
+            project, package, repository, arch
+        ));
+        Ok(())
+    }
+
+    fn change_request_state(&self, id: &str, new_state: &str, comment: &str) -> Result<()> {
+        self.call(format!(
+            "change_request_state {} {} {}",
+            id, new_state, comment
+        ));
+        Ok(())
+    }
+
+    fn add_review(
+        &self,
+        id: &str,
+        reviewer_kind: &str,
+        reviewer: &str,
+        comment: &str,
+    ) -> Result<()> {
+        self.call(format!(
+            "add_review {} {} {} {}",
+            id, reviewer_kind, reviewer, comment
+        ));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::MockServer;
+
+    fn credentials() -> ObsCredentials {
+        ObsCredentials {
+            backend: "opensuse.org".to_string(),
+            user: "bot".to_string(),
+            password: "secret".to_string(),
+        }
+    }
+
+    #[test]
+    fn result_list() {
+        let xml = r#"<resultlist state="c181538ad4f4c1be29e73f85b9237651">
+  <result project="devel:foo" repository="openSUSE_Tumbleweed" arch="x86_64" code="published" state="published">
+    <status package="bar" code="succeeded"/>
+  </result>
+  <result project="devel:foo" repository="openSUSE_Tumbleweed" arch="i586" code="building" state="building" dirty="true">
+    <status package="bar" code="failed">
+      <details>compile error</details>
+    </status>
+  </result>
+  <result project="devel:foo" repository="15.5" arch="x86_64" code="blocked" state="blocked"/>
+</resultlist>"#;
+        let list: ResultList = quick_xml::de::from_str(xml).unwrap();
+        assert_eq!(list.results.len(), 3);
+
+        let first = &list.results[0];
+        assert_eq!(first.repository, "openSUSE_Tumbleweed");
+        assert_eq!(first.arch, "x86_64");
+        assert_eq!(first.statuses[0].package, "bar");
+        assert_eq!(first.statuses[0].code, "succeeded");
+        assert_eq!(first.statuses[0].details, None);
+
+        let second = &list.results[1].statuses[0];
+        assert_eq!(second.code, "failed");
+        assert_eq!(second.details.as_deref(), Some("compile error"));
+
+        assert!(list.results[2].statuses.is_empty());
+    }
+
+    #[test]
+    fn empty_result_list() {
+        let list: ResultList = quick_xml::de::from_str("<resultlist/>").unwrap();
+        assert!(list.results.is_empty());
+    }
+
+    #[test]
+    fn request() {
+        let xml = r#"<request id="1234" creator="alice">
+  <action type="submit">
+    <source project="home:alice" package="bar" rev="3"/>
+    <target project="devel:foo" package="bar"/>
+  </action>
+  <action type="maintenance_incident">
+    <source project="home:alice:branches" package="baz"/>
+    <target project="SUSE:Maintenance" releaseproject="SUSE:Updates"/>
+  </action>
+  <state name="review" who="alice" when="2024-01-02T10:00:00" created="2024-01-01T10:00:00">
+    <comment>Please review</comment>
+  </state>
+  <review state="accepted" by_user="bob" who="bob" when="2024-01-02T09:00:00">
+    <comment>ok</comment>
+  </review>
+  <review state="new" by_group="factory-auto"/>
+  <review state="new" by_project="devel:foo" by_package="bar"/>
+  <history who="alice" when="2024-01-01T10:00:00">
+    <description>Request created</description>
+    <comment>First version</comment>
+  </history>
+  <history who="bob" when="2024-01-02T09:00:00">
+    <description>Request got a new review request</description>
+  </history>
+  <description>Update to 1.2</description>
+</request>"#;
+        let request: Request = quick_xml::de::from_str(xml).unwrap();
+        assert_eq!(request.id, "1234");
+        assert_eq!(request.creator.as_deref(), Some("alice"));
+
+        assert_eq!(request.actions.len(), 2);
+        assert_eq!(request.actions[0].kind, "submit");
+        let target = request.actions[0].target.as_ref().unwrap();
+        assert_eq!(target.to_string(), "devel:foo/bar");
+        let target = request.actions[1].target.as_ref().unwrap();
+        assert_eq!(target.package, None);
+        assert_eq!(target.to_string(), "SUSE:Maintenance");
+
+        assert_eq!(request.state.name, "review");
+        assert_eq!(request.state.who.as_deref(), Some("alice"));
+        assert_eq!(request.state.when.as_deref(), Some("2024-01-02T10:00:00"));
+
+        assert_eq!(request.reviews.len(), 3);
+        assert_eq!(request.reviews[0].state, "accepted");
+        assert_eq!(request.reviews[0].by_user.as_deref(), Some("bob"));
+        assert_eq!(request.reviews[1].by_group.as_deref(), Some("factory-auto"));
+        assert_eq!(request.reviews[2].by_project.as_deref(), Some("devel:foo"));
+        assert_eq!(request.reviews[2].by_package.as_deref(), Some("bar"));
+
+        assert_eq!(request.history.len(), 2);
+        assert_eq!(
+            request.history[0].description.as_deref(),
+            Some("Request created")
+        );
+        assert_eq!(request.history[0].comment.as_deref(), Some("First version"));
+        assert_eq!(request.history[1].comment, None);
+    }
+
+    #[test]
+    fn package_meta() {
+        let xml = r#"<package name="bar" project="devel:foo">
+  <title>Bar</title>
+  <description/>
+  <person userid="bob" role="maintainer"/>
+  <person userid="carol" role="bugowner"/>
+  <group groupid="foo-team" role="maintainer"/>
+</package>"#;
+        let meta: PackageMeta = quick_xml::de::from_str(xml).unwrap();
+        let persons = meta
+            .persons
+            .iter()
+            .map(|x| (x.userid.as_str(), x.role.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(persons, [("bob", "maintainer"), ("carol", "bugowner")]);
+        assert_eq!(meta.groups.len(), 1);
+        assert_eq!(meta.groups[0].groupid, "foo-team");
+        assert_eq!(meta.groups[0].role, "maintainer");
+    }
+
+    #[test]
+    fn group_members() {
+        let xml = r#"<group>
+  <title>foo-team</title>
+  <email>foo@example.org</email>
+  <maintainer userid="frank"/>
+  <person>
+    <person userid="erin"/>
+    <person userid="grace"/>
+  </person>
+</group>"#;
+        let server = MockServer::start(move |_| (200, xml.to_string()));
+        let client = ObsClient::new(&server.url, Some(credentials())).unwrap();
+        assert_eq!(client.group_members("foo-team").unwrap(), ["erin", "grace"]);
+        assert_eq!(server.paths(), ["/group/foo-team"]);
+
+        let group: Group = quick_xml::de::from_str("<group><title>empty</title></group>").unwrap();
+        assert!(group.person.is_none());
+    }
+
+    #[test]
+    fn project_meta() {
+        let xml = r#"<project name="devel:foo">
+  <title>Foo</title>
+  <description>Development of foo</description>
+  <person userid="dave" role="maintainer"/>
+  <repository name="openSUSE_Tumbleweed">
+    <path project="openSUSE:Factory" repository="snapshot"/>
+    <arch>x86_64</arch>
+  </repository>
+</project>"#;
+        let meta: ProjectMeta = quick_xml::de::from_str(xml).unwrap();
+        assert_eq!(meta.description.as_deref(), Some("Development of foo"));
+        assert_eq!(meta.persons.len(), 1);
+        assert_eq!(meta.persons[0].userid, "dave");
+    }
+
+    #[test]
+    fn path_segments_are_encoded() {
+        let server = MockServer::start(|_| (200, "<package/>".to_string()));
+        let client = ObsClient::new(&format!("{}/", server.url), None).unwrap();
+
+        client.package_meta("home:foo", "bar baz/?#").unwrap();
+        client.build_results("home:foo", "a&b").unwrap();
+        assert_eq!(
+            server.paths(),
+            [
+                "/public/source/home:foo/bar%20baz%2F%3F%23/_meta",
+                "/public/build/home:foo/_result?package=a%26b",
+            ]
+        );
+    }
+
+    #[test]
+    fn actions_are_posted() {
+        let server = MockServer::start(|_| (200, "<status code=\"ok\"/>".to_string()));
+        let client = ObsClient::new(&server.url, Some(credentials())).unwrap();
+
+        client
+            .rebuild("home:foo", "bar", Some("openSUSE_Tumbleweed"), None)
+            .unwrap();
+        let requests = server.requests();
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(
+            requests[0].path,
+            "/build/home:foo?cmd=rebuild&package=bar&repository=openSUSE_Tumbleweed"
+        );
+        assert_eq!(server.requests().len(), 1);
+        assert!(!format!("{:?}", credentials()).contains("secret"));
+    }
+
+    #[test]
+    fn build_log_tail() {
+        let server = MockServer::start(|request| {
+            if request.path.ends_with("view=entry") {
+                let xml = r#"<directory><entry name="_log" size="100000" mtime="1700000000"/></directory>"#;
+                (200, xml.to_string())
+            } else {
+                (200, "end of the log".to_string())
+            }
+        });
+        let client = ObsClient::new(&server.url, None).unwrap();
+
+        let log = client
+            .build_log_tail("home:foo", "openSUSE_Tumbleweed", "x86_64", "bar", 65536)
+            .unwrap();
+        assert_eq!(log, "end of the log");
+        let base = "/public/build/home:foo/openSUSE_Tumbleweed/x86_64/bar/_log";
+        assert_eq!(
+            server.paths(),
+            [
+                format!("{}?view=entry", base),
+                format!("{}?nostream=1&start=34464", base),
+            ]
+        );
+
+        // Short logs are downloaded completely
+        client
+            .build_log_tail("home:foo", "openSUSE_Tumbleweed", "x86_64", "bar", 200000)
+            .unwrap();
+        assert_eq!(server.paths()[3], format!("{}?nostream=1&start=0", base));
+    }
+
+    #[test]
+    fn build_log_tail_without_entry() {
+        let server = MockServer::start(|request| {
+            if request.path.ends_with("view=entry") {
+                (200, "<directory/>".to_string())
+            } else {
+                (200, "whole log".to_string())
+            }
+        });
+        let client = ObsClient::new(&server.url, None).unwrap();
+        assert_eq!(
+            client.build_log_tail("p", "r", "a", "b", 10).unwrap(),
+            "whole log"
+        );
+        assert!(server.paths()[1].ends_with("?nostream=1&start=0"));
+    }
+
+    #[test]
+    fn requests_need_credentials() {
+        let server = MockServer::start(|_| (200, String::new()));
+        let client = ObsClient::new(&server.url, None).unwrap();
+
+        // There is no /public/request, and /request always answers 401 without login
+        let error = client.request("1234").unwrap_err();
+        assert!(error.is::<NoCredentials>());
+        assert!(client
+            .group_members("foo-team")
+            .unwrap_err()
+            .is::<NoCredentials>());
+        assert!(client
+            .rebuild("home:foo", "bar", None, None)
+            .unwrap_err()
+            .is::<NoCredentials>());
+        assert!(server.paths().is_empty());
+    }
+}
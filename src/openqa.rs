@@ -1,156 +1,981 @@
-use crate::common::{prepend_prefix, ConnectionDetails, MessageParseResult, Subscriber};
-use anyhow::Result;
-use lapin::{
-    message::{Delivery, DeliveryResult},
-    options::*,
-    Connection, ConsumerDelegate,
+use crate::commands::{Command, CommandHandler, Token, Verb};
+use crate::common::{
+    html_escape, prepend_prefix, ConnectionDetails, EventHandler, MessageParseResult, NotifyMap,
+    Shared, Subscriber, SubscriptionKey, Target,
 };
-use matrix_bot_api::handlers::{HandleResult, MessageHandler};
-use matrix_bot_api::{ActiveBot, MatrixBot, Message, MessageType};
-use serde::Deserialize;
-use serde_json;
+use crate::i18n::{tr, trf};
+use crate::listing::ListEntry;
+use crate::outbox::EventKind;
+use crate::reference::Reference;
+use crate::settings::RoomSettings;
+use crate::templates;
+use anyhow::{anyhow, Result};
+use lapin::{message::Delivery, Connection};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
 use std::collections::hash_map::HashMap;
-use std::convert::TryFrom;
+use std::collections::{HashSet, VecDeque};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 const KEY_JOB_DONE: &str = "openqa.job.done";
-const SUBNAMES: [&str; 1] = [KEY_JOB_DONE];
+const KEY_JOB_RESTART: &str = "openqa.job.restart";
+const KEY_JOB_DUPLICATE: &str = "openqa.job.duplicate";
+const SUBNAMES: [&str; 3] = [KEY_JOB_DONE, KEY_JOB_RESTART, KEY_JOB_DUPLICATE];
 
 pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
     let without_prefix = [
         (
             "OPENQA_TEST_URL",
-            "Subscribe to a test. Get notification if test-status changes.",
+            "Subscribe to a test. Get notification if test-status changes. Restarts are followed.",
         ),
         (
             "unsub OPENQA_TEST_URL",
             "Unsubscribe from a test. Get no more notifications.",
         ),
-        ("list tests", "List all tests currently subscribed to."),
+        (
+            "OPENQA_GROUP_URL",
+            "Subscribe to all tests of a job group (group_overview URL).",
+        ),
+        (
+            "OPENQA_OVERVIEW_URL",
+            "Subscribe to all tests matching the distri, version, groupid, build, test, flavor, machine and arch of a tests/overview URL. Wildcards (*, ?) are allowed.",
+        ),
+        (
+            "summary OPENQA_GROUP_URL",
+            "Get only one summary per build (also for OPENQA_OVERVIEW_URL), once all its jobs are done.",
+        ),
+        (
+            "flaky tests",
+            "List the most flaky tests of the subscriptions of this room.",
+        ),
     ];
 
     prepend_prefix(prefix, &without_prefix)
 }
 
+/// If what reference points to can be subscribed to here, for checking the config
+pub fn subscribable(reference: &Reference, summary: bool) -> bool {
+    QAKey::from_reference(reference, summary).is_some()
+}
+
+/// Filters of a tests/overview URL. Unset fields match everything.
+#[derive(Debug, Clone, Default, std::cmp::PartialEq, std::cmp::Eq, Hash)]
+struct QAFilter {
+    distri: Option<String>,
+    version: Option<String>,
+    group: Option<String>,
+    build: Option<String>,
+    test: Option<String>,
+    flavor: Option<String>,
+    machine: Option<String>,
+    arch: Option<String>,
+}
+
+impl QAFilter {
+    fn fields(&self) -> [(&'static str, &Option<String>); 8] {
+        [
+            ("distri", &self.distri),
+            ("version", &self.version),
+            ("groupid", &self.group),
+            ("build", &self.build),
+            ("test", &self.test),
+            ("flavor", &self.flavor),
+            ("machine", &self.machine),
+            ("arch", &self.arch),
+        ]
+    }
+
+    fn is_empty(&self) -> bool {
+        self.fields().iter().all(|(_, value)| value.is_none())
+    }
+
+    fn matches(&self, jsondata: &QATestInfo) -> bool {
+        let group_id = jsondata.group_id.map(|x| x.to_string());
+        let testname = Some(jsondata.testname.clone());
+        let values = [
+            &jsondata.distri,
+            &jsondata.version,
+            &group_id,
+            &jsondata.build,
+            &testname,
+            &jsondata.flavor,
+            &jsondata.machine,
+            &jsondata.arch,
+        ];
+
+        self.fields()
+            .iter()
+            .zip(values.iter())
+            .all(|((_, pattern), value)| match (pattern, value) {
+                (None, _) => true,
+                (Some(pattern), Some(value)) => glob_match(pattern, value),
+                (Some(_), None) => false,
+            })
+    }
+
+    /// If the settings of the job are needed to decide if it matches. openQA does
+    /// not send DISTRI and VERSION with its events, so they have to be queried.
+    fn needs_settings(&self, jsondata: &QATestInfo) -> bool {
+        let missing = (self.distri.is_some() && jsondata.distri.is_none())
+            || (self.version.is_some() && jsondata.version.is_none());
+        let others = QAFilter {
+            distri: None,
+            version: None,
+            ..self.clone()
+        };
+        missing && others.matches(jsondata)
+    }
+}
+
+impl std::fmt::Display for QAFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let parts = self
+            .fields()
+            .iter()
+            .filter_map(|(name, value)| value.as_ref().map(|x| format!("{} {}", name, x)))
+            .collect::<Vec<_>>();
+        write!(f, "{}", parts.join(", "))
+    }
+}
+
 #[derive(Debug, Clone, std::cmp::PartialEq, std::cmp::Eq, Hash)]
-struct QAKey {
-    id: String,
+enum QAKey {
+    /// A single job, identified by its id
+    Job(String),
+    /// All jobs of a group, build or test-pattern
+    Filter(QAFilter),
+    /// Like Filter, but only one message per build, once all its jobs are done
+    Summary(QAFilter),
+}
+
+impl QAKey {
+    fn matches(&self, jsondata: &QATestInfo) -> bool {
+        match self {
+            QAKey::Job(id) => *id == jsondata.id.to_string(),
+            QAKey::Filter(filter) | QAKey::Summary(filter) => filter.matches(jsondata),
+        }
+    }
+
+    fn needs_settings(&self, jsondata: &QATestInfo) -> bool {
+        match self {
+            QAKey::Job(_) => false,
+            QAKey::Filter(filter) | QAKey::Summary(filter) => filter.needs_settings(jsondata),
+        }
+    }
 }
 
 impl std::fmt::Display for QAKey {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.id)
+        match self {
+            QAKey::Job(id) => write!(f, "{}", id),
+            QAKey::Filter(filter) => write!(f, "{}", filter),
+            QAKey::Summary(filter) => write!(f, "summary of {}", filter),
+        }
     }
 }
 
-impl TryFrom<String> for QAKey {
-    type Error = ();
+impl SubscriptionKey for QAKey {
+    type State = QAState;
 
-    fn try_from(line: String) -> Result<Self, Self::Error> {
-        let line = line.trim();
-        if line.contains('\n') {
-            return Err(());
+    fn url(&self, base_url: &str) -> String {
+        match self {
+            QAKey::Job(id) => format!("{}/{}", base_url, id),
+            QAKey::Filter(filter) | QAKey::Summary(filter) => {
+                let pairs = filter
+                    .fields()
+                    .iter()
+                    .filter_map(|(name, value)| value.as_ref().map(|x| (*name, x.as_str())))
+                    .collect::<Vec<_>>();
+                let mut url = format!("{}/overview", base_url);
+                if let Ok(mut parsed) = Url::parse(&url) {
+                    parsed.query_pairs_mut().extend_pairs(pairs);
+                    url = parsed.to_string();
+                }
+                url
+            }
         }
+    }
 
-        let parts: Vec<_> = line.split('/').collect();
-        if parts.len() < 3 {
-            return Err(());
+    fn export(&self, base_url: &str) -> String {
+        match self {
+            QAKey::Summary(_) => format!("summary {}", self.url(base_url)),
+            _ => self.url(base_url),
         }
+    }
+
+    fn from_reference(reference: &Reference, summary: bool) -> Option<Self> {
+        let filter = match reference {
+            Reference::QAJob(id) if !summary => return Some(QAKey::Job(id.clone())),
+            Reference::QAGroup(id) => QAFilter {
+                group: Some(id.clone()),
+                ..Default::default()
+            },
+            Reference::QAOverview(query) => {
+                let mut filter = QAFilter::default();
+                for (name, value) in query {
+                    let value = Some(value.clone());
+                    match name.as_str() {
+                        "distri" => filter.distri = value,
+                        "version" => filter.version = value,
+                        "groupid" => filter.group = value,
+                        "build" => filter.build = value,
+                        "test" => filter.test = value,
+                        "flavor" => filter.flavor = value,
+                        "machine" => filter.machine = value,
+                        "arch" => filter.arch = value,
+                        _ => { /* e.g. result, todo or modules of the overview page */ }
+                    }
+                }
+                if filter.is_empty() {
+                    return None;
+                }
+                filter
+            }
+            // Summaries of a single job make no sense
+            _ => return None,
+        };
+        Some(if summary {
+            QAKey::Summary(filter)
+        } else {
+            QAKey::Filter(filter)
+        })
+    }
+}
+
+/// Simple wildcard-matching, supporting '*' (any sequence) and '?' (any single character)
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
 
-        let mut iter = parts.iter().rev();
-        // These unwraps cannot fail, as there have to be at least 2 parts
-        let id = iter
-            .next()
-            .unwrap()
-            .trim()
-            .trim_end_matches('#')
-            .to_string();
-        Ok(QAKey { id })
+    let (mut p, mut t) = (0, 0);
+    // Position of the last '*' in pattern and the text-position it matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last '*' swallow one more character
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
     }
+
+    pattern[p..].iter().all(|&c| c == '*')
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 struct QATestInfo {
     id: i32,
-    #[serde(rename = "TEST")]
+    #[serde(rename(deserialize = "TEST"))]
     testname: String,
     result: String,
     reason: Option<String>,
-    // remaining: i32
+    group_id: Option<i32>,
+    /// Usually not part of the event, see QAFilter::needs_settings
+    #[serde(rename(deserialize = "DISTRI"))]
+    distri: Option<String>,
+    #[serde(rename(deserialize = "VERSION"))]
+    version: Option<String>,
+    #[serde(rename(deserialize = "BUILD"))]
+    build: Option<String>,
+    #[serde(rename(deserialize = "FLAVOR"))]
+    flavor: Option<String>,
+    #[serde(rename(deserialize = "MACHINE"))]
+    machine: Option<String>,
+    #[serde(rename(deserialize = "ARCH"))]
+    arch: Option<String>,
+    /// Number of jobs of the same build still pending
+    remaining: Option<i32>,
+}
+
+/// Everything the openQA template can use
+#[derive(Serialize)]
+struct QAContext<'a> {
+    #[serde(flatten)]
+    info: &'a QATestInfo,
+    url: String,
+    flaky: bool,
+    failed_modules: &'a [QAFailedModule],
+}
+
+#[derive(Debug, Clone)]
+struct QAJobResult {
+    id: i32,
+    testname: String,
+    result: String,
+}
+
+/// Results of a build collected for one summary-subscription
+#[derive(Debug, Clone)]
+struct QASummary {
+    results: Vec<QAJobResult>,
+    /// To drop builds whose last job never arrives
+    started: Instant,
+}
+
+/// Upper limit of jobs whose failed modules are cached
+const FAILED_MODULES_CACHE_SIZE: usize = 1000;
+
+/// Summaries of builds not done after this are dropped (e.g. a missed event)
+const SUMMARY_EXPIRY: Duration = Duration::from_secs(2 * 24 * 3600);
+
+#[derive(Default, Clone)]
+struct QAState {
+    /// Results collected for summary-subscriptions, per key and build
+    summaries: HashMap<(QAKey, String), QASummary>,
+    /// Failed modules of already queried jobs, per job id
+    failed_modules: HashMap<i32, Vec<QAFailedModule>>,
+    /// Recent results of subscribed tests
+    history: HashMap<QATestId, QAHistory>,
+    /// Number of recent results to look at for flakiness
+    flaky_window: usize,
+}
+
+/// Identifies a test across runs (job ids change with every run)
+#[derive(Debug, Clone, std::cmp::PartialEq, std::cmp::Eq, Hash)]
+struct QATestId {
+    test: String,
+    flavor: String,
+    machine: String,
+    arch: String,
+}
+
+impl QATestId {
+    fn new(jsondata: &QATestInfo) -> Self {
+        QATestId {
+            test: jsondata.testname.clone(),
+            flavor: jsondata.flavor.clone().unwrap_or_default(),
+            machine: jsondata.machine.clone().unwrap_or_default(),
+            arch: jsondata.arch.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl std::fmt::Display for QATestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({} / {} / {})",
+            self.test, self.flavor, self.machine, self.arch
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct QAHistory {
+    /// Most recent result last
+    results: VecDeque<String>,
+    /// Subscriptions this test was reported for
+    keys: HashSet<QAKey>,
+}
+
+impl QAHistory {
+    /// Number of changes between passed and failed, ignoring incompletes and the like
+    fn flips(&self) -> usize {
+        let outcomes = self
+            .results
+            .iter()
+            .filter_map(|result| match result.as_str() {
+                "passed" | "softfailed" => Some(true),
+                "failed" => Some(false),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        outcomes.windows(2).filter(|x| x[0] != x[1]).count()
+    }
+
+    /// A single regression or fix is fine, going back and forth is not
+    fn is_flaky(&self) -> bool {
+        self.flips() >= 2
+    }
+}
+
+/// Drops the history of tests no subscription reports anymore
+fn prune_history(history: &mut HashMap<QATestId, QAHistory>, subscribed: &HashSet<QAKey>) {
+    history.retain(|_, test| {
+        test.keys.retain(|key| subscribed.contains(key));
+        !test.keys.is_empty()
+    });
+}
+
+#[derive(Serialize, Debug, Clone)]
+struct QAFailedModule {
+    name: String,
+    /// Number of the first failing step of this module (starting at 1)
+    step: usize,
+}
+
+#[derive(Deserialize, Debug)]
+struct QAJobDetails {
+    job: QAJobDetailsJob,
+}
+
+#[derive(Deserialize, Debug)]
+struct QAJobDetailsJob {
+    #[serde(default)]
+    testresults: Vec<QAModuleResult>,
+}
+
+#[derive(Deserialize, Debug)]
+struct QAModuleResult {
+    name: String,
+    result: String,
+    #[serde(default)]
+    details: Vec<QAStepResult>,
+}
+
+#[derive(Deserialize, Debug)]
+struct QAStepResult {
+    result: Option<String>,
+    num: Option<usize>,
+}
+
+/// Queries the openQA API (e.g. https://openqa.opensuse.org) for the failed modules of a job
+fn fetch_failed_modules(api_url: &str, id: i32) -> Result<Vec<QAFailedModule>> {
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .build()?;
+    let details: QAJobDetails = client
+        .get(&format!("{}/api/v1/jobs/{}/details", api_url, id))
+        .send()?
+        .error_for_status()?
+        .json()?;
+
+    let failed = details
+        .job
+        .testresults
+        .into_iter()
+        .filter(|module| module.result == "failed")
+        .map(|module| {
+            let step = module
+                .details
+                .iter()
+                .enumerate()
+                .find(|(_, step)| step.result.as_deref() == Some("fail"))
+                .map(|(index, step)| step.num.unwrap_or(index + 1))
+                .unwrap_or(1);
+            QAFailedModule {
+                name: module.name,
+                step,
+            }
+        })
+        .collect();
+
+    Ok(failed)
+}
+
+#[derive(Deserialize, Debug)]
+struct QAJobSettingsResponse {
+    job: QAJobSettingsJob,
+}
+
+#[derive(Deserialize, Debug)]
+struct QAJobSettingsJob {
+    settings: QAJobSettings,
+}
+
+#[derive(Deserialize, Debug)]
+struct QAJobSettings {
+    #[serde(rename = "DISTRI")]
+    distri: Option<String>,
+    #[serde(rename = "VERSION")]
+    version: Option<String>,
+}
+
+/// Queries the openQA API for the settings of a job, which are not part of its events
+fn fetch_settings(api_url: &str, id: i32) -> Result<QAJobSettings> {
+    let client = reqwest::Client::builder()
+        .timeout(std::time::Duration::from_secs(10))
+        .build()?;
+    let response: QAJobSettingsResponse = client
+        .get(&format!("{}/api/v1/jobs/{}", api_url, id))
+        .send()?
+        .error_for_status()?
+        .json()?;
+    Ok(response.job.settings)
+}
+
+/// Failed modules of a job, either cached or freshly queried from the openQA API
+fn failed_modules(state: &Mutex<QAState>, api_url: &str, id: i32) -> Vec<QAFailedModule> {
+    if let Ok(state) = state.lock() {
+        if let Some(modules) = state.failed_modules.get(&id) {
+            return modules.clone();
+        }
+    }
+
+    let modules = match fetch_failed_modules(api_url, id) {
+        Ok(x) => x,
+        Err(x) => {
+            println!("Could not get failed modules of job {}: {:?}", id, x);
+            return Vec::new();
+        }
+    };
+
+    if let Ok(mut state) = state.lock() {
+        if state.failed_modules.len() >= FAILED_MODULES_CACHE_SIZE {
+            state.failed_modules.clear();
+        }
+        state.failed_modules.insert(id, modules.clone());
+    }
+    modules
+}
+
+/// Moves the subscriptions and notify-users of a job over to its clone.
+/// Returns the targets, or None if nobody is subscribed to the old job.
+fn move_job(
+    subscriptions: &mut HashMap<QAKey, HashSet<Target>>,
+    notify: &mut NotifyMap<QAKey>,
+    old_key: &QAKey,
+    new_key: &QAKey,
+) -> Option<HashSet<Target>> {
+    let targets = subscriptions.remove(old_key)?;
+    subscriptions
+        .entry(new_key.clone())
+        .or_default()
+        .extend(targets.iter().cloned());
+
+    for target in &targets {
+        if let Some(users) = notify.remove(&(old_key.clone(), target.clone())) {
+            notify.insert((new_key.clone(), target.clone()), users);
+        }
+    }
+    Some(targets)
 }
 
-impl MessageHandler for Subscriber<QAKey> {
-    /// Will be called for every text message send to a room the bot is in
-    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
-        let res = self.handle_message_helper(bot, &message.body, &message.room);
+#[derive(Deserialize, Debug)]
+struct QAJobCloneInfo {
+    id: serde_json::Value,
+    result: serde_json::Value,
+}
 
-        if res == MessageParseResult::SomethingForMe {
-            match self.register() {
-                Err(x) => {
-                    println!("Error while registering: {:?}", x);
+impl QAJobCloneInfo {
+    /// Returns pairs of (old id, new id).
+    /// Depending on the openQA-version and the event, 'result' is either
+    /// the new id, a map {old id: new id} or a list of such maps.
+    fn clones(&self) -> Vec<(String, String)> {
+        fn as_id(value: &serde_json::Value) -> Option<String> {
+            match value {
+                serde_json::Value::Number(x) => Some(x.to_string()),
+                serde_json::Value::String(x) if x.chars().all(|c| c.is_ascii_digit()) => {
+                    Some(x.clone())
                 }
-                Ok(consumer) => consumer.set_delegate(Box::new(self.clone())),
+                _ => None,
+            }
+        }
+
+        fn collect(old: Option<String>, result: &serde_json::Value) -> Vec<(String, String)> {
+            match result {
+                serde_json::Value::Object(map) => map
+                    .iter()
+                    .flat_map(|(key, value)| collect(Some(key.clone()), value))
+                    .collect(),
+                serde_json::Value::Array(list) => list
+                    .iter()
+                    .flat_map(|value| collect(old.clone(), value))
+                    .collect(),
+                value => match (old, as_id(value)) {
+                    (Some(old), Some(new)) if old != new => vec![(old, new)],
+                    _ => Vec::new(),
+                },
             }
         }
-        HandleResult::ContinueHandling
+
+        collect(as_id(&self.id), &self.result)
+    }
+}
+
+/// softfailed means the test passed with known issues
+fn result_kind(result: &str) -> EventKind {
+    match result {
+        "passed" | "softfailed" => EventKind::TestPassed,
+        _ => EventKind::TestFailed,
+    }
+}
+
+impl CommandHandler for Subscriber<QAKey> {
+    fn owns(&self, url: &str) -> bool {
+        self.owns_url(url)
+    }
+
+    fn execute(&mut self, bot: &ActiveBot, message: &Message, command: &Command, urls: &[Token]) {
+        if command.verb == Verb::Flaky {
+            self.list_flaky(bot, message);
+            return;
+        }
+
+        if self.execute_command(bot, message, command, urls) == MessageParseResult::SomethingForMe {
+            self.start_listening();
+        }
+    }
+
+    fn list(&self, room: &str, sender: &str) -> Vec<ListEntry> {
+        self.list_entries(room, sender)
+    }
+
+    fn all_subscriptions(&self) -> Vec<(Target, ListEntry)> {
+        self.all_entries()
+    }
+
+    fn unsubscribe_room(&mut self, room: &str) -> usize {
+        Subscriber::unsubscribe_room(self, room)
+    }
+
+    fn export(&self, room: &str) -> Vec<String> {
+        self.export_entries(room)
+    }
+
+    fn subscribe_default(&mut self, command: &Command, urls: &[Token], room: &str) -> Vec<String> {
+        let failed = self.subscribe_to_defaults(command, urls, room);
+        if failed.len() < urls.len() {
+            self.start_listening();
+        }
+        failed
     }
 }
 
 impl Subscriber<QAKey> {
-    fn generate_messages(&self, jsondata: QATestInfo) -> (String, String) {
-        let reason = match jsondata.reason {
-            Some(x) => format!(" (reason: {})", x),
-            None => String::new(),
+    fn get_api_url(&self) -> String {
+        format!(
+            "https://{}.{}",
+            self.server_details.buildprefix, self.server_details.domain
+        )
+    }
+
+    fn failed_modules(&self, id: i32) -> Vec<QAFailedModule> {
+        failed_modules(&self.state, &self.get_api_url(), id)
+    }
+
+    /// Number of most flaky tests to list
+    const FLAKY_LIST_SIZE: usize = 10;
+
+    fn list_flaky(&self, bot: &ActiveBot, message: &Message) {
+        let room = &message.room;
+        let targets = self.visible_targets(room, &message.sender);
+        let (all_keys, room_keys) = match self.subscriptions.lock() {
+            Ok(subscriptions) => (
+                subscriptions.keys().cloned().collect::<HashSet<_>>(),
+                subscriptions
+                    .iter()
+                    .filter(|(_, subscribed)| targets.iter().any(|x| subscribed.contains(x)))
+                    .map(|(key, _)| key.clone())
+                    .collect::<HashSet<_>>(),
+            ),
+            Err(_) => {
+                println!("ERROR! list_flaky: subscriptions not lockable");
+                return;
+            }
         };
 
-        let html_result = match &jsondata.result {
-            x if x == "passed" => x.clone(),
-            x => format!("<u>{}</u>", x),
+        let mut flaky = match self.state.lock() {
+            Ok(mut state) => {
+                prune_history(&mut state.history, &all_keys);
+                state
+                    .history
+                    .iter()
+                    .filter(|(_, history)| {
+                        history.is_flaky() && !history.keys.is_disjoint(&room_keys)
+                    })
+                    .map(|(id, history)| (id.clone(), history.flips(), history.results.len()))
+                    .collect::<Vec<_>>()
+            }
+            Err(_) => {
+                println!("ERROR! list_flaky: state not lockable");
+                return;
+            }
         };
 
-        let plain = format!(
-            "Test {}: {} ({}){}",
-            &jsondata.result, jsondata.testname, jsondata.id, reason
-        );
+        let lang = self.settings.language(room);
+        if flaky.is_empty() {
+            let answer = tr(&lang, "No flaky tests found");
+            bot.send_message(&answer, room, MessageType::TextMessage);
+            return;
+        }
+
+        // Worst offenders first
+        flaky.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.test.cmp(&b.0.test)));
+        flaky.truncate(Self::FLAKY_LIST_SIZE);
 
+        let lines = flaky
+            .iter()
+            .map(|(id, flips, runs)| {
+                trf(
+                    &lang,
+                    "{}: changed {} times in the last {} runs",
+                    &[id, flips, runs],
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let title = trf(&lang, "Flaky tests on {}:", &[&self.server_details.domain]);
+        let plain = format!("{}\n{}", title, lines.join("\n"));
         let html = format!(
-            "<strong>Test {}:</strong> Test {} (<a href={}>{}</a>){}",
-            html_result,
-            jsondata.testname,
-            format!("{}/{}", self.get_base_url(), jsondata.id,),
-            jsondata.id,
-            reason
+            "{}<br>{}",
+            html_escape(&title),
+            lines
+                .iter()
+                .map(|x| html_escape(x))
+                .collect::<Vec<_>>()
+                .join("<br>")
         );
-
-        (plain, html)
+        bot.send_html_message(&plain, &html, room, MessageType::TextMessage);
     }
 
-    fn delivery_wrapper(&self, delivery: Delivery) -> Result<()> {
-        let data = std::str::from_utf8(&delivery.data)?;
-        let jsondata: QATestInfo = serde_json::from_str(data)?;
+    /// Adds the result to the history of the test and returns if it was known to be flaky
+    fn record_result(
+        &self,
+        jsondata: &QATestInfo,
+        keys: &[QAKey],
+        subscribed: &HashSet<QAKey>,
+    ) -> bool {
+        if let Ok(mut state) = self.state.lock() {
+            prune_history(&mut state.history, subscribed);
+            let window = state.flaky_window;
+            let history = state.history.entry(QATestId::new(jsondata)).or_default();
+            let was_flaky = history.is_flaky();
+
+            history.results.push_back(jsondata.result.clone());
+            while history.results.len() > window {
+                history.results.pop_front();
+            }
+            history.keys.extend(keys.iter().cloned());
+
+            was_flaky
+        } else {
+            false
+        }
+    }
 
-        let key = QAKey {
-            id: format!("{}", jsondata.id),
+    fn generate_messages(
+        &self,
+        room: &str,
+        jsondata: &QATestInfo,
+        failed_modules: &[QAFailedModule],
+        flaky: bool,
+    ) -> (String, String) {
+        let context = QAContext {
+            info: jsondata,
+            url: format!("{}/{}", self.get_base_url(), jsondata.id),
+            flaky,
+            failed_modules,
         };
+        self.templates
+            .render(room, templates::EVENT_OPENQA, &context)
+    }
 
-        let rooms;
-        if let Ok(subscriptions) = self.subscriptions.lock() {
-            // This is a message we are not subscribed to
-            if !subscriptions.contains_key(&key) {
-                return Ok(());
-            }
+    fn generate_summary(
+        &self,
+        key: &QAKey,
+        build: &str,
+        results: &[QAJobResult],
+        lang: &str,
+    ) -> (String, String) {
+        let count = |result: &str| results.iter().filter(|x| x.result == result).count();
+        let passed = count("passed");
+        let softfailed = count("softfailed");
+        let failed = results
+            .iter()
+            .filter(|x| x.result == "failed")
+            .collect::<Vec<_>>();
+        // Everything else (incomplete, timeout_exceeded, parallel_failed, ...)
+        let incomplete = results.len() - passed - softfailed - failed.len();
+
+        let counts = trf(
+            lang,
+            "{} passed, {} softfailed, {} failed, {} incomplete",
+            &[&passed, &softfailed, &failed.len(), &incomplete],
+        );
+
+        let mut plain = trf(lang, "Build {} ({}) finished: {}", &[&build, &key, &counts]);
+        let mut html = trf(
+            lang,
+            "Build {} ({}) finished: {}",
+            &[
+                &format!("<strong>{}</strong>", html_escape(build)),
+                &format!(
+                    "<a href=\"{}\">{}</a>",
+                    html_escape(&key.url(&self.get_base_url())),
+                    html_escape(&key.to_string())
+                ),
+                &if failed.is_empty() && incomplete == 0 {
+                    counts
+                } else {
+                    format!("<u>{}</u>", counts)
+                },
+            ],
+        );
+
+        if !failed.is_empty() {
+            let plainlist = failed
+                .iter()
+                .map(|x| format!("{} ({})", x.testname, x.id))
+                .collect::<Vec<_>>();
+            let htmllist = failed
+                .iter()
+                .map(|x| {
+                    format!(
+                        "<a href=\"{}/{}\">{}</a>",
+                        self.get_base_url(),
+                        x.id,
+                        html_escape(&x.testname)
+                    )
+                })
+                .collect::<Vec<_>>();
+            let failed = tr(lang, "Failed");
+            plain += &format!(". {}: {}", failed, plainlist.join(", "));
+            html += &format!("<br>{}: {}", failed, htmllist.join(", "));
+        }
 
-            rooms = subscriptions[&key].clone();
+        (plain, html)
+    }
+
+    /// Adds the result of a job to the summary of key
+    fn collect_summary(&self, key: &QAKey, jsondata: &QATestInfo) {
+        let build = jsondata.build.clone().unwrap_or_default();
+        if let Ok(mut state) = self.state.lock() {
+            let summary = state
+                .summaries
+                .entry((key.clone(), build))
+                .or_insert_with(|| QASummary {
+                    results: Vec::new(),
+                    started: Instant::now(),
+                });
+            summary.results.push(QAJobResult {
+                id: jsondata.id,
+                testname: jsondata.testname.clone(),
+                result: jsondata.result.clone(),
+            });
+        }
+    }
+
+    /// Sends the summaries of build, once its last job is done.
+    /// 'remaining' counts all jobs of the build, not only the subscribed ones.
+    fn flush_summaries(&self, routing_key: &str, build: &str) {
+        let done;
+        if let Ok(mut state) = self.state.lock() {
+            state.summaries.retain(|(key, build), summary| {
+                let expired = summary.started.elapsed() > SUMMARY_EXPIRY;
+                if expired {
+                    println!(
+                        "Dropping summary of build {} ({}): never finished",
+                        build, key
+                    );
+                }
+                !expired
+            });
+
+            let keys = state
+                .summaries
+                .keys()
+                .filter(|(_, x)| x == build)
+                .cloned()
+                .collect::<Vec<_>>();
+            done = keys
+                .into_iter()
+                .filter_map(|x| state.summaries.remove(&x).map(|summary| (x.0, summary)))
+                .collect::<Vec<_>>();
         } else {
-            return Ok(());
+            return;
         }
 
-        println!("Test {}: {}", jsondata.result, jsondata.id);
+        for (key, summary) in done {
+            // Whoever is subscribed now gets it
+            let targets = match self.subscriptions.lock() {
+                Ok(subscriptions) => match subscriptions.get(&key) {
+                    Some(x) => x.clone(),
+                    None => continue,
+                },
+                Err(_) => return,
+            };
 
-        if let Ok(bot) = self.bot.lock() {
-            let (plain, html) = self.generate_messages(jsondata);
-            for room in &rooms {
-                bot.send_html_message(&plain, &html, room, MessageType::TextMessage);
+            println!("Test summary: {} ({})", build, key);
+
+            let results = summary.results;
+            let kind = if results
+                .iter()
+                .all(|x| result_kind(&x.result) == EventKind::TestPassed)
+            {
+                EventKind::TestPassed
+            } else {
+                EventKind::TestFailed
+            };
+            let sources = [key.url(&self.get_base_url())];
+            for room in &self.rooms_of(&targets) {
+                let lang = self.settings.language(room);
+                let (plain, html) = self.generate_summary(&key, build, &results, &lang);
+                self.send_notification(routing_key, room, kind, &sources, &plain, &html);
+            }
+        }
+    }
+
+    fn follow_clones(
+        &self,
+        routing_key: &str,
+        jsondata: QAJobCloneInfo,
+        changetype: &str,
+    ) -> Result<()> {
+        for (old, new) in jsondata.clones() {
+            let old_key = QAKey::Job(old.clone());
+            let new_key = QAKey::Job(new.clone());
+
+            let targets = match (self.subscriptions.lock(), self.notify.lock()) {
+                (Ok(mut subscriptions), Ok(mut notify)) => {
+                    match move_job(&mut subscriptions, &mut notify, &old_key, &new_key) {
+                        Some(x) => x,
+                        // This is a job we are not subscribed to
+                        None => continue,
+                    }
+                }
+                _ => return Ok(()),
+            };
+
+            // Keep the history of the test attached to the subscription
+            if let Ok(mut state) = self.state.lock() {
+                for history in state.history.values_mut() {
+                    if history.keys.remove(&old_key) {
+                        history.keys.insert(new_key.clone());
+                    }
+                }
+            }
+
+            println!("Test {}: {} -> {}", changetype, old, new);
+
+            let text = if changetype == "restarted" {
+                "Job {} was restarted as {}"
+            } else {
+                "Job {} was cloned as {}"
+            };
+            let link = |id: &str| format!("<a href=\"{}/{}\">{}</a>", self.get_base_url(), id, id);
+
+            let old_url = old_key.url(&self.get_base_url());
+            let new_url = new_key.url(&self.get_base_url());
+            for room in &self.rooms_of(&targets) {
+                // A snooze of the old job is meant for the new one as well
+                self.settings.update(room, |settings: &mut RoomSettings| {
+                    if let Some(until) = settings.snoozed.remove(&old_url) {
+                        settings.snoozed.insert(new_url.clone(), until);
+                    }
+                });
+
+                let lang = self.settings.language(room);
+                let plain = trf(&lang, text, &[&old, &new]);
+                let html = trf(&lang, text, &[&link(&old), &link(&new)]);
+                self.send_notification(
+                    routing_key,
+                    room,
+                    EventKind::Other,
+                    std::slice::from_ref(&new_url),
+                    &plain,
+                    &html,
+                );
             }
         }
 
@@ -158,61 +983,456 @@ impl Subscriber<QAKey> {
     }
 }
 
-impl ConsumerDelegate for Subscriber<QAKey> {
-    fn on_new_delivery(&self, delivery: DeliveryResult) {
-        if let Ok(Some(delivery)) = delivery {
-            if let Some(channel) = &self.channel {
-                let _ = channel
-                    .basic_ack(delivery.delivery_tag, BasicAckOptions::default())
-                    .wait();
+impl EventHandler for Subscriber<QAKey> {
+    fn delivery_wrapper(&self, delivery: Delivery) -> Result<()> {
+        let data = std::str::from_utf8(&delivery.data)?;
+
+        let routing_key = delivery.routing_key.as_str();
+        if routing_key.contains(KEY_JOB_RESTART) {
+            return self.follow_clones(routing_key, serde_json::from_str(data)?, "restarted");
+        } else if routing_key.contains(KEY_JOB_DUPLICATE) {
+            return self.follow_clones(routing_key, serde_json::from_str(data)?, "cloned");
+        } else if !delivery.routing_key.as_str().contains(KEY_JOB_DONE) {
+            return Err(anyhow!(
+                "Test event neither done, restart nor duplicate, but {}",
+                delivery.routing_key.as_str()
+            ));
+        }
+
+        let mut jsondata: QATestInfo = serde_json::from_str(data)?;
+
+        let needs_settings = match self.subscriptions.lock() {
+            Ok(subscriptions) => subscriptions.keys().any(|x| x.needs_settings(&jsondata)),
+            Err(_) => return Ok(()),
+        };
+        if needs_settings {
+            match fetch_settings(&self.get_api_url(), jsondata.id) {
+                Ok(settings) => {
+                    jsondata.distri = jsondata.distri.or(settings.distri);
+                    jsondata.version = jsondata.version.or(settings.version);
+                }
+                Err(x) => println!("Could not get settings of job {}: {:?}", jsondata.id, x),
             }
-            match self.delivery_wrapper(delivery) {
-                Ok(_) => {}
-                Err(x) => println!("Error while getting Event: {:?}. Skipping to continue", x),
+        }
+
+        // Jobs can be subscribed to directly or via filters, so we have to check all keys
+        let mut targets = HashSet::new();
+        let mut keys = Vec::new();
+        let all_keys;
+        if let Ok(subscriptions) = self.subscriptions.lock() {
+            all_keys = subscriptions.keys().cloned().collect::<HashSet<_>>();
+            for (key, subscribed) in subscriptions.iter() {
+                if !key.matches(&jsondata) {
+                    continue;
+                }
+                keys.push(key.clone());
+                match key {
+                    QAKey::Summary(_) => self.collect_summary(key, &jsondata),
+                    _ => targets.extend(subscribed.iter().cloned()),
+                }
             }
         } else {
-            println!(
-                "Delivery not ok on {}: {:?}",
-                self.server_details.domain, delivery
+            return Ok(());
+        }
+
+        // Even a job we are not subscribed to can finish a build
+        if jsondata.remaining == Some(0) {
+            let build = jsondata.build.clone().unwrap_or_default();
+            self.flush_summaries(delivery.routing_key.as_str(), &build);
+        }
+
+        // This is a message we are not subscribed to
+        if keys.is_empty() {
+            return Ok(());
+        }
+
+        let flaky = self.record_result(&jsondata, &keys, &all_keys);
+
+        // Only subscribed via summaries
+        if targets.is_empty() {
+            return Ok(());
+        }
+
+        println!("Test {}: {}", jsondata.result, jsondata.id);
+
+        let failed_modules = if jsondata.result == "failed" {
+            self.failed_modules(jsondata.id)
+        } else {
+            Vec::new()
+        };
+
+        let kind = result_kind(&jsondata.result);
+        let sources = keys
+            .iter()
+            .map(|x| x.url(&self.get_base_url()))
+            .collect::<Vec<_>>();
+        for room in &self.rooms_of(&targets) {
+            let (plain, html) = self.generate_messages(room, &jsondata, &failed_modules, flaky);
+            self.send_notification(
+                delivery.routing_key.as_str(),
+                room,
+                kind,
+                &sources,
+                &plain,
+                &html,
             );
         }
+
+        Ok(())
     }
 }
 
 pub fn init(
-    bot: &mut MatrixBot,
     details: &ConnectionDetails,
     conn: Connection,
-    prefix: Option<String>,
-    default_subs: &Option<Vec<(String, String)>>,
+    shared: &Shared,
+    flaky_window: usize,
 ) -> Result<()> {
-    let activebot = bot.get_activebot_clone();
     let mut server_details = *details;
     server_details.buildprefix = "openqa";
-    let mut sub: Subscriber<QAKey> = Subscriber {
+    let sub: Subscriber<QAKey> = Subscriber {
         subtype: "tests".to_string(),
         server_details,
         connection: conn,
         channel: None,
+        events: None,
         subnames: SUBNAMES.to_vec(),
-        bot: Arc::new(Mutex::new(activebot)),
         subscriptions: Arc::new(Mutex::new(HashMap::new())),
-        prefix,
+        notify: Arc::new(Mutex::new(HashMap::new())),
+        state: Arc::new(Mutex::new(QAState {
+            flaky_window,
+            ..Default::default()
+        })),
+        obs_api: None,
+        accounts: shared.accounts.clone(),
+        direct: shared.direct.clone(),
+        outbox: shared.outbox.clone(),
+        templates: shared.templates.clone(),
+        settings: shared.settings.clone(),
+        stats: shared.stats.clone(),
     };
 
-    match default_subs {
-        None => {}
-        Some(subs) => match sub.register() {
-            Err(_) => {}
-            Ok(consumer) => {
-                consumer.set_delegate(Box::new(sub.clone()));
-                for (room, url) in subs {
-                    sub.subscribe_to_defaults(&url, &room);
-                }
-            }
-        },
-    }
-    bot.add_handler(sub);
+    shared.router.add(Box::new(sub));
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::MockServer;
+
+    const BASE_URL: &str = "https://openqa.opensuse.org/tests";
+
+    fn info(test: &str, build: &str) -> QATestInfo {
+        QATestInfo {
+            id: 42,
+            testname: test.to_string(),
+            result: "passed".to_string(),
+            reason: None,
+            group_id: Some(1),
+            distri: None,
+            version: None,
+            build: Some(build.to_string()),
+            flavor: Some("DVD".to_string()),
+            machine: Some("64bit".to_string()),
+            arch: Some("x86_64".to_string()),
+            remaining: None,
+        }
+    }
+
+    fn filter(key: Option<QAKey>) -> QAFilter {
+        match key {
+            Some(QAKey::Filter(x)) | Some(QAKey::Summary(x)) => x,
+            x => panic!("Not a filter: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("textmode", "textmode"));
+        assert!(!glob_match("textmode", "textmode2"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("2024*", "20240101"));
+        assert!(!glob_match("2024*", "20230101"));
+        assert!(glob_match("*_kde", "install_kde"));
+        assert!(glob_match("*zypper*", "update_zypper_up"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("a*b*c", "aXbYbZ"));
+        assert!(glob_match("x86_6?", "x86_64"));
+        assert!(!glob_match("x86_6?", "x86_6"));
+        assert!(glob_match("**", "x"));
+        assert!(!glob_match("", "x"));
+    }
+
+    #[test]
+    fn overview_url() {
+        let key = QAKey::parse(
+            "https://openqa.opensuse.org/tests/overview?distri=opensuse&version=Tumbleweed&build=2024*&groupid=1&test=install%2Bupdate&flavor=DVD+Updates&result=failed",
+            false,
+        );
+        let filter = filter(key);
+        assert_eq!(filter.distri.as_deref(), Some("opensuse"));
+        assert_eq!(filter.version.as_deref(), Some("Tumbleweed"));
+        assert_eq!(filter.group.as_deref(), Some("1"));
+        assert_eq!(filter.build.as_deref(), Some("2024*"));
+        assert_eq!(filter.test.as_deref(), Some("install+update"));
+        assert_eq!(filter.flavor.as_deref(), Some("DVD Updates"));
+        assert_eq!(filter.machine, None);
+
+        // Only unknown or empty fields would match everything
+        assert!(QAKey::parse(&format!("{}/overview?result=failed", BASE_URL), false).is_none());
+        assert!(QAKey::parse(&format!("{}/overview?build=", BASE_URL), false).is_none());
+    }
+
+    #[test]
+    fn overview_url_round_trip() {
+        let url = format!(
+            "{}/overview?distri=opensuse&groupid=1&build=2024*&test=a%26b&flavor=DVD+Updates",
+            BASE_URL
+        );
+        let key = QAKey::parse(&url, true).unwrap();
+        assert!(matches!(key, QAKey::Summary(_)));
+        let exported = key.url(BASE_URL);
+        assert_eq!(
+            exported,
+            format!(
+                "{}/overview?distri=opensuse&groupid=1&build=2024*&test=a%26b&flavor=DVD+Updates",
+                BASE_URL
+            )
+        );
+        assert_eq!(QAKey::parse(&exported, true), Some(key));
+    }
+
+    #[test]
+    fn group_and_job_urls() {
+        let filter = filter(QAKey::parse(
+            "https://openqa.opensuse.org/group_overview/24",
+            false,
+        ));
+        assert_eq!(filter.group.as_deref(), Some("24"));
+        assert_eq!(filter.fields().iter().filter(|x| x.1.is_some()).count(), 1);
+
+        assert_eq!(
+            QAKey::parse("https://openqa.opensuse.org/tests/1234", false),
+            Some(QAKey::Job("1234".to_string()))
+        );
+        // Summaries of a single job make no sense
+        assert_eq!(
+            QAKey::parse("https://openqa.opensuse.org/tests/1234", true),
+            None
+        );
+    }
+
+    #[test]
+    fn filter_matches() {
+        let job = info("install_kde", "20240101");
+        let filter = QAFilter {
+            build: Some("2024*".to_string()),
+            test: Some("*_kde".to_string()),
+            ..Default::default()
+        };
+        assert!(filter.matches(&job));
+        assert!(!filter.matches(&info("install_gnome", "20240101")));
+        assert!(!filter.matches(&info("install_kde", "20230101")));
+
+        let group = QAFilter {
+            group: Some("2".to_string()),
+            ..Default::default()
+        };
+        assert!(!group.matches(&job));
+    }
+
+    #[test]
+    fn distri_and_version_need_settings() {
+        let filter = QAFilter {
+            version: Some("Tumbleweed".to_string()),
+            build: Some("2024*".to_string()),
+            ..Default::default()
+        };
+        let mut job = info("install_kde", "20240101");
+        // Not part of the event, so it must not simply match
+        assert!(!filter.matches(&job));
+        assert!(filter.needs_settings(&job));
+        // No need to ask for jobs of other builds
+        assert!(!filter.needs_settings(&info("install_kde", "20230101")));
+
+        job.version = Some("Tumbleweed".to_string());
+        assert!(!filter.needs_settings(&job));
+        assert!(filter.matches(&job));
+        job.version = Some("15.6".to_string());
+        assert!(!filter.matches(&job));
+
+        let no_settings = QAFilter {
+            build: Some("2024*".to_string()),
+            ..Default::default()
+        };
+        assert!(!no_settings.needs_settings(&info("install_kde", "20240101")));
+    }
+
+    fn history(results: &[&str]) -> QAHistory {
+        QAHistory {
+            results: results.iter().map(|x| x.to_string()).collect(),
+            keys: HashSet::new(),
+        }
+    }
+
+    #[test]
+    fn flips() {
+        assert_eq!(history(&[]).flips(), 0);
+        assert_eq!(history(&["passed", "passed", "passed"]).flips(), 0);
+        // A regression, and its fix
+        assert_eq!(history(&["passed", "failed"]).flips(), 1);
+        assert_eq!(history(&["failed", "failed", "passed"]).flips(), 1);
+        assert_eq!(history(&["passed", "failed", "passed"]).flips(), 2);
+        // softfailed counts as passed, incompletes are ignored
+        assert_eq!(history(&["softfailed", "passed", "failed"]).flips(), 1);
+        assert_eq!(
+            history(&["passed", "incomplete", "passed", "timeout_exceeded"]).flips(),
+            0
+        );
+        assert_eq!(history(&["failed", "incomplete", "passed"]).flips(), 1);
+    }
+
+    #[test]
+    fn flaky() {
+        assert!(!history(&["passed", "failed", "failed"]).is_flaky());
+        assert!(!history(&["failed", "incomplete", "failed"]).is_flaky());
+        assert!(history(&["passed", "failed", "passed"]).is_flaky());
+        assert!(history(&["failed", "softfailed", "incomplete", "failed"]).is_flaky());
+    }
+
+    #[test]
+    fn history_is_pruned() {
+        let job = |id: &str| QAKey::Job(id.to_string());
+        let test = |name: &str| QATestId {
+            test: name.to_string(),
+            flavor: String::new(),
+            machine: String::new(),
+            arch: String::new(),
+        };
+        let mut all = HashMap::new();
+        let mut both = history(&["passed"]);
+        both.keys.extend([job("1"), job("2")]);
+        all.insert(test("both"), both);
+        let mut gone = history(&["failed"]);
+        gone.keys.insert(job("3"));
+        all.insert(test("gone"), gone);
+
+        prune_history(&mut all, &vec![job("2")].into_iter().collect());
+        assert_eq!(all.len(), 1);
+        assert_eq!(
+            all[&test("both")].keys,
+            vec![job("2")].into_iter().collect::<HashSet<_>>()
+        );
+
+        prune_history(&mut all, &HashSet::new());
+        assert!(all.is_empty());
+    }
+
+    #[test]
+    fn fetch_job_settings() {
+        let server = MockServer::start(|_| {
+            (
+                200,
+                r#"{"job": {"id": 42, "settings": {"DISTRI": "opensuse", "VERSION": "Tumbleweed", "BUILD": "20240101"}}}"#
+                    .to_string(),
+            )
+        });
+        let settings = fetch_settings(&server.url, 42).unwrap();
+        assert_eq!(settings.distri.as_deref(), Some("opensuse"));
+        assert_eq!(settings.version.as_deref(), Some("Tumbleweed"));
+        assert_eq!(server.paths(), ["/api/v1/jobs/42"]);
+    }
+
+    const DETAILS: &str = r#"{"job": {"id": 42, "testresults": [
+        {"name": "boot", "result": "passed", "details": [{"result": "ok", "num": 1}]},
+        {"name": "zypper_in", "result": "failed", "details": [
+            {"result": "ok", "num": 1},
+            {"result": "ok", "num": 2},
+            {"result": "fail", "num": 3},
+            {"result": "fail", "num": 4}
+        ]},
+        {"name": "firefox", "result": "failed", "details": [
+            {"result": "ok"},
+            {"result": "fail"}
+        ]},
+        {"name": "shutdown", "result": "failed"}
+    ]}}"#;
+
+    fn names_and_steps(modules: &[QAFailedModule]) -> Vec<(&str, usize)> {
+        modules.iter().map(|x| (x.name.as_str(), x.step)).collect()
+    }
+
+    #[test]
+    fn fetch_failed_modules_and_steps() {
+        let server = MockServer::start(|_| (200, DETAILS.to_string()));
+        let modules = fetch_failed_modules(&server.url, 42).unwrap();
+        // Steps without num are counted, modules without details point at their start
+        assert_eq!(
+            names_and_steps(&modules),
+            [("zypper_in", 3), ("firefox", 2), ("shutdown", 1)]
+        );
+        assert_eq!(server.paths(), ["/api/v1/jobs/42/details"]);
+    }
+
+    #[test]
+    fn failed_modules_are_cached() {
+        let server = MockServer::start(|_| (200, DETAILS.to_string()));
+        let state = Mutex::new(QAState::default());
+
+        let first = failed_modules(&state, &server.url, 42);
+        let second = failed_modules(&state, &server.url, 42);
+        assert_eq!(first.len(), 3);
+        assert_eq!(names_and_steps(&first), names_and_steps(&second));
+        assert_eq!(server.paths().len(), 1);
+
+        failed_modules(&state, &server.url, 43);
+        assert_eq!(server.paths().len(), 2);
+    }
+
+    #[test]
+    fn failed_modules_errors_are_not_cached() {
+        let server = MockServer::start(|_| (500, "oops".to_string()));
+        let state = Mutex::new(QAState::default());
+
+        assert!(fetch_failed_modules(&server.url, 42).is_err());
+        assert!(failed_modules(&state, &server.url, 42).is_empty());
+        assert!(failed_modules(&state, &server.url, 42).is_empty());
+        assert_eq!(server.paths().len(), 3);
+    }
+
+    #[test]
+    fn clones_keep_subscriptions_and_notify() {
+        let old = QAKey::Job("1".to_string());
+        let new = QAKey::Job("2".to_string());
+        let room = Target::Room("!room:example.org".to_string());
+        let user = Target::User("@alice:example.org".to_string());
+        let mut subscriptions = HashMap::new();
+        subscriptions.insert(
+            old.clone(),
+            vec![room.clone(), user.clone()].into_iter().collect(),
+        );
+        let mut notify = HashMap::new();
+        notify.insert(
+            (old.clone(), room.clone()),
+            vec!["@bob:example.org".to_string()],
+        );
+
+        let targets = move_job(&mut subscriptions, &mut notify, &old, &new).unwrap();
+        assert_eq!(targets.len(), 2);
+        assert!(!subscriptions.contains_key(&old));
+        assert_eq!(subscriptions[&new], targets);
+        assert_eq!(notify.len(), 1);
+        assert_eq!(notify[&(new.clone(), room)], vec!["@bob:example.org"]);
+
+        // Not subscribed
+        let other = QAKey::Job("3".to_string());
+        assert_eq!(
+            move_job(&mut subscriptions, &mut notify, &other, &old),
+            None
+        );
+        assert!(!subscriptions.contains_key(&old));
+    }
+}
@@ -0,0 +1,913 @@
+use crate::common::{data_file, html_escape, load_json, prepend_prefix, save_json};
+use crate::i18n::{format_time, tr, trf};
+use crate::settings::{
+    parse_time, parse_until, DigestPeriod, DigestSchedule, QuietHours, RoomSettings, Settings,
+    Timezone, WEEKDAYS,
+};
+use crate::transport::{RetryQueue, Transport};
+use chrono::{DateTime, Datelike, TimeZone};
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often the scheduler looks for due reports and suppressed events
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(10);
+/// Maximum number of events listed per kind in a report
+const DIGEST_SECTION_SIZE: usize = 20;
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [
+        ("digest", "Show if this room gets live events or reports."),
+        (
+            "digest daily HH:MM",
+            "Only post a daily report of all events in this room.",
+        ),
+        (
+            "digest weekly DAY HH:MM",
+            "Only post a weekly report (DAY is mon, tue, ...).",
+        ),
+        ("digest now", "Post the report collected so far."),
+        ("digest off", "Go back to live events."),
+        (
+            "mute 2h | mute until 18:00 | mute off",
+            "Drop all notifications in this room for a while.",
+        ),
+        (
+            "snooze URL 1d | snooze URL until tomorrow",
+            "Drop notifications of one subscription for a while.",
+        ),
+        ("unsnooze URL", "Get notifications of a subscription again."),
+        (
+            "quiet 22:00-07:00 | quiet off",
+            "Hold notifications during these hours, post them as one message afterwards.",
+        ),
+    ];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+/// What a notification is about, used to group reports
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    BuildFailed,
+    BuildFixed,
+    BuildSucceeded,
+    RequestChanged,
+    TestFailed,
+    TestPassed,
+    Other,
+}
+
+impl EventKind {
+    /// In the order they appear in reports
+    const ALL: [EventKind; 7] = [
+        EventKind::BuildFailed,
+        EventKind::BuildFixed,
+        EventKind::BuildSucceeded,
+        EventKind::RequestChanged,
+        EventKind::TestFailed,
+        EventKind::TestPassed,
+        EventKind::Other,
+    ];
+
+    fn title(&self) -> &'static str {
+        match self {
+            EventKind::BuildFailed => "Build failures",
+            EventKind::BuildFixed => "Fixed packages",
+            EventKind::BuildSucceeded => "Successful builds",
+            EventKind::RequestChanged => "Request changes",
+            EventKind::TestFailed => "Failed openQA tests",
+            EventKind::TestPassed => "Passed openQA tests",
+            EventKind::Other => "Other events",
+        }
+    }
+}
+
+/// Allowed notifications per room: a burst, then a steady rate
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub burst: f64,
+    pub per_minute: f64,
+}
+
+/// Token bucket of a room
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(limit: &RateLimit) -> Self {
+        Bucket {
+            tokens: limit.burst,
+            last: Instant::now(),
+        }
+    }
+
+    fn take(&mut self, limit: &RateLimit) -> bool {
+        let refill = self.last.elapsed().as_secs_f64() * limit.per_minute / 60.0;
+        self.tokens = (self.tokens + refill).min(limit.burst);
+        self.last = Instant::now();
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Buffered {
+    kind: EventKind,
+    /// First line of the plain message
+    line: String,
+}
+
+/// All notifications go through here, so rooms can decide how to receive them.
+/// Collected reports are stored in the XDG data-directory to survive restarts,
+/// by the scheduler, so busy rooms don't cause a write per event.
+#[derive(Clone)]
+pub struct Outbox {
+    sender: Arc<RetryQueue>,
+    limit: RateLimit,
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+    /// Notifications over the rate limit, posted as one summary later
+    suppressed: Arc<Mutex<HashMap<String, Vec<Buffered>>>>,
+    settings: Settings,
+    timezone: Timezone,
+    prefix: Option<String>,
+    digests: Arc<Mutex<HashMap<String, Vec<Buffered>>>>,
+    path: Option<PathBuf>,
+    /// Notifications held back during quiet hours
+    held: Arc<Mutex<HashMap<String, Vec<Buffered>>>>,
+    held_path: Option<PathBuf>,
+    /// If digests or held changed since they were saved
+    unsaved: Arc<AtomicBool>,
+}
+
+/// Most recent time the schedule was due, at or before now
+fn last_due(schedule: &DigestSchedule, now: DateTime<Timezone>) -> DateTime<Timezone> {
+    let timezone = now.timezone();
+    let mut date = now.date().naive_local();
+    // A week and a day is enough to find the last slot of any schedule
+    for _ in 0..8 {
+        let weekday_matches = match schedule.period {
+            DigestPeriod::Daily => true,
+            DigestPeriod::Weekly(day) => date.weekday().num_days_from_monday() == day,
+        };
+        if let Some(candidate) = timezone
+            .from_local_datetime(&date.and_hms(schedule.hour, schedule.minute, 0))
+            .single()
+        {
+            if weekday_matches && candidate <= now {
+                return candidate;
+            }
+        }
+        date = date.pred();
+    }
+    now
+}
+
+impl Outbox {
+    pub fn new(
+        transport: Arc<dyn Transport>,
+        settings: Settings,
+        timezone: Timezone,
+        prefix: Option<String>,
+        limit: RateLimit,
+    ) -> Self {
+        let path = data_file("outbox.json");
+        let digests: HashMap<String, Vec<Buffered>> = load_json(&path);
+        let held_path = data_file("held.json");
+        let held: HashMap<String, Vec<Buffered>> = load_json(&held_path);
+
+        Outbox {
+            sender: Arc::new(RetryQueue::new(transport)),
+            limit,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            suppressed: Arc::new(Mutex::new(HashMap::new())),
+            settings,
+            timezone,
+            prefix,
+            digests: Arc::new(Mutex::new(digests)),
+            path,
+            held: Arc::new(Mutex::new(held)),
+            held_path,
+            unsaved: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn now(&self) -> DateTime<Timezone> {
+        self.timezone.now()
+    }
+
+    /// Sends a notification to a room, or drops, holds or collects it, as the room wishes.
+    /// sources are the URLs of the subscriptions that caused it.
+    pub fn send(&self, room: &str, kind: EventKind, sources: &[String], plain: &str, html: &str) {
+        let settings = self.settings.get(room);
+        let now = self.now();
+        if settings.is_muted(now.timestamp())
+            || sources
+                .iter()
+                .any(|x| settings.is_snoozed(x, now.timestamp()))
+        {
+            return;
+        }
+
+        if settings.digest.is_some() {
+            self.buffer(&self.digests, true, room, kind, plain);
+            return;
+        }
+
+        if settings
+            .quiet_hours
+            .map(|x| x.contains(now))
+            .unwrap_or(false)
+        {
+            self.buffer(&self.held, true, room, kind, plain);
+            return;
+        }
+
+        if !self.take_token(room) {
+            self.buffer(&self.suppressed, false, room, kind, plain);
+            return;
+        }
+        self.sender.send(room, plain, html);
+    }
+
+    fn take_token(&self, room: &str) -> bool {
+        match self.buckets.lock() {
+            Ok(mut buckets) => buckets
+                .entry(room.to_string())
+                .or_insert_with(|| Bucket::new(&self.limit))
+                .take(&self.limit),
+            Err(_) => true,
+        }
+    }
+
+    /// Posts what got suppressed by the rate limit as one message, once the room may get one
+    fn send_suppressed(&self, room: &str) {
+        let has_suppressed = self
+            .suppressed
+            .lock()
+            .map(|x| x.get(room).map(|x| !x.is_empty()).unwrap_or(false))
+            .unwrap_or(false);
+        if !has_suppressed || !self.take_token(room) {
+            return;
+        }
+
+        let events = self.take_buffered(&self.suppressed, false, room);
+        println!("{} events to {} were suppressed", events.len(), room);
+        let lang = self.settings.language(room);
+        let (plain, html) = Self::generate_digest(
+            &lang,
+            &trf(
+                &lang,
+                "{} more events were suppressed, summary",
+                &[&events.len()],
+            ),
+            &events,
+        );
+        self.sender.send(room, &plain, &html);
+    }
+
+    /// persistent buffers get saved by the scheduler
+    fn buffer(
+        &self,
+        buffers: &Mutex<HashMap<String, Vec<Buffered>>>,
+        persistent: bool,
+        room: &str,
+        kind: EventKind,
+        plain: &str,
+    ) {
+        if let Ok(mut buffers) = buffers.lock() {
+            buffers.entry(room.to_string()).or_default().push(Buffered {
+                kind,
+                line: plain.lines().next().unwrap_or_default().to_string(),
+            });
+            if persistent {
+                self.unsaved.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    fn take_buffered(
+        &self,
+        buffers: &Mutex<HashMap<String, Vec<Buffered>>>,
+        persistent: bool,
+        room: &str,
+    ) -> Vec<Buffered> {
+        match buffers.lock() {
+            Ok(mut buffers) => {
+                let events = buffers.remove(room).unwrap_or_default();
+                if persistent && !events.is_empty() {
+                    self.unsaved.store(true, Ordering::SeqCst);
+                }
+                events
+            }
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Writes the collected reports and held events, if they changed
+    fn save(&self) {
+        if !self.unsaved.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        if let Ok(digests) = self.digests.lock() {
+            save_json(&self.path, &*digests);
+        }
+        if let Ok(held) = self.held.lock() {
+            save_json(&self.held_path, &*held);
+        }
+    }
+
+    fn generate_digest(lang: &str, headline: &str, events: &[Buffered]) -> (String, String) {
+        let mut counts = Vec::new();
+        let mut plain = String::new();
+        let mut html = String::new();
+
+        for kind in EventKind::ALL.iter() {
+            let lines = events
+                .iter()
+                .filter(|x| x.kind == *kind)
+                .map(|x| x.line.as_str())
+                .collect::<Vec<_>>();
+            if lines.is_empty() {
+                continue;
+            }
+            let title = tr(lang, kind.title());
+            counts.push(format!("{} {}", lines.len(), title.to_lowercase()));
+
+            let shown = &lines[..lines.len().min(DIGEST_SECTION_SIZE)];
+            let more = if lines.len() > shown.len() {
+                trf(lang, "... and {} more", &[&(lines.len() - shown.len())])
+            } else {
+                String::new()
+            };
+
+            plain += &format!("\n\n{} ({}):\n{}", title, lines.len(), shown.join("\n"));
+            html += &format!(
+                "<h4>{} ({})</h4><ul><li>{}</li></ul>",
+                title,
+                lines.len(),
+                shown
+                    .iter()
+                    .map(|x| html_escape(x))
+                    .collect::<Vec<_>>()
+                    .join("</li><li>")
+            );
+            if !more.is_empty() {
+                plain += &format!("\n{}", more);
+                html += &more;
+            }
+        }
+
+        let headline = format!("{}: {}", headline, counts.join(", "));
+        (
+            format!("{}{}", headline, plain),
+            format!("<strong>{}</strong>{}", headline, html),
+        )
+    }
+
+    /// Posts and clears the collected events of a room. Returns if there were any.
+    pub fn send_digest(&self, room: &str) -> bool {
+        let events = self.take_buffered(&self.digests, true, room);
+        if events.is_empty() {
+            return false;
+        }
+
+        println!("Sending report of {} events to {}", events.len(), room);
+        let lang = self.settings.language(room);
+        let (plain, html) =
+            Self::generate_digest(&lang, &tr(&lang, "Report since the last one"), &events);
+        self.sender.send(room, &plain, &html);
+        true
+    }
+
+    /// Posts everything held back during quiet hours as one message
+    fn send_catch_up(&self, room: &str) {
+        let events = self.take_buffered(&self.held, true, room);
+        if events.is_empty() {
+            return;
+        }
+
+        println!("Sending {} held events to {}", events.len(), room);
+        let lang = self.settings.language(room);
+        let (plain, html) =
+            Self::generate_digest(&lang, &tr(&lang, "During the quiet hours"), &events);
+        self.sender.send(room, &plain, &html);
+    }
+
+    pub fn snooze(&self, room: &str, url: &str, until: Option<DateTime<Timezone>>) {
+        let now = self.now().timestamp();
+        self.settings.update(room, |settings: &mut RoomSettings| {
+            // Forget about the ones that ran out
+            settings.snoozed.retain(|_, x| now < *x);
+            match until {
+                Some(until) => settings.snoozed.insert(url.to_string(), until.timestamp()),
+                None => settings.snoozed.remove(url),
+            };
+        });
+    }
+
+    /// Posts reports, catch-ups and summaries when they are due, in a thread of its own
+    pub fn start_scheduler(&self) {
+        let outbox = self.clone();
+        std::thread::spawn(move || {
+            let mut last_check = outbox.now();
+            loop {
+                std::thread::sleep(SCHEDULER_INTERVAL);
+                let now = outbox.now();
+                for (room, settings) in outbox.settings.all() {
+                    if let Some(schedule) = settings.digest {
+                        if last_due(&schedule, now) > last_check {
+                            outbox.send_digest(&room);
+                        }
+                    }
+                    if !settings
+                        .quiet_hours
+                        .map(|x| x.contains(now))
+                        .unwrap_or(false)
+                    {
+                        outbox.send_catch_up(&room);
+                    }
+                }
+
+                let suppressed_rooms = outbox
+                    .suppressed
+                    .lock()
+                    .map(|x| x.keys().cloned().collect::<Vec<_>>())
+                    .unwrap_or_default();
+                for room in suppressed_rooms {
+                    outbox.send_suppressed(&room);
+                }
+                outbox.sender.retry_due(Instant::now());
+                outbox.save();
+                last_check = now;
+            }
+        });
+    }
+
+    /// strict is false without a prefix, where chat that doesn't parse is ignored
+    fn handle_digest_command(&self, room: &str, words: &[&str], strict: bool) -> Option<String> {
+        let lang = self.settings.language(room);
+        let usage = |text| if strict { Some(tr(&lang, text)) } else { None };
+        let schedule = match words {
+            [] => {
+                return Some(match self.settings.get(room).digest {
+                    Some(x) => trf(&lang, "This room gets a report {}", &[&x.describe(&lang)]),
+                    None => tr(&lang, "This room gets live events"),
+                })
+            }
+            ["now"] => {
+                if !self.send_digest(room) {
+                    return Some(tr(&lang, "Nothing collected since the last report"));
+                }
+                return Some(String::new());
+            }
+            ["off"] => None,
+            ["daily", time] => parse_time(time).map(|(hour, minute)| DigestSchedule {
+                period: DigestPeriod::Daily,
+                hour,
+                minute,
+            }),
+            ["weekly", day, time] => WEEKDAYS
+                .iter()
+                .position(|x| day.to_lowercase().starts_with(x))
+                .and_then(|day| {
+                    parse_time(time).map(|(hour, minute)| DigestSchedule {
+                        period: DigestPeriod::Weekly(day as u32),
+                        hour,
+                        minute,
+                    })
+                }),
+            _ => return usage("Usage: digest daily HH:MM | digest weekly DAY HH:MM | digest off"),
+        };
+
+        if schedule.is_none() && words != ["off"] {
+            return usage(
+                "Sorry, I could not parse that time. Please use e.g. 'digest daily 09:00'",
+            );
+        }
+
+        self.settings.update(room, |settings: &mut RoomSettings| {
+            settings.digest = schedule
+        });
+        Some(match schedule {
+            Some(x) => trf(
+                &lang,
+                "From now on, this room gets a report {} ({})",
+                &[&x.describe(&lang), &self.timezone],
+            ),
+            None => {
+                // Don't lose what was collected so far
+                self.send_digest(room);
+                tr(&lang, "From now on, this room gets live events")
+            }
+        })
+    }
+
+    fn handle_mute_command(&self, room: &str, words: &[&str], strict: bool) -> Option<String> {
+        let lang = self.settings.language(room);
+        let muted_until = match words {
+            [] => {
+                return Some(match self.settings.get(room).muted_until {
+                    Some(x) if self.now().timestamp() < x => trf(
+                        &lang,
+                        "This room is muted until {}",
+                        &[&format_time(&lang, self.timezone.timestamp(x, 0))],
+                    ),
+                    _ => tr(&lang, "This room is not muted"),
+                })
+            }
+            ["off"] => None,
+            _ => match parse_until(words, self.now()) {
+                Some(x) => Some(x),
+                None if strict => {
+                    return Some(tr(
+                        &lang,
+                        "Usage: mute 2h | mute until 18:00 | mute until tomorrow | mute off",
+                    ))
+                }
+                None => return None,
+            },
+        };
+
+        self.settings.update(room, |settings: &mut RoomSettings| {
+            settings.muted_until = muted_until.map(|x| x.timestamp())
+        });
+        Some(match muted_until {
+            Some(x) => trf(&lang, "Muted until {}", &[&format_time(&lang, x)]),
+            None => tr(&lang, "Not muted anymore"),
+        })
+    }
+
+    fn handle_quiet_command(&self, room: &str, words: &[&str], strict: bool) -> Option<String> {
+        let lang = self.settings.language(room);
+        let usage = || {
+            if strict {
+                Some(tr(&lang, "Usage: quiet HH:MM-HH:MM | quiet off"))
+            } else {
+                None
+            }
+        };
+        let quiet_hours = match words {
+            [] => {
+                return Some(match self.settings.get(room).quiet_hours {
+                    Some(x) => trf(
+                        &lang,
+                        "Quiet hours of this room: {} ({})",
+                        &[&x, &self.timezone],
+                    ),
+                    None => tr(&lang, "This room has no quiet hours"),
+                })
+            }
+            ["off"] => None,
+            [range] => {
+                let mut times = range.split('-').map(parse_time);
+                match (times.next(), times.next(), times.next()) {
+                    (Some(Some(start)), Some(Some(end)), None) => Some(QuietHours { start, end }),
+                    _ => return usage(),
+                }
+            }
+            _ => return usage(),
+        };
+
+        self.settings.update(room, |settings: &mut RoomSettings| {
+            settings.quiet_hours = quiet_hours
+        });
+        Some(match quiet_hours {
+            Some(x) => trf(
+                &lang,
+                "Notifications will be held from {} ({})",
+                &[&x, &self.timezone],
+            ),
+            None => {
+                self.send_catch_up(room);
+                tr(&lang, "No more quiet hours")
+            }
+        })
+    }
+
+    /// The answer to a message, or None if it is not meant for the outbox
+    fn answer(&self, room: &str, body: &str) -> Option<String> {
+        let prefix = self.prefix.as_deref().unwrap_or("");
+        let body = body.trim();
+        if !body.starts_with(prefix) {
+            return None;
+        }
+        // Without a prefix, chat like 'quiet please' is no command
+        let strict = !prefix.is_empty();
+
+        let words = body[prefix.len()..].split_whitespace().collect::<Vec<_>>();
+        match words.split_first() {
+            Some((&"digest", rest)) => self.handle_digest_command(room, rest, strict),
+            Some((&"mute", rest)) => self.handle_mute_command(room, rest, strict),
+            Some((&"unmute", [])) => self.handle_mute_command(room, &["off"], strict),
+            Some((&"quiet", rest)) => self.handle_quiet_command(room, rest, strict),
+            _ => None,
+        }
+    }
+}
+
+impl MessageHandler for Outbox {
+    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
+        let answer = match self.answer(&message.room, &message.body) {
+            Some(x) => x,
+            None => return HandleResult::ContinueHandling,
+        };
+
+        if !answer.is_empty() {
+            bot.send_message(&answer, &message.room, MessageType::TextMessage);
+        }
+        HandleResult::StopHandling
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::FakeTransport;
+    use chrono::{FixedOffset, Timelike};
+
+    const ROOM: &str = "!room:example.org";
+    const URL: &str = "https://build.opensuse.org/package/show/devel:foo/bar";
+
+    /// An outbox which stores nothing, with a burst of 3
+    fn outbox(transport: &Arc<FakeTransport>, prefix: Option<&str>) -> Outbox {
+        Outbox {
+            sender: Arc::new(RetryQueue::new(transport.clone())),
+            limit: RateLimit {
+                burst: 3.0,
+                per_minute: 1.0,
+            },
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+            suppressed: Arc::new(Mutex::new(HashMap::new())),
+            settings: Settings::default(),
+            timezone: Timezone::Fixed(FixedOffset::east(3600)),
+            prefix: prefix.map(|x| x.to_string()),
+            digests: Arc::new(Mutex::new(HashMap::new())),
+            path: None,
+            held: Arc::new(Mutex::new(HashMap::new())),
+            held_path: None,
+            unsaved: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    fn send(outbox: &Outbox, text: &str) {
+        outbox.send(ROOM, EventKind::BuildFailed, &[URL.to_string()], text, text);
+    }
+
+    fn sent(transport: &FakeTransport) -> Vec<String> {
+        transport.sent().into_iter().map(|(_, x)| x).collect()
+    }
+
+    #[test]
+    fn live_events_are_sent() {
+        let transport = FakeTransport::limited(0);
+        let outbox = outbox(&transport, None);
+        send(&outbox, "Build failed: devel:foo/bar");
+        assert_eq!(
+            transport.sent(),
+            vec![(ROOM.to_string(), "Build failed: devel:foo/bar".to_string())]
+        );
+    }
+
+    #[test]
+    fn muted_rooms_get_nothing() {
+        let transport = FakeTransport::limited(0);
+        let outbox = outbox(&transport, None);
+        assert_eq!(
+            outbox.answer(ROOM, "mute 2h").unwrap(),
+            format!(
+                "Muted until {}",
+                format_time("en", outbox.now() + chrono::Duration::hours(2))
+            )
+        );
+        send(&outbox, "one");
+        assert!(transport.sent().is_empty());
+
+        outbox.answer(ROOM, "unmute").unwrap();
+        send(&outbox, "two");
+        assert_eq!(sent(&transport), vec!["two"]);
+    }
+
+    #[test]
+    fn snoozed_subscriptions_get_nothing() {
+        let transport = FakeTransport::limited(0);
+        let outbox = outbox(&transport, None);
+        outbox.snooze(ROOM, URL, Some(outbox.now() + chrono::Duration::hours(1)));
+        send(&outbox, "snoozed");
+        outbox.send(ROOM, EventKind::Other, &["sr#1".to_string()], "other", "");
+        // Only one of the subscriptions has to be snoozed
+        outbox.send(
+            ROOM,
+            EventKind::Other,
+            &["sr#1".to_string(), URL.to_string()],
+            "both",
+            "",
+        );
+        assert_eq!(sent(&transport), vec!["other"]);
+
+        outbox.snooze(ROOM, URL, None);
+        send(&outbox, "unsnoozed");
+        assert_eq!(sent(&transport), vec!["other", "unsnoozed"]);
+    }
+
+    #[test]
+    fn digests_collect_events() {
+        let transport = FakeTransport::limited(0);
+        let outbox = outbox(&transport, None);
+        outbox.answer(ROOM, "digest daily 09:00").unwrap();
+        send(&outbox, "Build failed: devel:foo/bar\nlog excerpt");
+        outbox.send(
+            ROOM,
+            EventKind::RequestChanged,
+            &[],
+            "Request 1 was accepted",
+            "",
+        );
+        assert!(transport.sent().is_empty());
+        assert!(outbox.unsaved.load(Ordering::SeqCst));
+
+        assert_eq!(outbox.answer(ROOM, "digest now").unwrap(), "");
+        assert_eq!(
+            sent(&transport),
+            vec![
+                "Report since the last one: 1 build failures, 1 request changes\n\n\
+                  Build failures (1):\nBuild failed: devel:foo/bar\n\n\
+                  Request changes (1):\nRequest 1 was accepted"
+            ]
+        );
+        assert_eq!(
+            outbox.answer(ROOM, "digest now").unwrap(),
+            "Nothing collected since the last report"
+        );
+
+        // Going back to live events posts what was collected so far
+        send(&outbox, "collected");
+        outbox.answer(ROOM, "digest off").unwrap();
+        send(&outbox, "live");
+        let sent = sent(&transport);
+        assert_eq!(sent.len(), 3);
+        assert!(sent[1].ends_with("Build failures (1):\ncollected"));
+        assert_eq!(sent[2], "live");
+    }
+
+    #[test]
+    fn quiet_hours_hold_events() {
+        let transport = FakeTransport::limited(0);
+        let outbox = outbox(&transport, None);
+        // Starting now, so the test can't run into the end
+        let now = outbox.now();
+        let command = format!(
+            "quiet {:02}:{:02}-{:02}:00",
+            now.hour(),
+            now.minute(),
+            (now.hour() + 2) % 24
+        );
+        outbox.answer(ROOM, &command).unwrap();
+        send(&outbox, "held");
+        assert!(transport.sent().is_empty());
+
+        outbox.answer(ROOM, "quiet off").unwrap();
+        assert_eq!(
+            sent(&transport),
+            vec!["During the quiet hours: 1 build failures\n\nBuild failures (1):\nheld"]
+        );
+        send(&outbox, "live");
+        assert_eq!(sent(&transport).len(), 2);
+    }
+
+    #[test]
+    fn events_over_the_limit_are_suppressed() {
+        let transport = FakeTransport::limited(0);
+        let outbox = outbox(&transport, None);
+        for i in 0..5 {
+            send(&outbox, &format!("event {}", i));
+        }
+        assert_eq!(sent(&transport), vec!["event 0", "event 1", "event 2"]);
+
+        // Not before the room may get a message again
+        outbox.send_suppressed(ROOM);
+        assert_eq!(sent(&transport).len(), 3);
+        outbox.buckets.lock().unwrap().get_mut(ROOM).unwrap().tokens = 1.0;
+        outbox.send_suppressed(ROOM);
+        assert_eq!(
+            sent(&transport)[3],
+            "2 more events were suppressed, summary: 2 build failures\n\n\
+             Build failures (2):\nevent 3\nevent 4"
+        );
+    }
+
+    #[test]
+    fn chat_without_prefix() {
+        let transport = FakeTransport::limited(0);
+        let outbox = outbox(&transport, None);
+        for chat in [
+            "quiet please",
+            "mute that thread",
+            "digest this",
+            "digest daily at noon",
+        ] {
+            assert_eq!(outbox.answer(ROOM, chat), None, "{}", chat);
+        }
+        assert!(outbox.settings.get(ROOM).quiet_hours.is_none());
+        assert!(outbox.settings.get(ROOM).muted_until.is_none());
+
+        let outbox = self::outbox(&transport, Some("!bot "));
+        assert_eq!(outbox.answer(ROOM, "quiet please"), None);
+        assert_eq!(
+            outbox.answer(ROOM, "!bot quiet please").as_deref(),
+            Some("Usage: quiet HH:MM-HH:MM | quiet off")
+        );
+        assert_eq!(
+            outbox.answer(ROOM, "!bot digest this").as_deref(),
+            Some("Usage: digest daily HH:MM | digest weekly DAY HH:MM | digest off")
+        );
+    }
+
+    const LIMIT: RateLimit = RateLimit {
+        burst: 2.0,
+        per_minute: 6.0,
+    };
+
+    #[test]
+    fn bucket_allows_a_burst() {
+        let mut bucket = Bucket::new(&LIMIT);
+        assert!(bucket.take(&LIMIT));
+        assert!(bucket.take(&LIMIT));
+        assert!(!bucket.take(&LIMIT));
+        assert!(!bucket.take(&LIMIT));
+    }
+
+    #[test]
+    fn bucket_refills() {
+        let mut bucket = Bucket::new(&LIMIT);
+        bucket.tokens = 0.0;
+        // 6 per minute is one every 10 seconds
+        bucket.last = Instant::now() - Duration::from_secs(10);
+        assert!(bucket.take(&LIMIT));
+        assert!(!bucket.take(&LIMIT));
+
+        bucket.last = Instant::now() - Duration::from_secs(5);
+        assert!(!bucket.take(&LIMIT));
+        // The half token of before is kept
+        bucket.last = Instant::now() - Duration::from_secs(5);
+        assert!(bucket.take(&LIMIT));
+    }
+
+    #[test]
+    fn bucket_holds_at_most_a_burst() {
+        let mut bucket = Bucket::new(&LIMIT);
+        bucket.tokens = 0.0;
+        bucket.last = Instant::now() - Duration::from_secs(3600);
+        assert!(bucket.take(&LIMIT));
+        assert!(bucket.take(&LIMIT));
+        assert!(!bucket.take(&LIMIT));
+    }
+
+    fn schedule(period: DigestPeriod) -> DigestSchedule {
+        DigestSchedule {
+            period,
+            hour: 9,
+            minute: 0,
+        }
+    }
+
+    #[test]
+    fn last_due_follows_daylight_saving_time() {
+        let berlin = Timezone::Named(chrono_tz::Europe::Berlin);
+        let cet = FixedOffset::east(3600);
+        let cest = FixedOffset::east(2 * 3600);
+        // Monday after the switch to summer time
+        let now = berlin.ymd(2024, 4, 1).and_hms(8, 0, 0);
+
+        let daily = schedule(DigestPeriod::Daily);
+        assert_eq!(
+            last_due(&daily, now),
+            cest.ymd(2024, 3, 31).and_hms(9, 0, 0)
+        );
+        let due = berlin.ymd(2024, 4, 1).and_hms(9, 0, 0);
+        assert_eq!(last_due(&daily, due), cest.ymd(2024, 4, 1).and_hms(9, 0, 0));
+
+        // Friday was still in winter time
+        let weekly = schedule(DigestPeriod::Weekly(4));
+        assert_eq!(
+            last_due(&weekly, now),
+            cet.ymd(2024, 3, 29).and_hms(9, 0, 0)
+        );
+
+        let fixed = Timezone::Fixed(cet);
+        let now = fixed.ymd(2024, 4, 1).and_hms(8, 0, 0);
+        assert_eq!(last_due(&daily, now), cet.ymd(2024, 3, 31).and_hms(9, 0, 0));
+    }
+}
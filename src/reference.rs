@@ -0,0 +1,310 @@
+/// What an OBS or openQA URL (or shorthand) points to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reference {
+    Package {
+        project: String,
+        package: String,
+    },
+    Request(String),
+    QAJob(String),
+    QAGroup(String),
+    /// Query of a /tests/overview page
+    QAOverview(Vec<(String, String)>),
+}
+
+/// A parsed URL or shorthand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    /// None for shorthands, which go to the first backend
+    pub host: Option<String>,
+    pub reference: Reference,
+}
+
+impl Link {
+    /// If the link is on this backend, e.g. "opensuse.org"
+    pub fn is_on(&self, domain: &str) -> bool {
+        match &self.host {
+            None => true,
+            Some(host) => host == domain || host.ends_with(&format!(".{}", domain)),
+        }
+    }
+}
+
+/// Decodes %XX-escapes, like %3A in project names
+fn decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = text
+            .get(i + 1..i + 3)
+            .and_then(|x| u8::from_str_radix(x, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(x)) => {
+                res.push(x);
+                i += 3;
+            }
+            (x, _) => {
+                res.push(x);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&res).into_owned()
+}
+
+fn is_id(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Characters OBS allows in project and package names
+fn is_name(text: &str) -> bool {
+    !text.is_empty()
+        && text
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.:+".contains(c))
+}
+
+fn package(project: &str, package: &str) -> Option<Reference> {
+    if !is_name(project) || !is_name(package) {
+        return None;
+    }
+    Some(Reference::Package {
+        project: project.to_string(),
+        package: package.to_string(),
+    })
+}
+
+/// The path of an OBS (web-UI or API) or openQA URL
+fn parse_path(segments: &[String], query: &str) -> Option<Reference> {
+    let segments = segments.iter().map(|x| x.as_str()).collect::<Vec<_>>();
+    match segments.as_slice() {
+        // show, live_build_log, binaries, view_file, rdiff, revisions, ...
+        ["package", _, project, name, ..] => package(project, name),
+        ["projects", project, "packages", name, ..] => package(project, name),
+        ["source", project, name, ..] => package(project, name),
+        ["request", "show", id, ..] | ["requests", id, ..] | ["request", id, ..] if is_id(id) => {
+            Some(Reference::Request(id.to_string()))
+        }
+        ["tests", "overview"] => {
+            let pairs = query
+                .split('&')
+                .filter_map(|x| x.split_once('='))
+                .filter(|(_, value)| !value.is_empty())
+                // In queries, '+' is a space (a literal '+' is %2B)
+                .map(|(name, value)| (decode(name), decode(&value.replace('+', " "))))
+                .collect::<Vec<_>>();
+            Some(Reference::QAOverview(pairs))
+        }
+        ["tests", id, ..] | ["api", "v1", "jobs", id, ..] if is_id(id) => {
+            Some(Reference::QAJob(id.to_string()))
+        }
+        [short] if short.starts_with('t') && is_id(&short[1..]) => {
+            Some(Reference::QAJob(short[1..].to_string()))
+        }
+        ["group_overview", id, ..] if is_id(id) => Some(Reference::QAGroup(id.to_string())),
+        _ => None,
+    }
+}
+
+fn split_path(path: &str) -> Vec<String> {
+    path.split('/')
+        .filter(|x| !x.is_empty())
+        .map(decode)
+        .collect()
+}
+
+/// Parses URLs like https://build.opensuse.org/package/show/PROJECT/PACKAGE or
+/// https://openqa.opensuse.org/tests/1234 and shorthands like sr#1234, openqa#1234,
+/// obs://PROJECT/PACKAGE or PROJECT/PACKAGE (as osc takes them)
+pub fn parse(text: &str) -> Option<Link> {
+    let text = text.trim();
+    let shorthand = |reference| {
+        Some(Link {
+            host: None,
+            reference,
+        })
+    };
+
+    for (prefix, request) in [("sr#", true), ("mr#", true), ("openqa#", false)] {
+        if let Some(id) = text.strip_prefix(prefix) {
+            if !is_id(id) {
+                return None;
+            }
+            let id = id.to_string();
+            return shorthand(if request {
+                Reference::Request(id)
+            } else {
+                Reference::QAJob(id)
+            });
+        }
+    }
+
+    if let Some(path) = text.strip_prefix("obs://") {
+        let segments = split_path(path);
+        return match segments.as_slice() {
+            // obs://build.opensuse.org/PROJECT/PACKAGE
+            [host, project, name, ..] if host.contains('.') && !host.contains(':') => Some(Link {
+                host: Some(host.to_lowercase()),
+                reference: package(project, name)?,
+            }),
+            [project, name, ..] => shorthand(package(project, name)?),
+            _ => None,
+        };
+    }
+
+    if let Some((scheme, rest)) = text.split_once("://") {
+        if scheme != "https" && scheme != "http" {
+            return None;
+        }
+        let rest = rest.split('#').next().unwrap_or("");
+        let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+        // Strip away logins and ports
+        let host = host.rsplit('@').next().unwrap_or(host);
+        let host = host.split(':').next().unwrap_or(host).to_lowercase();
+        if host.is_empty() {
+            return None;
+        }
+        return Some(Link {
+            host: Some(host),
+            reference: parse_path(&split_path(path), query)?,
+        });
+    }
+
+    match text.split('/').collect::<Vec<_>>().as_slice() {
+        [project, name] => shorthand(package(project, name)?),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkg(project: &str, package: &str) -> Reference {
+        Reference::Package {
+            project: project.to_string(),
+            package: package.to_string(),
+        }
+    }
+
+    fn req(id: &str) -> Reference {
+        Reference::Request(id.to_string())
+    }
+
+    fn job(id: &str) -> Reference {
+        Reference::QAJob(id.to_string())
+    }
+
+    #[test]
+    fn parsing() {
+        let obs = Some("build.opensuse.org");
+        let api = Some("api.opensuse.org");
+        let openqa = Some("openqa.opensuse.org");
+        let factory = pkg("openSUSE:Factory", "hello");
+        let cases = vec![
+            // OBS web-UI
+            ("https://build.opensuse.org/package/show/openSUSE:Factory/hello", obs, factory.clone()),
+            ("https://build.opensuse.org/package/show/openSUSE:Factory/hello?rev=3", obs, factory.clone()),
+            ("https://build.opensuse.org/package/show/openSUSE:Factory/hello#files", obs, factory.clone()),
+            ("https://build.opensuse.org/package/show/openSUSE:Factory/hello/", obs, factory.clone()),
+            ("https://build.opensuse.org/package/show/openSUSE%3AFactory/hello", obs, factory.clone()),
+            ("http://build.opensuse.org/package/show/openSUSE:Factory/hello", obs, factory.clone()),
+            (
+                "https://build.opensuse.org/package/live_build_log/openSUSE:Factory/hello/standard/x86_64",
+                obs,
+                factory.clone(),
+            ),
+            (
+                "https://build.opensuse.org/projects/openSUSE:Factory/packages/hello/files/hello.spec",
+                obs,
+                factory.clone(),
+            ),
+            ("https://build.opensuse.org/request/show/1234", obs, req("1234")),
+            ("https://build.opensuse.org/requests/1234/changes", obs, req("1234")),
+            ("https://user@Build.openSUSE.org:443/request/show/1", obs, req("1")),
+            // OBS API
+            ("https://api.opensuse.org/source/openSUSE:Factory/hello", api, factory.clone()),
+            ("https://api.opensuse.org/request/1234", api, req("1234")),
+            // openQA
+            ("https://openqa.opensuse.org/tests/1234", openqa, job("1234")),
+            ("https://openqa.opensuse.org/tests/1234#step/boot/2", openqa, job("1234")),
+            ("https://openqa.opensuse.org/api/v1/jobs/1234", openqa, job("1234")),
+            ("https://openqa.opensuse.org/t1234", openqa, job("1234")),
+            ("https://openqa.opensuse.org/group_overview/5", openqa, Reference::QAGroup("5".to_string())),
+            (
+                "https://openqa.opensuse.org/tests/overview?distri=opensuse&build=&flavor=DVD+Live%2B&version=Tumbleweed",
+                openqa,
+                Reference::QAOverview(vec![
+                    ("distri".to_string(), "opensuse".to_string()),
+                    ("flavor".to_string(), "DVD Live+".to_string()),
+                    ("version".to_string(), "Tumbleweed".to_string()),
+                ]),
+            ),
+            // Shorthands
+            ("sr#1234", None, req("1234")),
+            ("mr#1234", None, req("1234")),
+            (" sr#1234 ", None, req("1234")),
+            ("openqa#1234", None, job("1234")),
+            ("obs://build.opensuse.org/openSUSE:Factory/hello", obs, factory.clone()),
+            ("obs://openSUSE:Factory/hello", None, factory.clone()),
+            ("openSUSE:Factory/hello", None, factory),
+        ];
+        for (text, host, reference) in cases {
+            assert_eq!(
+                parse(text),
+                Some(Link {
+                    host: host.map(|x| x.to_string()),
+                    reference
+                }),
+                "{}",
+                text
+            );
+        }
+
+        let invalid = [
+            "",
+            "hello",
+            "a/b/c",
+            "sr#",
+            "sr#12a",
+            "openqa#x",
+            "obs://hello",
+            "ftp://build.opensuse.org/package/show/openSUSE:Factory/hello",
+            "https:///request/show/1",
+            "https://build.opensuse.org/",
+            "https://build.opensuse.org/request/show/abc",
+            "https://build.opensuse.org/package/show/open%20SUSE/hello",
+            "https://openqa.opensuse.org/tests/abc",
+            "https://openqa.opensuse.org/t",
+        ];
+        for text in invalid {
+            assert_eq!(parse(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn hosts() {
+        let link = |host: Option<&str>| Link {
+            host: host.map(|x| x.to_string()),
+            reference: req("1"),
+        };
+        assert!(link(Some("build.opensuse.org")).is_on("opensuse.org"));
+        assert!(link(Some("opensuse.org")).is_on("opensuse.org"));
+        assert!(!link(Some("build.opensuse.org")).is_on("suse.de"));
+        assert!(!link(Some("build.notopensuse.org")).is_on("opensuse.org"));
+        assert!(!link(Some("opensuse.org.example.com")).is_on("opensuse.org"));
+        // Shorthands are on every backend
+        assert!(link(None).is_on("opensuse.org"));
+        assert!(link(None).is_on("suse.de"));
+    }
+
+    #[test]
+    fn decoding() {
+        assert_eq!(decode("openSUSE%3AFactory"), "openSUSE:Factory");
+        assert_eq!(decode("100%"), "100%");
+        assert_eq!(decode("%zz%41"), "%zzA");
+        assert_eq!(decode("a+b"), "a+b");
+    }
+}
@@ -0,0 +1,463 @@
+use crate::common::{data_file, load_json, save_json};
+use crate::i18n::{tr, trf, DEFAULT_LANGUAGE};
+use chrono::{
+    DateTime, Datelike, Duration, FixedOffset, LocalResult, NaiveDate, NaiveDateTime, Offset,
+    TimeZone, Timelike, Utc,
+};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+pub const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+/// Longest duration 'mute' and 'snooze' take, like "30d"
+const MAX_UNTIL_SECONDS: i64 = 30 * 24 * 3600;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum DigestPeriod {
+    Daily,
+    /// Days since monday
+    Weekly(u32),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct DigestSchedule {
+    pub period: DigestPeriod,
+    pub hour: u32,
+    pub minute: u32,
+}
+
+impl DigestSchedule {
+    /// E.g. "daily at 09:00" or "weekly on mon at 09:00", in the language
+    pub fn describe(&self, lang: &str) -> String {
+        let time = format!("{:02}:{:02}", self.hour, self.minute);
+        match self.period {
+            DigestPeriod::Daily => trf(lang, "daily at {}", &[&time]),
+            DigestPeriod::Weekly(day) => trf(
+                lang,
+                "weekly on {} at {}",
+                &[&tr(lang, WEEKDAYS[day as usize % 7]), &time],
+            ),
+        }
+    }
+}
+
+/// Time of day from start to end (which may be on the next day)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct QuietHours {
+    pub start: (u32, u32),
+    pub end: (u32, u32),
+}
+
+impl QuietHours {
+    pub fn contains<T: TimeZone>(&self, now: DateTime<T>) -> bool {
+        let now = (now.hour(), now.minute());
+        if self.start <= self.end {
+            self.start <= now && now < self.end
+        } else {
+            self.start <= now || now < self.end
+        }
+    }
+}
+
+impl std::fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start.0, self.start.1, self.end.0, self.end.1
+        )
+    }
+}
+
+/// Everything that can be configured per room
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RoomSettings {
+    /// If set, events are collected and posted as a report instead
+    pub digest: Option<DigestSchedule>,
+    /// Unix timestamp until which all notifications are dropped
+    pub muted_until: Option<i64>,
+    /// Subscription URL -> unix timestamp until which its notifications are dropped
+    pub snoozed: HashMap<String, i64>,
+    /// Notifications are held during these hours and posted as one message afterwards
+    pub quiet_hours: Option<QuietHours>,
+    /// Message templates to use instead of the default ones
+    pub template_set: Option<String>,
+    /// Language of replies and notifications, English if unset
+    pub language: Option<String>,
+}
+
+impl RoomSettings {
+    pub fn is_muted(&self, now: i64) -> bool {
+        self.muted_until.map(|x| now < x).unwrap_or(false)
+    }
+
+    pub fn is_snoozed(&self, url: &str, now: i64) -> bool {
+        self.snoozed.get(url).map(|x| now < *x).unwrap_or(false)
+    }
+}
+
+/// Settings of all rooms, stored in the XDG data-directory
+#[derive(Clone, Default)]
+pub struct Settings {
+    rooms: Arc<Mutex<HashMap<String, RoomSettings>>>,
+    path: Option<PathBuf>,
+}
+
+impl Settings {
+    pub fn new() -> Self {
+        let path = data_file("room_settings.json");
+        let rooms: HashMap<String, RoomSettings> = load_json(&path);
+
+        Settings {
+            rooms: Arc::new(Mutex::new(rooms)),
+            path,
+        }
+    }
+
+    pub fn get(&self, room: &str) -> RoomSettings {
+        self.rooms
+            .lock()
+            .ok()
+            .and_then(|x| x.get(room).cloned())
+            .unwrap_or_default()
+    }
+
+    pub fn language(&self, room: &str) -> String {
+        self.get(room)
+            .language
+            .unwrap_or_else(|| DEFAULT_LANGUAGE.to_string())
+    }
+
+    pub fn update<F: FnOnce(&mut RoomSettings)>(&self, room: &str, f: F) {
+        if let Ok(mut rooms) = self.rooms.lock() {
+            f(rooms.entry(room.to_string()).or_default());
+            save_json(&self.path, &*rooms);
+        }
+    }
+
+    /// All rooms and their settings
+    pub fn all(&self) -> Vec<(String, RoomSettings)> {
+        self.rooms
+            .lock()
+            .map(|x| x.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+            .unwrap_or_default()
+    }
+}
+
+/// Parses "HH:MM"
+pub fn parse_time(text: &str) -> Option<(u32, u32)> {
+    let mut parts = text.split(':');
+    let hour = parts.next()?.parse::<u32>().ok()?;
+    let minute = parts.next()?.parse::<u32>().ok()?;
+    if parts.next().is_some() || hour > 23 || minute > 59 {
+        return None;
+    }
+    Some((hour, minute))
+}
+
+/// Parses a timezone like "+02:00" or "-05:30"
+fn parse_offset(text: &str) -> Option<FixedOffset> {
+    let text = text.trim();
+    let sign = match text.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    let (hour, minute) = parse_time(&text[1..])?;
+    FixedOffset::east_opt(sign * (hour * 3600 + minute * 60) as i32)
+}
+
+/// The timezone of reports, quiet hours and 'until' times: a fixed offset like +02:00,
+/// or a region like Europe/Berlin, which follows daylight saving time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Timezone {
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+/// Offset of a Timezone at some time. Keeps the Timezone, so DateTime::timezone() does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimezoneOffset {
+    timezone: Timezone,
+    offset: FixedOffset,
+}
+
+impl Offset for TimezoneOffset {
+    fn fix(&self) -> FixedOffset {
+        self.offset
+    }
+}
+
+impl std::fmt::Display for TimezoneOffset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.offset)
+    }
+}
+
+impl Timezone {
+    /// Parses an offset like "+02:00" or a name like "Europe/Berlin"
+    pub fn parse(text: &str) -> Option<Self> {
+        let text = text.trim();
+        parse_offset(text)
+            .map(Timezone::Fixed)
+            .or_else(|| text.parse::<Tz>().ok().map(Timezone::Named))
+    }
+
+    pub fn now(&self) -> DateTime<Timezone> {
+        Utc::now().with_timezone(self)
+    }
+
+    fn with_offset(&self, offset: FixedOffset) -> TimezoneOffset {
+        TimezoneOffset {
+            timezone: *self,
+            offset,
+        }
+    }
+}
+
+impl TimeZone for Timezone {
+    type Offset = TimezoneOffset;
+
+    fn from_offset(offset: &TimezoneOffset) -> Self {
+        offset.timezone
+    }
+
+    fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<TimezoneOffset> {
+        match self {
+            Timezone::Fixed(x) => x.offset_from_local_date(local).map(|x| self.with_offset(x)),
+            Timezone::Named(x) => x
+                .offset_from_local_date(local)
+                .map(|x| self.with_offset(x.fix())),
+        }
+    }
+
+    fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<TimezoneOffset> {
+        match self {
+            Timezone::Fixed(x) => x
+                .offset_from_local_datetime(local)
+                .map(|x| self.with_offset(x)),
+            Timezone::Named(x) => x
+                .offset_from_local_datetime(local)
+                .map(|x| self.with_offset(x.fix())),
+        }
+    }
+
+    fn offset_from_utc_date(&self, utc: &NaiveDate) -> TimezoneOffset {
+        match self {
+            Timezone::Fixed(x) => self.with_offset(*x),
+            Timezone::Named(x) => self.with_offset(x.offset_from_utc_date(utc).fix()),
+        }
+    }
+
+    fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> TimezoneOffset {
+        match self {
+            Timezone::Fixed(x) => self.with_offset(*x),
+            Timezone::Named(x) => self.with_offset(x.offset_from_utc_datetime(utc).fix()),
+        }
+    }
+}
+
+/// Like "UTC+02:00" or "Europe/Berlin"
+impl std::fmt::Display for Timezone {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Timezone::Fixed(x) => write!(f, "UTC{}", x),
+            Timezone::Named(x) => write!(f, "{}", x.name()),
+        }
+    }
+}
+
+fn start_of_day(date: NaiveDate, timezone: Timezone) -> Option<DateTime<Timezone>> {
+    timezone
+        .from_local_datetime(&date.and_hms(0, 0, 0))
+        .single()
+}
+
+/// Parses "30m", "2h", "1d" (up to 30 days), "until 18:00", "until tomorrow" or "until mon"
+pub fn parse_until(words: &[&str], now: DateTime<Timezone>) -> Option<DateTime<Timezone>> {
+    let timezone = now.timezone();
+    let today = now.date().naive_local();
+    match words {
+        [duration] => {
+            let (split, _) = duration.char_indices().last()?;
+            let amount = duration[..split].parse::<i64>().ok()?;
+            let unit = match &duration[split..] {
+                "m" => 60,
+                "h" => 3600,
+                "d" => 24 * 3600,
+                _ => return None,
+            };
+            let seconds = amount.checked_mul(unit)?;
+            if amount <= 0 || seconds > MAX_UNTIL_SECONDS {
+                return None;
+            }
+            now.checked_add_signed(Duration::seconds(seconds))
+        }
+        ["until", "tomorrow"] => start_of_day(today.succ(), timezone),
+        ["until", text] => {
+            if let Some((hour, minute)) = parse_time(text) {
+                // Tomorrow may have another offset than today
+                let at = |date: NaiveDate| {
+                    timezone
+                        .from_local_datetime(&date.and_hms(hour, minute, 0))
+                        .single()
+                };
+                return match at(today) {
+                    Some(time) if time > now => Some(time),
+                    _ => at(today.succ()),
+                };
+            }
+
+            let day = WEEKDAYS
+                .iter()
+                .position(|x| text.to_lowercase().starts_with(x))? as u32;
+            let mut date = today.succ();
+            while date.weekday().num_days_from_monday() != day {
+                date = date.succ();
+            }
+            start_of_day(date, timezone)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc_plus_1() -> Timezone {
+        Timezone::Fixed(FixedOffset::east(3600))
+    }
+
+    /// Wednesday, 2024-05-15 10:30 at UTC+01:00
+    fn now() -> DateTime<Timezone> {
+        utc_plus_1().ymd(2024, 5, 15).and_hms(10, 30, 0)
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> Option<DateTime<Timezone>> {
+        Some(utc_plus_1().ymd(2024, 5, day).and_hms(hour, minute, 0))
+    }
+
+    #[test]
+    fn parse_until_durations() {
+        assert_eq!(parse_until(&["30m"], now()), at(15, 11, 0));
+        assert_eq!(parse_until(&["2h"], now()), at(15, 12, 30));
+        assert_eq!(parse_until(&["1d"], now()), at(16, 10, 30));
+        assert_eq!(
+            parse_until(&["30d"], now()),
+            Some(utc_plus_1().ymd(2024, 6, 14).and_hms(10, 30, 0))
+        );
+    }
+
+    #[test]
+    fn parse_until_rejects_bad_durations() {
+        for text in [
+            "0h",
+            "-5h",
+            "31d",
+            "99999999999999h",
+            "999999999999d",
+            "9223372036854775807m",
+            "5x",
+            "h",
+            "",
+            "2 h",
+        ] {
+            assert_eq!(parse_until(&[text], now()), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn parse_until_times() {
+        assert_eq!(parse_until(&["until", "18:00"], now()), at(15, 18, 0));
+        // Already over today
+        assert_eq!(parse_until(&["until", "09:00"], now()), at(16, 9, 0));
+        assert_eq!(parse_until(&["until", "10:30"], now()), at(16, 10, 30));
+        assert_eq!(parse_until(&["until", "tomorrow"], now()), at(16, 0, 0));
+        assert_eq!(parse_until(&["until", "mon"], now()), at(20, 0, 0));
+        // The next one, not today
+        assert_eq!(parse_until(&["until", "Wednesday"], now()), at(22, 0, 0));
+        assert_eq!(parse_until(&["until", "24:00"], now()), None);
+        assert_eq!(parse_until(&["until", "later"], now()), None);
+        assert_eq!(parse_until(&["until"], now()), None);
+        assert_eq!(parse_until(&[], now()), None);
+    }
+
+    #[test]
+    fn timezones() {
+        let berlin = Timezone::parse("Europe/Berlin").unwrap();
+        assert_eq!(berlin, Timezone::Named(chrono_tz::Europe::Berlin));
+        assert_eq!(berlin.to_string(), "Europe/Berlin");
+        assert_eq!(Timezone::parse(" +01:00 "), Some(utc_plus_1()));
+        assert_eq!(utc_plus_1().to_string(), "UTC+01:00");
+        assert_eq!(
+            Timezone::parse("-05:30"),
+            Some(Timezone::Fixed(FixedOffset::west(5 * 3600 + 1800)))
+        );
+        for text in ["", "Europe/Nowhere", "+25:00", "02:00", "CEST+1"] {
+            assert_eq!(Timezone::parse(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn parse_until_follows_daylight_saving_time() {
+        let berlin = Timezone::Named(chrono_tz::Europe::Berlin);
+        let cet = FixedOffset::east(3600);
+        let cest = FixedOffset::east(2 * 3600);
+        // Saturday before the switch to summer time, at 20:00 CET
+        let now = berlin.ymd(2024, 3, 30).and_hms(20, 0, 0);
+        assert_eq!(now.offset().fix(), cet);
+        assert_eq!(now.timezone(), berlin);
+
+        let until = parse_until(&["until", "18:00"], now).unwrap();
+        assert_eq!(until, cest.ymd(2024, 3, 31).and_hms(18, 0, 0));
+        assert_eq!(until.offset().fix(), cest);
+        let until = parse_until(&["until", "tomorrow"], now).unwrap();
+        assert_eq!(until, cet.ymd(2024, 3, 31).and_hms(0, 0, 0));
+        let until = parse_until(&["until", "mon"], now).unwrap();
+        assert_eq!(until, cest.ymd(2024, 4, 1).and_hms(0, 0, 0));
+        // Durations stay durations
+        let until = parse_until(&["1d"], now).unwrap();
+        assert_eq!(until, cest.ymd(2024, 3, 31).and_hms(21, 0, 0));
+
+        // A fixed offset does not switch
+        let now = utc_plus_1().ymd(2024, 3, 30).and_hms(20, 0, 0);
+        let until = parse_until(&["until", "18:00"], now).unwrap();
+        assert_eq!(until, cet.ymd(2024, 3, 31).and_hms(18, 0, 0));
+    }
+
+    fn quiet(start: (u32, u32), end: (u32, u32), hour: u32, minute: u32) -> bool {
+        let time = FixedOffset::east(0)
+            .ymd(2024, 5, 15)
+            .and_hms(hour, minute, 0);
+        QuietHours { start, end }.contains(time)
+    }
+
+    #[test]
+    fn quiet_hours_within_a_day() {
+        assert!(!quiet((9, 0), (17, 0), 8, 59));
+        assert!(quiet((9, 0), (17, 0), 9, 0));
+        assert!(quiet((9, 0), (17, 0), 16, 59));
+        assert!(!quiet((9, 0), (17, 0), 17, 0));
+    }
+
+    #[test]
+    fn quiet_hours_over_midnight() {
+        assert!(quiet((22, 0), (7, 0), 22, 0));
+        assert!(quiet((22, 0), (7, 0), 23, 30));
+        assert!(quiet((22, 0), (7, 0), 0, 0));
+        assert!(quiet((22, 0), (7, 0), 6, 59));
+        assert!(!quiet((22, 0), (7, 0), 7, 0));
+        assert!(!quiet((22, 0), (7, 0), 12, 0));
+        assert!(!quiet((22, 0), (7, 0), 21, 59));
+    }
+
+    #[test]
+    fn quiet_hours_empty() {
+        assert!(!quiet((8, 0), (8, 0), 8, 0));
+        assert!(!quiet((8, 0), (8, 0), 12, 0));
+    }
+}
@@ -1,16 +1,20 @@
-use crate::common::{prepend_prefix, ConnectionDetails, MessageParseResult, Subscriber};
-use anyhow::{anyhow, Result};
-use lapin::{
-    message::{Delivery, DeliveryResult},
-    options::*,
-    Connection, ConsumerDelegate,
+use crate::commands::{Command, CommandHandler, Token, Verb};
+use crate::common::{
+    html_escape, mention_pills, prepend_prefix, ConnectionDetails, EventHandler,
+    MessageParseResult, Shared, Subscriber, SubscriptionKey, Target,
 };
-use matrix_bot_api::handlers::{HandleResult, MessageHandler};
-use matrix_bot_api::{ActiveBot, MatrixBot, Message, MessageType};
-use serde::Deserialize;
-use serde_json;
+use crate::i18n::{tr, trf};
+use crate::listing::ListEntry;
+use crate::obs_api::Request;
+use crate::obs_api::{NoCredentials, ObsApi};
+use crate::outbox::EventKind;
+use crate::reference::Reference;
+use crate::templates;
+use anyhow::{anyhow, Result};
+use lapin::{message::Delivery, Connection};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use serde::{Deserialize, Serialize};
 use std::collections::hash_map::HashMap;
-use std::convert::TryFrom;
 use std::sync::{Arc, Mutex};
 
 const KEY_REQUEST_CHANGE: &str = "obs.request.change";
@@ -23,10 +27,12 @@ const SUBNAMES: [&str; 4] = [
     KEY_REQUEST_DELETE,
     KEY_REQUEST_COMMENT,
 ];
+/// Number of history entries shown by the status command
+const STATUS_HISTORY_SIZE: usize = 5;
 
 #[derive(Debug, Clone, std::cmp::PartialEq, std::cmp::Eq, Hash)]
-struct RequestKey {
-    id: String,
+pub struct RequestKey {
+    pub id: String,
 }
 
 impl std::fmt::Display for RequestKey {
@@ -35,24 +41,14 @@ impl std::fmt::Display for RequestKey {
     }
 }
 
-impl TryFrom<String> for RequestKey {
-    type Error = ();
+impl SubscriptionKey for RequestKey {
+    type State = ();
 
-    fn try_from(line: String) -> Result<Self, Self::Error> {
-        let line = line.trim();
-        if line.contains('\n') {
-            return Err(());
+    fn from_reference(reference: &Reference, summary: bool) -> Option<Self> {
+        match reference {
+            Reference::Request(id) if !summary => Some(RequestKey { id: id.clone() }),
+            _ => None,
         }
-
-        let parts: Vec<_> = line.split('/').collect();
-        if parts.len() < 3 {
-            return Err(());
-        }
-
-        let mut iter = parts.iter().rev();
-        // These unwraps cannot fail, as there have to be at least 2 parts
-        let id = iter.next().unwrap().trim().to_string();
-        Ok(RequestKey { id })
     }
 }
 
@@ -67,15 +63,20 @@ pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
             "Unsubscribe from a SR/MR. Get no more notifications.",
         ),
         (
-            "list requests",
-            "List all requests currently subscribed to.",
+            "status OBS_REQUEST_URL",
+            "Show state, open reviews and history of a SR/MR.",
         ),
     ];
 
     prepend_prefix(prefix, &without_prefix)
 }
 
-#[derive(Deserialize, Debug)]
+/// If what reference points to can be subscribed to here, for checking the config
+pub fn subscribable(reference: &Reference, summary: bool) -> bool {
+    RequestKey::from_reference(reference, summary).is_some()
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 struct SubmitRequestInfo {
     state: String,
     number: i32,
@@ -90,59 +91,262 @@ struct SubmitRequestInfo {
     oldstate: Option<String>,
 }
 
-impl MessageHandler for Subscriber<RequestKey> {
-    /// Will be called for every text message send to a room the bot is in
-    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
-        let res = self.handle_message_helper(bot, &message.body, &message.room);
+/// Everything the request template can use
+#[derive(Serialize)]
+struct RequestContext<'a> {
+    #[serde(flatten)]
+    info: &'a SubmitRequestInfo,
+    url: String,
+    changetype: &'a str,
+    /// Who changed the request, if it was changed
+    by: String,
+    /// Comment, prefixed by the commenter for new comments
+    commentfield: String,
+    /// Plain and HTML version of the users to mention
+    mentions: String,
+    mention_pills: String,
+}
 
-        if res == MessageParseResult::SomethingForMe {
-            match self.register() {
-                Err(x) => {
-                    println!("Error while registering: {:?}", x);
-                }
-                Ok(consumer) => consumer.set_delegate(Box::new(self.clone())),
+impl CommandHandler for Subscriber<RequestKey> {
+    fn owns(&self, url: &str) -> bool {
+        self.owns_url(url)
+    }
+
+    fn execute(&mut self, bot: &ActiveBot, message: &Message, command: &Command, urls: &[Token]) {
+        if command.verb == Verb::Status {
+            for url in urls {
+                self.send_status(bot, &url.text, &message.room);
             }
+            return;
+        }
+
+        if self.execute_command(bot, message, command, urls) == MessageParseResult::SomethingForMe {
+            self.start_listening();
         }
-        HandleResult::ContinueHandling
+    }
+
+    fn list(&self, room: &str, sender: &str) -> Vec<ListEntry> {
+        self.list_entries(room, sender)
+    }
+
+    fn all_subscriptions(&self) -> Vec<(Target, ListEntry)> {
+        self.all_entries()
+    }
+
+    fn unsubscribe_room(&mut self, room: &str) -> usize {
+        Subscriber::unsubscribe_room(self, room)
+    }
+
+    fn export(&self, room: &str) -> Vec<String> {
+        self.export_entries(room)
+    }
+
+    fn subscribe_default(&mut self, command: &Command, urls: &[Token], room: &str) -> Vec<String> {
+        let failed = self.subscribe_to_defaults(command, urls, room);
+        if failed.len() < urls.len() {
+            self.start_listening();
+        }
+        failed
     }
 }
 
 impl Subscriber<RequestKey> {
-    fn generate_messages(&self, jsondata: SubmitRequestInfo, changetype: &str) -> (String, String) {
-        let mut commentfield = String::new();
-        if changetype == "commented" {
-            if jsondata.commenter.is_some() {
-                commentfield += jsondata.commenter.as_ref().unwrap();
-                commentfield += ": ";
+    fn send_status(&self, bot: &ActiveBot, line: &str, room: &str) {
+        let lang = self.settings.language(room);
+        let key = match RequestKey::parse(line, false) {
+            Some(x) => x,
+            None => {
+                bot.send_message(
+                    &tr(
+                        &lang,
+                        "Sorry, I could not parse that. Please post a submitrequest URL",
+                    ),
+                    room,
+                    MessageType::TextMessage,
+                );
+                return;
             }
+        };
 
-            if jsondata.comment_body.is_some() {
-                commentfield += jsondata.comment_body.as_ref().unwrap();
+        match self.obs_api.as_ref().map(|api| api.request(&key.id)) {
+            Some(Ok(request)) => {
+                let (plain, html) = self.generate_status(&request, &lang);
+                bot.send_html_message(&plain, &html, room, MessageType::TextMessage);
+            }
+            Some(Err(x)) if x.is::<NoCredentials>() => {
+                bot.send_message(
+                    &trf(
+                        &lang,
+                        "Sorry, I need OBS credentials for {} to look up requests. Please ask the admin of this bot to add them to obs_credentials in the config.",
+                        &[&self.server_details.domain],
+                    ),
+                    room,
+                    MessageType::TextMessage,
+                );
             }
-        } else if jsondata.comment.is_some() {
-            commentfield += jsondata.comment.as_ref().unwrap();
+            Some(Err(x)) => {
+                println!("Could not get request {}: {:?}", key, x);
+                bot.send_message(
+                    &trf(
+                        &lang,
+                        "Sorry, I could not get request {} on {}",
+                        &[&key, &self.server_details.domain],
+                    ),
+                    room,
+                    MessageType::TextMessage,
+                );
+            }
+            None => {}
         }
+    }
 
-        let plain = format!(
-            "Request {} was {}. Status: {} ({})",
-            jsondata.number, changetype, jsondata.state, commentfield,
+    fn generate_status(&self, request: &Request, lang: &str) -> (String, String) {
+        let by = match (&request.state.who, &request.state.when) {
+            (Some(who), Some(when)) => format!(" ({})", trf(lang, "by {}, {}", &[who, when])),
+            (Some(who), None) => format!(" ({})", trf(lang, "by {}", &[who])),
+            (None, Some(when)) => format!(" ({})", when),
+            (None, None) => String::new(),
+        };
+        let state = tr(lang, &request.state.name);
+
+        let mut plain = trf(
+            lang,
+            "Request {} on {}: {}",
+            &[&request.id, &self.server_details.domain, &state],
+        ) + &by;
+        let link = format!(
+            "<a href=\"{}/{}\">{}</a>",
+            self.get_base_url(),
+            request.id,
+            request.id
         );
-        let html = format!(
-            "<a href={}>Request {}</a> was {}. Status <strong>{}</strong> {}",
-            format!("{}/{}", self.get_base_url(), jsondata.number,),
-            jsondata.number,
-            changetype,
-            jsondata.state,
-            if commentfield.is_empty() {
-                String::new()
-            } else {
-                format!("<br>{}", commentfield)
+        let mut html = trf(
+            lang,
+            "Request {} on {}: {}",
+            &[
+                &link,
+                &self.server_details.domain,
+                &format!("<strong>{}</strong>", state),
+            ],
+        ) + &by;
+
+        let actions = request
+            .actions
+            .iter()
+            .map(|action| match (&action.source, &action.target) {
+                (Some(source), Some(target)) => {
+                    format!("{} {} -> {}", action.kind, source, target)
+                }
+                (None, Some(target)) => format!("{} {}", action.kind, target),
+                (Some(source), None) => format!("{} {}", action.kind, source),
+                (None, None) => action.kind.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        let reviews = request
+            .reviews
+            .iter()
+            .filter(|review| review.state == "new")
+            .map(|review| {
+                let reviewers = [
+                    ("user", &review.by_user),
+                    ("group", &review.by_group),
+                    ("project", &review.by_project),
+                    ("package", &review.by_package),
+                ];
+                reviewers
+                    .iter()
+                    .filter_map(|(kind, name)| name.as_ref().map(|x| format!("{} {}", kind, x)))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+            .collect::<Vec<_>>();
+
+        let skip = request.history.len().saturating_sub(STATUS_HISTORY_SIZE);
+        let history = request.history[skip..]
+            .iter()
+            .map(|entry| {
+                format!(
+                    "{} {}: {}{}",
+                    entry.when.as_deref().unwrap_or(""),
+                    entry.who.as_deref().unwrap_or(""),
+                    entry.description.as_deref().unwrap_or(""),
+                    match entry.comment.as_deref() {
+                        Some(x) if !x.is_empty() => format!(" ({})", x),
+                        _ => String::new(),
+                    }
+                )
+            })
+            .collect::<Vec<_>>();
+
+        for (title, items) in [
+            ("Actions", &actions),
+            ("Open reviews", &reviews),
+            ("History", &history),
+        ]
+        .iter()
+        {
+            if items.is_empty() {
+                continue;
             }
-        );
+            let title = tr(lang, title);
+            plain += &format!("\n{}:\n  {}", title, items.join("\n  "));
+            html += &format!(
+                "<br>{}:<ul><li>{}</li></ul>",
+                title,
+                items
+                    .iter()
+                    .map(|x| html_escape(x))
+                    .collect::<Vec<_>>()
+                    .join("</li><li>")
+            );
+        }
 
         (plain, html)
     }
 
+    fn generate_messages(
+        &self,
+        room: &str,
+        jsondata: &SubmitRequestInfo,
+        changetype: &str,
+        mentions: &[String],
+    ) -> (String, String) {
+        let mut commentfield = String::new();
+        if changetype == "commented" {
+            if let Some(commenter) = &jsondata.commenter {
+                commentfield += &self.display_user(commenter);
+                commentfield += ": ";
+            }
+
+            if let Some(body) = &jsondata.comment_body {
+                commentfield += body;
+            }
+        } else if let Some(comment) = &jsondata.comment {
+            commentfield += comment;
+        }
+
+        let by = match (&jsondata.who, changetype) {
+            (Some(who), "changed") => self.display_user(who),
+            _ => String::new(),
+        };
+
+        let (plain_pills, html_pills) = mention_pills(mentions);
+        let context = RequestContext {
+            info: jsondata,
+            url: format!("{}/{}", self.get_base_url(), jsondata.number),
+            changetype,
+            by,
+            commentfield,
+            mentions: plain_pills,
+            mention_pills: html_pills,
+        };
+        self.templates
+            .render(room, templates::EVENT_REQUEST, &context)
+    }
+}
+
+impl EventHandler for Subscriber<RequestKey> {
     fn delivery_wrapper(&self, delivery: Delivery) -> Result<()> {
         let data = std::str::from_utf8(&delivery.data)?;
         let jsondata: SubmitRequestInfo = serde_json::from_str(data)?;
@@ -170,84 +374,81 @@ impl Subscriber<RequestKey> {
             id: format!("{}", jsondata.number),
         };
 
-        let rooms;
+        let targets;
         if let Ok(subscriptions) = self.subscriptions.lock() {
             // This is a message we are not subscribed to
             if !subscriptions.contains_key(&key) {
                 return Ok(());
             }
 
-            rooms = subscriptions[&key].clone();
+            targets = subscriptions[&key].clone();
         } else {
             return Ok(());
         }
 
         println!("Request got {}: {}", changetype, jsondata.number);
 
-        if let Ok(bot) = self.bot.lock() {
-            let (plain, html) = self.generate_messages(jsondata, changetype);
-            for room in &rooms {
-                bot.send_html_message(&plain, &html, room, MessageType::TextMessage);
-            }
-        }
+        // The author wants to know why the request got declined
+        let declined = changetype == "changed" && jsondata.state == "declined";
+        let author = if declined {
+            self.linked_matrix_users(jsondata.author.iter().map(|x| x.as_str()))
+        } else {
+            Vec::new()
+        };
 
-        Ok(())
-    }
-}
+        let sources = [key.url(&self.get_base_url())];
+        for target in &targets {
+            let room = match self.room_of(target) {
+                Some(x) => x,
+                None => continue,
+            };
+            let mentions = if declined {
+                self.mentions(&key, target, &author)
+            } else {
+                Vec::new()
+            };
 
-impl ConsumerDelegate for Subscriber<RequestKey> {
-    fn on_new_delivery(&self, delivery: DeliveryResult) {
-        if let Ok(Some(delivery)) = delivery {
-            if let Some(channel) = &self.channel {
-                let _ = channel
-                    .basic_ack(delivery.delivery_tag, BasicAckOptions::default())
-                    .wait();
-            }
-            match self.delivery_wrapper(delivery) {
-                Ok(_) => {}
-                Err(x) => println!("Error while getting Event: {:?}. Skipping to continue", x),
-            }
-        } else {
-            println!(
-                "Delivery not ok on {}: {:?}",
-                self.server_details.domain, delivery
+            let (plain, html) = self.generate_messages(&room, &jsondata, changetype, &mentions);
+            self.send_notification(
+                delivery.routing_key.as_str(),
+                &room,
+                EventKind::RequestChanged,
+                &sources,
+                &plain,
+                &html,
             );
         }
+
+        Ok(())
     }
 }
 
 pub fn init(
-    bot: &mut MatrixBot,
     details: &ConnectionDetails,
     conn: Connection,
-    prefix: Option<String>,
-    default_subs: &Option<Vec<(String, String)>>,
+    obs_api: Arc<dyn ObsApi>,
+    shared: &Shared,
 ) -> Result<()> {
-    let activebot = bot.get_activebot_clone();
-    let mut sub: Subscriber<RequestKey> = Subscriber {
+    let sub: Subscriber<RequestKey> = Subscriber {
         subtype: "request".to_string(),
         server_details: *details,
         connection: conn,
         channel: None,
+        events: None,
         subnames: SUBNAMES.to_vec(),
-        bot: Arc::new(Mutex::new(activebot)),
         subscriptions: Arc::new(Mutex::new(HashMap::new())),
-        prefix,
+        notify: Arc::new(Mutex::new(HashMap::new())),
+        state: Default::default(),
+        obs_api: Some(obs_api),
+        accounts: shared.accounts.clone(),
+        direct: shared.direct.clone(),
+        outbox: shared.outbox.clone(),
+        templates: shared.templates.clone(),
+        settings: shared.settings.clone(),
+        stats: shared.stats.clone(),
     };
 
-    match default_subs {
-        None => {}
-        Some(subs) => match sub.register() {
-            Err(_) => {}
-            Ok(consumer) => {
-                consumer.set_delegate(Box::new(sub.clone()));
-                for (room, url) in subs {
-                    sub.subscribe_to_defaults(&url, &room);
-                }
-            }
-        },
-    }
-    bot.add_handler(sub);
+    shared.router.add(Box::new(sub));
 
     Ok(())
 }
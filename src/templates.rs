@@ -0,0 +1,531 @@
+use crate::common::{html_escape, prepend_prefix};
+use crate::i18n::{tr, trf, DEFAULT_LANGUAGE, LANGUAGES};
+use crate::settings::{RoomSettings, Settings};
+use anyhow::{anyhow, Result};
+use handlebars::{no_escape, Context, Handlebars, Helper, HelperResult, Output, RenderContext};
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+
+pub const EVENT_BUILD_SUCCEEDED: &str = "build_succeeded";
+pub const EVENT_BUILD_FAILED: &str = "build_failed";
+pub const EVENT_REQUEST: &str = "request";
+pub const EVENT_OPENQA: &str = "openqa";
+
+const BUILD_PLAIN: &str =
+    "Build {{changetype}}: {{project}}/{{package}} ({{arch}} / {{repository}})\
+{{#if mentions}}\ncc {{mentions}}{{/if}}\
+{{#each log_excerpt}}\n{{this}}{{/each}}";
+
+const BUILD_HTML: &str = "<strong>Build {{#if (eq changetype \"succeeded\")}}{{changetype}}{{else}}<u>{{changetype}}</u>{{/if}}</strong>: \
+<a href=\"{{url}}\">{{project}}/{{package}}</a> ({{arch}} / {{repository}})\
+{{#if mention_pills}}<br>cc {{{mention_pills}}}{{/if}}\
+{{#if log_excerpt}}<details><summary>Log excerpt (<a href=\"{{log_url}}\">full log</a>)</summary><pre><code>\
+{{#each log_excerpt}}{{#unless @first}}\n{{/unless}}{{this}}{{/each}}</code></pre></details>{{/if}}";
+
+const REQUEST_PLAIN: &str = "Request {{number}} was {{changetype}}{{#if by}} by {{by}}{{/if}}. \
+Status: {{state}} ({{commentfield}})\
+{{#if mentions}}\ncc {{mentions}}{{/if}}";
+
+const REQUEST_HTML: &str =
+    "<a href=\"{{url}}\">Request {{number}}</a> was {{changetype}}{{#if by}} by {{by}}{{/if}}. \
+Status <strong>{{state}}</strong> {{#if commentfield}}<br>{{commentfield}}{{/if}}\
+{{#if mention_pills}}<br>cc {{{mention_pills}}}{{/if}}";
+
+const OPENQA_PLAIN: &str = "Test {{result}}: {{testname}} ({{id}}){{#if reason}} (reason: {{reason}}){{/if}}\
+{{#if flaky}} [flaky]{{/if}}\
+{{#if failed_modules}}. Failed modules: {{#each failed_modules}}{{#unless @first}}, {{/unless}}{{name}}{{/each}}{{/if}}";
+
+const OPENQA_HTML: &str = "<strong>Test {{#if (eq result \"passed\")}}{{result}}{{else}}<u>{{result}}</u>{{/if}}:</strong> \
+Test {{testname}} (<a href=\"{{url}}\">{{id}}</a>){{#if reason}} (reason: {{reason}}){{/if}}\
+{{#if flaky}} <em>(known to be flaky)</em>{{/if}}\
+{{#if failed_modules}}<br>Failed modules: {{#each failed_modules}}{{#unless @first}}, {{/unless}}\
+<a href=\"{{../url}}#step/{{name}}/{{step}}\">{{name}}</a>{{/each}}{{/if}}";
+
+/// For German and Czech, as only changetype needs translating
+const BUILD_PLAIN_TRANSLATED: &str =
+    "Build {{tr changetype}}: {{project}}/{{package}} ({{arch}} / {{repository}})\
+{{#if mentions}}\ncc {{mentions}}{{/if}}\
+{{#each log_excerpt}}\n{{this}}{{/each}}";
+
+const BUILD_HTML_DE: &str = "<strong>Build {{#if (eq changetype \"succeeded\")}}{{tr changetype}}{{else}}<u>{{tr changetype}}</u>{{/if}}</strong>: \
+<a href=\"{{url}}\">{{project}}/{{package}}</a> ({{arch}} / {{repository}})\
+{{#if mention_pills}}<br>cc {{{mention_pills}}}{{/if}}\
+{{#if log_excerpt}}<details><summary>Auszug aus dem Log (<a href=\"{{log_url}}\">ganzes Log</a>)</summary><pre><code>\
+{{#each log_excerpt}}{{#unless @first}}\n{{/unless}}{{this}}{{/each}}</code></pre></details>{{/if}}";
+
+const REQUEST_PLAIN_DE: &str =
+    "Request {{number}} wurde {{tr changetype}}{{#if by}} von {{by}}{{/if}}. \
+Status: {{tr state}} ({{commentfield}})\
+{{#if mentions}}\ncc {{mentions}}{{/if}}";
+
+const REQUEST_HTML_DE: &str =
+    "<a href=\"{{url}}\">Request {{number}}</a> wurde {{tr changetype}}{{#if by}} von {{by}}{{/if}}. \
+Status <strong>{{tr state}}</strong> {{#if commentfield}}<br>{{commentfield}}{{/if}}\
+{{#if mention_pills}}<br>cc {{{mention_pills}}}{{/if}}";
+
+const OPENQA_PLAIN_DE: &str = "Test {{tr result}}: {{testname}} ({{id}}){{#if reason}} (Grund: {{reason}}){{/if}}\
+{{#if flaky}} [instabil]{{/if}}\
+{{#if failed_modules}}. Fehlgeschlagene Module: {{#each failed_modules}}{{#unless @first}}, {{/unless}}{{name}}{{/each}}{{/if}}";
+
+const OPENQA_HTML_DE: &str = "<strong>Test {{#if (eq result \"passed\")}}{{tr result}}{{else}}<u>{{tr result}}</u>{{/if}}:</strong> \
+Test {{testname}} (<a href=\"{{url}}\">{{id}}</a>){{#if reason}} (Grund: {{reason}}){{/if}}\
+{{#if flaky}} <em>(bekanntermaßen instabil)</em>{{/if}}\
+{{#if failed_modules}}<br>Fehlgeschlagene Module: {{#each failed_modules}}{{#unless @first}}, {{/unless}}\
+<a href=\"{{../url}}#step/{{name}}/{{step}}\">{{name}}</a>{{/each}}{{/if}}";
+
+const BUILD_HTML_CS: &str = "<strong>Build {{#if (eq changetype \"succeeded\")}}{{tr changetype}}{{else}}<u>{{tr changetype}}</u>{{/if}}</strong>: \
+<a href=\"{{url}}\">{{project}}/{{package}}</a> ({{arch}} / {{repository}})\
+{{#if mention_pills}}<br>cc {{{mention_pills}}}{{/if}}\
+{{#if log_excerpt}}<details><summary>Výňatek z logu (<a href=\"{{log_url}}\">celý log</a>)</summary><pre><code>\
+{{#each log_excerpt}}{{#unless @first}}\n{{/unless}}{{this}}{{/each}}</code></pre></details>{{/if}}";
+
+const REQUEST_PLAIN_CS: &str =
+    "Požadavek {{number}} byl {{tr changetype}}{{#if by}} uživatelem {{by}}{{/if}}. \
+Stav: {{tr state}} ({{commentfield}})\
+{{#if mentions}}\ncc {{mentions}}{{/if}}";
+
+const REQUEST_HTML_CS: &str =
+    "<a href=\"{{url}}\">Požadavek {{number}}</a> byl {{tr changetype}}{{#if by}} uživatelem {{by}}{{/if}}. \
+Stav <strong>{{tr state}}</strong> {{#if commentfield}}<br>{{commentfield}}{{/if}}\
+{{#if mention_pills}}<br>cc {{{mention_pills}}}{{/if}}";
+
+const OPENQA_PLAIN_CS: &str = "Test {{tr result}}: {{testname}} ({{id}}){{#if reason}} (důvod: {{reason}}){{/if}}\
+{{#if flaky}} [nestabilní]{{/if}}\
+{{#if failed_modules}}. Selhané moduly: {{#each failed_modules}}{{#unless @first}}, {{/unless}}{{name}}{{/each}}{{/if}}";
+
+const OPENQA_HTML_CS: &str = "<strong>Test {{#if (eq result \"passed\")}}{{tr result}}{{else}}<u>{{tr result}}</u>{{/if}}:</strong> \
+Test {{testname}} (<a href=\"{{url}}\">{{id}}</a>){{#if reason}} (důvod: {{reason}}){{/if}}\
+{{#if flaky}} <em>(známý jako nestabilní)</em>{{/if}}\
+{{#if failed_modules}}<br>Selhané moduly: {{#each failed_modules}}{{#unless @first}}, {{/unless}}\
+<a href=\"{{../url}}#step/{{name}}/{{step}}\">{{name}}</a>{{/each}}{{/if}}";
+
+/// (name, plain, html), the English ones reproducing the messages from before templates existed.
+/// Translations are named LANGUAGE/EVENT.
+const DEFAULT_TEMPLATES: [(&str, &str, &str); 12] = [
+    (EVENT_BUILD_SUCCEEDED, BUILD_PLAIN, BUILD_HTML),
+    (EVENT_BUILD_FAILED, BUILD_PLAIN, BUILD_HTML),
+    (EVENT_REQUEST, REQUEST_PLAIN, REQUEST_HTML),
+    (EVENT_OPENQA, OPENQA_PLAIN, OPENQA_HTML),
+    ("de/build_succeeded", BUILD_PLAIN_TRANSLATED, BUILD_HTML_DE),
+    ("de/build_failed", BUILD_PLAIN_TRANSLATED, BUILD_HTML_DE),
+    ("de/request", REQUEST_PLAIN_DE, REQUEST_HTML_DE),
+    ("de/openqa", OPENQA_PLAIN_DE, OPENQA_HTML_DE),
+    ("cs/build_succeeded", BUILD_PLAIN_TRANSLATED, BUILD_HTML_CS),
+    ("cs/build_failed", BUILD_PLAIN_TRANSLATED, BUILD_HTML_CS),
+    ("cs/request", REQUEST_PLAIN_CS, REQUEST_HTML_CS),
+    ("cs/openqa", OPENQA_PLAIN_CS, OPENQA_HTML_CS),
+];
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [
+        ("templates", "Show which message templates this room uses."),
+        (
+            "templates SET",
+            "Use another set of message templates in this room ('default' to go back).",
+        ),
+    ];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+/// Message templates, with the built-in defaults overridden from the template directory
+/// or config. Templates named SET/EVENT form sets, which rooms can choose from.
+#[derive(Clone)]
+pub struct Templates {
+    plain: Arc<Handlebars<'static>>,
+    html: Arc<Handlebars<'static>>,
+    settings: Settings,
+    prefix: Option<String>,
+}
+
+/// {{tr word}} translates the word into the language of the room
+fn tr_helper<'reg, 'rc>(
+    h: &Helper<'reg, 'rc>,
+    registry: &'reg Handlebars<'reg>,
+    ctx: &'rc Context,
+    _: &mut RenderContext<'reg, 'rc>,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let text = h
+        .param(0)
+        .and_then(|x| x.value().as_str())
+        .unwrap_or_default();
+    let lang = ctx
+        .data()
+        .get("lang")
+        .and_then(|x| x.as_str())
+        .unwrap_or(DEFAULT_LANGUAGE);
+    out.write(&registry.get_escape_fn()(&tr(lang, text)))?;
+    Ok(())
+}
+
+/// "terse/build_failed.html" -> (is_html, "terse/build_failed")
+fn split_template_name(name: &str) -> Result<(bool, String)> {
+    if let Some(name) = name.strip_suffix(".plain") {
+        Ok((false, name.to_string()))
+    } else if let Some(name) = name.strip_suffix(".html") {
+        Ok((true, name.to_string()))
+    } else {
+        Err(anyhow!(
+            "Template {} has to end with .plain or .html (plus .hbs for files)",
+            name
+        ))
+    }
+}
+
+impl Templates {
+    pub fn new(
+        settings: Settings,
+        prefix: Option<String>,
+        template_dir: Option<&Path>,
+        configured: &HashMap<String, String>,
+    ) -> Result<Self> {
+        let mut plain = Handlebars::new();
+        plain.register_escape_fn(no_escape);
+        let mut html = Handlebars::new();
+        html.register_escape_fn(html_escape);
+        plain.register_helper("tr", Box::new(tr_helper));
+        html.register_helper("tr", Box::new(tr_helper));
+
+        for (name, plain_template, html_template) in DEFAULT_TEMPLATES.iter() {
+            plain.register_template_string(name, plain_template)?;
+            html.register_template_string(name, html_template)?;
+        }
+
+        let mut register = |name: &str, template: &str| -> Result<()> {
+            let (is_html, name) = split_template_name(name)?;
+            let registry = if is_html { &mut html } else { &mut plain };
+            registry
+                .register_template_string(&name, template)
+                .map_err(|x| anyhow!("Template {} is broken: {}", name, x))
+        };
+
+        // Files in the directory itself override the defaults, subdirectories are sets
+        if let Some(dir) = template_dir {
+            let mut files = Vec::new();
+            for entry in std::fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.is_dir() {
+                    for file in std::fs::read_dir(&path)? {
+                        files.push(file?.path());
+                    }
+                } else {
+                    files.push(path);
+                }
+            }
+
+            for file in files {
+                let relative = file.strip_prefix(dir)?.to_string_lossy().replace('\\', "/");
+                if let Some(name) = relative.strip_suffix(".hbs") {
+                    register(name, &std::fs::read_to_string(&file)?)?;
+                }
+            }
+        }
+
+        for (name, template) in configured {
+            register(name, template)?;
+        }
+
+        Ok(Templates {
+            plain: Arc::new(plain),
+            html: Arc::new(html),
+            settings,
+            prefix,
+        })
+    }
+
+    /// Names of all template sets (translations are no sets)
+    fn sets(&self) -> BTreeSet<String> {
+        self.plain
+            .get_templates()
+            .keys()
+            .chain(self.html.get_templates().keys())
+            .filter_map(|x| x.split_once('/').map(|(set, _)| set.to_string()))
+            .filter(|x| !LANGUAGES.iter().any(|(code, _)| code == x))
+            .collect()
+    }
+
+    /// Tries the template of the set, then the one of the language, then the default
+    fn render_one(
+        registry: &Handlebars,
+        set: Option<&str>,
+        lang: &str,
+        event: &str,
+        data: &serde_json::Value,
+    ) -> String {
+        let candidates = [set, Some(lang)]
+            .iter()
+            .flatten()
+            .map(|x| format!("{}/{}", x, event))
+            .collect::<Vec<_>>();
+        for name in candidates {
+            if registry.has_template(&name) {
+                match registry.render(&name, data) {
+                    Ok(x) => return x,
+                    Err(x) => println!("ERROR! Could not render template {}: {}", name, x),
+                }
+            }
+        }
+
+        registry.render(event, data).unwrap_or_else(|x| {
+            println!("ERROR! Could not render template {}: {}", event, x);
+            String::new()
+        })
+    }
+
+    /// Renders the plain and HTML message of an event, with the templates and language of the room
+    pub fn render<T: Serialize>(&self, room: &str, event: &str, data: &T) -> (String, String) {
+        let settings = self.settings.get(room);
+        let lang = settings
+            .language
+            .unwrap_or_else(|| DEFAULT_LANGUAGE.to_string());
+        let mut data = serde_json::to_value(data).unwrap_or_default();
+        if let Some(data) = data.as_object_mut() {
+            data.insert("lang".to_string(), lang.clone().into());
+        }
+
+        let set = settings.template_set.as_deref();
+        (
+            Self::render_one(&self.plain, set, &lang, event, &data),
+            Self::render_one(&self.html, set, &lang, event, &data),
+        )
+    }
+
+    fn handle_templates_command(&self, room: &str, words: &[&str]) -> String {
+        let lang = self.settings.language(room);
+        let sets = self.sets();
+        let set = match words {
+            [] => {
+                let current = self.settings.get(room).template_set;
+                return trf(
+                    &lang,
+                    "This room uses the {} templates. Available: default{}",
+                    &[
+                        &current.as_deref().unwrap_or("default"),
+                        &sets.iter().map(|x| format!(", {}", x)).collect::<String>(),
+                    ],
+                );
+            }
+            ["default"] => None,
+            [set] if sets.contains(*set) => Some(set.to_string()),
+            [set] => return trf(&lang, "Sorry, there is no template set {}", &[set]),
+            _ => return tr(&lang, "Usage: templates [SET]"),
+        };
+
+        let answer = trf(
+            &lang,
+            "This room now uses the {} templates",
+            &[&set.as_deref().unwrap_or("default")],
+        );
+        self.settings.update(room, |settings: &mut RoomSettings| {
+            settings.template_set = set
+        });
+        answer
+    }
+}
+
+impl MessageHandler for Templates {
+    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
+        let prefix = self.prefix.as_deref().unwrap_or("");
+        let body = message.body.trim();
+        if !body.starts_with(prefix) {
+            return HandleResult::ContinueHandling;
+        }
+
+        let words = body[prefix.len()..].split_whitespace().collect::<Vec<_>>();
+        let answer = match words.split_first() {
+            Some((&"templates", rest)) => self.handle_templates_command(&message.room, rest),
+            _ => return HandleResult::ContinueHandling,
+        };
+
+        bot.send_message(&answer, &message.room, MessageType::TextMessage);
+        HandleResult::StopHandling
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn templates() -> Templates {
+        Templates::new(Settings::default(), None, None, &HashMap::new()).unwrap()
+    }
+
+    #[test]
+    fn build_messages_stay_the_same() {
+        let data = json!({
+            "project": "home:alice",
+            "package": "hello",
+            "arch": "x86_64",
+            "repository": "openSUSE_Tumbleweed",
+            "changetype": "failed",
+            "url": "https://build.example.org/package/show/home:alice/hello",
+            "log_url": "https://build.example.org/package/live_build_log/home:alice/hello/openSUSE_Tumbleweed/x86_64",
+            "log_excerpt": ["error: a < b", "make: *** [all] Error 1"],
+            "mentions": "@alice:example.org",
+            "mention_pills": "<a href=\"https://matrix.to/#/@alice:example.org\">@alice:example.org</a>",
+        });
+        let (plain, html) = templates().render("!room", EVENT_BUILD_FAILED, &data);
+        assert_eq!(
+            plain,
+            "Build failed: home:alice/hello (x86_64 / openSUSE_Tumbleweed)\n\
+             cc @alice:example.org\n\
+             error: a < b\n\
+             make: *** [all] Error 1"
+        );
+        assert_eq!(
+            html,
+            "<strong>Build <u>failed</u></strong>: \
+             <a href=\"https://build.example.org/package/show/home:alice/hello\">home:alice/hello</a> \
+             (x86_64 / openSUSE_Tumbleweed)\
+             <br>cc <a href=\"https://matrix.to/#/@alice:example.org\">@alice:example.org</a>\
+             <details><summary>Log excerpt (<a href=\"https://build.example.org/package/live_build_log/home:alice/hello/openSUSE_Tumbleweed/x86_64\">full log</a>)</summary>\
+             <pre><code>error: a &lt; b\nmake: *** [all] Error 1</code></pre></details>"
+        );
+
+        let data = json!({
+            "project": "home:alice",
+            "package": "hello",
+            "arch": "x86_64",
+            "repository": "openSUSE_Tumbleweed",
+            "changetype": "succeeded",
+            "url": "https://build.example.org/package/show/home:alice/hello",
+            "log_excerpt": [],
+            "mentions": "",
+            "mention_pills": "",
+        });
+        let (plain, html) = templates().render("!room", EVENT_BUILD_SUCCEEDED, &data);
+        assert_eq!(
+            plain,
+            "Build succeeded: home:alice/hello (x86_64 / openSUSE_Tumbleweed)"
+        );
+        assert_eq!(
+            html,
+            "<strong>Build succeeded</strong>: \
+             <a href=\"https://build.example.org/package/show/home:alice/hello\">home:alice/hello</a> \
+             (x86_64 / openSUSE_Tumbleweed)"
+        );
+    }
+
+    #[test]
+    fn request_messages_stay_the_same() {
+        let data = json!({
+            "number": 42,
+            "state": "review",
+            "changetype": "commented",
+            "url": "https://build.example.org/request/show/42",
+            "by": "",
+            "commentfield": "bob (@bob:example.org): Looks good & builds",
+            "mentions": "",
+            "mention_pills": "",
+        });
+        let (plain, html) = templates().render("!room", EVENT_REQUEST, &data);
+        assert_eq!(
+            plain,
+            "Request 42 was commented. Status: review (bob (@bob:example.org): Looks good & builds)"
+        );
+        // Comments are written by OBS users, so unlike before they are escaped
+        assert_eq!(
+            html,
+            "<a href=\"https://build.example.org/request/show/42\">Request 42</a> was commented. \
+             Status <strong>review</strong> <br>bob (@bob:example.org): Looks good &amp; builds"
+        );
+
+        let data = json!({
+            "number": 42,
+            "state": "accepted",
+            "changetype": "changed",
+            "url": "https://build.example.org/request/show/42",
+            "by": "bob (@bob:example.org)",
+            "commentfield": "",
+            "mentions": "@alice:example.org",
+            "mention_pills": "<a href=\"https://matrix.to/#/@alice:example.org\">@alice:example.org</a>",
+        });
+        let (plain, html) = templates().render("!room", EVENT_REQUEST, &data);
+        assert_eq!(
+            plain,
+            "Request 42 was changed by bob (@bob:example.org). Status: accepted ()\n\
+             cc @alice:example.org"
+        );
+        assert_eq!(
+            html,
+            "<a href=\"https://build.example.org/request/show/42\">Request 42</a> \
+             was changed by bob (@bob:example.org). Status <strong>accepted</strong> \
+             <br>cc <a href=\"https://matrix.to/#/@alice:example.org\">@alice:example.org</a>"
+        );
+    }
+
+    #[test]
+    fn openqa_messages_stay_the_same() {
+        let data = json!({
+            "id": 123,
+            "testname": "textmode",
+            "result": "failed",
+            "reason": "timeout",
+            "url": "https://openqa.example.org/tests/123",
+            "flaky": true,
+            "failed_modules": [{"name": "boot", "step": 2}, {"name": "login", "step": 5}],
+        });
+        let (plain, html) = templates().render("!room", EVENT_OPENQA, &data);
+        assert_eq!(
+            plain,
+            "Test failed: textmode (123) (reason: timeout) [flaky]. Failed modules: boot, login"
+        );
+        assert_eq!(
+            html,
+            "<strong>Test <u>failed</u>:</strong> \
+             Test textmode (<a href=\"https://openqa.example.org/tests/123\">123</a>) (reason: timeout) \
+             <em>(known to be flaky)</em><br>Failed modules: \
+             <a href=\"https://openqa.example.org/tests/123#step/boot/2\">boot</a>, \
+             <a href=\"https://openqa.example.org/tests/123#step/login/5\">login</a>"
+        );
+
+        let data = json!({
+            "id": 123,
+            "testname": "textmode",
+            "result": "passed",
+            "url": "https://openqa.example.org/tests/123",
+            "flaky": false,
+            "failed_modules": [],
+        });
+        let (plain, html) = templates().render("!room", EVENT_OPENQA, &data);
+        assert_eq!(plain, "Test passed: textmode (123)");
+        assert_eq!(
+            html,
+            "<strong>Test passed:</strong> \
+             Test textmode (<a href=\"https://openqa.example.org/tests/123\">123</a>)"
+        );
+    }
+
+    #[test]
+    fn translations_are_used() {
+        let templates = templates();
+        templates
+            .settings
+            .update("!room", |settings: &mut RoomSettings| {
+                settings.language = Some("de".to_string())
+            });
+        let data = json!({
+            "number": 42,
+            "state": "accepted",
+            "changetype": "changed",
+            "url": "https://build.example.org/request/show/42",
+            "by": "bob",
+            "commentfield": "",
+        });
+        let (plain, _) = templates.render("!room", EVENT_REQUEST, &data);
+        assert_eq!(
+            plain,
+            format!(
+                "Request 42 wurde {} von bob. Status: {} ()",
+                tr("de", "changed"),
+                tr("de", "accepted")
+            )
+        );
+    }
+}
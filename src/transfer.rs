@@ -0,0 +1,280 @@
+use crate::commands::{looks_like_url, CommandRouter};
+use crate::common::{html_escape, prepend_prefix};
+use crate::i18n::{tr, trf};
+use crate::matrix_api::MatrixApi;
+use crate::settings::Settings;
+use anyhow::{anyhow, Result};
+use config::FileFormat;
+use matrix_bot_api::handlers::{HandleResult, MessageHandler};
+use matrix_bot_api::{ActiveBot, Message, MessageType};
+use serde_json::json;
+use std::sync::Arc;
+
+const EXPORT_NAME: &str = "subscriptions.toml";
+/// Larger files are not downloaded for 'import'
+const MAX_IMPORT_SIZE: u64 = 1024 * 1024;
+
+pub fn help_str(prefix: Option<&str>) -> Vec<(String, String)> {
+    let without_prefix = [
+        (
+            "export",
+            "Get a file with the subscriptions of this room, usable as default_subs in the config or with 'import'.",
+        ),
+        (
+            "import [CONTENT]",
+            "Subscribe this room to everything in the file you posted last (or in the text after 'import').",
+        ),
+    ];
+
+    prepend_prefix(prefix, &without_prefix)
+}
+
+/// A file for default_subs of the config, subscribing room to the entries
+fn export_file(room: &str, entries: &[String]) -> String {
+    let mut text = format!(
+        "# Subscriptions of {}, for default_subs of the config or 'import'\ndefault_subs = [\n",
+        room
+    );
+    for entry in entries {
+        // JSON strings are valid TOML strings
+        text += &format!("    [{}, {}],\n", json!(room), json!(entry));
+    }
+    text + "]\n"
+}
+
+/// Entries of a file like the config (TOML or JSON, with default_subs), of a JSON list
+/// of [room, URL] or just lines of URLs. The rooms of the file do not matter.
+fn parse_entries(text: &str) -> Result<Vec<String>> {
+    let text = text.trim();
+    if let Ok(pairs) = serde_json::from_str::<Vec<(String, String)>>(text) {
+        return Ok(pairs.into_iter().map(|(_, entry)| entry).collect());
+    }
+
+    if text.contains("default_subs") {
+        let format = if text.starts_with('{') {
+            FileFormat::Json
+        } else {
+            FileFormat::Toml
+        };
+        let mut file = config::Config::default();
+        file.merge(config::File::from_str(text, format))?;
+        let pairs = file.get::<Vec<(String, String)>>("default_subs")?;
+        return Ok(pairs.into_iter().map(|(_, entry)| entry).collect());
+    }
+
+    Ok(text
+        .lines()
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty() && !x.starts_with('#'))
+        .map(|x| x.to_string())
+        .collect())
+}
+
+/// Whether the text after 'import' could be subscriptions: a config with default_subs or
+/// URLs. Without a prefix, 'import os' and the like are chat and not meant for the bot.
+fn looks_importable(content: &str) -> bool {
+    content.contains("default_subs")
+        || content
+            .split(|c: char| c.is_whitespace() || "\"',[]".contains(c))
+            .any(looks_like_url)
+}
+
+/// 'export' and 'import' of the subscriptions of a room
+pub struct TransferHandler {
+    pub prefix: Option<String>,
+    pub settings: Settings,
+    pub router: CommandRouter,
+    /// For sending and fetching files, matrix_bot_api only knows text messages
+    pub matrix_api: Option<Arc<MatrixApi>>,
+}
+
+impl TransferHandler {
+    fn export(&self, bot: &ActiveBot, room: &str, lang: &str) {
+        let mut entries = self.router.export(room);
+        if entries.is_empty() {
+            let answer = tr(lang, "No subscriptions to export");
+            bot.send_message(&answer, room, MessageType::TextMessage);
+            return;
+        }
+        entries.sort();
+        let text = export_file(room, &entries);
+
+        if let Some(api) = &self.matrix_api {
+            let sent = api
+                .upload(EXPORT_NAME, "application/toml", text.clone().into_bytes())
+                .and_then(|mxc| {
+                    api.send_file(room, EXPORT_NAME, &mxc, "application/toml", text.len())
+                });
+            match sent {
+                Ok(()) => return,
+                Err(x) => println!("ERROR! Could not send the export to {}: {:?}", room, x),
+            }
+        }
+        // Without files, the content can still be copied
+        let html = format!("<pre><code>{}</code></pre>", html_escape(&text));
+        bot.send_html_message(&text, &html, room, MessageType::TextMessage);
+    }
+
+    /// The content given after 'import', or of the newest file of sender in room
+    fn import_content(&self, room: &str, sender: &str, content: &str) -> Result<String> {
+        if !content.is_empty() {
+            return Ok(content.to_string());
+        }
+        let api = self
+            .matrix_api
+            .as_ref()
+            .ok_or_else(|| anyhow!("no Matrix API login"))?;
+        let (name, mxc) = api
+            .latest_file(room, sender)?
+            .ok_or_else(|| anyhow!("no file found"))?;
+        println!("Importing {} ({}) into {}", name, mxc, room);
+        Ok(String::from_utf8(api.download(&mxc, MAX_IMPORT_SIZE)?)?)
+    }
+
+    /// Without a prefix, only content that looks like subscriptions or a posted file is
+    /// imported, anything else is left to the other handlers.
+    fn import(
+        &self,
+        bot: &ActiveBot,
+        message: &Message,
+        content: &str,
+        lang: &str,
+    ) -> HandleResult {
+        let room = &message.room;
+        let quiet = self.prefix.is_none();
+        if quiet && !content.is_empty() && !looks_importable(content) {
+            return HandleResult::ContinueHandling;
+        }
+        let text = match self.import_content(room, &message.sender, content) {
+            Ok(x) => x,
+            Err(x) => {
+                println!("Import into {} failed: {:?}", room, x);
+                if quiet {
+                    return HandleResult::ContinueHandling;
+                }
+                let answer = tr(lang, "Sorry, I found no file of yours to import. Post it first, or put its content after 'import'.");
+                bot.send_message(&answer, room, MessageType::TextMessage);
+                return HandleResult::StopHandling;
+            }
+        };
+        let entries = match parse_entries(&text) {
+            Ok(x) => x,
+            Err(x) => {
+                let answer = trf(lang, "Sorry, I could not read the file: {}", &[&x]);
+                bot.send_message(&answer, room, MessageType::TextMessage);
+                return HandleResult::StopHandling;
+            }
+        };
+
+        let (subscribed, failed) = self.router.subscribe_entries(room, &entries, lang);
+        let mut answer = trf(lang, "Subscribed to {} URLs.", &[&subscribed]);
+        if !failed.is_empty() {
+            answer += &format!("\n{}", tr(lang, "These entries failed:"));
+            for (entry, reason) in &failed {
+                answer += &format!("\n  {}: {}", entry, reason);
+            }
+        }
+        bot.send_message(&answer, room, MessageType::TextMessage);
+        HandleResult::StopHandling
+    }
+}
+
+impl MessageHandler for TransferHandler {
+    fn handle_message(&mut self, bot: &ActiveBot, message: &Message) -> HandleResult {
+        let prefix = self.prefix.as_deref().unwrap_or("");
+        let body = message.body.trim();
+        if !body.starts_with(prefix) {
+            return HandleResult::ContinueHandling;
+        }
+
+        let (verb, rest) = body[prefix.len()..]
+            .trim_start()
+            .split_once(char::is_whitespace)
+            .map(|(verb, rest)| (verb, rest.trim()))
+            .unwrap_or((body[prefix.len()..].trim(), ""));
+        let lang = self.settings.language(&message.room);
+        match (verb, rest) {
+            ("export", "") => self.export(bot, &message.room, &lang),
+            // The content has lines of URLs, which are not meant as commands
+            ("import", content) => return self.import(bot, message, content, &lang),
+            _ => return HandleResult::ContinueHandling,
+        }
+        HandleResult::StopHandling
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(x: &[&str]) -> Vec<String> {
+        x.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn entries_of_json_list() {
+        let text = r#"[["!a:example.org", "https://build.opensuse.org/package/show/foo/bar"],
+            ["!b:example.org", "sr#1234"]]"#;
+        assert_eq!(
+            parse_entries(text).unwrap(),
+            strings(&["https://build.opensuse.org/package/show/foo/bar", "sr#1234"])
+        );
+    }
+
+    #[test]
+    fn entries_of_config() {
+        let toml = r#"
+matrix_user = "bot"
+default_subs = [
+    ["!a:example.org", "sr#1234"],
+    ["!a:example.org", "https://openqa.opensuse.org/tests/1"],
+]
+"#;
+        assert_eq!(
+            parse_entries(toml).unwrap(),
+            strings(&["sr#1234", "https://openqa.opensuse.org/tests/1"])
+        );
+
+        let json = r#"{"matrix_user": "bot", "default_subs": [["!a:example.org", "sr#1234"]]}"#;
+        assert_eq!(parse_entries(json).unwrap(), strings(&["sr#1234"]));
+
+        assert!(parse_entries("default_subs = [[").is_err());
+    }
+
+    #[test]
+    fn entries_of_lines() {
+        let text = "\n# From the old room\nsr#1234\n\n  https://openqa.opensuse.org/tests/1  \n";
+        assert_eq!(
+            parse_entries(text).unwrap(),
+            strings(&["sr#1234", "https://openqa.opensuse.org/tests/1"])
+        );
+        assert!(parse_entries("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn export_round_trip() {
+        let entries = strings(&[
+            "https://build.opensuse.org/package/show/foo/bar",
+            "obs://devel:foo/bar",
+            r#"openqa:overview?distri=opensuse&build="quoted" \ slash"#,
+        ]);
+        let text = export_file("!a:example.org", &entries);
+        assert!(text.starts_with("# Subscriptions of !a:example.org"));
+        assert_eq!(parse_entries(&text).unwrap(), entries);
+        assert!(parse_entries(&export_file("!a:example.org", &[]))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn importable() {
+        assert!(looks_importable("sr#1234"));
+        assert!(looks_importable(
+            "https://build.opensuse.org/package/show/foo/bar"
+        ));
+        assert!(looks_importable(r#"[["!a:example.org", "sr#1234"]]"#));
+        assert!(looks_importable("default_subs = []"));
+        assert!(!looks_importable("os"));
+        assert!(!looks_importable("the old settings later"));
+    }
+}
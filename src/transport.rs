@@ -0,0 +1,252 @@
+use crate::matrix_api::{MatrixApi, MatrixError};
+use anyhow::Result;
+use matrix_bot_api::{ActiveBot, MessageType};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How often a rate-limited message is retried before giving up
+const MAX_ATTEMPTS: usize = 5;
+
+#[derive(Debug)]
+pub enum SendError {
+    /// The homeserver wants us to wait this long (M_LIMIT_EXCEEDED)
+    RateLimited(Duration),
+    Failed(anyhow::Error),
+}
+
+/// Whatever gets messages into a room
+pub trait Transport: Send + Sync {
+    fn send(&self, room: &str, plain: &str, html: &str) -> Result<(), SendError>;
+}
+
+/// A message the homeserver did not take yet
+struct Retry {
+    room: String,
+    plain: String,
+    html: String,
+    attempts: usize,
+    due: Instant,
+}
+
+/// Sends messages, keeping the ones the homeserver wants us to wait with for later.
+/// retry_due() sends them once it allows, so no thread sleeps in the meantime.
+pub struct RetryQueue {
+    transport: Arc<dyn Transport>,
+    queue: Mutex<VecDeque<Retry>>,
+}
+
+impl RetryQueue {
+    pub fn new(transport: Arc<dyn Transport>) -> Self {
+        RetryQueue {
+            transport,
+            queue: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Sends a message, or queues it if we are too fast.
+    /// Rooms with queued messages get new ones queued as well, to keep their order.
+    pub fn send(&self, room: &str, plain: &str, html: &str) {
+        let mut queue = match self.queue.lock() {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        let retry = Retry {
+            room: room.to_string(),
+            plain: plain.to_string(),
+            html: html.to_string(),
+            attempts: 0,
+            due: Instant::now(),
+        };
+        if queue.iter().any(|x| x.room == room) {
+            queue.push_back(retry);
+            return;
+        }
+        if let Some(retry) = self.attempt(retry, Instant::now()) {
+            queue.push_back(retry);
+        }
+    }
+
+    /// Sends the queued messages that are due by now. Returns how many are still queued.
+    pub fn retry_due(&self, now: Instant) -> usize {
+        let mut queue = match self.queue.lock() {
+            Ok(x) => x,
+            Err(_) => return 0,
+        };
+        while let Some(retry) = queue.pop_front() {
+            if retry.due > now {
+                queue.push_front(retry);
+                break;
+            }
+            // The limit is for all rooms, so the rest waits as well
+            if let Some(retry) = self.attempt(retry, now) {
+                queue.push_front(retry);
+                break;
+            }
+        }
+        queue.len()
+    }
+
+    /// Returns the message again if it has to be retried later
+    fn attempt(&self, mut retry: Retry, now: Instant) -> Option<Retry> {
+        match self.transport.send(&retry.room, &retry.plain, &retry.html) {
+            Ok(()) => None,
+            Err(SendError::RateLimited(wait)) => {
+                retry.attempts += 1;
+                if retry.attempts >= MAX_ATTEMPTS {
+                    println!(
+                        "ERROR! Giving up sending to {}, still rate limited",
+                        retry.room
+                    );
+                    return None;
+                }
+                println!("Rate limited in {}, retrying in {:?}", retry.room, wait);
+                retry.due = now + wait;
+                Some(retry)
+            }
+            Err(SendError::Failed(x)) => {
+                println!("ERROR! Could not send to {}: {:?}", retry.room, x);
+                None
+            }
+        }
+    }
+}
+
+impl Transport for MatrixApi {
+    fn send(&self, room: &str, plain: &str, html: &str) -> Result<(), SendError> {
+        self.send_message(room, plain, html)
+            .map_err(|x| match x.downcast_ref::<MatrixError>() {
+                Some(error) if error.errcode == "M_LIMIT_EXCEEDED" || error.status == 429 => {
+                    SendError::RateLimited(Duration::from_millis(
+                        error.retry_after_ms.unwrap_or(1000),
+                    ))
+                }
+                _ => SendError::Failed(x),
+            })
+    }
+}
+
+/// Fallback without a Matrix API login. Cannot see errors, so never retries.
+pub struct BotTransport(pub Mutex<ActiveBot>);
+
+impl Transport for BotTransport {
+    fn send(&self, room: &str, plain: &str, html: &str) -> Result<(), SendError> {
+        if let Ok(bot) = self.0.lock() {
+            bot.send_html_message(plain, html, room, MessageType::TextMessage);
+        }
+        Ok(())
+    }
+}
+
+/// Answers with the given results, then with Ok
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeTransport {
+    pub results: Mutex<VecDeque<Result<(), SendError>>>,
+    pub sent: Mutex<Vec<(String, String)>>,
+}
+
+#[cfg(test)]
+impl FakeTransport {
+    pub fn limited(count: usize) -> Arc<Self> {
+        let transport = FakeTransport::default();
+        for _ in 0..count {
+            transport
+                .results
+                .lock()
+                .unwrap()
+                .push_back(Err(SendError::RateLimited(Duration::from_secs(2))));
+        }
+        Arc::new(transport)
+    }
+
+    /// Room and plain text of the messages sent so far
+    pub fn sent(&self) -> Vec<(String, String)> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+impl Transport for FakeTransport {
+    fn send(&self, room: &str, plain: &str, _html: &str) -> Result<(), SendError> {
+        let result = self.results.lock().unwrap().pop_front().unwrap_or(Ok(()));
+        if result.is_ok() {
+            self.sent
+                .lock()
+                .unwrap()
+                .push((room.to_string(), plain.to_string()));
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(room: &str, plain: &str) -> (String, String) {
+        (room.to_string(), plain.to_string())
+    }
+
+    #[test]
+    fn sends_directly() {
+        let transport = FakeTransport::limited(0);
+        let queue = RetryQueue::new(transport.clone());
+        queue.send("!a", "one", "one");
+        assert_eq!(transport.sent(), vec![message("!a", "one")]);
+        assert_eq!(queue.retry_due(Instant::now()), 0);
+    }
+
+    #[test]
+    fn rate_limited_messages_wait() {
+        let transport = FakeTransport::limited(1);
+        let queue = RetryQueue::new(transport.clone());
+        queue.send("!a", "one", "one");
+        // Behind the first one, although the homeserver would take it
+        queue.send("!a", "two", "two");
+        queue.send("!b", "three", "three");
+        assert_eq!(transport.sent(), vec![message("!b", "three")]);
+
+        assert_eq!(queue.retry_due(Instant::now()), 2);
+        assert_eq!(transport.sent().len(), 1);
+        let later = Instant::now() + Duration::from_secs(3);
+        assert_eq!(queue.retry_due(later), 0);
+        assert_eq!(
+            transport.sent(),
+            vec![
+                message("!b", "three"),
+                message("!a", "one"),
+                message("!a", "two")
+            ]
+        );
+    }
+
+    #[test]
+    fn gives_up_eventually() {
+        let transport = FakeTransport::limited(MAX_ATTEMPTS);
+        let queue = RetryQueue::new(transport.clone());
+        queue.send("!a", "one", "one");
+        let mut now = Instant::now();
+        for _ in 1..MAX_ATTEMPTS {
+            now += Duration::from_secs(3);
+            queue.retry_due(now);
+        }
+        assert_eq!(queue.retry_due(now + Duration::from_secs(3)), 0);
+        assert!(transport.sent().is_empty());
+    }
+
+    #[test]
+    fn failures_are_dropped() {
+        let transport = FakeTransport::limited(0);
+        transport
+            .results
+            .lock()
+            .unwrap()
+            .push_back(Err(SendError::Failed(anyhow::anyhow!("no such room"))));
+        let queue = RetryQueue::new(transport.clone());
+        queue.send("!a", "one", "one");
+        assert_eq!(queue.retry_due(Instant::now()), 0);
+        queue.send("!a", "two", "two");
+        assert_eq!(transport.sent(), vec![message("!a", "two")]);
+    }
+}